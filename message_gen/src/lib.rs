@@ -1,7 +1,7 @@
 use proc_macro_error::{abort, proc_macro_error};
 use quote::{ToTokens, format_ident, quote};
 use syn::{
-    Attribute, Field, Fields, ItemEnum, LitStr, MetaList, parse_macro_input, spanned::Spanned,
+    Attribute, Field, Fields, ItemEnum, LitStr, MetaList, Type, parse_macro_input, spanned::Spanned,
 };
 
 extern crate proc_macro;
@@ -23,6 +23,7 @@ pub fn message_enum_source(
     let mut command_structs = Vec::new();
     let mut event_variants = Vec::new();
     let mut event_variant_types = Vec::new();
+    let mut event_variant_kinds = Vec::new();
     for variant in en.variants {
         let mut event_sub_variants = Vec::new();
         let Fields::Named(fields) = variant.fields else {
@@ -39,6 +40,8 @@ pub fn message_enum_source(
         let mut server_authoritative_fields = Vec::new();
         // Basically exists just for the user password.
         let mut secret_fields = Vec::new();
+        // Fields the server checks before creating, with the type saying which check failed.
+        let mut rejectable_fields = Vec::new();
         for field in fields.named {
             let mut is_id = false;
             let mut is_permanent = false;
@@ -91,6 +94,20 @@ pub fn message_enum_source(
                             is_other = false;
                             is_secret = true;
                         }
+                        "rejected_with" => {
+                            let Ok(ty) = meta
+                                .value()
+                                .and_then(|v| v.parse::<LitStr>())
+                                .and_then(|s| s.parse::<Type>())
+                            else {
+                                abort!(
+                                    field.span(),
+                                    "rejected_with must name the type saying why, i.e. rejected_with = \"PasswordRequirement\""
+                                );
+                            };
+                            let name = field.ident.as_ref().expect("fields are named").to_string();
+                            rejectable_fields.push((format_ident!("{}Rejected", upper_camel(&name)), ty));
+                        }
                         _ => {}
                     }
                     Ok(())
@@ -168,6 +185,8 @@ pub fn message_enum_source(
         let variant_ident = &variant.ident;
         let create_command_ident = format_ident!("{}CreateCommand", variant.ident);
         let create_command_response_ident = format_ident!("{}CreateCommandResponse", variant.ident);
+        let (rejected_variants, rejected_types): (Vec<_>, Vec<_>) =
+            rejectable_fields.into_iter().unzip();
         command_structs.push(quote! {
             #[derive(::serde::Deserialize, ::utoipa::ToSchema)]
             #[serde(rename_all = "camelCase")]
@@ -192,7 +211,14 @@ pub fn message_enum_source(
                 },
                 Error {
                     cause: Option<Cow<'static, str>>,
-                }
+                },
+                #(
+                    #rejected_variants {
+                        requirement: #rejected_types,
+                        /// Explains the requirement to the user, in their language.
+                        reason: Cow<'static, str>,
+                    },
+                )*
             }
         });
         event_sub_variants.push(quote! {
@@ -301,8 +327,12 @@ pub fn message_enum_source(
         event_variants.push(quote! {
             #variant_ident(#variant_ident)
         });
+        let variant_kind = lower_first(&variant.ident.to_string());
+        event_variant_kinds.push(quote! {
+            ServerEvent::#variant_ident(_) => #variant_kind
+        });
         event_variant_types.push(quote! {
-            #[derive(::serde::Serialize)]
+            #[derive(Debug, Clone, ::serde::Serialize, ::serde::Deserialize)]
             #[serde(rename_all = "camelCase")]
             pub enum #variant_ident {
                 #(#event_sub_variants,)*
@@ -324,18 +354,49 @@ pub fn message_enum_source(
                 use super::*;
                 #(#event_variant_types)*
             }
-            #[derive(::serde::Serialize)]
+            #[derive(Debug, Clone, ::serde::Serialize, ::serde::Deserialize)]
             #[serde(rename_all = "camelCase")]
             #[serde(tag = "serverEvent")]
             pub enum ServerEvent {
                 #(#event_variants),*
             }
+
+            impl ServerEvent {
+                /// The value of the `serverEvent` tag this event serializes with.
+                pub fn kind(&self) -> &'static str {
+                    match self {
+                        #(#event_variant_kinds,)*
+                    }
+                }
+            }
         }
     }
     .into_token_stream()
     .into()
 }
 
+/// Matches serde's `rename_all = "camelCase"` for PascalCase variant names.
+fn lower_first(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// `snake_case` field names as PascalCase variant names.
+fn upper_camel(s: &str) -> String {
+    s.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
 fn our_attrs<'a>(attrs: impl Iterator<Item = &'a Attribute>) -> impl Iterator<Item = &'a MetaList> {
     attrs.filter_map(|a| {
         a.path().is_ident("message_gen").then(|| {
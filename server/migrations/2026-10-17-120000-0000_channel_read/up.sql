@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE "channel_read"(
+    "user" UUID NOT NULL,
+    "channel" UUID NOT NULL,
+    "last_read" TIMESTAMP NOT NULL,
+    PRIMARY KEY ("user", "channel"),
+    FOREIGN KEY ("user") REFERENCES "user"("id"),
+    FOREIGN KEY ("channel") REFERENCES "channel"("id")
+);
\ No newline at end of file
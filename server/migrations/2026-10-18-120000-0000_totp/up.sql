@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE "totp"(
+    "user" UUID NOT NULL PRIMARY KEY,
+    "secret" BYTEA NOT NULL,
+    "confirmed" BOOLEAN NOT NULL,
+    "last_step" BIGINT,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
+
+CREATE TABLE "recovery_code"(
+    "code_hash" TEXT NOT NULL PRIMARY KEY,
+    "user" UUID NOT NULL,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
+
+CREATE TABLE "login_challenge"(
+    "token" TEXT NOT NULL PRIMARY KEY,
+    "user" UUID NOT NULL,
+    "expires" TIMESTAMP NOT NULL,
+    "attempts" INTEGER NOT NULL,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
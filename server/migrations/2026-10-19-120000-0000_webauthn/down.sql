@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "webauthn_challenge";
+DROP TABLE IF EXISTS "webauthn_credential";
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE "webauthn_credential"(
+    "id" BYTEA NOT NULL PRIMARY KEY,
+    "user" UUID NOT NULL,
+    "public_key" BYTEA NOT NULL,
+    "sign_count" BIGINT NOT NULL,
+    "name" TEXT NOT NULL,
+    "created" TIMESTAMP NOT NULL,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
+
+CREATE TABLE "webauthn_challenge"(
+    "challenge" TEXT NOT NULL PRIMARY KEY,
+    "user" UUID,
+    "registration" BOOLEAN NOT NULL,
+    "expires" TIMESTAMP NOT NULL,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
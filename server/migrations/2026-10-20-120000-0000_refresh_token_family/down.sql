@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS "refresh_token_family";
+ALTER TABLE "refresh_token" DROP COLUMN "rotated";
+ALTER TABLE "refresh_token" DROP COLUMN "family";
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE "refresh_token" ADD COLUMN "family" UUID;
+UPDATE "refresh_token" SET "family" = gen_random_uuid();
+ALTER TABLE "refresh_token" ALTER COLUMN "family" SET NOT NULL;
+ALTER TABLE "refresh_token" ADD COLUMN "rotated" BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX "refresh_token_family" ON "refresh_token"("family");
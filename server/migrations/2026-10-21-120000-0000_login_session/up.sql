@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE "login_session"(
+    "family" UUID NOT NULL PRIMARY KEY,
+    "user" UUID NOT NULL,
+    "client_name" TEXT,
+    "user_agent" TEXT,
+    "ip" TEXT,
+    "created" TIMESTAMP NOT NULL,
+    "last_used" TIMESTAMP NOT NULL,
+    FOREIGN KEY ("user") REFERENCES "user"("id")
+);
+
+INSERT INTO "login_session"("family", "user", "created", "last_used")
+SELECT DISTINCT "family", "user", now() AT TIME ZONE 'UTC', now() AT TIME ZONE 'UTC'
+FROM "refresh_token";
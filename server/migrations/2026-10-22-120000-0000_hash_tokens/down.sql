@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+-- The hashes can't be turned back into tokens, so everyone has to log in again.
+DELETE FROM "session";
+DELETE FROM "refresh_token";
+DELETE FROM "login_session";
+DELETE FROM "other_server_auth_token";
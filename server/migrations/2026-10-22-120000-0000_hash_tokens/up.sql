@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Tokens are now stored as keyed hashes. The secret isn't known here, so existing tokens can't be
+-- converted and everyone has to log in again.
+DELETE FROM "session";
+DELETE FROM "refresh_token";
+DELETE FROM "login_session";
+DELETE FROM "other_server_auth_token";
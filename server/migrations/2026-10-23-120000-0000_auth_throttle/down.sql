@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "audit_log";
+DROP TABLE IF EXISTS "auth_throttle";
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE "auth_throttle"(
+    "key" TEXT NOT NULL PRIMARY KEY,
+    "failures" INTEGER NOT NULL,
+    "last_failure" TIMESTAMP NOT NULL,
+    "locked_until" TIMESTAMP
+);
+
+-- Entries outlive the users they mention, so "user" is deliberately not a foreign key.
+CREATE TABLE "audit_log"(
+    "id" UUID NOT NULL PRIMARY KEY,
+    "time" TIMESTAMP NOT NULL,
+    "kind" TEXT NOT NULL,
+    "user" UUID,
+    "ip" TEXT,
+    "detail" TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "password_reset";
+ALTER TABLE "user" DROP COLUMN IF EXISTS "email";
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE "user" ADD COLUMN "email" TEXT UNIQUE;
+
+CREATE TABLE "password_reset"(
+    "token" TEXT NOT NULL PRIMARY KEY,
+    "user" UUID NOT NULL REFERENCES "user"("id"),
+    "created" TIMESTAMP NOT NULL,
+    "expires" TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "user" DROP COLUMN IF EXISTS "email_verified";
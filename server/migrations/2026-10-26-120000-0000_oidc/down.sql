@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "oidc_identity";
+DROP TABLE IF EXISTS "oidc_login";
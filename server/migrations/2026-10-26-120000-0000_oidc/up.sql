@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "oidc_login"(
+    "state" TEXT NOT NULL PRIMARY KEY,
+    "nonce" TEXT NOT NULL,
+    "code_verifier" TEXT NOT NULL,
+    "expires" TIMESTAMP NOT NULL
+);
+
+CREATE TABLE "oidc_identity"(
+    "issuer" TEXT NOT NULL,
+    "subject" TEXT NOT NULL,
+    "user" UUID NOT NULL REFERENCES "user"("id"),
+    PRIMARY KEY ("issuer", "subject")
+);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE "user_role"(
+    "user" UUID NOT NULL REFERENCES "user"("id"),
+    "role" TEXT NOT NULL,
+    PRIMARY KEY ("user", "role")
+);
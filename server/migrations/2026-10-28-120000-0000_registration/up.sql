@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE "user" ADD COLUMN "approved" BOOL NOT NULL DEFAULT TRUE;
+
+CREATE TABLE "invite"(
+    "code" TEXT NOT NULL PRIMARY KEY,
+    "created_by" UUID NOT NULL REFERENCES "user"("id"),
+    "uses_left" INTEGER NOT NULL,
+    "created" TIMESTAMP NOT NULL,
+    "expires" TIMESTAMP NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE "federated_identity"(
+    "server" TEXT NOT NULL,
+    "remote_user" UUID NOT NULL,
+    "user" UUID NOT NULL REFERENCES "user"("id"),
+    PRIMARY KEY ("server", "remote_user")
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "icon" DROP COLUMN IF EXISTS "owner";
+ALTER TABLE "community" DROP COLUMN IF EXISTS "owner";
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Communities and icons from before owners were recorded are left to admins.
+ALTER TABLE "community" ADD COLUMN "owner" UUID REFERENCES "user"("id");
+ALTER TABLE "icon" ADD COLUMN "owner" UUID REFERENCES "user"("id");
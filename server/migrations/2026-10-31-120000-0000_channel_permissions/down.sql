@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS "channel_role_overwrite";
+ALTER TABLE "channel" DROP COLUMN IF EXISTS "everyone_deny";
+ALTER TABLE "channel" DROP COLUMN IF EXISTS "everyone_allow";
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+ALTER TABLE "channel" ADD COLUMN "everyone_allow" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "channel" ADD COLUMN "everyone_deny" INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE "channel_role_overwrite"(
+    "channel" UUID NOT NULL REFERENCES "channel"("id"),
+    "role" TEXT NOT NULL,
+    "allow" INTEGER NOT NULL,
+    "deny" INTEGER NOT NULL,
+    PRIMARY KEY ("channel", "role")
+);
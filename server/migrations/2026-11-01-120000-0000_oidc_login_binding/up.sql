@@ -0,0 +1,3 @@
+-- Pending logins only last minutes, the ones started without a binding can't be finished anymore.
+DELETE FROM "oidc_login";
+ALTER TABLE "oidc_login" ADD COLUMN "binding" TEXT NOT NULL;
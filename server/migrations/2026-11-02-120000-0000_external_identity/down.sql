@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "user_role" DROP COLUMN IF EXISTS "external";
+DROP TABLE IF EXISTS "external_identity";
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE "external_identity"(
+    "username" TEXT NOT NULL PRIMARY KEY,
+    -- Deferred so the link can be claimed before the account it names is created.
+    "user" UUID NOT NULL UNIQUE REFERENCES "user"("id") DEFERRABLE INITIALLY DEFERRED
+);
+
+-- Roles the authenticator gave, replaced on each login. The others were granted here and stay.
+ALTER TABLE "user_role" ADD COLUMN "external" BOOL NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- The hashes can't be turned back into challenges, so open ones are dropped.
+DELETE FROM "login_challenge";
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Second factor challenges are now stored as keyed hashes, the few that are open are dropped.
+DELETE FROM "login_challenge";
@@ -1,20 +1,37 @@
-use crate::api::GlobalServerContext;
+use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     CategoryCreateCommand, CategoryCreateCommandResponse, CategoryDeleteCommand,
     CategoryDeleteCommandResponse, CategoryReadCommand, CategoryReadCommandResponse,
     CategoryUpdateCommand, CategoryUpdateCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
 
+use crate::app;
+use crate::app::category::sort_index_from_db;
+
 #[utoipa::path(post, path = "/category", responses((status = OK, body=CategoryCreateCommandResponse)))]
 
 pub async fn create_category(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<CategoryCreateCommand>,
 ) -> (StatusCode, Json<CategoryCreateCommandResponse>) {
-    todo!()
+    match app::category::create_category(state, user.id, &command).await {
+        Ok(category) => (
+            StatusCode::OK,
+            CategoryCreateCommandResponse::CreateOk {
+                id: category.id,
+                name: category.name,
+                sort_index: sort_index_from_db(category.sort_index),
+                community: command.community,
+            }
+            .into(),
+        ),
+        Err(e) => command_error_response!(CategoryCreateCommandResponse, e, "creating category"),
+    }
 }
 
 #[utoipa::path(get, path = "/category", responses((status = OK, body=CategoryReadCommandResponse)))]
@@ -28,15 +45,29 @@ pub async fn read_category(
 #[utoipa::path(patch, path = "/category", responses((status = OK, body=CategoryUpdateCommandResponse)))]
 pub async fn update_category(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<CategoryUpdateCommand>,
 ) -> (StatusCode, Json<CategoryUpdateCommandResponse>) {
-    todo!()
+    match app::category::update_category(state, user.id, &command).await {
+        Ok(()) => (
+            StatusCode::OK,
+            CategoryUpdateCommandResponse::UpdateOk.into(),
+        ),
+        Err(e) => command_error_response!(CategoryUpdateCommandResponse, e, "updating category"),
+    }
 }
 
 #[utoipa::path(delete, path = "/category", responses((status = OK, body=CategoryDeleteCommandResponse)))]
 pub async fn delete_category(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<CategoryDeleteCommand>,
 ) -> (StatusCode, Json<CategoryDeleteCommandResponse>) {
-    todo!()
+    match app::category::delete_category(state, user.id, command.id).await {
+        Ok(()) => (
+            StatusCode::OK,
+            CategoryDeleteCommandResponse::DeleteOk.into(),
+        ),
+        Err(e) => command_error_response!(CategoryDeleteCommandResponse, e, "deleting category"),
+    }
 }
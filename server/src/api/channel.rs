@@ -1,19 +1,40 @@
-use crate::api::GlobalServerContext;
+use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     ChannelCreateCommand, ChannelCreateCommandResponse, ChannelDeleteCommand,
     ChannelDeleteCommandResponse, ChannelReadCommand, ChannelReadCommandResponse,
     ChannelUpdateCommand, ChannelUpdateCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
 
+use crate::app;
+use crate::app::category::sort_index_from_db;
+use crate::app::channel::{ChannelMarkRead, ChannelMarkReadResponse};
+
 #[utoipa::path(post, path = "/channel", responses((status = OK, body=ChannelCreateCommandResponse)))]
 pub async fn create_channel(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<ChannelCreateCommand>,
 ) -> (StatusCode, Json<ChannelCreateCommandResponse>) {
-    todo!()
+    match app::channel::create_channel(state, user.id, &command).await {
+        Ok(channel) => (
+            StatusCode::OK,
+            ChannelCreateCommandResponse::CreateOk {
+                id: channel.id,
+                parent_category: command.parent_category,
+                name: channel.name,
+                permissions: command.permissions,
+                sort_index: sort_index_from_db(channel.sort_index),
+                community: command.community,
+                ty: channel.ty,
+            }
+            .into(),
+        ),
+        Err(e) => command_error_response!(ChannelCreateCommandResponse, e, "creating channel"),
+    }
 }
 
 #[utoipa::path(get, path = "/channel", responses((status = OK, body=ChannelReadCommandResponse)))]
@@ -27,15 +48,41 @@ pub async fn read_channel(
 #[utoipa::path(patch, path = "/channel", responses((status = OK, body=ChannelUpdateCommandResponse)))]
 pub async fn update_channel(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<ChannelUpdateCommand>,
 ) -> (StatusCode, Json<ChannelUpdateCommandResponse>) {
-    todo!()
+    match app::channel::update_channel(state, user.id, &command).await {
+        Ok(()) => (
+            StatusCode::OK,
+            ChannelUpdateCommandResponse::UpdateOk.into(),
+        ),
+        Err(e) => command_error_response!(ChannelUpdateCommandResponse, e, "updating channel"),
+    }
 }
 
 #[utoipa::path(delete, path = "/channel", responses((status = OK, body=ChannelDeleteCommandResponse)))]
 pub async fn delete_channel(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<ChannelDeleteCommand>,
 ) -> (StatusCode, Json<ChannelDeleteCommandResponse>) {
-    todo!()
+    match app::channel::delete_channel(state, user.id, command.id).await {
+        Ok(()) => (
+            StatusCode::OK,
+            ChannelDeleteCommandResponse::DeleteOk.into(),
+        ),
+        Err(e) => command_error_response!(ChannelDeleteCommandResponse, e, "deleting channel"),
+    }
+}
+
+#[utoipa::path(post, path = "/channel/read", responses((status = OK, body=ChannelMarkReadResponse)))]
+pub async fn mark_channel_read(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(command): Json<ChannelMarkRead>,
+) -> (StatusCode, Json<ChannelMarkReadResponse>) {
+    match app::channel::mark_channel_read(state, user.id, command.channel_id).await {
+        Ok(()) => (StatusCode::OK, ChannelMarkReadResponse::MarkReadOk.into()),
+        Err(e) => command_error_response!(ChannelMarkReadResponse, e, "marking channel read"),
+    }
 }
@@ -1,10 +1,10 @@
-use crate::api::GlobalServerContext;
 use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     CommunityCreateCommand, CommunityCreateCommandResponse, CommunityDeleteCommand,
     CommunityDeleteCommandResponse, CommunityReadCommand, CommunityReadCommandResponse,
     CommunityUpdateCommand, CommunityUpdateCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
 use crate::app;
 use axum::Json;
 use axum::extract::State;
@@ -15,10 +15,10 @@ use tracing::error;
 #[utoipa::path(post, path = "/community", responses((status = OK, body=CommunityCreateCommandResponse)))]
 pub async fn create_community(
     State(state): State<GlobalServerContext>,
-    _: SessionUser,
+    SessionUser(user): SessionUser,
     Json(command): Json<CommunityCreateCommand>,
 ) -> (StatusCode, Json<CommunityCreateCommandResponse>) {
-    let new_community = match app::community::create_community(state, &command).await {
+    let new_community = match app::community::create_community(state, user.id, &command).await {
         Ok(value) => value,
         Err(e) => {
             return {
@@ -55,15 +55,29 @@ pub async fn read_community(
 #[utoipa::path(patch, path = "/community", responses((status = OK, body=CommunityUpdateCommandResponse)))]
 pub async fn update_community(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<CommunityUpdateCommand>,
 ) -> (StatusCode, Json<CommunityUpdateCommandResponse>) {
-    todo!()
+    match app::community::update_community(state, user.id, &command).await {
+        Ok(()) => (
+            StatusCode::OK,
+            CommunityUpdateCommandResponse::UpdateOk.into(),
+        ),
+        Err(e) => command_error_response!(CommunityUpdateCommandResponse, e, "updating community"),
+    }
 }
 
 #[utoipa::path(delete, path = "/community", responses((status = OK, body=CommunityDeleteCommandResponse)))]
 pub async fn delete_community(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<CommunityDeleteCommand>,
 ) -> (StatusCode, Json<CommunityDeleteCommandResponse>) {
-    todo!()
+    match app::community::delete_community(state, user.id, command.id).await {
+        Ok(()) => (
+            StatusCode::OK,
+            CommunityDeleteCommandResponse::DeleteOk.into(),
+        ),
+        Err(e) => command_error_response!(CommunityDeleteCommandResponse, e, "deleting community"),
+    }
 }
@@ -0,0 +1,89 @@
+use crate::api::GlobalServerContext;
+use crate::api::login::{ClientDevice, SessionUser};
+use crate::app;
+use crate::app::email::{
+    ChangeEmail, ChangeEmailResponse, SendConfirmationResponse, VerifyEmail, VerifyEmailResponse,
+};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(post, path = "/email/send_confirmation", responses((status = OK, body=SendConfirmationResponse)))]
+pub async fn send_email_confirmation(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+) -> (StatusCode, Json<SendConfirmationResponse>) {
+    let resp = match app::email::try_send_confirmation(&state, &user).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error sending email confirmation {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                SendConfirmationResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        SendConfirmationResponse::Ok => StatusCode::OK,
+        SendConfirmationResponse::NoEmail | SendConfirmationResponse::AlreadyVerified => {
+            StatusCode::CONFLICT
+        }
+        SendConfirmationResponse::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        SendConfirmationResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/email/verify", responses((status = OK, body=VerifyEmailResponse)))]
+pub async fn verify_email(
+    State(state): State<GlobalServerContext>,
+    Json(verify): Json<VerifyEmail>,
+) -> (StatusCode, Json<VerifyEmailResponse>) {
+    let resp = match app::email::try_verify(&state, &verify).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error verifying email {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                VerifyEmailResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        VerifyEmailResponse::Ok | VerifyEmailResponse::ChangeApproved => StatusCode::OK,
+        VerifyEmailResponse::InvalidToken => StatusCode::UNAUTHORIZED,
+        VerifyEmailResponse::EmailTaken => StatusCode::CONFLICT,
+        VerifyEmailResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/email/change", responses((status = OK, body=ChangeEmailResponse)))]
+pub async fn change_email(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    ClientDevice(device): ClientDevice,
+    Json(change): Json<ChangeEmail>,
+) -> (StatusCode, Json<ChangeEmailResponse>) {
+    let resp = match app::email::try_change(&state, &user, &change, device.ip.as_deref()).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error changing email {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ChangeEmailResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        ChangeEmailResponse::Ok => StatusCode::OK,
+        ChangeEmailResponse::PasswordIncorrect => StatusCode::UNAUTHORIZED,
+        ChangeEmailResponse::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        ChangeEmailResponse::InvalidEmail => StatusCode::BAD_REQUEST,
+        ChangeEmailResponse::EmailTaken => StatusCode::CONFLICT,
+        ChangeEmailResponse::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ChangeEmailResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
@@ -0,0 +1,260 @@
+//! Wire encodings besides JSON for commands, responses and events.
+//!
+//! Every encoding carries the same data as the JSON form: values are first serialized to a
+//! [`serde_json::Value`] which is then written out in the chosen encoding, and read back the same
+//! way. REST clients pick an encoding with `Content-Type` and `Accept`, see [`negotiate`], the event
+//! stream and WebSocket with an `encoding` query parameter.
+
+use axum::body::{Body, to_bytes};
+use axum::extract::Request;
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::app;
+
+/// Largest request body that is transcoded, axum's default limit that JSON requests are held to.
+const REQUEST_BODY_LIMIT: usize = 2 * 1024 * 1024;
+/// Largest response body that is transcoded.
+const RESPONSE_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+/// The `encoding` query parameter of the event stream and WebSocket.
+#[derive(Debug, Default, Deserialize)]
+pub struct EncodingQuery {
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+impl Encoding {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MessagePack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        let essence = mime_type.split(';').next()?.trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The encoding of a body with `Content-Type` from `headers`.
+    fn of_content(headers: &HeaderMap) -> Option<Self> {
+        Self::from_mime_type(headers.get(CONTENT_TYPE)?.to_str().ok()?)
+    }
+
+    /// The first encoding listed in `Accept` that we support, quality values are not considered.
+    fn accepted(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(Self::from_mime_type)
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, app::Error> {
+        let value = serde_json::to_value(value)?;
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(&value)?,
+            Encoding::MessagePack => rmp_serde::to_vec_named(&value)?,
+            Encoding::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(&value, &mut out)?;
+                out
+            }
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, app::Error> {
+        let value: serde_json::Value = match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MessagePack => rmp_serde::from_slice(bytes)?,
+            Encoding::Cbor => ciborium::from_reader(bytes)?,
+        };
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Lets REST handlers keep speaking JSON. Request bodies in another encoding are converted to JSON
+/// before the handler sees them, and JSON responses are converted to the first encoding the
+/// client accepts.
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let accepted = Encoding::accepted(request.headers()).unwrap_or_default();
+    let request = match Encoding::of_content(request.headers()) {
+        Some(encoding) if encoding != Encoding::Json => {
+            match transcode_request(request, encoding).await {
+                Ok(request) => request,
+                Err(response) => return response,
+            }
+        }
+        _ => request,
+    };
+    let response = next.run(request).await;
+    if accepted == Encoding::Json
+        || Encoding::of_content(response.headers()) != Some(Encoding::Json)
+    {
+        return response;
+    }
+    transcode_response(response, accepted).await
+}
+
+async fn transcode_request(request: Request, encoding: Encoding) -> Result<Request, Response> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, REQUEST_BODY_LIMIT).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(StatusCode::PAYLOAD_TOO_LARGE.into_response()),
+    };
+    let json = match encoding
+        .decode::<serde_json::Value>(&bytes)
+        .and_then(|value| Ok(serde_json::to_vec(&value)?))
+    {
+        Ok(json) => json,
+        Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+    };
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(Encoding::Json.mime_type()),
+    );
+    parts.headers.insert(CONTENT_LENGTH, json.len().into());
+    Ok(Request::from_parts(parts, Body::from(json)))
+}
+
+async fn transcode_response(response: Response, encoding: Encoding) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let encoded = match to_bytes(body, RESPONSE_BODY_LIMIT)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| {
+            Encoding::Json
+                .decode::<serde_json::Value>(&bytes)
+                .and_then(|value| encoding.encode(&value))
+                .map_err(|e| e.to_string())
+        }) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            error!("error encoding response as {} {e}", encoding.mime_type());
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(encoding.mime_type()));
+    parts.headers.insert(CONTENT_LENGTH, encoded.len().into());
+    Response::from_parts(parts, Body::from(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, REQUEST_BODY_LIMIT, transcode_request};
+    use crate::api::message_enum::command::{MessageCreateCommand, ReactCreateCommandResponse};
+    use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+    use crate::app::{ChannelId, MessageId, UserId};
+    use axum::http::HeaderMap;
+    use axum::http::header::ACCEPT;
+    use chrono::Utc;
+    use serde_json::json;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+    #[test]
+    fn server_event_round_trips() {
+        let event = ServerEvent::Message(sub_variant::Message::Create {
+            id: MessageId::new(),
+            channel_id: ChannelId::new(),
+            content: "hello".to_string(),
+            author: UserId::new(),
+            timestamp: Utc::now(),
+        });
+        let json = serde_json::to_value(&event).unwrap();
+        for encoding in ENCODINGS {
+            let encoded = encoding.encode(&event).unwrap();
+            let decoded: ServerEvent = encoding.decode(&encoded).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                json,
+                "{encoding:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn response_matches_json() {
+        let response = ReactCreateCommandResponse::CreateOk {
+            message_id: MessageId::new(),
+            emoji: "🌲".to_string(),
+            user_id: UserId::new(),
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        for encoding in ENCODINGS {
+            let encoded = encoding.encode(&response).unwrap();
+            let decoded: serde_json::Value = encoding.decode(&encoded).unwrap();
+            assert_eq!(decoded, json, "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn command_decodes() {
+        let channel_id = ChannelId::new();
+        let json = json!({ "channelId": channel_id, "content": "hello" });
+        for encoding in ENCODINGS {
+            let encoded = encoding.encode(&json).unwrap();
+            let command: MessageCreateCommand = encoding.decode(&encoded).unwrap();
+            assert_eq!(command.channel_id, channel_id);
+            assert_eq!(command.content, "hello");
+        }
+    }
+
+    #[test]
+    fn accept_picks_first_supported() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            "text/html, application/cbor;q=0.9, application/json"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(Encoding::accepted(&headers), Some(Encoding::Cbor));
+    }
+
+    #[tokio::test]
+    async fn large_requests_refused_like_json() {
+        let request = |size| {
+            let body = Encoding::MessagePack.encode(&"a".repeat(size)).unwrap();
+            axum::extract::Request::new(axum::body::Body::from(body))
+        };
+        assert!(
+            transcode_request(request(1024), Encoding::MessagePack)
+                .await
+                .is_ok()
+        );
+        let Err(response) =
+            transcode_request(request(REQUEST_BODY_LIMIT), Encoding::MessagePack).await
+        else {
+            panic!("expected the request to be refused");
+        };
+        assert_eq!(response.status(), axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
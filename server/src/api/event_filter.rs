@@ -0,0 +1,88 @@
+//! Keeps events about the content of channels a user can't read from reaching them, even though
+//! they are published to the whole community.
+
+use std::collections::HashMap;
+
+use async_nats::HeaderMap;
+use tracing::error;
+
+use crate::api::GlobalServerContext;
+use crate::api::event_publisher;
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::app::channel::can_read;
+use crate::app::{ChannelId, UserId};
+
+/// One connection's view of which channels its user may read. Answers are cached until an event
+/// that could change them passes through [`ReadableChannels::invalidate`].
+pub(super) struct ReadableChannels {
+    user: UserId,
+    cache: HashMap<ChannelId, bool>,
+}
+
+impl ReadableChannels {
+    pub(super) fn new(user: UserId) -> Self {
+        Self {
+            user,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Whether an event published with `headers` may be delivered. Events that aren't about a
+    /// channel's content always may.
+    pub(super) async fn allows(
+        &mut self,
+        state: &GlobalServerContext,
+        headers: Option<&HeaderMap>,
+    ) -> bool {
+        let Some(channel) = event_publisher::channel(headers) else {
+            return true;
+        };
+        if let Some(readable) = self.cache.get(&channel) {
+            return *readable;
+        }
+        let readable = match state.connection_pool.get().await {
+            Ok(mut conn) => can_read(conn.as_mut(), self.user, channel).await,
+            Err(e) => {
+                error!("error getting database connection from pool {e}");
+                return false;
+            }
+        };
+        match readable {
+            Ok(readable) => {
+                self.cache.insert(channel, readable);
+                readable
+            }
+            // Not cached so the next event tries again.
+            Err(e) => {
+                error!("error checking if {} can read {channel} {e}", self.user);
+                false
+            }
+        }
+    }
+
+    /// Forgets the answers `event` may have changed. Permissions are set on channels and follow from
+    /// membership and roles, so those are the events to watch. Role changes come as an update of
+    /// the user, see [`publish_role_change`](crate::app::user::publish_role_change).
+    pub(super) fn invalidate(&mut self, event: &ServerEvent) {
+        match event {
+            ServerEvent::Channel(
+                sub_variant::Channel::Update { id, .. } | sub_variant::Channel::Delete { id },
+            ) => {
+                self.cache.remove(id);
+            }
+            ServerEvent::UserCommunity(
+                sub_variant::UserCommunity::Create { user, .. }
+                | sub_variant::UserCommunity::Delete { user, .. },
+            ) if *user == self.user => {
+                self.cache.clear();
+            }
+            ServerEvent::User(sub_variant::User::Update { id, .. }) if *id == self.user => {
+                self.cache.clear();
+            }
+            ServerEvent::Community(sub_variant::Community::Delete { .. }) => {
+                self.cache.clear();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Durable history of [`ServerEvent`](super::message_enum::server_event::ServerEvent)s, kept in a
+//! JetStream stream so a client that lost its event stream can resume where it left off.
+//!
+//! The stream numbers every event it stores and republishes it on the live community subject with the
+//! number in the `Nats-Sequence` header, see [`subject`]. Numbers increase across the whole stream, and
+//! therefore within every community, so a single number is all a client needs to resume from.
+
+use std::time::Duration;
+
+use async_nats::Message;
+use async_nats::jetstream::consumer::{AckPolicy, DeliverPolicy, pull};
+use async_nats::jetstream::stream::{Config, Republish};
+use async_nats::jetstream::{self, Context};
+
+use crate::api::subject;
+use crate::app::{self, CommunityId};
+
+pub const EVENT_STREAM: &str = "ASPEN_EVENTS";
+
+/// How long a replay consumer survives without being read from.
+const REPLAY_CONSUMER_INACTIVE_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// Creates the event stream, or brings an existing one in line with the current config.
+pub async fn ensure_stream(jetstream: &Context, retention: Duration) -> Result<(), app::Error> {
+    jetstream
+        .create_or_update_stream(Config {
+            name: EVENT_STREAM.to_string(),
+            subjects: vec![subject::STORED_COMMUNITY_EVENTS_WILDCARD.to_string()],
+            max_age: retention,
+            republish: Some(Republish {
+                source: subject::STORED_COMMUNITY_EVENTS_WILDCARD.to_string(),
+                destination: subject::COMMUNITY_EVENTS_REPUBLISH.to_string(),
+                headers_only: false,
+            }),
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+/// The event log sequence number of a live event.
+pub fn sequence(message: &Message) -> Option<u64> {
+    message
+        .headers
+        .as_ref()?
+        .get(async_nats::header::NATS_SEQUENCE)?
+        .as_str()
+        .parse()
+        .ok()
+}
+
+pub enum Replay {
+    /// Every stored event of the requested communities after the requested sequence number, read it
+    /// with `fetch` until a batch comes back empty.
+    Events(Box<jetstream::consumer::Consumer<pull::Config>>),
+    /// None of the requested communities can have events, there were none requested.
+    Nothing,
+    /// Events after the requested sequence number have already expired. The client needs to fetch
+    /// its state again, after which it is up to date with `last_sequence`.
+    ResyncRequired { last_sequence: u64 },
+}
+
+/// Starts replaying the events of `communities` published after `after`.
+pub async fn replay(
+    jetstream: &Context,
+    communities: impl IntoIterator<Item = CommunityId>,
+    after: u64,
+) -> Result<Replay, async_nats::Error> {
+    let mut stream = jetstream.get_stream(EVENT_STREAM).await?;
+    let state = &stream.info().await?.state;
+    let (first_sequence, last_sequence) = (state.first_sequence, state.last_sequence);
+    if after.saturating_add(1) < first_sequence {
+        return Ok(Replay::ResyncRequired { last_sequence });
+    }
+    let filter_subjects = communities
+        .into_iter()
+        .map(subject::stored_community_events)
+        .collect::<Vec<_>>();
+    // An empty filter would replay every community.
+    if filter_subjects.is_empty() {
+        return Ok(Replay::Nothing);
+    }
+    let consumer = stream
+        .create_consumer(pull::Config {
+            filter_subjects,
+            deliver_policy: DeliverPolicy::ByStartSequence {
+                start_sequence: after + 1,
+            },
+            ack_policy: AckPolicy::None,
+            inactive_threshold: REPLAY_CONSUMER_INACTIVE_THRESHOLD,
+            ..Default::default()
+        })
+        .await?;
+    Ok(Replay::Events(Box::new(consumer)))
+}
@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use async_nats::HeaderMap;
+use async_nats::jetstream::Context;
+use bytes::Bytes;
+use tokio::sync::RwLock;
+use tracing::error;
+
+use crate::api::message_enum::server_event::ServerEvent;
+use crate::api::subject;
+use crate::app::{self, ChannelId, CommunityId, UserId};
+use crate::nats_connection_manager::NatsConnectionManager;
+
+/// Sends [`ServerEvent`]s to every server node with a client interested in them. Publish only after
+/// the change an event describes has been committed to the database, clients that receive an event
+/// may immediately read the record back.
+#[derive(Clone)]
+pub struct EventPublisher {
+    sink: EventSink,
+}
+
+#[derive(Clone)]
+// Only tests have a second, smaller, variant.
+#[allow(clippy::large_enum_variant)]
+enum EventSink {
+    Nats {
+        nats_connection_manager: Arc<RwLock<NatsConnectionManager>>,
+        jetstream: Context,
+    },
+    #[cfg(test)]
+    Recorder(Recorded),
+}
+
+/// Subject, headers and payload of everything a recording publisher was asked to publish.
+#[cfg(test)]
+type Recorded = Arc<std::sync::Mutex<Vec<(String, HeaderMap, Bytes)>>>;
+
+/// Header naming the channel an event is about, for events on a channel's content such as
+/// messages. Lets event streams check who may read it without looking anything up.
+pub const CHANNEL_HEADER: &str = "Aspen-Channel";
+
+/// The channel an event is about, see [`CHANNEL_HEADER`].
+pub fn channel(headers: Option<&HeaderMap>) -> Option<ChannelId> {
+    headers?
+        .get(CHANNEL_HEADER)?
+        .as_str()
+        .parse::<uuid::Uuid>()
+        .ok()
+        .map(ChannelId)
+}
+
+impl EventPublisher {
+    pub fn new(
+        nats_connection_manager: Arc<RwLock<NatsConnectionManager>>,
+        jetstream: Context,
+    ) -> Self {
+        Self {
+            sink: EventSink::Nats {
+                nats_connection_manager,
+                jetstream,
+            },
+        }
+    }
+
+    /// Publishes `event` to every member of `community`. Returns the event's sequence number in the
+    /// [event log](super::event_log) if it was published.
+    pub async fn publish(&self, community: CommunityId, event: &ServerEvent) -> Option<u64> {
+        self.publish_with_headers(community, HeaderMap::new(), event)
+            .await
+    }
+
+    /// Publishes `event` about the content of `channel` to every member of `community` that may
+    /// read `channel`.
+    pub async fn publish_in_channel(
+        &self,
+        community: CommunityId,
+        channel: ChannelId,
+        event: &ServerEvent,
+    ) -> Option<u64> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CHANNEL_HEADER, channel.0.to_string());
+        self.publish_with_headers(community, headers, event).await
+    }
+
+    async fn publish_with_headers(
+        &self,
+        community: CommunityId,
+        headers: HeaderMap,
+        event: &ServerEvent,
+    ) -> Option<u64> {
+        // Events are published after the database commit, a failure here can't undo the change, so
+        // it is logged rather than reported to the client that made it.
+        match self.try_publish(community, headers, event).await {
+            Ok(sequence) => Some(sequence),
+            Err(e) => {
+                error!("error publishing {} event to {community} {e}", event.kind());
+                None
+            }
+        }
+    }
+
+    /// Publishes a change to `user`'s membership in `community`. Both the community and the user are
+    /// told, the user may not be subscribed to the community yet or may be about to unsubscribe.
+    pub async fn publish_membership(
+        &self,
+        community: CommunityId,
+        user: UserId,
+        event: &ServerEvent,
+    ) {
+        let Some(sequence) = self.publish(community, event).await else {
+            return;
+        };
+        if let Err(e) = self.try_publish_to_user(user, sequence, event).await {
+            error!("error publishing {} event to {user} {e}", event.kind());
+        }
+    }
+
+    async fn try_publish(
+        &self,
+        community: CommunityId,
+        headers: HeaderMap,
+        event: &ServerEvent,
+    ) -> Result<u64, app::Error> {
+        let subject = subject::stored_community_events(community);
+        let payload = Bytes::from(serde_json::to_vec(event)?);
+        match &self.sink {
+            EventSink::Nats { jetstream, .. } => Ok(jetstream
+                .publish_with_headers(subject, headers, payload)
+                .await?
+                .await?
+                .sequence),
+            #[cfg(test)]
+            EventSink::Recorder(recorded) => {
+                let mut recorded = recorded.lock().unwrap();
+                recorded.push((subject, headers, payload));
+                Ok(recorded.len() as u64)
+            }
+        }
+    }
+
+    async fn try_publish_to_user(
+        &self,
+        user: UserId,
+        sequence: u64,
+        event: &ServerEvent,
+    ) -> Result<(), app::Error> {
+        let subject = subject::user_events(user);
+        let payload = Bytes::from(serde_json::to_vec(event)?);
+        let mut headers = HeaderMap::new();
+        headers.insert(async_nats::header::NATS_SEQUENCE, sequence.to_string());
+        match &self.sink {
+            EventSink::Nats {
+                nats_connection_manager,
+                ..
+            } => {
+                nats_connection_manager
+                    .read()
+                    .await
+                    .publish_with_headers(subject, headers, payload)
+                    .await?
+            }
+            #[cfg(test)]
+            EventSink::Recorder(recorded) => {
+                recorded.lock().unwrap().push((subject, headers, payload))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl EventPublisher {
+    /// A publisher that keeps everything it publishes for inspection.
+    pub fn recorder() -> (Self, Recorded) {
+        let recorded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        (
+            Self {
+                sink: EventSink::Recorder(recorded.clone()),
+            },
+            recorded,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventPublisher;
+    use crate::api::message_enum::command::ReactCreateCommand;
+    use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+    use crate::api::subject;
+    use crate::app::react::React;
+    use crate::app::{ChannelId, CommunityId, MessageId, UserId};
+
+    #[tokio::test]
+    async fn react_create_published_to_community() {
+        let (publisher, recorded) = EventPublisher::recorder();
+        let community = CommunityId::new();
+        let user = UserId::new();
+        let command = ReactCreateCommand {
+            message_id: MessageId::new(),
+            emoji: "🌲".to_string(),
+        };
+        let react = React::new(&command, user);
+        publisher.publish(community, &react.created_event()).await;
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        let (subject, _, payload) = &recorded[0];
+        assert_eq!(*subject, subject::stored_community_events(community));
+        let ServerEvent::React(sub_variant::React::Create {
+            message_id,
+            emoji,
+            user_id,
+        }) = serde_json::from_slice(payload).unwrap()
+        else {
+            panic!("expected a react create event");
+        };
+        assert_eq!(message_id, command.message_id);
+        assert_eq!(emoji, command.emoji);
+        assert_eq!(user_id, user);
+    }
+
+    #[tokio::test]
+    async fn channel_content_carries_channel() {
+        let (publisher, recorded) = EventPublisher::recorder();
+        let community = CommunityId::new();
+        let channel = ChannelId::new();
+        let event = ServerEvent::Message(sub_variant::Message::Delete {
+            id: MessageId::new(),
+        });
+        publisher
+            .publish_in_channel(community, channel, &event)
+            .await;
+
+        let recorded = recorded.lock().unwrap();
+        let (subject, headers, _) = &recorded[0];
+        assert_eq!(*subject, subject::stored_community_events(community));
+        assert_eq!(super::channel(Some(headers)), Some(channel));
+    }
+
+    #[tokio::test]
+    async fn membership_published_to_community_and_user() {
+        let (publisher, recorded) = EventPublisher::recorder();
+        let community = CommunityId::new();
+        let user = UserId::new();
+        let event =
+            ServerEvent::UserCommunity(sub_variant::UserCommunity::Create { community, user });
+        publisher.publish_membership(community, user, &event).await;
+
+        let subjects = recorded
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(subject, _, _)| subject.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subjects,
+            [
+                subject::stored_community_events(community),
+                subject::user_events(user)
+            ]
+        );
+    }
+}
@@ -1,37 +1,552 @@
-use std::sync::Arc;
+use std::convert::Infallible;
 
-use crate::app::CommunityId;
-use anyhow::Error;
+use crate::api::encoding::{Encoding, EncodingQuery};
+use crate::api::event_filter::ReadableChannels;
+use crate::api::event_log::{self, Replay};
+use crate::api::login::Session;
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::api::stream_compression::StreamCompression;
+use crate::api::{GlobalServerContext, metrics, subject};
+use crate::app::ready::{Ready, load_ready};
+use crate::app::{self, CommunityId, UserId};
+use crate::aspen_config::aspen_config;
+use crate::database::schema::community_user;
+use async_nats::Message;
+use async_nats::jetstream::consumer::{Consumer, pull};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::{
-    Sse,
+    IntoResponse, Response, Sse,
     sse::{Event, KeepAlive},
 };
-use futures_util::{Stream, StreamExt};
-use tokio_stream::{StreamMap, wrappers::BroadcastStream};
-
-use super::message_enum::server_event::ServerEvent;
-
-pub async fn event_stream() -> Sse<impl Stream<Item = Result<Event, Error>>> {
-    let stream: StreamMap<CommunityId, BroadcastStream<Arc<ServerEvent>>> = StreamMap::new();
-
-    // Subscribe to relevant community mailboxes.
-    // use schema::community_user;
-    // let mailbox_subscriptions = community_user::table
-    //     .select(community_user::community)
-    //     .filter(community_user::user.eq(user_id))
-    //     .load(&mut conn)?
-    //     .into_iter()
-    //     .map(|c: Uuid| SubscribeCommand {
-    //         community: CommunityId::from(c),
-    //         desire_subscribed: true,
-    //     })
-    //     .collect();
-    // let mut sess_context_write = session_context.write().await;
-    // sess_context_write.signed_in_user = Some(user_id.into());
-    // sess_context_write
-    //     .community_mailbox_subscribe_commands
-    //     .send(mailbox_subscriptions)
-    //     .await;
-
-    Sse::new(stream.map(|e| todo!())).keep_alive(KeepAlive::default())
+use base64::{Engine, prelude::BASE64_STANDARD};
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::RunQueryDsl;
+use futures_util::{Stream, StreamExt, future, stream};
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio_stream::StreamMap;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tracing::{error, warn};
+
+/// How many stored events are read from the event log at a time while replaying.
+const REPLAY_BATCH_SIZE: usize = 256;
+
+/// SSE event name telling the client that the events it missed are no longer available.
+const RESYNC_REQUIRED: &str = "resyncRequired";
+
+/// SSE event name of the [`Ready`] snapshot.
+const READY: &str = "ready";
+
+/// SSE event name of the last event sent to a client that is being disconnected for falling behind.
+const RESYNC: &str = "resync";
+
+/// A new stream starts with a `ready` event holding a [`Ready`] snapshot of the user's
+/// communities, followed by live events.
+///
+/// Every event carries its [event log](event_log) sequence number as its SSE id. A client that
+/// reconnects with `Last-Event-ID` first receives the events of its communities it missed, or a
+/// `resyncRequired` event followed by a new `ready` event if they have expired, then live events.
+///
+/// Event data is JSON unless another encoding is picked with the `encoding` query parameter,
+/// `msgpack` or `cbor`, whose data is base64 encoded.
+///
+/// The stream is compressed with zstd or deflate when the client accepts either, flushed after
+/// every event.
+///
+/// The stream ends when its session is revoked, for example by logging out.
+///
+/// A client that falls too far behind is sent a `resync` event with the reason and disconnected,
+/// it can resume from the id of the last event it received.
+pub async fn event_stream(
+    State(state): State<GlobalServerContext>,
+    Session { user, family }: Session,
+    Query(EncodingQuery { encoding }): Query<EncodingQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let registered = match state.connection_pool.get().await {
+        Ok(mut conn) => state.live_sessions.register(conn.as_mut(), family).await,
+        Err(e) => Err(e.into()),
+    };
+    let mut live = match registered {
+        Ok(live) => live,
+        Err(e) => {
+            error!("error registering event stream for {} {e}", user.id);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+        }
+    };
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let subscriptions = match EventSubscriptions::new(&state, user.id).await {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            error!("error subscribing event stream for {} {e}", user.id);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+        }
+    };
+    // Live events are already being buffered by the subscriptions, so nothing published while the
+    // replay is read falls in between.
+    let replay = match last_event_id {
+        Some(after) => match event_log::replay(
+            &state.jetstream,
+            subscriptions.communities.keys().copied(),
+            after,
+        )
+        .await
+        {
+            Ok(replay) => Some(replay),
+            Err(e) => {
+                error!("error replaying events for {} {e}", user.id);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+            }
+        },
+        None => None,
+    };
+    let ready = match replay {
+        None | Some(Replay::ResyncRequired { .. }) => match subscriptions.ready(&state).await {
+            Ok(ready) => Some(ready),
+            Err(e) => {
+                error!("error loading ready snapshot for {} {e}", user.id);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+            }
+        },
+        Some(Replay::Events(_) | Replay::Nothing) => None,
+    };
+    let (outbox, inbox) = mailbox().await;
+    tokio::spawn(subscriptions.forward(state, replay, ready, outbox));
+    let events = inbox
+        .into_stream()
+        .take_until(async move { live.revoked().await })
+        .filter_map(move |delivery| {
+            future::ready(delivery.into_sse(encoding).map(Ok::<_, Infallible>))
+        });
+    let response = Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response();
+    Ok(match StreamCompression::negotiate(&headers) {
+        Some(compression) => compression.compress(response),
+        None => response,
+    })
+}
+
+/// The channel between [`EventSubscriptions::forward`] and the client, holding the configured
+/// number of deliveries.
+pub(super) async fn mailbox() -> (Outbox, Inbox) {
+    channel(aspen_config().await.event_mailbox_size)
+}
+
+fn channel(size: usize) -> (Outbox, Inbox) {
+    let (deliveries, receiver) = mpsc::channel(size);
+    let (resync, resync_receiver) = oneshot::channel();
+    (
+        Outbox { deliveries, resync },
+        Inbox {
+            deliveries: receiver,
+            resync: Some(resync_receiver),
+        },
+    )
+}
+
+/// The sending half of a [`mailbox`].
+pub(super) struct Outbox {
+    deliveries: mpsc::Sender<Delivery>,
+    /// Kept apart from the deliveries so a full mailbox never keeps the client from learning why
+    /// it is disconnected.
+    resync: oneshot::Sender<ResyncReason>,
+}
+
+impl Outbox {
+    /// Tells the client it is about to be disconnected for falling behind, once the deliveries
+    /// already in the mailbox are read.
+    fn resync(self, reason: ResyncReason) {
+        metrics::count_event_stream_drop(reason);
+        let _ = self.resync.send(reason);
+    }
+}
+
+/// The receiving half of a [`mailbox`].
+pub(super) struct Inbox {
+    deliveries: mpsc::Receiver<Delivery>,
+    resync: Option<oneshot::Receiver<ResyncReason>>,
+}
+
+impl Inbox {
+    /// The next delivery, ending with a [`Delivery::Resync`] if the client fell behind.
+    pub(super) async fn recv(&mut self) -> Option<Delivery> {
+        if let Some(delivery) = self.deliveries.recv().await {
+            return Some(delivery);
+        }
+        // Not taken until it resolves, so that being cancelled while waiting for it loses nothing.
+        let reason = self.resync.as_mut()?.await;
+        self.resync = None;
+        Some(Delivery::Resync {
+            reason: reason.ok()?,
+        })
+    }
+
+    fn into_stream(self) -> impl Stream<Item = Delivery> {
+        stream::unfold(self, |mut inbox| async move {
+            inbox.recv().await.map(|delivery| (delivery, inbox))
+        })
+    }
+}
+
+/// Why a client was sent a [`Delivery::Resync`] and disconnected.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) enum ResyncReason {
+    /// The client didn't read its events quickly enough.
+    MailboxFull,
+    /// This server node didn't forward events to the client quickly enough.
+    Lagged,
+}
+
+/// Something an event stream sends its client.
+pub(super) enum Delivery {
+    Event {
+        sequence: u64,
+        event: ServerEvent,
+    },
+    /// The events the client asked to resume from have expired, see [`Replay::ResyncRequired`].
+    ResyncRequired {
+        last_sequence: u64,
+    },
+    Ready(Box<Ready>),
+    /// The last delivery before disconnecting a client that fell behind.
+    Resync {
+        reason: ResyncReason,
+    },
+}
+
+impl Delivery {
+    fn into_sse(self, encoding: Encoding) -> Option<Event> {
+        match self {
+            Delivery::Event { sequence, event } => sse_data(encoding, &event)
+                .inspect_err(|e| error!("error serializing server event {e}"))
+                .ok()
+                .map(|data| {
+                    Event::default()
+                        .event(event.kind())
+                        .id(sequence.to_string())
+                        .data(data)
+                }),
+            Delivery::ResyncRequired { last_sequence } => Some(
+                Event::default()
+                    .event(RESYNC_REQUIRED)
+                    .id(last_sequence.to_string())
+                    .data(""),
+            ),
+            Delivery::Ready(ready) => sse_data(encoding, &ready)
+                .inspect_err(|e| error!("error serializing ready snapshot {e}"))
+                .ok()
+                .map(|data| Event::default().event(READY).data(data)),
+            Delivery::Resync { reason } => {
+                sse_data(encoding, &serde_json::json!({ "reason": reason }))
+                    .inspect_err(|e| error!("error serializing resync {e}"))
+                    .ok()
+                    .map(|data| Event::default().event(RESYNC).data(data))
+            }
+        }
+    }
+}
+
+/// SSE data is text, so binary encodings are sent base64 encoded.
+fn sse_data<T: Serialize>(encoding: Encoding, value: &T) -> Result<String, app::Error> {
+    let bytes = encoding.encode(value)?;
+    Ok(match encoding {
+        Encoding::Json => String::from_utf8(bytes).expect("JSON is UTF-8"),
+        Encoding::MessagePack | Encoding::Cbor => BASE64_STANDARD.encode(bytes),
+    })
+}
+
+/// The NATS subjects a single event stream is listening to, and what it filters out of them.
+pub(super) struct EventSubscriptions {
+    user: UserId,
+    /// Membership changes for `user`, this is how we learn about communities to subscribe to.
+    membership: BroadcastStream<Message>,
+    communities: StreamMap<CommunityId, BroadcastStream<Message>>,
+    /// Live events up to this sequence number were already sent, or made obsolete by a resync.
+    replayed_through: u64,
+    readable: ReadableChannels,
+}
+
+impl EventSubscriptions {
+    pub(super) async fn new(state: &GlobalServerContext, user: UserId) -> Result<Self, app::Error> {
+        // Subscribe to membership changes before reading memberships, otherwise a community joined
+        // in between would never be subscribed to.
+        let membership = state
+            .nats_connection_manager
+            .write()
+            .await
+            .subscribe(subject::user_events(user))
+            .await?;
+        let mut conn = state.connection_pool.get().await?;
+        let communities: Vec<CommunityId> = community_user::table
+            .select(community_user::community)
+            .filter(community_user::user.eq(user))
+            .load(conn.as_mut())
+            .await?;
+        let mut subscriptions = Self {
+            user,
+            membership: BroadcastStream::new(membership),
+            communities: StreamMap::new(),
+            replayed_through: 0,
+            readable: ReadableChannels::new(user),
+        };
+        for community in communities {
+            subscriptions.subscribe(state, community).await?;
+        }
+        Ok(subscriptions)
+    }
+
+    async fn subscribe(
+        &mut self,
+        state: &GlobalServerContext,
+        community: CommunityId,
+    ) -> Result<(), app::Error> {
+        if self.communities.contains_key(&community) {
+            return Ok(());
+        }
+        let receiver = state
+            .nats_connection_manager
+            .write()
+            .await
+            .subscribe(subject::community_events(community))
+            .await?;
+        self.communities
+            .insert(community, BroadcastStream::new(receiver));
+        Ok(())
+    }
+
+    /// Loads the user's [`Ready`] snapshot. Done after subscribing so that every change the
+    /// snapshot misses arrives as a live event, some changes may be both in it and delivered.
+    pub(super) async fn ready(&self, state: &GlobalServerContext) -> Result<Ready, app::Error> {
+        let mut conn = state.connection_pool.get().await?;
+        Ok(load_ready(conn.as_mut(), self.user).await?)
+    }
+
+    /// Pumps events into `outbox` until either the client goes away or falls too far behind.
+    pub(super) async fn forward(
+        mut self,
+        state: GlobalServerContext,
+        replay: Option<Replay>,
+        ready: Option<Ready>,
+        outbox: Outbox,
+    ) {
+        let sender = outbox.deliveries.clone();
+        match replay {
+            Some(Replay::Events(consumer)) => {
+                if let Err(e) = self.replay(&state, consumer, &sender).await {
+                    error!("error replaying events for {} {e}", self.user);
+                    return;
+                }
+            }
+            Some(Replay::ResyncRequired { last_sequence }) => {
+                if sender
+                    .send(Delivery::ResyncRequired { last_sequence })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                self.replayed_through = last_sequence;
+            }
+            Some(Replay::Nothing) | None => {}
+        }
+        if let Some(ready) = ready
+            && sender.send(Delivery::Ready(Box::new(ready))).await.is_err()
+        {
+            return;
+        }
+        loop {
+            let (message, from_membership) = tokio::select! {
+                _ = sender.closed() => break,
+                message = self.membership.next() => match message {
+                    Some(message) => (message, true),
+                    None => break,
+                },
+                Some((_, message)) = self.communities.next() => (message, false),
+            };
+            let message = match message {
+                Ok(message) => message,
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    warn!(
+                        "event stream for {} missed {missed} events, disconnecting",
+                        self.user
+                    );
+                    outbox.resync(ResyncReason::Lagged);
+                    return;
+                }
+            };
+            let Some(sequence) = event_log::sequence(&message) else {
+                error!(
+                    "server event without a sequence number on {}",
+                    message.subject
+                );
+                continue;
+            };
+            if sequence <= self.replayed_through {
+                continue;
+            }
+            let Some(event) = parse_event(&message.subject, &message.payload) else {
+                continue;
+            };
+            if !self.track_membership(&state, &event, from_membership).await {
+                continue;
+            }
+            self.readable.invalidate(&event);
+            if !self.readable.allows(&state, message.headers.as_ref()).await {
+                continue;
+            }
+            match sender.try_send(Delivery::Event { sequence, event }) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "event stream for {} exceeded its mailbox, disconnecting",
+                        self.user
+                    );
+                    outbox.resync(ResyncReason::MailboxFull);
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+    }
+
+    /// Sends the stored events of `consumer` ahead of any live event. These are already in the
+    /// past, so the client is waited on rather than held to the mailbox size.
+    ///
+    /// Membership events are only stored on community subjects and are all delivered, the
+    /// subscriptions already reflect the memberships as they are now.
+    async fn replay(
+        &mut self,
+        state: &GlobalServerContext,
+        consumer: Box<Consumer<pull::Config>>,
+        sender: &mpsc::Sender<Delivery>,
+    ) -> Result<(), async_nats::Error> {
+        loop {
+            let mut batch = consumer
+                .fetch()
+                .max_messages(REPLAY_BATCH_SIZE)
+                .messages()
+                .await?;
+            let mut empty = true;
+            while let Some(message) = batch.next().await {
+                let message = message?;
+                empty = false;
+                let sequence = message.info()?.stream_sequence;
+                self.replayed_through = sequence;
+                let Some(event) = parse_event(&message.subject, &message.payload) else {
+                    continue;
+                };
+                self.readable.invalidate(&event);
+                if !self.readable.allows(state, message.headers.as_ref()).await {
+                    continue;
+                }
+                if sender
+                    .send(Delivery::Event { sequence, event })
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            if empty {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Updates community subscriptions when our user joins or leaves. Returns whether `event`
+    /// should be delivered to the client.
+    ///
+    /// Membership events about our own user are published on both the user and community subjects,
+    /// only the copy from the user subject is delivered so the client sees each of them once.
+    async fn track_membership(
+        &mut self,
+        state: &GlobalServerContext,
+        event: &ServerEvent,
+        from_membership: bool,
+    ) -> bool {
+        match event {
+            ServerEvent::UserCommunity(sub_variant::UserCommunity::Create { community, user })
+                if *user == self.user =>
+            {
+                if from_membership && let Err(e) = self.subscribe(state, *community).await {
+                    error!("error subscribing {} to {community} {e}", self.user);
+                }
+                from_membership
+            }
+            ServerEvent::UserCommunity(sub_variant::UserCommunity::Delete { community, user })
+                if *user == self.user =>
+            {
+                if from_membership {
+                    self.communities.remove(community);
+                }
+                from_membership
+            }
+            ServerEvent::Community(sub_variant::Community::Delete { id }) => {
+                self.communities.remove(id);
+                true
+            }
+            _ => true,
+        }
+    }
+}
+
+fn parse_event(subject: &str, payload: &[u8]) -> Option<ServerEvent> {
+    serde_json::from_slice(payload)
+        .inspect_err(|e| error!("malformed server event on {subject} {e}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delivery, ResyncReason, channel};
+    use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+    use crate::app::CommunityId;
+
+    fn event(sequence: u64) -> Delivery {
+        Delivery::Event {
+            sequence,
+            event: ServerEvent::Community(sub_variant::Community::Delete {
+                id: CommunityId::new(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn resync_follows_a_full_mailbox() {
+        let (outbox, mut inbox) = channel(2);
+        outbox.deliveries.try_send(event(1)).unwrap();
+        outbox.deliveries.try_send(event(2)).unwrap();
+        assert!(outbox.deliveries.try_send(event(3)).is_err());
+        outbox.resync(ResyncReason::MailboxFull);
+        for expected in [1, 2] {
+            let Some(Delivery::Event { sequence, .. }) = inbox.recv().await else {
+                panic!("expected event {expected}");
+            };
+            assert_eq!(sequence, expected);
+        }
+        assert!(matches!(
+            inbox.recv().await,
+            Some(Delivery::Resync {
+                reason: ResyncReason::MailboxFull
+            })
+        ));
+        assert!(inbox.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn no_resync_when_the_stream_just_ends() {
+        let (outbox, mut inbox) = channel(2);
+        outbox.deliveries.try_send(event(1)).unwrap();
+        drop(outbox);
+        assert!(matches!(inbox.recv().await, Some(Delivery::Event { .. })));
+        assert!(inbox.recv().await.is_none());
+    }
 }
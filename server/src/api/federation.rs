@@ -0,0 +1,184 @@
+use crate::api::GlobalServerContext;
+use crate::api::login::{ClientDevice, login_status_code};
+use crate::app;
+use crate::app::federation::{
+    OtherServerLogin, VerifyOtherServerAuth, VerifyOtherServerAuthResponse, own_domain,
+};
+use crate::app::login::LoginResponse;
+use crate::aspen_config::aspen_config;
+use crate::server_key::{self, PeerKeys, ServerInfo};
+use axum::body::{Body, to_bytes};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::Utc;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+/// Largest body of a request from another server.
+const BODY_LIMIT: usize = 1024 * 1024;
+
+/// The domain of the other server a request was signed by, see [`require_signature`].
+#[derive(Clone)]
+pub struct PeerServer(pub String);
+
+/// Turns away requests not signed by another server, and tells handlers which one signed the rest
+/// with a [`PeerServer`] extension.
+pub async fn require_signature(
+    State(peer_keys): State<Arc<PeerKeys>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(this_domain) = aspen_config()
+        .await
+        .public_origin
+        .as_deref()
+        .and_then(own_domain)
+    else {
+        error!("public_origin must be configured to accept requests from other servers");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    let (mut parts, body) = request.into_parts();
+    let Ok(bytes) = to_bytes(body, BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let path_and_query = parts.uri.path_and_query().map_or_else(
+        || parts.uri.path(),
+        |path_and_query| path_and_query.as_str(),
+    );
+    let signed_by = server_key::check_signature(
+        &peer_keys,
+        parts.method.as_str(),
+        path_and_query,
+        &parts.headers,
+        &bytes,
+        &this_domain,
+        Utc::now(),
+    )
+    .await;
+    match signed_by {
+        Ok(Some(peer)) => {
+            parts.extensions.insert(PeerServer(peer));
+        }
+        Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(e) => {
+            warn!("unable to check server signature {e}");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[utoipa::path(get, path = "/.well-known/aspen/server", responses((status = OK, body=ServerInfo)))]
+pub async fn server_info(State(state): State<GlobalServerContext>) -> Json<ServerInfo> {
+    Json(state.server_key.info())
+}
+
+#[utoipa::path(post, path = "/other_server_auth/verify", responses((status = OK, body=VerifyOtherServerAuthResponse)))]
+pub async fn verify_other_server_auth(
+    State(state): State<GlobalServerContext>,
+    Extension(PeerServer(peer)): Extension<PeerServer>,
+    Json(verify): Json<VerifyOtherServerAuth>,
+) -> (StatusCode, Json<VerifyOtherServerAuthResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::federation::try_verify(conn.as_mut(), &verify, &peer).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error verifying other server auth token {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                VerifyOtherServerAuthResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        VerifyOtherServerAuthResponse::Ok { .. } => StatusCode::OK,
+        VerifyOtherServerAuthResponse::InvalidToken => StatusCode::UNAUTHORIZED,
+        VerifyOtherServerAuthResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/login/other_server", responses((status = OK, body=LoginResponse)))]
+pub async fn other_server_login(
+    State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
+    Json(login): Json<OtherServerLogin>,
+) -> (StatusCode, Json<LoginResponse>) {
+    match app::federation::try_login(&state, login, device).await {
+        Ok(resp) => (login_status_code(&resp), resp.into()),
+        Err(e) => {
+            error!("error during login with another server {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                LoginResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aspen_config::load_test_config;
+    use crate::server_key::ServerKey;
+    use crate::server_key::tests::mock_peer;
+    use axum::Router;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::post;
+
+    /// Serves a route behind [`require_signature`] that answers with the signing server.
+    async fn signed_route() -> String {
+        let router = Router::new()
+            .route(
+                "/other_server_auth/verify",
+                post(|Extension(PeerServer(peer)): Extension<PeerServer>| async move { peer }),
+            )
+            .route_layer(from_fn_with_state(
+                Arc::new(PeerKeys::plain_http()),
+                require_signature,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{address}/other_server_auth/verify")
+    }
+
+    /// Sends a request to `url` signed by `key` for `from` as if it went to this server.
+    async fn send(url: &str, key: Option<&ServerKey>, from: &str) -> reqwest::Response {
+        let client = reqwest::Client::new();
+        let mut request = client
+            .post("https://aspen.example.org/other_server_auth/verify")
+            .body("{}")
+            .build()
+            .unwrap();
+        if let Some(key) = key {
+            key.sign_request(&mut request, from, Utc::now());
+        }
+        *request.url_mut() = url.parse().unwrap();
+        client.execute(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_signed_requests_reach_handlers() {
+        load_test_config().await;
+        let url = signed_route().await;
+        let key = Arc::new(ServerKey::generate());
+        let from = mock_peer(key.clone()).await;
+
+        let response = send(&url, Some(&key), &from).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), from);
+
+        let unsigned = send(&url, None, &from).await;
+        assert_eq!(unsigned.status(), StatusCode::UNAUTHORIZED);
+        let impostor = send(&url, Some(&ServerKey::generate()), &from).await;
+        assert_eq!(impostor.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,18 +1,33 @@
-use crate::api::GlobalServerContext;
+use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     IconCreateCommand, IconCreateCommandResponse, IconDeleteCommand, IconDeleteCommandResponse,
     IconReadCommand, IconReadCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
 
+use crate::app;
+
 #[utoipa::path(post, path = "/icon", responses((status = OK, body=IconCreateCommandResponse)))]
 pub async fn create_icon(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<IconCreateCommand>,
 ) -> (StatusCode, Json<IconCreateCommandResponse>) {
-    todo!()
+    match app::icon::create_icon(state, user.id, &command).await {
+        Ok(icon) => (
+            StatusCode::OK,
+            IconCreateCommandResponse::CreateOk {
+                id: icon.id,
+                data: icon.data,
+                mime_type: icon.mime_type,
+            }
+            .into(),
+        ),
+        Err(e) => command_error_response!(IconCreateCommandResponse, e, "creating icon"),
+    }
 }
 
 #[utoipa::path(get, path = "/icon", responses((status = OK, body=IconReadCommandResponse)))]
@@ -26,7 +41,11 @@ pub async fn read_icon(
 #[utoipa::path(delete, path = "/icon", responses((status = OK, body=IconDeleteCommandResponse)))]
 pub async fn delete_icon(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<IconDeleteCommand>,
 ) -> (StatusCode, Json<IconDeleteCommandResponse>) {
-    todo!()
+    match app::icon::delete_icon(state, user.id, command.id).await {
+        Ok(()) => (StatusCode::OK, IconDeleteCommandResponse::DeleteOk.into()),
+        Err(e) => command_error_response!(IconDeleteCommandResponse, e, "deleting icon"),
+    }
 }
@@ -0,0 +1,276 @@
+//! Tracks which sessions have an event stream or WebSocket open on this node, so they can be closed
+//! when the session is revoked on any node.
+//!
+//! Sessions are identified by their refresh token family, which stays the same as the refresh token
+//! rotates and reveals nothing of it. Revocations are published on [`subject::SESSIONS_REVOKED`] as
+//! a JSON list of families, and every node, including the publishing one, closes its matching
+//! streams. A node that misses revocations checks every session it has streams of against the
+//! database instead.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::pooled_connection::deadpool::Pool;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use tokio::sync::{RwLock, broadcast, watch};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::api::subject;
+use crate::app;
+use crate::database::schema::refresh_token;
+use crate::nats_connection_manager::NatsConnectionManager;
+
+#[derive(Default)]
+pub struct LiveSessions {
+    /// Every stream of a session shares one sender, streams watch it for `true`.
+    streams: Mutex<HashMap<Uuid, watch::Sender<bool>>>,
+}
+
+impl LiveSessions {
+    /// Registers a stream of `session`, it is closed once [`LiveStream::revoked`] resolves.
+    ///
+    /// Checks that the session still exists once registered, as a revocation published between
+    /// the session being looked up and registering would be missed.
+    pub async fn register(
+        self: &Arc<Self>,
+        conn: &mut AsyncPgConnection,
+        session: Uuid,
+    ) -> Result<LiveStream, app::Error> {
+        let stream = self.register_unchecked(session);
+        if live_families(conn, &[session]).await?.is_empty() {
+            self.close(&[session]);
+        }
+        Ok(stream)
+    }
+
+    fn register_unchecked(self: &Arc<Self>, session: Uuid) -> LiveStream {
+        let mut streams = self.streams.lock().expect("live sessions lock poisoned");
+        let revoked = streams
+            .entry(session)
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe();
+        LiveStream {
+            sessions: self.clone(),
+            session,
+            revoked,
+        }
+    }
+
+    /// Tells every node, this one included, to close the streams of `sessions`. Failures are only
+    /// logged, the sessions are already gone from the database by the time this is called.
+    pub async fn revoke(
+        nats_connection_manager: &RwLock<NatsConnectionManager>,
+        sessions: &[Uuid],
+    ) {
+        if sessions.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_vec(sessions) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("error serializing session revocation {e}");
+                return;
+            }
+        };
+        if let Err(e) = nats_connection_manager
+            .read()
+            .await
+            .publish(subject::SESSIONS_REVOKED, payload.into())
+            .await
+        {
+            error!("error publishing session revocation {e}");
+        }
+    }
+
+    /// Closes the streams of revoked sessions as revocations arrive from `revocations`.
+    pub fn listen(
+        self: Arc<Self>,
+        mut revocations: broadcast::Receiver<async_nats::Message>,
+        connection_pool: Pool<AsyncPgConnection>,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                let message = match revocations.recv().await {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("missed {missed} session revocations, checking every session");
+                        self.recheck(&connection_pool).await;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        error!("session revocations stopped arriving");
+                        return;
+                    }
+                };
+                match serde_json::from_slice::<Vec<Uuid>>(&message.payload) {
+                    Ok(sessions) => self.close(&sessions),
+                    Err(e) => error!("malformed session revocation {e}"),
+                }
+            }
+        });
+    }
+
+    /// Closes the streams of every session that is gone from the database, or of every session if
+    /// that can't be told.
+    pub(crate) async fn recheck(&self, connection_pool: &Pool<AsyncPgConnection>) {
+        let sessions = self
+            .streams
+            .lock()
+            .expect("live sessions lock poisoned")
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let live = match connection_pool.get().await {
+            Ok(mut conn) => live_families(conn.as_mut(), &sessions).await,
+            Err(e) => Err(e.into()),
+        };
+        let revoked = match live {
+            Ok(live) => sessions
+                .into_iter()
+                .filter(|session| !live.contains(session))
+                .collect(),
+            Err(e) => {
+                error!("error checking live sessions, closing every stream {e}");
+                sessions
+            }
+        };
+        self.close(&revoked);
+    }
+
+    fn close(&self, sessions: &[Uuid]) {
+        let mut streams = self.streams.lock().expect("live sessions lock poisoned");
+        for session in sessions {
+            if let Some(revoked) = streams.remove(session) {
+                revoked.send_replace(true);
+            }
+        }
+    }
+}
+
+/// Which of `families` haven't been revoked.
+async fn live_families(
+    conn: &mut AsyncPgConnection,
+    families: &[Uuid],
+) -> Result<HashSet<Uuid>, app::Error> {
+    Ok(refresh_token::table
+        .select(refresh_token::family)
+        .filter(refresh_token::family.eq_any(families))
+        .distinct()
+        .load::<Uuid>(conn)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// A registered stream, unregisters itself when dropped.
+pub struct LiveStream {
+    sessions: Arc<LiveSessions>,
+    session: Uuid,
+    revoked: watch::Receiver<bool>,
+}
+
+impl LiveStream {
+    /// Resolves once the stream's session is revoked.
+    pub async fn revoked(&mut self) {
+        // An error means the sender is gone, which only happens once revoked.
+        let _ = self.revoked.wait_for(|revoked| *revoked).await;
+    }
+}
+
+impl Drop for LiveStream {
+    fn drop(&mut self) {
+        let mut streams = self
+            .sessions
+            .streams
+            .lock()
+            .expect("live sessions lock poisoned");
+        // Ours is the last stream of the session once only our receiver is left.
+        if let Some(revoked) = streams.get(&self.session)
+            && revoked.receiver_count() <= 1
+        {
+            streams.remove(&self.session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use diesel_async::pooled_connection::deadpool::Pool;
+    use diesel_async::{AsyncPgConnection, SimpleAsyncConnection};
+    use uuid::Uuid;
+
+    use super::LiveSessions;
+    use crate::database::test_database_url;
+
+    #[tokio::test]
+    async fn revoking_closes_every_stream_of_session() {
+        let sessions = Arc::new(LiveSessions::default());
+        let (a, b) = (Uuid::now_v7(), Uuid::now_v7());
+        let mut first = sessions.register_unchecked(a);
+        let mut second = sessions.register_unchecked(a);
+        let mut other = sessions.register_unchecked(b);
+        sessions.close(&[a]);
+        first.revoked().await;
+        second.revoked().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), other.revoked())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn last_stream_unregisters() {
+        let sessions = Arc::new(LiveSessions::default());
+        let a = Uuid::now_v7();
+        let first = sessions.register_unchecked(a);
+        let second = sessions.register_unchecked(a);
+        drop(first);
+        assert_eq!(sessions.streams.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(sessions.streams.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_of_sessions_gone_from_database_close() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        let pool = Pool::builder(AsyncDieselConnectionManager::<AsyncPgConnection>::new(url))
+            .build()
+            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        let (live, revoked, missed) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{live}', 'alice', '');
+            INSERT INTO refresh_token(token, expires, "user", family)
+                VALUES ('a', now() + interval '1 day', '{live}', '{live}'),
+                    ('b', now() + interval '1 day', '{live}', '{missed}');"#
+        ))
+        .await
+        .unwrap();
+        let sessions = Arc::new(LiveSessions::default());
+        let mut live_stream = sessions.register(conn.as_mut(), live).await.unwrap();
+        // Revoked before registering, its revocation was never seen.
+        let mut revoked_stream = sessions.register(conn.as_mut(), revoked).await.unwrap();
+        revoked_stream.revoked().await;
+        // Revoked while registered, but the revocation was missed.
+        let mut missed_stream = sessions.register(conn.as_mut(), missed).await.unwrap();
+        conn.batch_execute("DELETE FROM refresh_token WHERE token = 'b'")
+            .await
+            .unwrap();
+        sessions.recheck(&pool).await;
+        missed_stream.revoked().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), live_stream.revoked())
+                .await
+                .is_err()
+        );
+    }
+}
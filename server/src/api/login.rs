@@ -1,13 +1,18 @@
 use crate::api::{GlobalServerContext, UserId};
 use crate::app;
+use crate::app::login::hash_token;
 use crate::app::login::{
     ChangePassword, ChangePasswordResponse, Login, LoginResponse, Logout, LogoutResponse,
-    OtherServerAuth, OtherServerAuthResponse, TokenRefresh, TokenRefreshResponse,
+    OtherServerAuth, OtherServerAuthResponse, SecondFactorLogin, TokenRefresh,
+    TokenRefreshResponse,
 };
+use crate::app::login_session::Device;
+use crate::app::totp::{TotpConfirm, TotpConfirmResponse, TotpEnrollResponse};
 use crate::app::user::User;
+use crate::aspen_config::aspen_config;
 use crate::database::schema::refresh_token;
 use axum::Json;
-use axum::extract::{FromRequest, FromRequestParts, Request, State};
+use axum::extract::{ConnectInfo, FromRequest, FromRequestParts, Request, State};
 use axum::http::StatusCode;
 use axum::http::header::ToStrError;
 use axum::http::request::Parts;
@@ -16,17 +21,23 @@ use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, SelectableHelpe
 use diesel_async::return_futures::GetResult;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use futures_util::TryFutureExt;
-use hyper::header::AUTHORIZATION;
+use hyper::header::{AUTHORIZATION, USER_AGENT};
+use hyper::http::HeaderName;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::task::{Context, Poll};
 use tower::Service;
 use tracing::error;
 
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
 #[utoipa::path(post, path = "/login", responses((status = OK, body=LoginResponse)))]
 pub async fn login(
     State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
     Json(login): Json<Login>,
 ) -> (StatusCode, Json<LoginResponse>) {
-    let resp = match app::login::try_login(&state, login).await {
+    let resp = match app::login::try_login(&state, login, device).await {
         Ok(resp) => resp,
         Err(e) => {
             error!("error during login {e}");
@@ -36,24 +47,104 @@ pub async fn login(
             );
         }
     };
-    let status_code = match &resp {
-        LoginResponse::Ok { .. } => StatusCode::OK,
+    (login_status_code(&resp), resp.into())
+}
+
+pub(super) fn login_status_code(resp: &LoginResponse) -> StatusCode {
+    match resp {
+        LoginResponse::Ok { .. } | LoginResponse::SecondFactorRequired { .. } => StatusCode::OK,
         LoginResponse::InvalidCredentials => StatusCode::UNAUTHORIZED,
+        LoginResponse::PendingApproval | LoginResponse::RegistrationRefused { .. } => {
+            StatusCode::FORBIDDEN
+        }
+        LoginResponse::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
         LoginResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[utoipa::path(post, path = "/login/second_factor", responses((status = OK, body=LoginResponse)))]
+pub async fn login_second_factor(
+    State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
+    Json(second_factor): Json<SecondFactorLogin>,
+) -> (StatusCode, Json<LoginResponse>) {
+    let resp = match app::login::try_second_factor_login(&state, &second_factor, device).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error during second factor login {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                LoginResponse::ServerError.into(),
+            );
+        }
+    };
+    (login_status_code(&resp), resp.into())
+}
+
+#[utoipa::path(post, path = "/totp/enroll", responses((status = OK, body=TotpEnrollResponse)))]
+pub async fn totp_enroll(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+) -> (StatusCode, Json<TotpEnrollResponse>) {
+    let conn = state.connection_pool.get().map_err(Into::into);
+    let resp = match conn
+        .and_then(|mut conn| async move { app::totp::try_enroll(conn.as_mut(), &user).await })
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error during totp enroll {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                TotpEnrollResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        TotpEnrollResponse::Ok { .. } => StatusCode::OK,
+        TotpEnrollResponse::AlreadyEnrolled => StatusCode::CONFLICT,
+        TotpEnrollResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
     };
     (status_code, resp.into())
 }
 
-#[utoipa::path(post, path = "/logout", responses((status = OK, body=LogoutResponse)))]
-pub async fn logout(
+#[utoipa::path(post, path = "/totp/confirm", responses((status = OK, body=TotpConfirmResponse)))]
+pub async fn totp_confirm(
     State(state): State<GlobalServerContext>,
-    Json(logout): Json<Logout>,
-) -> (StatusCode, Json<LogoutResponse>) {
+    SessionUser(user): SessionUser,
+    Json(confirm): Json<TotpConfirm>,
+) -> (StatusCode, Json<TotpConfirmResponse>) {
     let conn = state.connection_pool.get().map_err(Into::into);
     let resp = match conn
-        .and_then(|conn| app::login::try_logout(conn, &logout))
+        .and_then(|mut conn| async move {
+            app::totp::try_confirm(conn.as_mut(), user.id, &confirm).await
+        })
         .await
     {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error during totp confirm {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                TotpConfirmResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        TotpConfirmResponse::Ok { .. } => StatusCode::OK,
+        TotpConfirmResponse::InvalidCode => StatusCode::UNAUTHORIZED,
+        TotpConfirmResponse::NotEnrolling => StatusCode::CONFLICT,
+        TotpConfirmResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/logout", responses((status = OK, body=LogoutResponse)))]
+pub async fn logout(
+    State(state): State<GlobalServerContext>,
+    Json(logout): Json<Logout>,
+) -> (StatusCode, Json<LogoutResponse>) {
+    let resp = match app::login::try_logout(&state, &logout).await {
         Ok(resp) => resp,
         Err(e) => {
             error!("error during logout {e}");
@@ -76,11 +167,7 @@ pub async fn token_refresh(
     State(state): State<GlobalServerContext>,
     Json(token_refresh): Json<TokenRefresh>,
 ) -> (StatusCode, Json<TokenRefreshResponse>) {
-    let conn = state.connection_pool.get().map_err(Into::into);
-    let resp = match conn
-        .and_then(|conn| app::login::try_token_refresh(conn, &token_refresh))
-        .await
-    {
+    let resp = match app::login::try_token_refresh(&state, &token_refresh).await {
         Ok(resp) => resp,
         Err(e) => {
             error!("error during token refresh {e}");
@@ -101,11 +188,10 @@ pub async fn token_refresh(
 #[utoipa::path(post, path = "/change_password", responses((status = OK, body=ChangePasswordResponse)))]
 pub async fn change_password(
     State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
     Json(change_password): Json<ChangePassword>,
 ) -> (StatusCode, Json<ChangePasswordResponse>) {
-    let conn = state.connection_pool.get().map_err(Into::into);
-    let resp = match conn
-        .and_then(|conn| app::login::try_change_password(conn, &change_password))
+    let resp = match app::login::try_change_password(&state, &change_password, device.ip.as_deref())
         .await
     {
         Ok(resp) => resp,
@@ -119,8 +205,10 @@ pub async fn change_password(
     };
     let status_code = match &resp {
         ChangePasswordResponse::Ok { .. } => StatusCode::OK,
-        ChangePasswordResponse::OldPasswordIncorrect => StatusCode::UNAUTHORIZED,
+        ChangePasswordResponse::InvalidCredentials
+        | ChangePasswordResponse::OldPasswordIncorrect => StatusCode::UNAUTHORIZED,
         ChangePasswordResponse::NewPasswordDoesntMeetRequirements { .. } => StatusCode::BAD_REQUEST,
+        ChangePasswordResponse::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
         ChangePasswordResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
     };
     (status_code, resp.into())
@@ -169,7 +257,7 @@ pub async fn authenticated_user(
         .select(refresh_token::dsl::user)
         .filter(
             session::token
-                .eq(session_token)
+                .eq(hash_token(&session_token).await)
                 .and(session::expires.ge(now))
                 .and(refresh_token::expires.ge(now)),
         )
@@ -183,11 +271,80 @@ pub async fn authenticated_user(
     Ok(maybe_user_id)
 }
 
+/// The device a login comes from. The IP is the peer's, or behind one of the
+/// [`trusted_proxies`](crate::aspen_config::AspenConfig::trusted_proxies) the one it forwarded for.
+pub struct ClientDevice(pub Device);
+impl<S: Send + Sync> FromRequestParts<S> for ClientDevice {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let trusted_proxies = aspen_config().await.trusted_proxies;
+        let forwarded_for = parts
+            .headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| client_ip(addr.ip(), &forwarded_for, &trusted_proxies))
+            .map(|ip| ip.to_string());
+        Ok(ClientDevice(Device {
+            client_name: None,
+            user_agent,
+            ip,
+        }))
+    }
+}
+
+/// Who `peer` made the request for. Proxies append the address they got a request from to
+/// `X-Forwarded-For`, so going from the end, the first address not of a trusted proxy is the
+/// client's. Anything before it may have been made up by the client.
+fn client_ip(peer: IpAddr, forwarded_for: &str, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    for forwarded in forwarded_for.rsplit(',') {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match forwarded.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
 #[derive(Clone)]
 pub struct SessionUser(pub User);
 impl FromRequestParts<GlobalServerContext> for SessionUser {
     type Rejection = (StatusCode, &'static str);
 
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &GlobalServerContext,
+    ) -> Result<Self, Self::Rejection> {
+        Session::from_request_parts(parts, state)
+            .await
+            .map(|session| SessionUser(session.user))
+    }
+}
+
+/// The authenticated user along with the refresh token family their session token was issued from,
+/// for handlers that need to tie something to the session.
+pub struct Session {
+    pub user: User,
+    pub family: uuid::Uuid,
+}
+impl FromRequestParts<GlobalServerContext> for Session {
+    type Rejection = (StatusCode, &'static str);
+
     fn from_request_parts(
         parts: &mut Parts,
         state: &GlobalServerContext,
@@ -219,18 +376,18 @@ impl FromRequestParts<GlobalServerContext> for SessionUser {
             };
             let now = Utc::now().naive_utc();
             let select_result = schema::user::table
-                .select(User::as_select())
                 .inner_join(refresh_token::table.inner_join(session::table))
+                .select((User::as_select(), refresh_token::dsl::family))
                 .filter(
                     session::dsl::token
-                        .eq(&token)
+                        .eq(hash_token(token).await)
                         .and(session::dsl::expires.ge(now))
                         .and(refresh_token::dsl::expires.ge(now)),
                 )
                 .first(conn.as_mut())
                 .await;
             match select_result {
-                Ok(user) => Ok(SessionUser(user)),
+                Ok((user, family)) => Ok(Session { user, family }),
                 Err(e) => {
                     if let diesel::result::Error::NotFound = e {
                         Err(INVALID_AUTH)
@@ -243,3 +400,29 @@ impl FromRequestParts<GlobalServerContext> for SessionUser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::client_ip;
+
+    #[test]
+    fn forwarded_for_only_trusted_from_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        for (peer, forwarded_for, client) in [
+            ("192.0.2.1", "203.0.113.9", "192.0.2.1"),
+            ("10.0.0.1", "", "10.0.0.1"),
+            ("10.0.0.1", "192.0.2.1", "192.0.2.1"),
+            ("10.0.0.1", "203.0.113.9, 192.0.2.1, 10.0.0.2", "192.0.2.1"),
+            ("10.0.0.1", "192.0.2.1,not an ip", "10.0.0.1"),
+        ] {
+            assert_eq!(
+                client_ip(ip(peer), forwarded_for, &proxies),
+                ip(client),
+                "{peer} forwarding for {forwarded_for}"
+            );
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::api::GlobalServerContext;
+use crate::api::login::{Session, SessionUser};
+use crate::app;
+use crate::app::login_session::{
+    RevokeOtherSessionsResponse, RevokeSession, RevokeSessionResponse, RevokeUserSessions,
+    RevokeUserSessionsResponse, SessionsResponse,
+};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(get, path = "/sessions", responses((status = OK, body=SessionsResponse)))]
+pub async fn sessions(
+    State(state): State<GlobalServerContext>,
+    Session { user, family }: Session,
+) -> (StatusCode, Json<SessionsResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::login_session::try_list(conn.as_mut(), user.id, family).await,
+        Err(e) => Err(e.into()),
+    };
+    match resp {
+        Ok(resp) => (StatusCode::OK, resp.into()),
+        Err(e) => {
+            error!("error listing sessions {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                SessionsResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/sessions/revoke", responses((status = OK, body=RevokeSessionResponse)))]
+pub async fn revoke_session(
+    State(state): State<GlobalServerContext>,
+    Session { user, .. }: Session,
+    Json(revoke): Json<RevokeSession>,
+) -> (StatusCode, Json<RevokeSessionResponse>) {
+    let resp = match app::login_session::try_revoke(&state, user.id, &revoke).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error revoking session {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                RevokeSessionResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        RevokeSessionResponse::Ok => StatusCode::OK,
+        RevokeSessionResponse::NotFound => StatusCode::NOT_FOUND,
+        RevokeSessionResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/sessions/revoke_others", responses((status = OK, body=RevokeOtherSessionsResponse)))]
+pub async fn revoke_other_sessions(
+    State(state): State<GlobalServerContext>,
+    Session { user, family }: Session,
+) -> (StatusCode, Json<RevokeOtherSessionsResponse>) {
+    match app::login_session::try_revoke_others(&state, user.id, family).await {
+        Ok(resp) => (StatusCode::OK, resp.into()),
+        Err(e) => {
+            error!("error revoking other sessions {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                RevokeOtherSessionsResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+/// Logs another user out everywhere, for admins.
+#[utoipa::path(post, path = "/sessions/revoke_user", responses((status = OK, body=RevokeUserSessionsResponse)))]
+pub async fn revoke_user_sessions(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(revoke): Json<RevokeUserSessions>,
+) -> (StatusCode, Json<RevokeUserSessionsResponse>) {
+    let resp = match app::login_session::try_revoke_user(&state, user.id, &revoke).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error revoking sessions of user {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                RevokeUserSessionsResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        RevokeUserSessionsResponse::Ok => StatusCode::OK,
+        RevokeUserSessionsResponse::NotFound => StatusCode::NOT_FOUND,
+        RevokeUserSessionsResponse::NotAllowed => StatusCode::FORBIDDEN,
+        RevokeUserSessionsResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
@@ -1,20 +1,37 @@
-use crate::api::GlobalServerContext;
+use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     MessageCreateCommand, MessageCreateCommandResponse, MessageDeleteCommand,
     MessageDeleteCommandResponse, MessageReadCommand, MessageReadCommandResponse,
     MessageUpdateCommand, MessageUpdateCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
 
+use crate::app;
+
 #[utoipa::path(post, path = "/message", responses((status = OK, body=MessageCreateCommandResponse)))]
 
 pub async fn create_message(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<MessageCreateCommand>,
 ) -> (StatusCode, Json<MessageCreateCommandResponse>) {
-    todo!()
+    match app::message::create_message(state, user.id, &command).await {
+        Ok(message) => (
+            StatusCode::OK,
+            MessageCreateCommandResponse::CreateOk {
+                id: message.id,
+                content: message.content,
+                channel_id: command.channel_id,
+                author: user.id,
+                timestamp: message.time.and_utc(),
+            }
+            .into(),
+        ),
+        Err(e) => command_error_response!(MessageCreateCommandResponse, e, "creating message"),
+    }
 }
 
 #[utoipa::path(get, path = "/message", responses((status = OK, body=MessageReadCommandResponse)))]
@@ -30,15 +47,29 @@ pub async fn read_message(
 
 pub async fn update_message(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<MessageUpdateCommand>,
 ) -> (StatusCode, Json<MessageUpdateCommandResponse>) {
-    todo!()
+    match app::message::update_message(state, user.id, &command).await {
+        Ok(()) => (
+            StatusCode::OK,
+            MessageUpdateCommandResponse::UpdateOk.into(),
+        ),
+        Err(e) => command_error_response!(MessageUpdateCommandResponse, e, "updating message"),
+    }
 }
 
 #[utoipa::path(delete, path = "/message", responses((status = OK, body=MessageDeleteCommandResponse)))]
 pub async fn delete_message(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<MessageDeleteCommand>,
 ) -> (StatusCode, Json<MessageDeleteCommandResponse>) {
-    todo!()
+    match app::message::delete_message(state, user.id, command.id).await {
+        Ok(()) => (
+            StatusCode::OK,
+            MessageDeleteCommandResponse::DeleteOk.into(),
+        ),
+        Err(e) => command_error_response!(MessageDeleteCommandResponse, e, "deleting message"),
+    }
 }
@@ -11,8 +11,12 @@ enum MessageEnumSource {
         #[message_gen(id)]
         id: UserId,
         name: String,
-        #[message_gen(secret)]
+        #[message_gen(secret, rejected_with = "crate::app::password::PasswordRequirement")]
         password: String,
+        #[message_gen(secret)]
+        email: Option<String>,
+        #[message_gen(secret)]
+        invite_code: Option<String>,
         icon: Option<IconId>,
     },
     Message {
@@ -37,6 +41,8 @@ enum MessageEnumSource {
     Channel {
         #[message_gen(id)]
         id: ChannelId,
+        #[message_gen(permanent)]
+        community: CommunityId,
         parent_category: Option<CategoryId>,
         name: String,
         permissions: ChannelPermissions,
@@ -0,0 +1,104 @@
+//! Counters for operators, served at `/metrics` in the Prometheus text format to scrapers with the
+//! configured [`metrics_token`](crate::aspen_config::AspenConfig::metrics_token).
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use axum::http::{HeaderMap, StatusCode, header::AUTHORIZATION};
+
+use crate::api::event_stream::ResyncReason;
+use crate::aspen_config::aspen_config;
+
+static EVENT_STREAM_DROPS_MAILBOX_FULL: AtomicU64 = AtomicU64::new(0);
+static EVENT_STREAM_DROPS_LAGGED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a client disconnected for falling behind on its events.
+pub(super) fn count_event_stream_drop(reason: ResyncReason) {
+    let counter = match reason {
+        ResyncReason::MailboxFull => &EVENT_STREAM_DROPS_MAILBOX_FULL,
+        ResyncReason::Lagged => &EVENT_STREAM_DROPS_LAGGED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub async fn metrics(headers: HeaderMap) -> Result<String, StatusCode> {
+    authorize(&headers, aspen_config().await.metrics_token.as_deref())?;
+    Ok(render())
+}
+
+/// Not found without a token configured, so the endpoint isn't there at all.
+fn authorize(headers: &HeaderMap, token: Option<&str>) -> Result<(), StatusCode> {
+    let Some(token) = token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok() => {
+            Ok(())
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP aspen_event_stream_drops_total Event streams disconnected for falling behind.\n",
+    );
+    out.push_str("# TYPE aspen_event_stream_drops_total counter\n");
+    for (reason, counter) in [
+        ("mailboxFull", &EVENT_STREAM_DROPS_MAILBOX_FULL),
+        ("lagged", &EVENT_STREAM_DROPS_LAGGED),
+    ] {
+        let _ = writeln!(
+            out,
+            "aspen_event_stream_drops_total{{reason=\"{reason}\"}} {}",
+            counter.load(Ordering::Relaxed)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, StatusCode, header::AUTHORIZATION};
+
+    use super::{authorize, count_event_stream_drop, render};
+    use crate::api::event_stream::ResyncReason;
+
+    #[test]
+    fn needs_the_configured_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(authorize(&headers, None), Err(StatusCode::NOT_FOUND));
+        assert_eq!(
+            authorize(&headers, Some("secret")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert_eq!(
+            authorize(&headers, Some("secret")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert_eq!(authorize(&headers, Some("secret")), Ok(()));
+        assert_eq!(authorize(&headers, None), Err(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn drops_counted_by_reason() {
+        count_event_stream_drop(ResyncReason::Lagged);
+        let rendered = render();
+        let lagged = rendered
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix("aspen_event_stream_drops_total{reason=\"lagged\"} ")
+            })
+            .unwrap();
+        assert!(lagged.parse::<u64>().unwrap() >= 1);
+        assert!(rendered.contains("aspen_event_stream_drops_total{reason=\"mailboxFull\"} "));
+    }
+}
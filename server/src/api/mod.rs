@@ -1,5 +1,11 @@
+use crate::app::authenticator::Authenticator;
+use crate::app::ldap::LdapAuthenticator;
+use crate::app::oidc::OidcClient;
 use crate::app::{AttachmentId, UserId};
+use crate::mailer::{self, Mailer};
+use crate::server_key::{PeerKeys, ServerKey};
 use crate::{app, aspen_config::aspen_config, nats_connection_manager::NatsConnectionManager};
+use axum::middleware;
 use axum::routing::{get, post};
 use diesel_async::{
     AsyncPgConnection,
@@ -9,21 +15,44 @@ use std::fs;
 pub(crate) mod category;
 pub(crate) mod channel;
 pub(crate) mod community;
+pub(crate) mod email;
+pub(crate) mod encoding;
+mod event_filter;
+pub(crate) mod event_log;
+pub(crate) mod event_publisher;
 mod event_stream;
+pub(crate) mod federation;
 pub(crate) mod icon;
+pub(crate) mod live_sessions;
 pub(crate) mod login;
+pub(crate) mod login_session;
 pub(crate) mod message;
 pub(crate) mod message_enum;
+mod metrics;
+pub(crate) mod oidc;
+pub(crate) mod passkey;
+pub(crate) mod password_reset;
 pub(crate) mod react;
+pub(crate) mod registration;
+mod stream_compression;
+pub(crate) mod subject;
 pub(crate) mod user;
+pub(crate) mod user_community;
+mod ws;
 
+use crate::api::event_publisher::EventPublisher;
+use crate::api::live_sessions::LiveSessions;
 use crate::api::login::SessionUser;
 use axum::Extension;
 use axum::routing::{delete, patch};
+use diesel::deserialize::FromSqlRow;
+use diesel::expression::AsExpression;
 use diesel::{BoolExpressionMethods, ExpressionMethods as _, QueryDsl};
 use futures_util::TryFutureExt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tower::{Layer, ServiceBuilder};
 use utoipa::openapi;
@@ -32,12 +61,47 @@ use utoipa_axum::router::OpenApiRouter;
 use utoipa_axum::routes;
 
 pub(crate) async fn make_router(write_schema: bool) -> Result<axum::Router, app::Error> {
+    let state = GlobalServerContext::new().await?;
+    let signed = OpenApiRouter::new()
+        .routes(routes!(federation::verify_other_server_auth,))
+        .route_layer(middleware::from_fn_with_state(
+            state.peer_keys.clone(),
+            federation::require_signature,
+        ));
     let mut router = OpenApiRouter::new()
         .routes(routes!(login::login,))
+        .routes(routes!(login::login_second_factor,))
+        .routes(routes!(login::totp_enroll,))
+        .routes(routes!(login::totp_confirm,))
+        .routes(routes!(passkey::passkey_register_options,))
+        .routes(routes!(passkey::passkey_register,))
+        .routes(routes!(passkey::passkeys,))
+        .routes(routes!(passkey::delete_passkey,))
+        .routes(routes!(passkey::passkey_login_options,))
+        .routes(routes!(passkey::passkey_login,))
+        .routes(routes!(passkey::second_factor_passkey_options,))
+        .routes(routes!(oidc::oidc_start,))
+        .routes(routes!(oidc::oidc_login,))
         .routes(routes!(login::logout,))
         .routes(routes!(login::token_refresh,))
         .routes(routes!(login::change_password,))
         .routes(routes!(login::other_server_login,))
+        .routes(routes!(federation::other_server_login,))
+        .routes(routes!(federation::server_info,))
+        .merge(signed)
+        .routes(routes!(email::send_email_confirmation,))
+        .routes(routes!(email::verify_email,))
+        .routes(routes!(email::change_email,))
+        .routes(routes!(password_reset::request_password_reset,))
+        .routes(routes!(password_reset::verify_password_reset,))
+        .routes(routes!(password_reset::complete_password_reset,))
+        .routes(routes!(registration::create_invite,))
+        .routes(routes!(registration::pending_users,))
+        .routes(routes!(registration::review_user,))
+        .routes(routes!(login_session::sessions,))
+        .routes(routes!(login_session::revoke_session,))
+        .routes(routes!(login_session::revoke_other_sessions,))
+        .routes(routes!(login_session::revoke_user_sessions,))
         .routes(routes!(
             // User
             user::create_user,
@@ -59,6 +123,7 @@ pub(crate) async fn make_router(write_schema: bool) -> Result<axum::Router, app:
             channel::update_channel,
             channel::delete_channel,
         ))
+        .routes(routes!(channel::mark_channel_read))
         .routes(routes!(
             // Category
             category::create_category,
@@ -73,6 +138,10 @@ pub(crate) async fn make_router(write_schema: bool) -> Result<axum::Router, app:
             community::update_community,
             community::delete_community,
         ))
+        .routes(routes!(
+            // UserCommunity
+            user_community::delete_user_community,
+        ))
         .routes(routes!(
             // Icon
             icon::create_icon,
@@ -85,7 +154,10 @@ pub(crate) async fn make_router(write_schema: bool) -> Result<axum::Router, app:
         ))
         // Events
         .route("/event_stream", get(event_stream::event_stream))
-        .with_state(GlobalServerContext::new().await?);
+        .route("/ws", get(ws::ws))
+        .route("/metrics", get(metrics::metrics))
+        .layer(middleware::from_fn(encoding::negotiate))
+        .with_state(state);
     if write_schema {
         let mut openapi = router.to_openapi();
         openapi.info.title = "Aspen API".into();
@@ -114,40 +186,149 @@ pub struct AttachmentMeta {
     preview: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, FromSqlRow, AsExpression, utoipa::ToSchema)]
+#[diesel(sql_type = diesel::sql_types::Integer)]
 pub enum ChannelType {
     Text,
     Voice,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
+/// What members of a channel's community may do in it, as bits like
+/// [`READ`](crate::app::channel::READ), see [`ChannelPermissions::effective`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct ChannelPermissions {
-    // TODO
+    /// Applies to every member.
+    #[serde(default)]
+    pub everyone: PermissionOverwrite,
+    /// Applies to members with each role, winning over `everyone`.
+    #[serde(default)]
+    pub roles: BTreeMap<String, PermissionOverwrite>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionOverwrite {
+    /// Granted even where otherwise denied.
+    #[serde(default)]
+    pub allow: u32,
+    /// Taken away unless allowed.
+    #[serde(default)]
+    pub deny: u32,
 }
 
 #[derive(Clone)]
 pub struct GlobalServerContext {
     pub connection_pool: Pool<AsyncPgConnection>,
     pub nats_connection_manager: Arc<RwLock<NatsConnectionManager>>,
+    pub event_publisher: EventPublisher,
+    pub jetstream: async_nats::jetstream::Context,
+    pub live_sessions: Arc<LiveSessions>,
+    /// `None` when no mailer is configured.
+    pub mailer: Option<Arc<dyn Mailer>>,
+    /// `None` when no OpenID Connect provider is configured.
+    pub oidc: Option<Arc<OidcClient>>,
+    /// Makes requests to other servers, see [`peer_client`](app::federation::peer_client).
+    pub http: reqwest::Client,
+    /// Signs requests to other servers.
+    pub server_key: Arc<ServerKey>,
+    pub peer_keys: Arc<PeerKeys>,
+    /// Checks passwords before the local password hashes, `None` when only those are used.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 impl GlobalServerContext {
     pub async fn new() -> Result<Self, app::Error> {
         let config = aspen_config().await;
+        if config.password_policy.reject_common {
+            app::password::load_common_passwords(&config.password_policy)?;
+        }
+        let mut nats_connection_manager =
+            NatsConnectionManager::new(config.nats_url, config.nats_auth_token).await?;
+        let jetstream = nats_connection_manager.jetstream();
+        event_log::ensure_stream(&jetstream, Duration::from_secs(config.event_retention_secs))
+            .await?;
+        let connection_pool = {
+            let conn_manager =
+                AsyncDieselConnectionManager::<AsyncPgConnection>::new(config.database_url);
+            Pool::builder(conn_manager)
+                .build()
+                .expect("Failed to init database connection pool")
+        };
+        if !config.admins.is_empty() {
+            let mut conn = connection_pool.get().await?;
+            app::registration::grant_configured_admins(conn.as_mut(), &config.admins).await?;
+        }
+        let live_sessions = Arc::new(LiveSessions::default());
+        live_sessions.clone().listen(
+            nats_connection_manager
+                .subscribe(subject::SESSIONS_REVOKED)
+                .await?,
+            connection_pool.clone(),
+        );
+        let nats_connection_manager = Arc::new(RwLock::new(nats_connection_manager));
+        let http = app::federation::peer_client()?;
         Ok(Self {
-            connection_pool: {
-                let conn_manager =
-                    AsyncDieselConnectionManager::<AsyncPgConnection>::new(config.database_url);
-                Pool::builder(conn_manager)
-                    .build()
-                    .expect("Failed to init database connection pool")
-            },
-            nats_connection_manager: Arc::new(RwLock::new(
-                NatsConnectionManager::new(config.nats_url, config.nats_auth_token).await?,
-            )),
+            connection_pool,
+            nats_connection_manager: nats_connection_manager.clone(),
+            event_publisher: EventPublisher::new(nats_connection_manager, jetstream.clone()),
+            jetstream,
+            live_sessions,
+            mailer: config
+                .mailer
+                .as_ref()
+                .map(mailer::from_config)
+                .transpose()?,
+            oidc: config
+                .oidc
+                .map(|oidc| OidcClient::new(oidc).map(Arc::new))
+                .transpose()?,
+            http: http.clone(),
+            server_key: Arc::new(ServerKey::load_or_generate(
+                &crate::data_dir().join("server_key.der"),
+            )?),
+            peer_keys: Arc::new(PeerKeys::new(http)),
+            authenticator: config
+                .ldap
+                .map(|ldap| Arc::new(LdapAuthenticator::new(ldap)) as Arc<dyn Authenticator>),
         })
     }
 }
 
-/// If a client misses this many messages at once it will be forcefully disconnected.
-const MAILBOX_SIZE: usize = 512;
+/// Maps an error from the app layer to the `NotAllowed` or `Error` variant every generated
+/// `*CommandResponse` has. A macro because the responses only share variant names, not a type.
+macro_rules! command_error_response {
+    ($response:ident, $e:expr, $doing:literal) => {
+        match $e {
+            $crate::app::Error::NotAllowed => (
+                ::axum::http::StatusCode::FORBIDDEN,
+                $response::NotAllowed { reason: None }.into(),
+            ),
+            $crate::app::Error::EmailNotVerified => (
+                ::axum::http::StatusCode::FORBIDDEN,
+                $response::NotAllowed {
+                    reason: Some(::rust_i18n::t!("emailNotVerified").into()),
+                }
+                .into(),
+            ),
+            $crate::app::Error::Diesel(::diesel::result::Error::NotFound) => (
+                ::axum::http::StatusCode::NOT_FOUND,
+                $response::Error {
+                    cause: Some(::rust_i18n::t!("notFound").into()),
+                }
+                .into(),
+            ),
+            e => {
+                ::tracing::error!("error {} {e}", $doing);
+                (
+                    ::axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    $response::Error {
+                        cause: Some(::rust_i18n::t!("tryAgainLater").into()),
+                    }
+                    .into(),
+                )
+            }
+        }
+    };
+}
+pub(crate) use command_error_response;
@@ -0,0 +1,49 @@
+use crate::api::GlobalServerContext;
+use crate::api::login::{ClientDevice, login_status_code};
+use crate::app;
+use crate::app::login::LoginResponse;
+use crate::app::oidc::{OidcLogin, OidcStartResponse};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(post, path = "/login/oidc/start", responses((status = OK, body=OidcStartResponse)))]
+pub async fn oidc_start(
+    State(state): State<GlobalServerContext>,
+) -> (StatusCode, Json<OidcStartResponse>) {
+    let resp = match app::oidc::try_start(&state).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error starting OpenID Connect login {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                OidcStartResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        OidcStartResponse::Ok { .. } => StatusCode::OK,
+        OidcStartResponse::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        OidcStartResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/login/oidc", responses((status = OK, body=LoginResponse)))]
+pub async fn oidc_login(
+    State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
+    Json(login): Json<OidcLogin>,
+) -> (StatusCode, Json<LoginResponse>) {
+    match app::oidc::try_login(&state, &login, device).await {
+        Ok(resp) => (login_status_code(&resp), resp.into()),
+        Err(e) => {
+            error!("error during OpenID Connect login {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                LoginResponse::ServerError.into(),
+            )
+        }
+    }
+}
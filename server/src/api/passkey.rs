@@ -0,0 +1,169 @@
+use crate::api::GlobalServerContext;
+use crate::api::login::{ClientDevice, SessionUser, login_status_code};
+use crate::app;
+use crate::app::login::{LoginResponse, PasskeyLogin, SecondFactorPasskeyOptions};
+use crate::app::webauthn::{
+    DeletePasskey, DeletePasskeyResponse, PasskeyRegister, PasskeyRegisterOptionsResponse,
+    PasskeyRegisterResponse, PasskeyRequestOptionsResponse, PasskeysResponse,
+};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(post, path = "/passkey/register_options", responses((status = OK, body=PasskeyRegisterOptionsResponse)))]
+pub async fn passkey_register_options(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+) -> (StatusCode, Json<PasskeyRegisterOptionsResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::webauthn::try_register_options(conn.as_mut(), &user).await,
+        Err(e) => Err(e.into()),
+    };
+    match resp {
+        Ok(resp) => (StatusCode::OK, resp.into()),
+        Err(e) => {
+            error!("error during passkey register options {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PasskeyRegisterOptionsResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/passkey/register", responses((status = OK, body=PasskeyRegisterResponse)))]
+pub async fn passkey_register(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(register): Json<PasskeyRegister>,
+) -> (StatusCode, Json<PasskeyRegisterResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::webauthn::try_register(conn.as_mut(), user.id, &register).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error during passkey register {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PasskeyRegisterResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        PasskeyRegisterResponse::Ok { .. } => StatusCode::OK,
+        PasskeyRegisterResponse::InvalidCredential => StatusCode::BAD_REQUEST,
+        PasskeyRegisterResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(get, path = "/passkeys", responses((status = OK, body=PasskeysResponse)))]
+pub async fn passkeys(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+) -> (StatusCode, Json<PasskeysResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::webauthn::try_list(conn.as_mut(), user.id).await,
+        Err(e) => Err(e.into()),
+    };
+    match resp {
+        Ok(resp) => (StatusCode::OK, resp.into()),
+        Err(e) => {
+            error!("error listing passkeys {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PasskeysResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/passkeys/delete", responses((status = OK, body=DeletePasskeyResponse)))]
+pub async fn delete_passkey(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(delete): Json<DeletePasskey>,
+) -> (StatusCode, Json<DeletePasskeyResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::webauthn::try_delete(conn.as_mut(), user.id, &delete).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error deleting passkey {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                DeletePasskeyResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        DeletePasskeyResponse::Ok => StatusCode::OK,
+        DeletePasskeyResponse::NotFound => StatusCode::NOT_FOUND,
+        DeletePasskeyResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/login/passkey_options", responses((status = OK, body=PasskeyRequestOptionsResponse)))]
+pub async fn passkey_login_options(
+    State(state): State<GlobalServerContext>,
+) -> (StatusCode, Json<PasskeyRequestOptionsResponse>) {
+    match app::login::try_passkey_login_options(&state).await {
+        Ok(resp) => (request_options_status_code(&resp), resp.into()),
+        Err(e) => {
+            error!("error during passkey login options {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PasskeyRequestOptionsResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/login/passkey", responses((status = OK, body=LoginResponse)))]
+pub async fn passkey_login(
+    State(state): State<GlobalServerContext>,
+    ClientDevice(device): ClientDevice,
+    Json(login): Json<PasskeyLogin>,
+) -> (StatusCode, Json<LoginResponse>) {
+    match app::login::try_passkey_login(&state, &login, device).await {
+        Ok(resp) => (login_status_code(&resp), resp.into()),
+        Err(e) => {
+            error!("error during passkey login {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                LoginResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/login/second_factor/passkey_options", responses((status = OK, body=PasskeyRequestOptionsResponse)))]
+pub async fn second_factor_passkey_options(
+    State(state): State<GlobalServerContext>,
+    Json(options): Json<SecondFactorPasskeyOptions>,
+) -> (StatusCode, Json<PasskeyRequestOptionsResponse>) {
+    match app::login::try_second_factor_passkey_options(&state, &options).await {
+        Ok(resp) => (request_options_status_code(&resp), resp.into()),
+        Err(e) => {
+            error!("error during second factor passkey options {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PasskeyRequestOptionsResponse::ServerError.into(),
+            )
+        }
+    }
+}
+
+fn request_options_status_code(resp: &PasskeyRequestOptionsResponse) -> StatusCode {
+    match resp {
+        PasskeyRequestOptionsResponse::Ok { .. } => StatusCode::OK,
+        PasskeyRequestOptionsResponse::InvalidChallenge => StatusCode::UNAUTHORIZED,
+        PasskeyRequestOptionsResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,86 @@
+use crate::api::GlobalServerContext;
+use crate::app;
+use crate::app::password_reset::{
+    CompletePasswordReset, CompletePasswordResetResponse, RequestPasswordReset,
+    RequestPasswordResetResponse, VerifyPasswordReset, VerifyPasswordResetResponse,
+};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(post, path = "/password_reset/request", responses((status = OK, body=RequestPasswordResetResponse)))]
+pub async fn request_password_reset(
+    State(state): State<GlobalServerContext>,
+    Json(request): Json<RequestPasswordReset>,
+) -> (StatusCode, Json<RequestPasswordResetResponse>) {
+    let resp = match app::password_reset::try_request(&state, &request).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error requesting password reset {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                RequestPasswordResetResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        RequestPasswordResetResponse::Ok => StatusCode::OK,
+        RequestPasswordResetResponse::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        RequestPasswordResetResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/password_reset/verify", responses((status = OK, body=VerifyPasswordResetResponse)))]
+pub async fn verify_password_reset(
+    State(state): State<GlobalServerContext>,
+    Json(verify): Json<VerifyPasswordReset>,
+) -> (StatusCode, Json<VerifyPasswordResetResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::password_reset::try_verify(conn.as_mut(), &verify).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error verifying password reset token {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                VerifyPasswordResetResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        VerifyPasswordResetResponse::Valid => StatusCode::OK,
+        VerifyPasswordResetResponse::InvalidToken => StatusCode::UNAUTHORIZED,
+        VerifyPasswordResetResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/password_reset/complete", responses((status = OK, body=CompletePasswordResetResponse)))]
+pub async fn complete_password_reset(
+    State(state): State<GlobalServerContext>,
+    Json(complete): Json<CompletePasswordReset>,
+) -> (StatusCode, Json<CompletePasswordResetResponse>) {
+    let resp = match app::password_reset::try_complete(&state, &complete).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error completing password reset {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                CompletePasswordResetResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        CompletePasswordResetResponse::Ok => StatusCode::OK,
+        CompletePasswordResetResponse::InvalidToken => StatusCode::UNAUTHORIZED,
+        CompletePasswordResetResponse::NewPasswordDoesntMeetRequirements { .. } => {
+            StatusCode::BAD_REQUEST
+        }
+        CompletePasswordResetResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
@@ -1,8 +1,9 @@
-use crate::api::GlobalServerContext;
+use crate::api::login::SessionUser;
 use crate::api::message_enum::command::{
     ReactCreateCommand, ReactCreateCommandResponse, ReactDeleteCommand, ReactDeleteCommandResponse,
-    UserDeleteCommand, UserDeleteCommandResponse,
 };
+use crate::api::{GlobalServerContext, command_error_response};
+use crate::app;
 use axum::Json;
 use axum::extract::State;
 use axum::http::StatusCode;
@@ -11,15 +12,31 @@ use axum::http::StatusCode;
 
 pub async fn create_react(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<ReactCreateCommand>,
 ) -> (StatusCode, Json<ReactCreateCommandResponse>) {
-    todo!()
+    match app::react::create_react(state, user.id, &command).await {
+        Ok(react) => (
+            StatusCode::OK,
+            ReactCreateCommandResponse::CreateOk {
+                message_id: react.message,
+                emoji: react.emoji,
+                user_id: react.author,
+            }
+            .into(),
+        ),
+        Err(e) => command_error_response!(ReactCreateCommandResponse, e, "creating react"),
+    }
 }
 
 #[utoipa::path(delete, path = "/react", responses((status = OK, body=ReactDeleteCommandResponse)))]
 pub async fn delete_react(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<ReactDeleteCommand>,
 ) -> (StatusCode, Json<ReactDeleteCommandResponse>) {
-    todo!()
+    match app::react::delete_react(state, user.id, &command).await {
+        Ok(()) => (StatusCode::OK, ReactDeleteCommandResponse::DeleteOk.into()),
+        Err(e) => command_error_response!(ReactDeleteCommandResponse, e, "deleting react"),
+    }
 }
@@ -0,0 +1,99 @@
+use crate::api::GlobalServerContext;
+use crate::api::live_sessions::LiveSessions;
+use crate::api::login::SessionUser;
+use crate::app;
+use crate::app::registration::{
+    CreateInvite, CreateInviteResponse, PendingUsersResponse, ReviewUser, ReviewUserResponse,
+};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use tracing::error;
+
+#[utoipa::path(post, path = "/invite", responses((status = OK, body=CreateInviteResponse)))]
+pub async fn create_invite(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(create): Json<CreateInvite>,
+) -> (StatusCode, Json<CreateInviteResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::registration::try_create_invite(conn.as_mut(), user.id, &create).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error creating invite {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                CreateInviteResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        CreateInviteResponse::Ok { .. } => StatusCode::OK,
+        CreateInviteResponse::Invalid => StatusCode::BAD_REQUEST,
+        CreateInviteResponse::NotAllowed => StatusCode::FORBIDDEN,
+        CreateInviteResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(get, path = "/registration/pending", responses((status = OK, body=PendingUsersResponse)))]
+pub async fn pending_users(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+) -> (StatusCode, Json<PendingUsersResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::registration::try_list_pending(conn.as_mut(), user.id).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("error listing pending users {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                PendingUsersResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        PendingUsersResponse::Ok { .. } => StatusCode::OK,
+        PendingUsersResponse::NotAllowed => StatusCode::FORBIDDEN,
+        PendingUsersResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
+
+#[utoipa::path(post, path = "/registration/review", responses((status = OK, body=ReviewUserResponse)))]
+pub async fn review_user(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(review): Json<ReviewUser>,
+) -> (StatusCode, Json<ReviewUserResponse>) {
+    let resp = match state.connection_pool.get().await {
+        Ok(mut conn) => app::registration::try_review(conn.as_mut(), user.id, &review).await,
+        Err(e) => Err(e.into()),
+    };
+    let resp = match resp {
+        Ok((resp, revoked)) => {
+            LiveSessions::revoke(&state.nats_connection_manager, &revoked).await;
+            resp
+        }
+        Err(e) => {
+            error!("error reviewing pending user {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ReviewUserResponse::ServerError.into(),
+            );
+        }
+    };
+    let status_code = match &resp {
+        ReviewUserResponse::Ok => StatusCode::OK,
+        ReviewUserResponse::NotFound => StatusCode::NOT_FOUND,
+        ReviewUserResponse::NotAllowed => StatusCode::FORBIDDEN,
+        ReviewUserResponse::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status_code, resp.into())
+}
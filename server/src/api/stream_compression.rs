@@ -0,0 +1,181 @@
+//! Compression for long-lived responses like the event stream. Unlike compressing a whole body,
+//! the compressor is flushed after every chunk so each event reaches the client as soon as it is
+//! sent, while still sharing one compression context for the whole connection.
+
+use std::io::{self, Write};
+
+use axum::body::Body;
+use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::Response;
+use bytes::Bytes;
+use flate2::write::ZlibEncoder;
+use futures_util::StreamExt;
+use tracing::error;
+
+/// Low enough to keep per connection memory small, the gains past this are marginal for events.
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum StreamCompression {
+    Zstd,
+    /// HTTP's `deflate`, which is zlib framed.
+    Deflate,
+}
+
+impl StreamCompression {
+    /// Our preferred compression out of those in `Accept-Encoding`. Clients' preference order is
+    /// not considered, only whether they accept an encoding at all.
+    pub(super) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accepted = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next()?.trim().to_ascii_lowercase();
+                let refused = params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect::<Vec<_>>();
+        [StreamCompression::Zstd, StreamCompression::Deflate]
+            .into_iter()
+            .find(|compression| accepted.iter().any(|name| name == compression.name()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StreamCompression::Zstd => "zstd",
+            StreamCompression::Deflate => "deflate",
+        }
+    }
+
+    /// Compresses the body of `response`, flushing after every chunk of it.
+    pub(super) fn compress(self, response: Response) -> Response {
+        let mut encoder = match Encoder::new(self) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                error!("error creating {} encoder {e}", self.name());
+                return response;
+            }
+        };
+        let (mut parts, body) = response.into_parts();
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(self.name()));
+        parts.headers.remove(CONTENT_LENGTH);
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        let body = body.into_data_stream().map(move |chunk| {
+            chunk.and_then(|chunk| encoder.compress(&chunk).map_err(axum::Error::new))
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+}
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: StreamCompression) -> io::Result<Self> {
+        Ok(match compression {
+            StreamCompression::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+            StreamCompression::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+        })
+    }
+
+    /// Compresses `chunk` and returns everything the client needs to decompress it.
+    fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::http::HeaderMap;
+    use axum::http::header::ACCEPT_ENCODING;
+    use flate2::read::ZlibDecoder;
+
+    use super::{Encoder, StreamCompression};
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn prefers_zstd() {
+        assert_eq!(
+            StreamCompression::negotiate(&accept_encoding("gzip, deflate, br, zstd")),
+            Some(StreamCompression::Zstd)
+        );
+        assert_eq!(
+            StreamCompression::negotiate(&accept_encoding("zstd;q=0, deflate;q=0.5")),
+            Some(StreamCompression::Deflate)
+        );
+        assert_eq!(StreamCompression::negotiate(&accept_encoding("gzip")), None);
+    }
+
+    /// Every chunk must be decodable on its own arrival, not just once the stream ends.
+    #[test]
+    fn each_chunk_flushed() {
+        let events = [
+            "event: message\ndata: {\"serverEvent\":\"message\"}\n\n",
+            "event: message\ndata: {\"serverEvent\":\"message\"}\n\n",
+            ": keep-alive\n\n",
+        ];
+        for compression in [StreamCompression::Zstd, StreamCompression::Deflate] {
+            let mut encoder = Encoder::new(compression).unwrap();
+            let mut compressed = Vec::new();
+            let mut expected = String::new();
+            for event in events {
+                compressed.extend_from_slice(&encoder.compress(event.as_bytes()).unwrap());
+                expected.push_str(event);
+                let mut decompressed = vec![0; expected.len()];
+                match compression {
+                    StreamCompression::Zstd => {
+                        zstd::stream::read::Decoder::new(compressed.as_slice())
+                            .unwrap()
+                            .read_exact(&mut decompressed)
+                            .unwrap();
+                    }
+                    StreamCompression::Deflate => {
+                        ZlibDecoder::new(compressed.as_slice())
+                            .read_exact(&mut decompressed)
+                            .unwrap();
+                    }
+                }
+                assert_eq!(decompressed, expected.as_bytes(), "{compression:?}");
+            }
+        }
+    }
+}
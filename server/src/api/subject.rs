@@ -0,0 +1,41 @@
+//! NATS subjects that [`ServerEvent`](super::message_enum::server_event::ServerEvent)s travel over.
+//!
+//! Every event belongs to exactly one community. Publishers write it to that community's stored
+//! subject, which the [event log](super::event_log) captures, numbers and republishes on the
+//! community's live subject. Each server node subscribes to the live subjects of the communities its
+//! connected users belong to. Events about a user themself, such as a profile update, are published
+//! once per community the user is in.
+//!
+//! `UserCommunity` events are additionally published on the member's user subject, carrying the same
+//! sequence number. This is how a node learns about a community a connected user just joined before
+//! it is subscribed to it. The user subject is not stored.
+//!
+//! Payloads are the JSON serialization of the event. See
+//! [`EventPublisher`](super::event_publisher::EventPublisher).
+
+use crate::app::{CommunityId, UserId};
+
+/// `aspen.community.<community uuid>.events`
+pub fn community_events(community: CommunityId) -> String {
+    format!("aspen.community.{}.events", community.0)
+}
+
+/// `aspen.stored.community.<community uuid>.events`
+pub fn stored_community_events(community: CommunityId) -> String {
+    format!("aspen.stored.community.{}.events", community.0)
+}
+
+/// Every stored community subject.
+pub const STORED_COMMUNITY_EVENTS_WILDCARD: &str = "aspen.stored.community.*.events";
+
+/// Republish destination turning a stored community subject into its live counterpart.
+pub const COMMUNITY_EVENTS_REPUBLISH: &str = "aspen.community.{{wildcard(1)}}.events";
+
+/// Revoked sessions whose streams every node closes, see
+/// [`LiveSessions`](super::live_sessions::LiveSessions).
+pub const SESSIONS_REVOKED: &str = "aspen.sessions.revoked";
+
+/// `aspen.user.<user uuid>.events`
+pub fn user_events(user: UserId) -> String {
+    format!("aspen.user.{}.events", user.0)
+}
@@ -3,11 +3,12 @@ use crate::api::message_enum::command::{
     UserCreateCommand, UserCreateCommandResponse, UserDeleteCommand, UserDeleteCommandResponse,
     UserReadCommand, UserReadCommandResponse, UserUpdateCommand, UserUpdateCommandResponse,
 };
-use crate::api::{GlobalServerContext, UserId};
+use crate::api::{GlobalServerContext, UserId, command_error_response};
 use crate::app;
 use crate::app::Error;
 use crate::app::login::hash_password;
 use crate::app::user::User;
+use crate::aspen_config::aspen_config;
 use crate::database::schema;
 use axum::extract::State;
 use axum::http::StatusCode;
@@ -32,6 +33,16 @@ pub async fn create_user(
                     e,
                 )) = err
                 {
+                    let cause = if e.constraint_name() == Some("user_email_key") {
+                        t!("emailAlreadyTaken")
+                    } else {
+                        t!("usernameAlreadyTaken")
+                    };
+                    (
+                        StatusCode::BAD_REQUEST,
+                        UserCreateCommandResponse::Error { cause: Some(cause) }.into(),
+                    )
+                } else if let app::Error::NameReserved = err {
                     (
                         StatusCode::BAD_REQUEST,
                         UserCreateCommandResponse::Error {
@@ -39,6 +50,32 @@ pub async fn create_user(
                         }
                         .into(),
                     )
+                } else if let app::Error::MailAddress(_) = err {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        UserCreateCommandResponse::Error {
+                            cause: Some(t!("invalidEmail")),
+                        }
+                        .into(),
+                    )
+                } else if let app::Error::Registration(refusal) = err {
+                    (
+                        StatusCode::FORBIDDEN,
+                        UserCreateCommandResponse::NotAllowed {
+                            reason: Some(refusal.describe().into()),
+                        }
+                        .into(),
+                    )
+                } else if let app::Error::PasswordRequirement(requirement) = err {
+                    let policy = aspen_config().await.password_policy;
+                    (
+                        StatusCode::BAD_REQUEST,
+                        UserCreateCommandResponse::PasswordRejected {
+                            requirement,
+                            reason: requirement.describe(&policy).into(),
+                        }
+                        .into(),
+                    )
                 } else {
                     error!("error inserting new user into database {err}");
                     (
@@ -92,15 +129,23 @@ pub async fn read_user(
 
 pub async fn update_user(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<UserUpdateCommand>,
 ) -> (StatusCode, Json<UserUpdateCommandResponse>) {
-    todo!()
+    match app::user::update_user(state, user.id, &command).await {
+        Ok(()) => (StatusCode::OK, UserUpdateCommandResponse::UpdateOk.into()),
+        Err(e) => command_error_response!(UserUpdateCommandResponse, e, "updating user"),
+    }
 }
 
 #[utoipa::path(delete, path = "/user", responses((status = OK, body=UserDeleteCommandResponse)))]
 pub async fn delete_user(
     State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
     Json(command): Json<UserDeleteCommand>,
 ) -> (StatusCode, Json<UserDeleteCommandResponse>) {
-    todo!()
+    match app::user::delete_user(state, user.id, command.id).await {
+        Ok(()) => (StatusCode::OK, UserDeleteCommandResponse::DeleteOk.into()),
+        Err(e) => command_error_response!(UserDeleteCommandResponse, e, "deleting user"),
+    }
 }
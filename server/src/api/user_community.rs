@@ -0,0 +1,33 @@
+use crate::api::login::SessionUser;
+use crate::api::message_enum::command::{
+    UserCommunityDeleteCommand, UserCommunityDeleteCommandResponse,
+};
+use crate::api::{GlobalServerContext, command_error_response};
+use crate::app;
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+
+/// Removes the session user from a community, users can't remove anyone but themselves.
+#[utoipa::path(delete, path = "/user_community", responses((status = OK, body=UserCommunityDeleteCommandResponse)))]
+pub async fn delete_user_community(
+    State(state): State<GlobalServerContext>,
+    SessionUser(user): SessionUser,
+    Json(command): Json<UserCommunityDeleteCommand>,
+) -> (StatusCode, Json<UserCommunityDeleteCommandResponse>) {
+    if command.user != user.id {
+        return (
+            StatusCode::FORBIDDEN,
+            UserCommunityDeleteCommandResponse::NotAllowed { reason: None }.into(),
+        );
+    }
+    match app::community::leave_community(state, user.id, command.community).await {
+        Ok(()) => (
+            StatusCode::OK,
+            UserCommunityDeleteCommandResponse::DeleteOk.into(),
+        ),
+        Err(e) => {
+            command_error_response!(UserCommunityDeleteCommandResponse, e, "leaving community")
+        }
+    }
+}
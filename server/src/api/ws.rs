@@ -0,0 +1,360 @@
+//! A single WebSocket carrying both commands and [`ServerEvent`]s, an alternative to the REST
+//! endpoints plus `/event_stream`.
+//!
+//! Every frame is a JSON text message. Clients send commands tagged with the command's name and a
+//! request id of their choosing,
+//!
+//! ```json
+//! {"requestId": 1, "command": "reactCreate", "body": {"messageId": "...", "emoji": "🌲"}}
+//! ```
+//!
+//! and the server answers each with the same `*CommandResponse` the REST endpoint would,
+//!
+//! ```json
+//! {"type": "response", "requestId": 1, "command": "reactCreate", "response": {"createOk": {...}}}
+//! ```
+//!
+//! Responses arrive in the order commands finish, not the order they were sent. The socket starts
+//! with a `{"type": "ready", "ready": {...}}` frame holding a [`Ready`] snapshot, after which events
+//! are pushed as `{"type": "event", "id": 42, "event": {...}}` where `id` is the event's
+//! [event log](super::event_log) sequence number.
+//!
+//! A client that falls behind on either responses or events is sent
+//! `{"type": "resync", "reason": ...}` and disconnected, same as on the event stream. The socket is
+//! also closed when its session is revoked.
+//!
+//! Connecting with `?encoding=msgpack` or `?encoding=cbor` switches every frame in both directions
+//! to a binary message holding the same data in that [encoding](super::encoding).
+//!
+//! Frames aren't compressed, the WebSocket implementation axum uses can't negotiate
+//! permessage-deflate. Clients that need compression use `/event_stream` for events.
+
+use crate::api::encoding::{Encoding, EncodingQuery};
+use crate::api::event_stream::{Delivery, EventSubscriptions, ResyncReason, mailbox};
+use crate::api::live_sessions::LiveStream;
+use crate::api::login::{Session, SessionUser};
+use crate::api::message_enum::command::*;
+use crate::api::message_enum::server_event::ServerEvent;
+use crate::api::{
+    GlobalServerContext, category, channel, community, icon, message, metrics, react, user,
+    user_community,
+};
+use crate::app;
+use crate::app::channel::{ChannelMarkRead, ChannelMarkReadResponse};
+use crate::app::ready::Ready;
+use crate::aspen_config::aspen_config;
+use axum::Json;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+
+pub async fn ws(
+    State(state): State<GlobalServerContext>,
+    session: Session,
+    Query(EncodingQuery { encoding }): Query<EncodingQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let user = session.user.id;
+    let registered = match state.connection_pool.get().await {
+        Ok(mut conn) => {
+            state
+                .live_sessions
+                .register(conn.as_mut(), session.family)
+                .await
+        }
+        Err(e) => Err(e.into()),
+    };
+    let live = match registered {
+        Ok(live) => live,
+        Err(e) => {
+            error!("error registering websocket for {user} {e}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+        }
+    };
+    let session_user = SessionUser(session.user);
+    let subscriptions = match EventSubscriptions::new(&state, user).await {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            error!("error subscribing websocket for {user} {e}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+        }
+    };
+    let ready = match subscriptions.ready(&state).await {
+        Ok(ready) => ready,
+        Err(e) => {
+            error!("error loading ready snapshot for {user} {e}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Please try again later."));
+        }
+    };
+    Ok(upgrade.on_upgrade(move |socket| {
+        serve(
+            state,
+            session_user,
+            live,
+            subscriptions,
+            ready,
+            encoding,
+            socket,
+        )
+    }))
+}
+
+/// Lists every command the socket accepts along with its REST handler, which it is run through
+/// so both transports behave the same.
+macro_rules! ws_commands {
+    ($($variant:ident($command:ty) -> $response:ty = $handler:path;)*) => {
+        #[derive(Deserialize)]
+        #[serde(tag = "command", content = "body", rename_all = "camelCase")]
+        enum Command {
+            $($variant($command),)*
+        }
+
+        #[derive(Serialize)]
+        #[serde(tag = "command", content = "response", rename_all = "camelCase")]
+        enum CommandResponse {
+            $($variant($response),)*
+        }
+
+        impl Command {
+            async fn run(self, state: GlobalServerContext, user: SessionUser) -> CommandResponse {
+                match self {
+                    $(Command::$variant(command) => {
+                        let (_, Json(response)) = $handler(State(state), user, Json(command)).await;
+                        CommandResponse::$variant(response)
+                    })*
+                }
+            }
+        }
+    };
+}
+
+ws_commands! {
+    UserRead(UserReadCommand) -> UserReadCommandResponse = user::read_user;
+    UserUpdate(UserUpdateCommand) -> UserUpdateCommandResponse = user::update_user;
+    UserDelete(UserDeleteCommand) -> UserDeleteCommandResponse = user::delete_user;
+    MessageCreate(MessageCreateCommand) -> MessageCreateCommandResponse = message::create_message;
+    MessageUpdate(MessageUpdateCommand) -> MessageUpdateCommandResponse = message::update_message;
+    MessageDelete(MessageDeleteCommand) -> MessageDeleteCommandResponse = message::delete_message;
+    ChannelCreate(ChannelCreateCommand) -> ChannelCreateCommandResponse = channel::create_channel;
+    ChannelUpdate(ChannelUpdateCommand) -> ChannelUpdateCommandResponse = channel::update_channel;
+    ChannelDelete(ChannelDeleteCommand) -> ChannelDeleteCommandResponse = channel::delete_channel;
+    ChannelMarkRead(ChannelMarkRead) -> ChannelMarkReadResponse = channel::mark_channel_read;
+    CategoryCreate(CategoryCreateCommand) -> CategoryCreateCommandResponse = category::create_category;
+    CategoryUpdate(CategoryUpdateCommand) -> CategoryUpdateCommandResponse = category::update_category;
+    CategoryDelete(CategoryDeleteCommand) -> CategoryDeleteCommandResponse = category::delete_category;
+    CommunityCreate(CommunityCreateCommand) -> CommunityCreateCommandResponse = community::create_community;
+    CommunityUpdate(CommunityUpdateCommand) -> CommunityUpdateCommandResponse = community::update_community;
+    CommunityDelete(CommunityDeleteCommand) -> CommunityDeleteCommandResponse = community::delete_community;
+    UserCommunityDelete(UserCommunityDeleteCommand) -> UserCommunityDeleteCommandResponse = user_community::delete_user_community;
+    IconCreate(IconCreateCommand) -> IconCreateCommandResponse = icon::create_icon;
+    IconDelete(IconDeleteCommand) -> IconDeleteCommandResponse = icon::delete_icon;
+    ReactCreate(ReactCreateCommand) -> ReactCreateCommandResponse = react::create_react;
+    ReactDelete(ReactDeleteCommand) -> ReactDeleteCommandResponse = react::delete_react;
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientFrame {
+    request_id: u64,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ServerFrame {
+    #[serde(rename_all = "camelCase")]
+    Response {
+        request_id: u64,
+        #[serde(flatten)]
+        response: CommandResponse,
+    },
+    Event {
+        id: u64,
+        event: ServerEvent,
+    },
+    Ready {
+        ready: Box<Ready>,
+    },
+    /// Sent right before disconnecting a client that fell behind.
+    Resync {
+        reason: ResyncReason,
+    },
+    /// The client sent something that isn't a command, it is not answered otherwise.
+    InvalidFrame {
+        cause: String,
+    },
+}
+
+async fn serve(
+    state: GlobalServerContext,
+    session_user: SessionUser,
+    mut live: LiveStream,
+    subscriptions: EventSubscriptions,
+    ready: Ready,
+    encoding: Encoding,
+    socket: WebSocket,
+) {
+    let user = session_user.0.id;
+    let (event_sender, mut events) = mailbox().await;
+    tokio::spawn(subscriptions.forward(state.clone(), None, Some(ready), event_sender));
+    let (response_sender, mut responses) = mpsc::channel(aspen_config().await.event_mailbox_size);
+    let (mut sink, mut stream) = socket.split();
+    loop {
+        let frame = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) => {
+                    match decode_frame(encoding, message) {
+                        Ok(ClientFrame { request_id, command }) => {
+                            // Commands waiting on their answer count towards the mailbox too.
+                            match response_sender.clone().try_reserve_owned() {
+                                Ok(permit) => {
+                                    let state = state.clone();
+                                    let session_user = session_user.clone();
+                                    tokio::spawn(async move {
+                                        let response = command.run(state, session_user).await;
+                                        permit.send((request_id, response));
+                                    });
+                                    continue;
+                                }
+                                Err(TrySendError::Full(_)) => {
+                                    warn!("websocket for {user} exceeded its mailbox, disconnecting");
+                                    metrics::count_event_stream_drop(ResyncReason::MailboxFull);
+                                    ServerFrame::Resync { reason: ResyncReason::MailboxFull }
+                                }
+                                Err(TrySendError::Closed(_)) => break,
+                            }
+                        }
+                        Err(e) => ServerFrame::InvalidFrame { cause: e.to_string() },
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("websocket error for {user} {e}");
+                    break;
+                }
+            },
+            Some((request_id, response)) = responses.recv() => ServerFrame::Response {
+                request_id,
+                response,
+            },
+            delivery = events.recv() => match delivery {
+                Some(Delivery::Event { sequence, event }) => ServerFrame::Event {
+                    id: sequence,
+                    event,
+                },
+                Some(Delivery::Ready(ready)) => ServerFrame::Ready { ready },
+                Some(Delivery::Resync { reason }) => ServerFrame::Resync { reason },
+                // Only sent when replaying, which the socket doesn't offer.
+                Some(Delivery::ResyncRequired { .. }) => continue,
+                // The event stream ended, either the client fell behind or we failed.
+                None => break,
+            },
+            _ = live.revoked() => break,
+        };
+        let message = match encode_frame(encoding, &frame) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("error serializing websocket frame {e}");
+                continue;
+            }
+        };
+        if sink.send(message).await.is_err() || matches!(frame, ServerFrame::Resync { .. }) {
+            break;
+        }
+    }
+}
+
+/// Binary encodings are only accepted in binary messages, JSON only in text messages.
+fn decode_frame(encoding: Encoding, message: Message) -> Result<ClientFrame, String> {
+    match (encoding, message) {
+        (Encoding::Json, Message::Text(text)) => {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        }
+        (Encoding::MessagePack | Encoding::Cbor, Message::Binary(bytes)) => {
+            encoding.decode(&bytes).map_err(|e| e.to_string())
+        }
+        _ => Err(format!(
+            "expected frames encoded as {}",
+            encoding.mime_type()
+        )),
+    }
+}
+
+fn encode_frame(encoding: Encoding, frame: &ServerFrame) -> Result<Message, app::Error> {
+    Ok(match encoding {
+        Encoding::Json => Message::Text(serde_json::to_string(frame)?.into()),
+        Encoding::MessagePack | Encoding::Cbor => Message::Binary(encoding.encode(frame)?.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use axum::extract::ws::Message;
+
+    use super::{ClientFrame, Command, CommandResponse, ServerFrame, decode_frame};
+    use crate::api::encoding::Encoding;
+    use crate::api::message_enum::command::ReactDeleteCommandResponse;
+    use crate::app::MessageId;
+
+    #[test]
+    fn command_frame_parses() {
+        let message_id = MessageId::new();
+        let frame: ClientFrame = serde_json::from_value(json!({
+            "requestId": 7,
+            "command": "reactCreate",
+            "body": {
+                "messageId": message_id,
+                "emoji": "🌲"
+            }
+        }))
+        .unwrap();
+        assert_eq!(frame.request_id, 7);
+        let Command::ReactCreate(command) = frame.command else {
+            panic!("expected a react create command");
+        };
+        assert_eq!(command.message_id, message_id);
+        assert_eq!(command.emoji, "🌲");
+    }
+
+    #[test]
+    fn binary_frames_need_binary_messages() {
+        let frame = json!({
+            "requestId": 7,
+            "command": "reactCreate",
+            "body": {
+                "messageId": MessageId::new(),
+                "emoji": "🌲"
+            }
+        });
+        let bytes = Encoding::Cbor.encode(&frame).unwrap();
+        assert!(decode_frame(Encoding::Cbor, Message::Binary(bytes.into())).is_ok());
+        let text = serde_json::to_string(&frame).unwrap();
+        assert!(decode_frame(Encoding::Cbor, Message::Text(text.into())).is_err());
+    }
+
+    #[test]
+    fn response_frame_correlates() {
+        let frame = ServerFrame::Response {
+            request_id: 7,
+            response: CommandResponse::ReactDelete(ReactDeleteCommandResponse::DeleteOk),
+        };
+        assert_eq!(
+            serde_json::to_value(frame).unwrap(),
+            json!({
+                "type": "response",
+                "requestId": 7,
+                "command": "reactDelete",
+                "response": "deleteOk"
+            })
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Security relevant events, kept in the database for administrators to review.
+
+use chrono::{DateTime, Utc};
+use diesel::ExpressionMethods;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::database::schema::audit_log;
+use crate::{app, app::UserId};
+
+pub enum AuditEvent<'a> {
+    /// Too many failed password attempts for an account or from an IP.
+    Lockout {
+        user: Option<UserId>,
+        ip: Option<&'a str>,
+        failures: i32,
+        until: DateTime<Utc>,
+    },
+}
+
+impl AuditEvent<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            AuditEvent::Lockout { .. } => "lockout",
+        }
+    }
+
+    fn user(&self) -> Option<UserId> {
+        match self {
+            AuditEvent::Lockout { user, .. } => *user,
+        }
+    }
+
+    fn ip(&self) -> Option<&str> {
+        match self {
+            AuditEvent::Lockout { ip, .. } => *ip,
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            AuditEvent::Lockout {
+                failures, until, ..
+            } => format!("locked out after {failures} failed attempts until {until}"),
+        }
+    }
+}
+
+/// Writes `event` to the audit log, and to the `audit` tracing target.
+pub async fn record(conn: &mut AsyncPgConnection, event: AuditEvent<'_>) -> Result<(), app::Error> {
+    let (kind, user, ip, detail) = (event.kind(), event.user(), event.ip(), event.detail());
+    info!(target: "audit", kind, user = ?user, ip, "{detail}");
+    diesel::insert_into(audit_log::table)
+        .values((
+            audit_log::id.eq(Uuid::now_v7()),
+            audit_log::time.eq(Utc::now().naive_utc()),
+            audit_log::kind.eq(kind),
+            audit_log::user.eq(user),
+            audit_log::ip.eq(ip),
+            audit_log::detail.eq(detail),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
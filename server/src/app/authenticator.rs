@@ -0,0 +1,301 @@
+//! Password checks against somewhere other than the `user` table, such as an LDAP directory.
+//!
+//! [`try_login`](crate::app::login::try_login) asks the configured [`Authenticator`] first and
+//! only checks the local password hash of users it doesn't know. The accounts it creates are
+//! linked to its usernames in `external_identity`. It never takes over a local account that has
+//! one of its usernames, whose owner might not be the directory's user of that name.
+
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures_util::future::BoxFuture;
+
+use crate::app::login::{hash_password, make_token};
+use crate::app::registration;
+use crate::app::user::User;
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{external_identity, user, user_role};
+use crate::{app, app::UserId};
+
+/// Who an authenticator says logged in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalAccount {
+    /// The name as the authenticator spells it, which the local account is linked by.
+    pub username: String,
+    /// Trusted as verified.
+    pub email: Option<String>,
+    /// Replace the roles the authenticator gave the local account before, roles granted locally
+    /// stay. Sorted, without duplicates.
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Authentication {
+    Authenticated(ExternalAccount),
+    /// The user is known but the password is wrong.
+    Rejected,
+    /// The local password hash decides.
+    UnknownUser,
+}
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Authentication, app::Error>>;
+}
+
+/// The local account of `account`, created if it's the first login, with its roles updated. Also
+/// says whether the roles changed. `None` if `account` has never logged in and a local account
+/// already has its name.
+pub async fn local_account(
+    conn: &mut AsyncPgConnection,
+    account: &ExternalAccount,
+) -> Result<Option<(UserId, bool)>, app::Error> {
+    let linked = linked_account(conn, &account.username).await?;
+    let new_user = match linked {
+        Some(_) => None,
+        None => Some(new_user(conn, account).await?),
+    };
+    conn.transaction::<_, app::Error, _>(|conn| {
+        async move {
+            let user_id = match (linked, new_user) {
+                (Some(user_id), _) => user_id,
+                (None, Some(new_user)) => {
+                    let name_taken = registration::reserved_name(&new_user.name).await
+                        || diesel::select(diesel::dsl::exists(
+                            user::table.filter(user::name.eq(&new_user.name)),
+                        ))
+                        .get_result::<bool>(conn)
+                        .await?;
+                    if name_taken {
+                        // By the account a concurrent first login just made, a local one or an
+                        // admin's.
+                        match linked_account(conn, &account.username).await? {
+                            Some(user_id) => user_id,
+                            None => return Ok(None),
+                        }
+                    } else {
+                        // A concurrent first login may claim the username first, then its account
+                        // is used.
+                        let claimed: Option<UserId> = diesel::insert_into(external_identity::table)
+                            .values((
+                                external_identity::username.eq(&account.username),
+                                external_identity::user.eq(new_user.id),
+                            ))
+                            .on_conflict_do_nothing()
+                            .returning(external_identity::user)
+                            .get_result(conn)
+                            .await
+                            .optional()?;
+                        match claimed {
+                            Some(user_id) => {
+                                diesel::insert_into(user::table)
+                                    .values(new_user)
+                                    .execute(conn)
+                                    .await?;
+                                user_id
+                            }
+                            None => linked_account(conn, &account.username)
+                                .await?
+                                .ok_or(diesel::result::Error::NotFound)?,
+                        }
+                    }
+                }
+                (None, None) => unreachable!("made above for unlinked accounts"),
+            };
+            update_roles(conn, user_id, account).await.map(Some)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Replaces the roles `account` had from the authenticator, saying whether the roles of `user_id`
+/// changed.
+async fn update_roles(
+    conn: &mut AsyncPgConnection,
+    user_id: UserId,
+    account: &ExternalAccount,
+) -> Result<(UserId, bool), app::Error> {
+    let old_roles = roles(conn, user_id).await?;
+    diesel::delete(
+        user_role::table.filter(
+            user_role::user
+                .eq(user_id)
+                .and(user_role::external.eq(true))
+                .and(user_role::role.ne_all(&account.roles)),
+        ),
+    )
+    .execute(conn)
+    .await?;
+    let granted = account
+        .roles
+        .iter()
+        .map(|role| {
+            (
+                user_role::user.eq(user_id),
+                user_role::role.eq(role),
+                user_role::external.eq(true),
+            )
+        })
+        .collect::<Vec<_>>();
+    // Roles granted locally too stay local, so they outlast the group.
+    diesel::insert_into(user_role::table)
+        .values(granted)
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+    let roles_changed = roles(conn, user_id).await? != old_roles;
+    Ok((user_id, roles_changed))
+}
+
+async fn linked_account(
+    conn: &mut AsyncPgConnection,
+    username: &str,
+) -> Result<Option<UserId>, diesel::result::Error> {
+    external_identity::table
+        .select(external_identity::user)
+        .filter(external_identity::username.eq(username))
+        .first(conn)
+        .await
+        .optional()
+}
+
+/// Sorted.
+async fn roles(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<String>, diesel::result::Error> {
+    user_role::table
+        .select(user_role::role)
+        .filter(user_role::user.eq(user))
+        .order(user_role::role)
+        .load(conn)
+        .await
+}
+
+async fn new_user(
+    conn: &mut AsyncPgConnection,
+    account: &ExternalAccount,
+) -> Result<User, app::Error> {
+    let email_taken = match &account.email {
+        Some(email) => {
+            diesel::select(diesel::dsl::exists(
+                user::table.filter(user::email.eq(email)),
+            ))
+            .get_result::<bool>(conn)
+            .await?
+        }
+        None => false,
+    };
+    let email = account.email.clone().filter(|_| !email_taken);
+    // The authenticator checks the password, the local one is never used.
+    let config = aspen_config().await;
+    let password_hash = hash_password(&config.password_hashing, &make_token())?;
+    Ok(User {
+        id: UserId::new(),
+        name: account.username.clone(),
+        icon: None,
+        password_hash,
+        email_verified: email.is_some(),
+        email,
+        approved: registration::starts_approved(config.registration),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    use super::{ExternalAccount, local_account, roles};
+    use crate::app::UserId;
+    use crate::aspen_config::load_test_config;
+    use crate::database::test_database_url;
+
+    fn account(username: &str, roles: &[&str]) -> ExternalAccount {
+        ExternalAccount {
+            username: username.into(),
+            email: None,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn accounts_linked_by_identity_not_name() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let bob = UserId::new();
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{}', 'bob', '');"#,
+            bob.0
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            local_account(&mut conn, &account("bob", &[]))
+                .await
+                .unwrap(),
+            None
+        );
+        // Named in the configured admins.
+        assert_eq!(
+            local_account(&mut conn, &account("root", &[]))
+                .await
+                .unwrap(),
+            None
+        );
+
+        let (alice, changed) = local_account(&mut conn, &account("alice", &["moderator"]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(changed);
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user_role"("user", role) VALUES ('{}', 'admin');"#,
+            alice.0
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            local_account(&mut conn, &account("alice", &[]))
+                .await
+                .unwrap(),
+            Some((alice, true))
+        );
+        assert_eq!(roles(&mut conn, alice).await.unwrap(), ["admin"]);
+        assert_eq!(
+            local_account(&mut conn, &account("alice", &["admin"]))
+                .await
+                .unwrap(),
+            Some((alice, false))
+        );
+        assert_eq!(
+            local_account(&mut conn, &account("alice", &[]))
+                .await
+                .unwrap(),
+            Some((alice, false))
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_first_logins_share_an_account() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut first = AsyncPgConnection::establish(&url).await.unwrap();
+        let mut second = AsyncPgConnection::establish(&url).await.unwrap();
+        let carol = account("carol", &["moderator"]);
+        let (a, b) = tokio::join!(
+            local_account(&mut first, &carol),
+            local_account(&mut second, &carol)
+        );
+        let (a, b) = (a.unwrap().unwrap(), b.unwrap().unwrap());
+        assert_eq!(a.0, b.0);
+    }
+}
@@ -1,12 +1,23 @@
-use crate::app::community::Community;
-use crate::app::{CategoryId, Loadable, MaybeLoaded};
-use diesel_async::AsyncPgConnection;
+use crate::api::GlobalServerContext;
+use crate::api::message_enum::command::{CategoryCreateCommand, CategoryUpdateCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::app;
+use crate::app::community::{Community, require_owner};
+use crate::app::email;
+use crate::app::{CategoryId, Loadable, MaybeLoaded, UserId};
+use crate::database::schema::{self, category, channel};
+use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, Selectable, SelectableHelper};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = category)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Category {
     pub id: CategoryId,
     pub community: MaybeLoaded<Community>,
     pub name: String,
-    pub sort_index: u32,
+    pub sort_index: i32,
 }
 
 impl Loadable for Category {
@@ -16,10 +27,125 @@ impl Loadable for Category {
         pg_connection: &mut AsyncPgConnection,
         id: Self::Id,
     ) -> Result<Self, diesel::result::Error> {
-        todo!()
+        schema::category::table
+            .select(Category::as_select())
+            .filter(category::id.eq(id))
+            .first(pg_connection)
+            .await
     }
 
     fn id(&self) -> &Self::Id {
         &self.id
     }
 }
+
+/// `sort_index` is unsigned in the API but stored in a signed column.
+pub fn sort_index_to_db(sort_index: u32) -> i32 {
+    i32::try_from(sort_index).unwrap_or(i32::MAX)
+}
+
+pub fn sort_index_from_db(sort_index: i32) -> u32 {
+    u32::try_from(sort_index).unwrap_or_default()
+}
+
+/// Only owners of a community may create, update or delete its categories.
+pub(crate) async fn create_category(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &CategoryCreateCommand,
+) -> Result<Category, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    require_owner(conn.as_mut(), user, command.community).await?;
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    let category = Category {
+        id: CategoryId::new(),
+        community: MaybeLoaded::NotLoaded(command.community),
+        name: command.name.clone(),
+        sort_index: sort_index_to_db(command.sort_index),
+    };
+    diesel::insert_into(category::table)
+        .values(category.clone())
+        .execute(conn.as_mut())
+        .await?;
+    state
+        .event_publisher
+        .publish(
+            command.community,
+            &ServerEvent::Category(sub_variant::Category::Create {
+                id: category.id,
+                community: command.community,
+                name: category.name.clone(),
+                sort_index: command.sort_index,
+            }),
+        )
+        .await;
+    Ok(category)
+}
+
+pub(crate) async fn update_category(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &CategoryUpdateCommand,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let category = Category::load_from_db(conn.as_mut(), command.id).await?;
+    let community = *category.community.id();
+    require_owner(conn.as_mut(), user, community).await?;
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    diesel::update(category::table.filter(category::id.eq(command.id)))
+        .set((
+            category::name.eq(&command.name),
+            category::sort_index.eq(sort_index_to_db(command.sort_index)),
+        ))
+        .execute(conn.as_mut())
+        .await?;
+    state
+        .event_publisher
+        .publish(
+            community,
+            &ServerEvent::Category(sub_variant::Category::Update {
+                id: command.id,
+                name: command.name.clone(),
+                sort_index: command.sort_index,
+            }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Deletes a category, channels inside it are kept and moved to the top level of the community.
+pub(crate) async fn delete_category(
+    state: GlobalServerContext,
+    user: UserId,
+    id: CategoryId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let category = Category::load_from_db(conn.as_mut(), id).await?;
+    let community = *category.community.id();
+    require_owner(conn.as_mut(), user, community).await?;
+    conn.transaction::<_, app::Error, _>(|conn| {
+        async move {
+            diesel::update(channel::table.filter(channel::parent_category.eq(id)))
+                .set((
+                    channel::parent_category.eq(None::<CategoryId>),
+                    channel::community.eq(community),
+                ))
+                .execute(conn)
+                .await?;
+            diesel::delete(category::table.filter(category::id.eq(id)))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            community,
+            &ServerEvent::Category(sub_variant::Category::Delete { id }),
+        )
+        .await;
+    Ok(())
+}
@@ -1,17 +1,46 @@
-use crate::api::{ChannelPermissions, ChannelType};
-use crate::app::category::Category;
-use crate::app::community::Community;
-use crate::app::{ChannelId, Loadable, MaybeLoaded};
-use diesel_async::AsyncPgConnection;
+use crate::api::message_enum::command::{ChannelCreateCommand, ChannelUpdateCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::api::{ChannelPermissions, ChannelType, GlobalServerContext, PermissionOverwrite};
+use crate::app;
+use crate::app::category::{Category, sort_index_from_db, sort_index_to_db};
+use crate::app::community::{Community, is_member, require_owner};
+use crate::app::email;
+use crate::app::{CategoryId, ChannelId, CommunityId, Loadable, MaybeLoaded, UserId};
+use crate::database::schema::{
+    self, category, channel, channel_read, channel_role_overwrite, message, user_role,
+};
+use chrono::Utc;
+use diesel::deserialize::FromSql;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Integer;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, JoinOnDsl, NullableExpressionMethods,
+    QueryDsl, Queryable, Selectable, SelectableHelper,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error as StdError;
 
+/// Lets members see a channel's messages, and events about them.
+pub const READ: u32 = 1 << 0;
+
+/// What members may do in a channel without overwrites.
+const DEFAULT_PERMISSIONS: u32 = READ;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = channel)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Channel {
     pub id: ChannelId,
-    pub community: MaybeLoaded<Community>,
-    pub parent_category: MaybeLoaded<Category>,
+    /// Always set for channels created through the API, older rows may only have a category.
+    pub community: Option<MaybeLoaded<Community>>,
+    pub parent_category: Option<MaybeLoaded<Category>>,
     pub name: String,
-    pub permissions: ChannelPermissions,
     pub ty: ChannelType,
-    pub sort_index: u32,
+    pub sort_index: i32,
 }
 
 impl Loadable for Channel {
@@ -21,10 +50,467 @@ impl Loadable for Channel {
         pg_connection: &mut AsyncPgConnection,
         id: Self::Id,
     ) -> Result<Self, diesel::result::Error> {
-        todo!()
+        schema::channel::table
+            .select(Channel::as_select())
+            .filter(channel::id.eq(id))
+            .first(pg_connection)
+            .await
     }
 
     fn id(&self) -> &Self::Id {
         &self.id
     }
 }
+
+impl FromSql<Integer, Pg> for ChannelType {
+    fn from_sql(v: PgValue) -> Result<Self, Box<dyn StdError + Send + Sync + 'static>> {
+        match i32::from_sql(v)? {
+            0 => Ok(ChannelType::Text),
+            1 => Ok(ChannelType::Voice),
+            other => Err(format!("unrecognized channel type {other}").into()),
+        }
+    }
+}
+
+impl ToSql<Integer, Pg> for ChannelType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        let v: i32 = match self {
+            ChannelType::Text => 0,
+            ChannelType::Voice => 1,
+        };
+        <i32 as ToSql<Integer, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl ChannelPermissions {
+    /// What a member with `roles` may do. The overwrites of all their roles together win over the
+    /// one for everyone.
+    pub fn effective(&self, roles: &[String]) -> u32 {
+        let everyone = (DEFAULT_PERMISSIONS & !self.everyone.deny) | self.everyone.allow;
+        let (allow, deny) = roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .fold((0, 0), |(allow, deny), overwrite| {
+                (allow | overwrite.allow, deny | overwrite.deny)
+            });
+        (everyone & !deny) | allow
+    }
+}
+
+/// Which of `channels` `user` has the [`READ`] permission in, leaving membership of their
+/// communities to the caller. Bits are stored as signed integers, so they are cast back and forth
+/// unchanged.
+pub async fn readable(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    channels: &[ChannelId],
+) -> Result<HashSet<ChannelId>, diesel::result::Error> {
+    let roles = roles(pg_connection, user).await?;
+    let everyone: Vec<(ChannelId, i32, i32)> = channel::table
+        .select((channel::id, channel::everyone_allow, channel::everyone_deny))
+        .filter(channel::id.eq_any(channels))
+        .load(pg_connection)
+        .await?;
+    let mut permissions = everyone
+        .into_iter()
+        .map(|(channel, allow, deny)| {
+            let everyone = PermissionOverwrite {
+                allow: allow as u32,
+                deny: deny as u32,
+            };
+            (
+                channel,
+                ChannelPermissions {
+                    everyone,
+                    roles: BTreeMap::new(),
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let overwrites: Vec<(ChannelId, String, i32, i32)> = channel_role_overwrite::table
+        .select((
+            channel_role_overwrite::channel,
+            channel_role_overwrite::role,
+            channel_role_overwrite::allow,
+            channel_role_overwrite::deny,
+        ))
+        .filter(channel_role_overwrite::channel.eq_any(channels))
+        .filter(channel_role_overwrite::role.eq_any(&roles))
+        .load(pg_connection)
+        .await?;
+    for (channel, role, allow, deny) in overwrites {
+        if let Some(permissions) = permissions.get_mut(&channel) {
+            let overwrite = PermissionOverwrite {
+                allow: allow as u32,
+                deny: deny as u32,
+            };
+            permissions.roles.insert(role, overwrite);
+        }
+    }
+    Ok(permissions
+        .into_iter()
+        .filter(|(_, permissions)| permissions.effective(&roles) & READ != 0)
+        .map(|(channel, _)| channel)
+        .collect())
+}
+
+/// Replaces the permissions set on `channel`, call it in a transaction.
+async fn store_permissions(
+    conn: &mut AsyncPgConnection,
+    channel: ChannelId,
+    permissions: &ChannelPermissions,
+) -> Result<(), diesel::result::Error> {
+    diesel::update(channel::table.filter(channel::id.eq(channel)))
+        .set((
+            channel::everyone_allow.eq(permissions.everyone.allow as i32),
+            channel::everyone_deny.eq(permissions.everyone.deny as i32),
+        ))
+        .execute(conn)
+        .await?;
+    diesel::delete(
+        channel_role_overwrite::table.filter(channel_role_overwrite::channel.eq(channel)),
+    )
+    .execute(conn)
+    .await?;
+    let roles = permissions
+        .roles
+        .iter()
+        .map(|(role, overwrite)| {
+            (
+                channel_role_overwrite::channel.eq(channel),
+                channel_role_overwrite::role.eq(role),
+                channel_role_overwrite::allow.eq(overwrite.allow as i32),
+                channel_role_overwrite::deny.eq(overwrite.deny as i32),
+            )
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(channel_role_overwrite::table)
+        .values(roles)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// The community `channel` belongs to, either directly or through its category.
+pub async fn channel_community(
+    pg_connection: &mut AsyncPgConnection,
+    channel: ChannelId,
+) -> Result<CommunityId, diesel::result::Error> {
+    let (community, category_community): (Option<CommunityId>, Option<CommunityId>) =
+        channel::table
+            .left_join(category::table)
+            .select((channel::community, category::community.nullable()))
+            .filter(channel::id.eq(channel))
+            .first(pg_connection)
+            .await?;
+    community
+        .or(category_community)
+        .ok_or(diesel::result::Error::NotFound)
+}
+
+/// Fails with [`app::Error::NotAllowed`] unless `category` is part of `community`.
+async fn require_category_in(
+    pg_connection: &mut AsyncPgConnection,
+    category: Option<CategoryId>,
+    community: CommunityId,
+) -> Result<(), app::Error> {
+    let Some(category) = category else {
+        return Ok(());
+    };
+    let category = Category::load_from_db(pg_connection, category).await?;
+    if *category.community.id() == community {
+        Ok(())
+    } else {
+        Err(app::Error::NotAllowed)
+    }
+}
+
+pub(crate) async fn create_channel(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &ChannelCreateCommand,
+) -> Result<Channel, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    require_owner(conn.as_mut(), user, command.community).await?;
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    require_category_in(conn.as_mut(), command.parent_category, command.community).await?;
+    let channel = Channel {
+        id: ChannelId::new(),
+        community: Some(MaybeLoaded::NotLoaded(command.community)),
+        parent_category: command.parent_category.map(MaybeLoaded::NotLoaded),
+        name: command.name.clone(),
+        ty: command.ty.clone(),
+        sort_index: sort_index_to_db(command.sort_index),
+    };
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let channel = channel.clone();
+        async move {
+            let id = channel.id;
+            diesel::insert_into(channel::table)
+                .values(channel)
+                .execute(conn)
+                .await?;
+            store_permissions(conn, id, &command.permissions).await
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            command.community,
+            &ServerEvent::Channel(sub_variant::Channel::Create {
+                id: channel.id,
+                parent_category: command.parent_category,
+                name: channel.name.clone(),
+                permissions: command.permissions.clone(),
+                community: command.community,
+                ty: channel.ty.clone(),
+                sort_index: sort_index_from_db(channel.sort_index),
+            }),
+        )
+        .await;
+    Ok(channel)
+}
+
+/// Event streams re-check who may read the channel when they see the update.
+pub(crate) async fn update_channel(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &ChannelUpdateCommand,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let community = channel_community(conn.as_mut(), command.id).await?;
+    require_owner(conn.as_mut(), user, community).await?;
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    require_category_in(conn.as_mut(), command.parent_category, community).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::update(channel::table.filter(channel::id.eq(command.id)))
+                .set((
+                    channel::community.eq(community),
+                    channel::parent_category.eq(command.parent_category),
+                    channel::name.eq(&command.name),
+                    channel::sort_index.eq(sort_index_to_db(command.sort_index)),
+                ))
+                .execute(conn)
+                .await?;
+            store_permissions(conn, command.id, &command.permissions).await
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            community,
+            &ServerEvent::Channel(sub_variant::Channel::Update {
+                id: command.id,
+                parent_category: command.parent_category,
+                name: command.name.clone(),
+                permissions: command.permissions.clone(),
+                sort_index: command.sort_index,
+            }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Deletes a channel along with its messages.
+pub(crate) async fn delete_channel(
+    state: GlobalServerContext,
+    user: UserId,
+    id: ChannelId,
+) -> Result<(), app::Error> {
+    use crate::database::schema::react;
+
+    let mut conn = state.connection_pool.get().await?;
+    let community = channel_community(conn.as_mut(), id).await?;
+    require_owner(conn.as_mut(), user, community).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let messages = message::table
+                .select(message::id)
+                .filter(message::channel.eq(id));
+            diesel::delete(react::table.filter(react::message.eq_any(messages)))
+                .execute(conn)
+                .await?;
+            diesel::delete(message::table.filter(message::channel.eq(id)))
+                .execute(conn)
+                .await?;
+            diesel::delete(channel_read::table.filter(channel_read::channel.eq(id)))
+                .execute(conn)
+                .await?;
+            diesel::delete(
+                channel_role_overwrite::table.filter(channel_role_overwrite::channel.eq(id)),
+            )
+            .execute(conn)
+            .await?;
+            diesel::delete(channel::table.filter(channel::id.eq(id)))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            community,
+            &ServerEvent::Channel(sub_variant::Channel::Delete { id }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Whether `user` may read the messages in `channel`, as a member of its community with the
+/// [`READ`] permission.
+pub async fn can_read(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    channel: ChannelId,
+) -> Result<bool, diesel::result::Error> {
+    let community = match channel_community(pg_connection, channel).await {
+        Ok(community) => community,
+        Err(diesel::result::Error::NotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !is_member(pg_connection, user, community).await? {
+        return Ok(false);
+    }
+    Ok(readable(pg_connection, user, &[channel])
+        .await?
+        .contains(&channel))
+}
+
+/// The community `channel` is in, fails with [`app::Error::NotAllowed`] unless `user` may read
+/// it, see [`can_read`].
+pub async fn require_read(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    channel: ChannelId,
+) -> Result<CommunityId, app::Error> {
+    let community = channel_community(pg_connection, channel).await?;
+    if can_read(pg_connection, user, channel).await? {
+        Ok(community)
+    } else {
+        Err(app::Error::NotAllowed)
+    }
+}
+
+/// The roles of `user`, which [`ChannelPermissions`] are set for.
+pub async fn roles(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<String>, diesel::result::Error> {
+    user_role::table
+        .select(user_role::role)
+        .filter(user_role::user.eq(user))
+        .load(pg_connection)
+        .await
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelMarkRead {
+    pub channel_id: ChannelId,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ChannelMarkReadResponse {
+    MarkReadOk,
+    NotAllowed { reason: Option<String> },
+    Error { cause: Option<String> },
+}
+
+/// Marks every message currently in `channel` as read by `user`, if they may read it.
+pub(crate) async fn mark_channel_read(
+    state: GlobalServerContext,
+    user: UserId,
+    channel: ChannelId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    require_read(conn.as_mut(), user, channel).await?;
+    let now = Utc::now().naive_utc();
+    diesel::insert_into(channel_read::table)
+        .values((
+            channel_read::user.eq(user),
+            channel_read::channel.eq(channel),
+            channel_read::last_read.eq(now),
+        ))
+        .on_conflict((channel_read::user, channel_read::channel))
+        .do_update()
+        .set(channel_read::last_read.eq(now))
+        .execute(conn.as_mut())
+        .await?;
+    Ok(())
+}
+
+/// How many messages from other users `user` hasn't read yet in each of `channels`. Channels
+/// without any are left out.
+pub async fn unread_counts(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    channels: &[ChannelId],
+) -> Result<HashMap<ChannelId, i64>, diesel::result::Error> {
+    let counts: Vec<(ChannelId, i64)> = message::table
+        .left_join(
+            channel_read::table.on(channel_read::channel
+                .eq(message::channel)
+                .and(channel_read::user.eq(user))),
+        )
+        .filter(message::channel.eq_any(channels))
+        .filter(message::author.ne(user))
+        .filter(
+            channel_read::last_read
+                .is_null()
+                .or(message::time.gt(channel_read::last_read.assume_not_null())),
+        )
+        .group_by(message::channel)
+        .select((message::channel, diesel::dsl::count_star()))
+        .load(pg_connection)
+        .await?;
+    Ok(counts.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::READ;
+    use crate::api::{ChannelPermissions, PermissionOverwrite};
+
+    fn overwrite(allow: u32, deny: u32) -> PermissionOverwrite {
+        PermissionOverwrite { allow, deny }
+    }
+
+    #[test]
+    fn members_read_unless_denied() {
+        assert_eq!(ChannelPermissions::default().effective(&[]) & READ, READ);
+        let private = ChannelPermissions {
+            everyone: overwrite(0, READ),
+            roles: BTreeMap::from([("staff".into(), overwrite(READ, 0))]),
+        };
+        assert_eq!(private.effective(&[]) & READ, 0);
+        assert_eq!(private.effective(&["guest".into()]) & READ, 0);
+        assert_eq!(private.effective(&["staff".into()]) & READ, READ);
+    }
+
+    #[test]
+    fn role_allows_win_over_role_denies() {
+        let permissions = ChannelPermissions {
+            everyone: PermissionOverwrite::default(),
+            roles: BTreeMap::from([
+                ("muted".into(), overwrite(0, READ)),
+                ("staff".into(), overwrite(READ, 0)),
+            ]),
+        };
+        assert_eq!(permissions.effective(&["muted".into()]) & READ, 0);
+        assert_eq!(
+            permissions.effective(&["muted".into(), "staff".into()]) & READ,
+            READ
+        );
+    }
+}
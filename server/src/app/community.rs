@@ -1,11 +1,21 @@
 use crate::api::GlobalServerContext;
-use crate::api::message_enum::command::CommunityCreateCommand;
+use crate::api::message_enum::command::{CommunityCreateCommand, CommunityUpdateCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
 use crate::app;
+use crate::app::email;
 use crate::app::icon::Icon;
-use crate::app::{CommunityId, Loadable, MaybeLoaded};
-use crate::database::schema::{self, community};
-use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, Selectable, SelectableHelper};
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use crate::app::registration::is_admin;
+use crate::app::{CategoryId, ChannelId, CommunityId, Loadable, MaybeLoaded, UserId};
+use crate::database::schema::{
+    self, category, channel, channel_read, channel_role_overwrite, community, community_user,
+    message, react,
+};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, QueryDsl, Queryable, Selectable,
+    SelectableHelper,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
 #[derive(Debug, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = community)]
@@ -14,6 +24,9 @@ pub struct Community {
     pub id: CommunityId,
     pub name: String,
     pub icon: Option<MaybeLoaded<Icon>>,
+    /// Who may rename or delete the community besides admins, `None` for communities from before
+    /// owners were recorded and ones whose owner deleted their account.
+    pub owner: Option<UserId>,
 }
 
 impl Loadable for Community {
@@ -35,19 +48,238 @@ impl Loadable for Community {
     }
 }
 
+/// Whether `user` has joined `community`.
+pub async fn is_member(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    community: CommunityId,
+) -> Result<bool, diesel::result::Error> {
+    diesel::select(diesel::dsl::exists(
+        community_user::table.filter(
+            community_user::user
+                .eq(user)
+                .and(community_user::community.eq(community)),
+        ),
+    ))
+    .get_result(pg_connection)
+    .await
+}
+
+/// Fails with [`app::Error::NotAllowed`] unless `user` owns `community` or is an admin.
+pub async fn require_owner(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    community: CommunityId,
+) -> Result<(), app::Error> {
+    let owner: Option<UserId> = schema::community::table
+        .select(schema::community::owner)
+        .filter(schema::community::id.eq(community))
+        .first(pg_connection)
+        .await?;
+    if owner == Some(user) || is_admin(pg_connection, user).await? {
+        Ok(())
+    } else {
+        Err(app::Error::NotAllowed)
+    }
+}
+
+/// Every community `user` has joined.
+pub async fn member_communities(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<CommunityId>, diesel::result::Error> {
+    community_user::table
+        .select(community_user::community)
+        .filter(community_user::user.eq(user))
+        .load(pg_connection)
+        .await
+}
+
+/// Creates a community with `creator` as its owner and first member.
 pub(crate) async fn create_community(
     state: GlobalServerContext,
+    creator: UserId,
     command: &CommunityCreateCommand,
 ) -> Result<Community, app::Error> {
     let mut conn = state.connection_pool.get().await?;
+    email::require_verified_to_post(conn.as_mut(), creator).await?;
     let community = Community {
         id: CommunityId::new(),
         icon: command.icon.map(MaybeLoaded::NotLoaded),
         name: command.name.clone(),
+        owner: Some(creator),
     };
-    diesel::insert_into(schema::community::table)
-        .values(community.clone())
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        let community = community.clone();
+        async move {
+            let id = community.id;
+            diesel::insert_into(schema::community::table)
+                .values(community)
+                .execute(conn)
+                .await?;
+            diesel::insert_into(community_user::table)
+                .values((
+                    community_user::user.eq(creator),
+                    community_user::community.eq(id),
+                ))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            community.id,
+            &ServerEvent::Community(sub_variant::Community::Create {
+                id: community.id,
+                name: community.name.clone(),
+                icon: command.icon,
+            }),
+        )
+        .await;
+    state
+        .event_publisher
+        .publish_membership(
+            community.id,
+            creator,
+            &ServerEvent::UserCommunity(sub_variant::UserCommunity::Create {
+                community: community.id,
+                user: creator,
+            }),
+        )
+        .await;
+    Ok(community)
+}
+
+/// Only the owner or an admin may rename a community or change its icon.
+pub(crate) async fn update_community(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &CommunityUpdateCommand,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    require_owner(conn.as_mut(), user, command.id).await?;
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    diesel::update(community::table.filter(community::id.eq(command.id)))
+        .set((
+            community::name.eq(&command.name),
+            community::icon.eq(command.icon),
+        ))
         .execute(conn.as_mut())
         .await?;
-    Ok(community)
+    state
+        .event_publisher
+        .publish(
+            command.id,
+            &ServerEvent::Community(sub_variant::Community::Update {
+                id: command.id,
+                name: command.name.clone(),
+                icon: command.icon,
+            }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Deletes a community along with every channel, category, message and membership inside it. Only
+/// the owner or an admin may.
+pub(crate) async fn delete_community(
+    state: GlobalServerContext,
+    user: UserId,
+    id: CommunityId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    require_owner(conn.as_mut(), user, id).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let categories: Vec<CategoryId> = category::table
+                .select(category::id)
+                .filter(category::community.eq(id))
+                .load(conn)
+                .await?;
+            let channels: Vec<ChannelId> = channel::table
+                .select(channel::id)
+                .filter(
+                    channel::community
+                        .eq(id)
+                        .or(channel::parent_category.eq_any(&categories)),
+                )
+                .load(conn)
+                .await?;
+            let messages = message::table
+                .select(message::id)
+                .filter(message::channel.eq_any(&channels));
+            diesel::delete(react::table.filter(react::message.eq_any(messages)))
+                .execute(conn)
+                .await?;
+            diesel::delete(message::table.filter(message::channel.eq_any(&channels)))
+                .execute(conn)
+                .await?;
+            diesel::delete(channel_read::table.filter(channel_read::channel.eq_any(&channels)))
+                .execute(conn)
+                .await?;
+            diesel::delete(
+                channel_role_overwrite::table
+                    .filter(channel_role_overwrite::channel.eq_any(&channels)),
+            )
+            .execute(conn)
+            .await?;
+            diesel::delete(channel::table.filter(channel::id.eq_any(&channels)))
+                .execute(conn)
+                .await?;
+            diesel::delete(category::table.filter(category::community.eq(id)))
+                .execute(conn)
+                .await?;
+            diesel::delete(community_user::table.filter(community_user::community.eq(id)))
+                .execute(conn)
+                .await?;
+            diesel::delete(community::table.filter(community::id.eq(id)))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish(
+            id,
+            &ServerEvent::Community(sub_variant::Community::Delete { id }),
+        )
+        .await;
+    Ok(())
+}
+
+/// `user` leaves `community`.
+pub(crate) async fn leave_community(
+    state: GlobalServerContext,
+    user: UserId,
+    community: CommunityId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let rows_deleted = diesel::delete(
+        community_user::table.filter(
+            community_user::user
+                .eq(user)
+                .and(community_user::community.eq(community)),
+        ),
+    )
+    .execute(conn.as_mut())
+    .await?;
+    if rows_deleted == 0 {
+        return Err(diesel::result::Error::NotFound.into());
+    }
+    state
+        .event_publisher
+        .publish_membership(
+            community,
+            user,
+            &ServerEvent::UserCommunity(sub_variant::UserCommunity::Delete { community, user }),
+        )
+        .await;
+    Ok(())
 }
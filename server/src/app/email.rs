@@ -0,0 +1,464 @@
+//! Confirms users can read mail sent to their address, and lets them change it.
+//!
+//! Confirmation links carry a token signed with the token secret rather than one kept in the
+//! database. Besides the address to confirm it names the address the user had when it was sent,
+//! so it stops working once the address changes some other way.
+//!
+//! Changing a verified address takes a link opened at the old address first, so a stolen session
+//! or password alone can't redirect password resets.
+
+use std::sync::Arc;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use diesel::result::DatabaseErrorKind;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, PgExpressionMethods, QueryDsl,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::api::GlobalServerContext;
+use crate::app::login::check_password;
+use crate::app::throttle::{self, Subject};
+use crate::app::user::User;
+use crate::aspen_config::aspen_config;
+use crate::database::schema::user;
+use crate::mailer::{self, Mail, Mailer};
+use crate::{app, app::UserId};
+
+const CONFIRMATION_LIFETIME: Duration = Duration::days(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Purpose {
+    /// Opened at `email`, which becomes the user's verified address.
+    Confirm,
+    /// Opened at `previous`, which allows replacing it with `email`.
+    ApproveChange,
+}
+
+/// What a confirmation token vouches for.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Confirmation {
+    purpose: Purpose,
+    user: UserId,
+    /// The user's address when the token was made, which must still be theirs to use it.
+    previous: Option<String>,
+    email: String,
+    /// Unix timestamp.
+    expires: i64,
+}
+
+fn mac(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    // Keeps the MAC from ever equaling a token hash made with the same secret.
+    mac.update(b"aspen email confirmation\0");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn sign(secret: &[u8], confirmation: &Confirmation) -> String {
+    let payload = BASE64_URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(confirmation).expect("confirmations always serialize"));
+    let signature = BASE64_URL_SAFE_NO_PAD.encode(mac(secret, &payload).finalize().into_bytes());
+    format!("{payload}.{signature}")
+}
+
+/// The confirmation `token` carries, if it was signed with `secret` and hasn't expired.
+fn open(secret: &[u8], token: &str, now: DateTime<Utc>) -> Option<Confirmation> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+    mac(secret, payload).verify_slice(&signature).ok()?;
+    let confirmation: Confirmation =
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    (confirmation.expires > now.timestamp()).then_some(confirmation)
+}
+
+/// Mails `email` a link confirming it belongs to `user`, who currently has `previous`.
+async fn send_confirmation(
+    mailer: Arc<dyn Mailer>,
+    user: UserId,
+    name: &str,
+    previous: Option<String>,
+    email: String,
+) {
+    let config = aspen_config().await;
+    let token = sign(
+        config.token_secret.as_bytes(),
+        &Confirmation {
+            purpose: Purpose::Confirm,
+            user,
+            previous,
+            email: email.clone(),
+            expires: (Utc::now() + CONFIRMATION_LIFETIME).timestamp(),
+        },
+    );
+    let mail = confirmation_mail(email, name, &token, config.public_origin.as_deref());
+    mailer::send_in_background(mailer, mail, "email confirmation");
+}
+
+/// Mails the verified address `previous` of `user` a link allowing it to be replaced by `email`.
+async fn send_change_approval(
+    mailer: Arc<dyn Mailer>,
+    user: UserId,
+    name: &str,
+    previous: String,
+    email: String,
+) {
+    let config = aspen_config().await;
+    let token = sign(
+        config.token_secret.as_bytes(),
+        &Confirmation {
+            purpose: Purpose::ApproveChange,
+            user,
+            previous: Some(previous.clone()),
+            email: email.clone(),
+            expires: (Utc::now() + CONFIRMATION_LIFETIME).timestamp(),
+        },
+    );
+    let mail = change_approval_mail(
+        previous,
+        name,
+        &email,
+        &token,
+        config.public_origin.as_deref(),
+    );
+    mailer::send_in_background(mailer, mail, "email change approval");
+}
+
+fn confirmation_mail(to: String, name: &str, token: &str, public_origin: Option<&str>) -> Mail {
+    let action = mailer::token_action(public_origin, "verify_email", token);
+    Mail {
+        subject: rust_i18n::t!("emailConfirmMailSubject").into(),
+        body: rust_i18n::t!(
+            "emailConfirmMailBody",
+            name = name,
+            email = to,
+            action = action,
+            hours = CONFIRMATION_LIFETIME.num_hours()
+        )
+        .into(),
+        to,
+    }
+}
+
+/// Asks the old address of an account to allow replacing it.
+fn change_approval_mail(
+    to: String,
+    name: &str,
+    new_email: &str,
+    token: &str,
+    public_origin: Option<&str>,
+) -> Mail {
+    let action = mailer::token_action(public_origin, "verify_email", token);
+    Mail {
+        to,
+        subject: rust_i18n::t!("emailChangeMailSubject").into(),
+        body: rust_i18n::t!(
+            "emailChangeMailBody",
+            name = name,
+            email = new_email,
+            action = action,
+            hours = CONFIRMATION_LIFETIME.num_hours()
+        )
+        .into(),
+    }
+}
+
+/// Asks a user who just signed up with `email` to confirm it, if mail can be sent.
+pub async fn confirm_new_account(
+    state: &GlobalServerContext,
+    user: UserId,
+    name: &str,
+    email: &str,
+) {
+    if let Some(mailer) = state.mailer.clone() {
+        send_confirmation(mailer, user, name, Some(email.into()), email.into()).await;
+    }
+}
+
+/// Fails with [`app::Error::EmailNotVerified`] if the instance requires `user` to have verified an
+/// address before posting, and they haven't.
+pub async fn require_verified_to_post(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<(), app::Error> {
+    if aspen_config().await.unverified_can_post {
+        return Ok(());
+    }
+    let verified: bool = user::table
+        .select(user::email_verified)
+        .filter(user::id.eq(user))
+        .first(conn)
+        .await?;
+    if verified {
+        Ok(())
+    } else {
+        Err(app::Error::EmailNotVerified)
+    }
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum SendConfirmationResponse {
+    Ok,
+    NoEmail,
+    AlreadyVerified,
+    /// The server has no mailer configured.
+    Unavailable,
+    ServerError,
+}
+
+/// Mails `user` another link confirming the address they have.
+pub async fn try_send_confirmation(
+    state: &GlobalServerContext,
+    user: &User,
+) -> Result<SendConfirmationResponse, app::Error> {
+    let Some(email) = &user.email else {
+        return Ok(SendConfirmationResponse::NoEmail);
+    };
+    if user.email_verified {
+        return Ok(SendConfirmationResponse::AlreadyVerified);
+    }
+    let Some(mailer) = state.mailer.clone() else {
+        return Ok(SendConfirmationResponse::Unavailable);
+    };
+    send_confirmation(
+        mailer,
+        user.id,
+        &user.name,
+        Some(email.clone()),
+        email.clone(),
+    )
+    .await;
+    Ok(SendConfirmationResponse::Ok)
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyEmail {
+    token: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum VerifyEmailResponse {
+    Ok,
+    /// The link was opened at the old address, a link confirming the new one went there.
+    ChangeApproved,
+    /// Wrong, expired, or made before the address last changed.
+    InvalidToken,
+    /// Another account verified the address first.
+    EmailTaken,
+    ServerError,
+}
+
+/// Marks the address a confirmation link was sent to as verified, making it the user's address if
+/// it wasn't already. A link approving a change instead sends the confirmation link for the new
+/// address.
+pub async fn try_verify(
+    state: &GlobalServerContext,
+    v: &VerifyEmail,
+) -> Result<VerifyEmailResponse, app::Error> {
+    let secret = aspen_config().await.token_secret;
+    let Some(confirmation) = open(secret.as_bytes(), &v.token, Utc::now()) else {
+        return Ok(VerifyEmailResponse::InvalidToken);
+    };
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    if confirmation.purpose == Purpose::ApproveChange {
+        let name: Option<String> = user::table
+            .select(user::name)
+            .filter(
+                user::id
+                    .eq(confirmation.user)
+                    .and(user::email.is_not_distinct_from(&confirmation.previous))
+                    .and(user::email_verified.eq(true)),
+            )
+            .first(conn)
+            .await
+            .optional()?;
+        let (Some(name), Some(mailer)) = (name, state.mailer.clone()) else {
+            return Ok(VerifyEmailResponse::InvalidToken);
+        };
+        send_confirmation(
+            mailer,
+            confirmation.user,
+            &name,
+            confirmation.previous,
+            confirmation.email,
+        )
+        .await;
+        return Ok(VerifyEmailResponse::ChangeApproved);
+    }
+    let updated = diesel::update(
+        user::table.filter(
+            user::id
+                .eq(confirmation.user)
+                .and(user::email.is_not_distinct_from(&confirmation.previous)),
+        ),
+    )
+    .set((
+        user::email.eq(&confirmation.email),
+        user::email_verified.eq(true),
+    ))
+    .execute(conn)
+    .await;
+    match updated {
+        Ok(0) => Ok(VerifyEmailResponse::InvalidToken),
+        Ok(_) => Ok(VerifyEmailResponse::Ok),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Ok(VerifyEmailResponse::EmailTaken)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEmail {
+    current_password: String,
+    new_email: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum ChangeEmailResponse {
+    /// A confirmation link went to the new address, which replaces the old one once it's opened.
+    /// If the old address is verified the link goes there first, and opening it sends the one for
+    /// the new address.
+    Ok,
+    PasswordIncorrect,
+    /// Like [`LoginResponse::RateLimited`](crate::app::login::LoginResponse::RateLimited), wrong
+    /// passwords count as failed logins.
+    RateLimited {
+        retry_after: u64,
+    },
+    InvalidEmail,
+    EmailTaken,
+    /// The server has no mailer configured.
+    Unavailable,
+    ServerError,
+}
+
+/// Starts changing the address of `user` to `c.new_email`. A verified old address has to approve
+/// the change, so a stolen password can't take over password resets.
+pub async fn try_change(
+    state: &GlobalServerContext,
+    user: &User,
+    c: &ChangeEmail,
+    ip: Option<&str>,
+) -> Result<ChangeEmailResponse, app::Error> {
+    let Some(mailer) = state.mailer.clone() else {
+        return Ok(ChangeEmailResponse::Unavailable);
+    };
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let subjects = [Some(Subject::Account(user.id)), ip.map(Subject::Ip)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    if let Some(retry_after) = throttle::attempt(conn, &subjects).await? {
+        return Ok(ChangeEmailResponse::RateLimited { retry_after });
+    }
+    let config = aspen_config().await;
+    if !check_password(
+        &config.password_hashing,
+        &c.current_password,
+        &user.password_hash,
+    ) {
+        return Ok(ChangeEmailResponse::PasswordIncorrect);
+    }
+    throttle::succeeded(conn, &subjects).await?;
+    let Ok(new_email) = mailer::normalize_address(&c.new_email) else {
+        return Ok(ChangeEmailResponse::InvalidEmail);
+    };
+    let taken = diesel::select(diesel::dsl::exists(
+        user::table.filter(user::email.eq(&new_email).and(user::id.ne(user.id))),
+    ))
+    .get_result::<bool>(conn)
+    .await?;
+    if taken {
+        return Ok(ChangeEmailResponse::EmailTaken);
+    }
+    match &user.email {
+        Some(old_email) if user.email_verified && *old_email != new_email => {
+            send_change_approval(mailer, user.id, &user.name, old_email.clone(), new_email).await;
+        }
+        // Nobody showed they read mail at an unverified address, so it can't vouch for anything.
+        _ => send_confirmation(mailer, user.id, &user.name, user.email.clone(), new_email).await,
+    }
+    Ok(ChangeEmailResponse::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::{Confirmation, Purpose, open, sign};
+    use crate::app::UserId;
+
+    fn confirmation() -> Confirmation {
+        Confirmation {
+            purpose: Purpose::Confirm,
+            user: UserId::from(uuid::Uuid::nil()),
+            previous: Some("alice@example.com".into()),
+            email: "alice@example.org".into(),
+            expires: (Utc::now() + Duration::hours(1)).timestamp(),
+        }
+    }
+
+    #[test]
+    fn signed_confirmation_opens() {
+        let token = sign(b"secret", &confirmation());
+        assert_eq!(open(b"secret", &token, Utc::now()), Some(confirmation()));
+    }
+
+    #[test]
+    fn rejects_tampered_expired_or_foreign_tokens() {
+        let token = sign(b"secret", &confirmation());
+        assert_eq!(open(b"other secret", &token, Utc::now()), None);
+        assert_eq!(
+            open(b"secret", &token, Utc::now() + Duration::hours(2)),
+            None
+        );
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = sign(
+            b"secret",
+            &Confirmation {
+                email: "mallory@example.com".into(),
+                ..confirmation()
+            },
+        );
+        let (payload, _) = forged.split_once('.').unwrap();
+        assert_eq!(
+            open(b"secret", &format!("{payload}.{signature}"), Utc::now()),
+            None
+        );
+        assert_eq!(open(b"secret", "garbage", Utc::now()), None);
+    }
+
+    #[test]
+    fn approvals_cant_pass_for_confirmations() {
+        let approval = sign(
+            b"secret",
+            &Confirmation {
+                purpose: Purpose::ApproveChange,
+                ..confirmation()
+            },
+        );
+        let (_, signature) = approval.split_once('.').unwrap();
+        let confirmation = sign(b"secret", &confirmation());
+        let (payload, _) = confirmation.split_once('.').unwrap();
+        assert_eq!(
+            open(b"secret", &format!("{payload}.{signature}"), Utc::now()),
+            None
+        );
+        assert_eq!(
+            open(b"secret", &approval, Utc::now()).map(|c| c.purpose),
+            Some(Purpose::ApproveChange)
+        );
+    }
+}
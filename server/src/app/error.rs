@@ -1,3 +1,5 @@
+use crate::app::password::PasswordRequirement;
+use crate::app::registration::Refusal;
 use diesel_async::pooled_connection::deadpool;
 
 #[derive(thiserror::Error, Debug)]
@@ -10,8 +12,56 @@ pub enum Error {
     Argon2(#[from] argon2::password_hash::Error),
     #[error("error while connecting to NATS message broker {0}")]
     NatsConnectError(#[from] async_nats::ConnectError),
+    #[error("error subscribing to NATS subject {0}")]
+    NatsSubscribe(#[from] async_nats::SubscribeError),
+    #[error("error publishing to NATS {0}")]
+    NatsPublish(#[from] async_nats::PublishError),
+    #[error("error publishing to JetStream {0}")]
+    JetStreamPublish(#[from] async_nats::jetstream::context::PublishError),
+    #[error("error creating JetStream stream {0}")]
+    JetStreamCreateStream(#[from] async_nats::jetstream::context::CreateStreamError),
+    #[error("NATS error {0}")]
+    Nats(#[from] async_nats::Error),
+    #[error("error serializing as JSON {0}")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("error serializing as MessagePack {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("error deserializing MessagePack {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("error serializing as CBOR {0}")]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("error deserializing CBOR {0}")]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
     #[error("error serializing as YAML {0}")]
     SerdeNorway(#[from] serde_norway::Error),
     #[error("I/O error {0}")]
     Io(#[from] std::io::Error),
+    #[error("error sending mail over SMTP {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("invalid email address {0}")]
+    MailAddress(#[from] lettre::address::AddressError),
+    #[error("error building mail {0}")]
+    MailBuild(#[from] lettre::error::Error),
+    #[error("HTTP request error {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("OpenID Connect provider error {0}")]
+    Oidc(String),
+    #[error("LDAP error {0}")]
+    Ldap(#[from] ldap3::LdapError),
+    #[error("invalid server signing key {0}")]
+    ServerKeyRejected(#[from] aws_lc_rs::error::KeyRejected),
+    #[error("cryptography error")]
+    Crypto(#[from] aws_lc_rs::error::Unspecified),
+    #[error("public_origin must be configured to use passkeys")]
+    PublicOriginNotConfigured,
+    #[error("user is not allowed to do this")]
+    NotAllowed,
+    #[error("user has to verify their email address first")]
+    EmailNotVerified,
+    #[error("password doesn't meet requirement {0:?}")]
+    PasswordRequirement(PasswordRequirement),
+    #[error("registration refused {0:?}")]
+    Registration(Refusal),
+    #[error("username is reserved for an admin")]
+    NameReserved,
 }
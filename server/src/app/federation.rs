@@ -0,0 +1,511 @@
+//! Logging in with an account on another Aspen server.
+//!
+//! The user's home server hands them an other server auth token bound to this server's domain,
+//! see [`try_other_server_auth`](crate::app::login::try_other_server_auth). This server passes it
+//! back to the home server's `/other_server_auth/verify` in a request signed with its
+//! [`ServerKey`], and the home server deletes it and says whose it was. This server then logs the
+//! user in to a local account linked to that identity.
+//!
+//! Anyone can name a server to log in from, so requests to other servers are made with
+//! [`peer_client`], which only reaches the public internet.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::api::GlobalServerContext;
+use crate::app::login::{LoginResponse, hash_password, hash_token, issue_tokens, make_token};
+use crate::app::login_session::Device;
+use crate::app::registration;
+use crate::app::user::{User, free_name};
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{federated_identity, other_server_auth_token, user};
+use crate::server_key::ServerKey;
+use crate::{app, app::IconId, app::UserId};
+
+#[derive(Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyOtherServerAuth {
+    /// Must have been made for the server that signed the request.
+    other_server_auth_token: String,
+}
+
+#[derive(Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum VerifyOtherServerAuthResponse {
+    Ok {
+        user_id: UserId,
+        name: String,
+        /// On the server the user is from.
+        icon: Option<IconId>,
+    },
+    /// Wrong, expired, already used or made for another domain.
+    InvalidToken,
+    ServerError,
+}
+
+/// Says who an other server auth token made on this server for the server at domain `peer`
+/// belongs to, using it up.
+pub async fn try_verify(
+    conn: &mut AsyncPgConnection,
+    v: &VerifyOtherServerAuth,
+    peer: &str,
+) -> Result<VerifyOtherServerAuthResponse, app::Error> {
+    let now = Utc::now().naive_utc();
+    diesel::delete(other_server_auth_token::table.filter(other_server_auth_token::expires.le(now)))
+        .execute(conn)
+        .await?;
+    let owner: Option<UserId> = diesel::delete(
+        other_server_auth_token::table.filter(
+            other_server_auth_token::token
+                .eq(hash_token(&v.other_server_auth_token).await)
+                .and(other_server_auth_token::domain.eq(peer)),
+        ),
+    )
+    .returning(other_server_auth_token::user)
+    .get_result(conn)
+    .await
+    .optional()?;
+    let Some(owner) = owner else {
+        return Ok(VerifyOtherServerAuthResponse::InvalidToken);
+    };
+    let (name, icon) = user::table
+        .select((user::name, user::icon))
+        .filter(user::id.eq(owner))
+        .first::<(String, Option<IconId>)>(conn)
+        .await?;
+    Ok(VerifyOtherServerAuthResponse::Ok {
+        user_id: owner,
+        name,
+        icon,
+    })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OtherServerLogin {
+    /// The domain of the user's home server, like `aspen.example.com`.
+    server: String,
+    other_server_auth_token: String,
+    /// Shown in the user's list of sessions, see [`Device::client_name`].
+    #[serde(default)]
+    client_name: Option<String>,
+}
+
+/// The host of `origin`, followed by its port unless that's the default.
+pub fn domain(origin: &Url) -> Option<String> {
+    let host = origin.host_str()?;
+    Some(match origin.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+/// The domain other servers know this one by.
+pub fn own_domain(public_origin: &str) -> Option<String> {
+    domain(&Url::parse(public_origin).ok()?)
+}
+
+/// Where the server at `domain` is reached, if `domain` is just a host with an optional port and
+/// isn't the address of a machine off the public internet.
+pub fn server_origin(domain: &str) -> Option<Url> {
+    let origin = Url::parse(&format!("https://{domain}")).ok()?;
+    let plain = origin.username().is_empty()
+        && origin.password().is_none()
+        && origin.path() == "/"
+        && origin.query().is_none()
+        && origin.fragment().is_none();
+    let host = origin.host_str()?;
+    // Names are checked once resolved, see PublicResolver.
+    let public = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public(ip),
+        Err(_) => true,
+    };
+    (plain && public).then_some(origin)
+}
+
+/// Whether `ip` is on the public internet, rather than this machine, a private network, or one
+/// of the other ranges set aside for special use.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space for carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments.
+                || (a == 192 && b == 0 && ip.octets()[2] == 0)
+                // Benchmarking.
+                || (a == 198 && (18..20).contains(&b))
+                // Reserved.
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(ip.into());
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Documentation.
+                || (first == 0x2001 && second == 0xdb8))
+        }
+    }
+}
+
+/// Resolves the hosts of other servers, leaving out the addresses that aren't
+/// [public](is_public), so a name that points into this server's network can't be used to reach
+/// it.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let public = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| is_public(address.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if public.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(public.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// How long connecting to another server may take.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a whole request to another server may take.
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Makes requests to other servers. Names it resolves only lead to [public](is_public) addresses,
+/// addresses are checked by [`server_origin`] instead. It doesn't follow redirects, which could lead
+/// anywhere, and gives up on servers that are slow to answer.
+pub fn peer_client() -> Result<reqwest::Client, reqwest::Error> {
+    reqwest::Client::builder()
+        .dns_resolver(PublicResolver)
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(PEER_CONNECT_TIMEOUT)
+        .timeout(PEER_REQUEST_TIMEOUT)
+        .build()
+}
+
+/// Asks the server at `origin` whose `token` is, signing the request as the server at `domain`.
+async fn verify_remote(
+    http: &reqwest::Client,
+    server_key: &ServerKey,
+    origin: &Url,
+    token: &str,
+    domain: &str,
+) -> Result<VerifyOtherServerAuthResponse, app::Error> {
+    let url = origin
+        .join("other_server_auth/verify")
+        .expect("a relative path joins any origin");
+    let mut request = http
+        .post(url)
+        .json(&VerifyOtherServerAuth {
+            other_server_auth_token: token.into(),
+        })
+        .build()?;
+    server_key.sign_request(&mut request, domain, Utc::now());
+    let response = http.execute(request).await?;
+    // Invalid tokens, and requests the home server couldn't check the signature of.
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Ok(VerifyOtherServerAuthResponse::InvalidToken);
+    }
+    Ok(response.error_for_status()?.json().await?)
+}
+
+/// Logs in the user of another server with a token it gave them for this one.
+pub async fn try_login(
+    state: &GlobalServerContext,
+    l: OtherServerLogin,
+    device: Device,
+) -> Result<LoginResponse, app::Error> {
+    let public_origin = aspen_config().await.public_origin;
+    let Some(this_domain) = public_origin.as_deref().and_then(own_domain) else {
+        return Err(app::Error::PublicOriginNotConfigured);
+    };
+    let Some(origin) = server_origin(&l.server) else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let verified = verify_remote(
+        &state.http,
+        &state.server_key,
+        &origin,
+        &l.other_server_auth_token,
+        &this_domain,
+    )
+    .await;
+    let verified = match verified {
+        Ok(verified) => verified,
+        Err(app::Error::Http(e)) => {
+            warn!("unable to verify other server auth token with {origin} {e}");
+            return Ok(LoginResponse::InvalidCredentials);
+        }
+        Err(e) => return Err(e),
+    };
+    let VerifyOtherServerAuthResponse::Ok { user_id, name, .. } = verified else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let server = domain(&origin).expect("parsed from a domain");
+    let local = match account(conn, &server, user_id, &name).await {
+        Ok(local) => local,
+        Err(app::Error::Registration(refusal)) => {
+            return Ok(LoginResponse::RegistrationRefused {
+                reason: refusal.describe(),
+            });
+        }
+        Err(e) => return Err(e),
+    };
+    let device = Device {
+        client_name: l.client_name,
+        ..device
+    };
+    issue_tokens(conn, local, &device).await
+}
+
+/// The local account of `remote_user` of `server`, created the first time they log in if
+/// [`registration::admit_federated`] lets them.
+async fn account(
+    conn: &mut AsyncPgConnection,
+    server: &str,
+    remote_user: UserId,
+    name: &str,
+) -> Result<UserId, app::Error> {
+    let linked: Option<UserId> = federated_identity::table
+        .select(federated_identity::user)
+        .filter(
+            federated_identity::server
+                .eq(server)
+                .and(federated_identity::remote_user.eq(remote_user)),
+        )
+        .first(conn)
+        .await
+        .optional()?;
+    if let Some(linked) = linked {
+        return Ok(linked);
+    }
+    let config = aspen_config().await;
+    let trusted = config
+        .trusted_servers
+        .iter()
+        .any(|trusted| trusted.eq_ignore_ascii_case(server));
+    let approved = registration::admit_federated(config.registration, trusted)?;
+    let name = free_name(conn, &format!("{name}@{server}")).await?;
+    // Federated users log in through their home server, the local password is never used.
+    let password_hash = hash_password(&config.password_hashing, &make_token())?;
+    let new_user = User {
+        id: UserId::new(),
+        name,
+        icon: None,
+        password_hash,
+        email: None,
+        email_verified: false,
+        approved,
+    };
+    let user_id = new_user.id;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::insert_into(user::table)
+                .values(new_user)
+                .execute(conn)
+                .await?;
+            diesel::insert_into(federated_identity::table)
+                .values((
+                    federated_identity::server.eq(server),
+                    federated_identity::remote_user.eq(remote_user),
+                    federated_identity::user.eq(user_id),
+                ))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use reqwest::Url;
+
+    use super::{
+        VerifyOtherServerAuth, VerifyOtherServerAuthResponse, is_public, own_domain, peer_client,
+        server_origin, verify_remote,
+    };
+    use crate::app::UserId;
+    use crate::server_key::{SERVER_HEADER, ServerKey};
+
+    /// Stands in for a home server that made `"token"` for `aspen.example.org`. It trusts the
+    /// domain the request says it's from, signatures are checked in [`crate::server_key`].
+    async fn verify(
+        headers: HeaderMap,
+        Json(v): Json<VerifyOtherServerAuth>,
+    ) -> (StatusCode, Json<VerifyOtherServerAuthResponse>) {
+        let peer = headers
+            .get(SERVER_HEADER)
+            .and_then(|peer| peer.to_str().ok());
+        if v.other_server_auth_token == "broken" {
+            // A body that says yes with a status that says no.
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(VerifyOtherServerAuthResponse::Ok {
+                    user_id: UserId::from(uuid::Uuid::nil()),
+                    name: "mallory".into(),
+                    icon: None,
+                }),
+            );
+        }
+        if v.other_server_auth_token == "token" && peer == Some("aspen.example.org") {
+            return (
+                StatusCode::OK,
+                Json(VerifyOtherServerAuthResponse::Ok {
+                    user_id: UserId::from(uuid::Uuid::nil()),
+                    name: "alice".into(),
+                    icon: None,
+                }),
+            );
+        }
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(VerifyOtherServerAuthResponse::InvalidToken),
+        )
+    }
+
+    async fn mock_home_server() -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let router = Router::new().route("/other_server_auth/verify", post(verify));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Url::parse(&origin).unwrap()
+    }
+
+    #[tokio::test]
+    async fn home_server_vouches_for_token() {
+        let origin = mock_home_server().await;
+        let http = reqwest::Client::new();
+        let key = ServerKey::generate();
+        let verified = verify_remote(&http, &key, &origin, "token", "aspen.example.org")
+            .await
+            .unwrap();
+        assert!(matches!(
+            verified,
+            VerifyOtherServerAuthResponse::Ok { name, .. } if name == "alice"
+        ));
+        for (token, domain) in [
+            ("token", "evil.example.org"),
+            ("other", "aspen.example.org"),
+        ] {
+            assert!(matches!(
+                verify_remote(&http, &key, &origin, token, domain)
+                    .await
+                    .unwrap(),
+                VerifyOtherServerAuthResponse::InvalidToken
+            ));
+        }
+        assert!(
+            verify_remote(&http, &key, &origin, "broken", "aspen.example.org")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_client_only_reaches_public_addresses() {
+        let origin = mock_home_server().await;
+        let port = origin.port().unwrap();
+        let key = ServerKey::generate();
+        let localhost = Url::parse(&format!("http://localhost:{port}")).unwrap();
+        for (http, reachable) in [
+            (peer_client().unwrap(), false),
+            (reqwest::Client::new(), true),
+        ] {
+            let verified = verify_remote(&http, &key, &localhost, "token", "aspen.example.org");
+            assert_eq!(verified.await.is_ok(), reachable);
+        }
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fc00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn domains_from_origins() {
+        assert_eq!(
+            own_domain("https://Aspen.Example.org").as_deref(),
+            Some("aspen.example.org")
+        );
+        assert_eq!(
+            own_domain("https://aspen.example.org:8443/").as_deref(),
+            Some("aspen.example.org:8443")
+        );
+        assert_eq!(own_domain("not an origin"), None);
+    }
+
+    #[test]
+    fn only_plain_domains_reach_servers() {
+        assert_eq!(
+            server_origin("aspen.example.com:8443").unwrap().as_str(),
+            "https://aspen.example.com:8443/"
+        );
+        for domain in [
+            "evil.example.com/path",
+            "user@aspen.example.com",
+            "aspen.example.com?query",
+            "aspen.example.com#fragment",
+            "",
+            "127.0.0.1:8080",
+            "169.254.169.254",
+            "[::1]:8443",
+            "[::ffff:10.0.0.1]",
+        ] {
+            assert_eq!(server_origin(domain), None, "{domain}");
+        }
+    }
+}
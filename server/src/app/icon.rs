@@ -1,11 +1,26 @@
-use crate::app::{IconId, Loadable};
-use diesel_async::AsyncPgConnection;
+use crate::api::GlobalServerContext;
+use crate::api::message_enum::command::IconCreateCommand;
+use crate::app;
+use crate::app::email;
+use crate::app::registration::is_admin;
+use crate::app::{IconId, Loadable, UserId};
+use crate::database::schema::{self, community, icon, user};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Insertable, QueryDsl, Queryable, Selectable,
+    SelectableHelper,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = icon)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Icon {
     pub id: IconId,
     pub data: Vec<u8>,
+    #[diesel(column_name = icon_mime_type)]
     pub mime_type: String,
+    /// Who may delete the icon besides admins.
+    pub owner: Option<UserId>,
 }
 
 impl Loadable for Icon {
@@ -15,10 +30,71 @@ impl Loadable for Icon {
         pg_connection: &mut AsyncPgConnection,
         id: IconId,
     ) -> Result<Self, diesel::result::Error> {
-        todo!()
+        schema::icon::table
+            .select(Icon::as_select())
+            .filter(icon::id.eq(id))
+            .first(pg_connection)
+            .await
     }
 
     fn id(&self) -> &Self::Id {
         &self.id
     }
 }
+
+/// Icons are immutable and aren't visible to anyone until a user or community refers to them, so no
+/// event is published for them. The referring record publishes its own update.
+pub(crate) async fn create_icon(
+    state: GlobalServerContext,
+    owner: UserId,
+    command: &IconCreateCommand,
+) -> Result<Icon, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    email::require_verified_to_post(conn.as_mut(), owner).await?;
+    let icon = Icon {
+        id: IconId::new(),
+        data: command.data.clone(),
+        mime_type: command.mime_type.clone(),
+        owner: Some(owner),
+    };
+    diesel::insert_into(icon::table)
+        .values(icon.clone())
+        .execute(conn.as_mut())
+        .await?;
+    Ok(icon)
+}
+
+/// Icons still in use by a user or community can't be deleted, so there is never anyone to notify.
+/// Only the user who uploaded an icon or an admin may delete it.
+pub(crate) async fn delete_icon(
+    state: GlobalServerContext,
+    user: UserId,
+    id: IconId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let owner: Option<UserId> = icon::table
+        .select(icon::owner)
+        .filter(icon::id.eq(id))
+        .first(conn.as_mut())
+        .await?;
+    if owner != Some(user) && !is_admin(conn.as_mut(), user).await? {
+        return Err(app::Error::NotAllowed);
+    }
+    let in_use: bool = diesel::select(
+        diesel::dsl::exists(user::table.filter(user::icon.eq(id))).or(diesel::dsl::exists(
+            community::table.filter(community::icon.eq(id)),
+        )),
+    )
+    .get_result(conn.as_mut())
+    .await?;
+    if in_use {
+        return Err(app::Error::NotAllowed);
+    }
+    let rows_deleted = diesel::delete(icon::table.filter(icon::id.eq(id)))
+        .execute(conn.as_mut())
+        .await?;
+    if rows_deleted == 0 {
+        return Err(diesel::result::Error::NotFound.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,351 @@
+//! An [`Authenticator`] checking passwords by binding to an LDAP directory as the user.
+//!
+//! Users are first searched for by their username attribute, with the configured service account
+//! if there is one, and then bound as with the password they entered. The groups the entry is a
+//! member of give the account its roles, as mapped by [`LdapConfig::group_roles`].
+
+use futures_util::future::BoxFuture;
+use ldap3::{LdapConnAsync, LdapError, Scope, SearchEntry, ldap_escape};
+
+use crate::app;
+use crate::app::authenticator::{Authentication, Authenticator, ExternalAccount};
+use crate::aspen_config::LdapConfig;
+use crate::mailer;
+
+/// LDAP result code for a wrong password, or a DN that doesn't exist.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// What [`LdapAuthenticator`] needs of a directory server, so tests can use a fake one.
+pub trait Directory: Send + Sync {
+    /// The entries under the base DN matching `filter`, with `attributes`.
+    fn search<'a>(
+        &'a self,
+        filter: &'a str,
+        attributes: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<SearchEntry>, app::Error>>;
+
+    /// Whether `password` is right for `dn`.
+    fn check_bind<'a>(
+        &'a self,
+        dn: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<bool, app::Error>>;
+}
+
+/// A directory server reached over the network, connected to for each operation.
+pub struct LdapServer {
+    config: LdapConfig,
+}
+
+impl LdapServer {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Directory for LdapServer {
+    fn search<'a>(
+        &'a self,
+        filter: &'a str,
+        attributes: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<SearchEntry>, app::Error>> {
+        Box::pin(async move {
+            let (conn, mut ldap) = LdapConnAsync::new(&self.config.url).await?;
+            ldap3::drive!(conn);
+            if let Some(bind_dn) = &self.config.bind_dn {
+                let bind_password = self.config.bind_password.as_deref().unwrap_or_default();
+                ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+            }
+            let (entries, _) = ldap
+                .search(&self.config.base_dn, Scope::Subtree, filter, attributes)
+                .await?
+                .success()?;
+            ldap.unbind().await?;
+            Ok(entries.into_iter().map(SearchEntry::construct).collect())
+        })
+    }
+
+    fn check_bind<'a>(
+        &'a self,
+        dn: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<bool, app::Error>> {
+        Box::pin(async move {
+            let (conn, mut ldap) = LdapConnAsync::new(&self.config.url).await?;
+            ldap3::drive!(conn);
+            let bound = match ldap.simple_bind(dn, password).await?.success() {
+                Ok(_) => true,
+                Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => false,
+                Err(e) => return Err(e.into()),
+            };
+            ldap.unbind().await?;
+            Ok(bound)
+        })
+    }
+}
+
+pub struct LdapAuthenticator<D = LdapServer> {
+    config: LdapConfig,
+    directory: D,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> Self {
+        Self {
+            directory: LdapServer::new(config.clone()),
+            config,
+        }
+    }
+}
+
+impl<D: Directory> LdapAuthenticator<D> {
+    fn user_filter(&self, username: &str) -> String {
+        format!(
+            "(&{}({}={}))",
+            self.config.user_filter,
+            self.config.username_attribute,
+            ldap_escape(username)
+        )
+    }
+
+    fn account(&self, entry: &SearchEntry, username: &str) -> ExternalAccount {
+        let first = |attribute: &str| entry.attrs.get(attribute).and_then(|values| values.first());
+        let mut roles = entry
+            .attrs
+            .get(&self.config.group_attribute)
+            .into_iter()
+            .flatten()
+            .filter_map(|group| {
+                self.config
+                    .group_roles
+                    .iter()
+                    .find(|(mapped, _)| mapped.eq_ignore_ascii_case(group))
+                    .map(|(_, role)| role.clone())
+            })
+            .collect::<Vec<_>>();
+        roles.sort_unstable();
+        roles.dedup();
+        ExternalAccount {
+            username: first(&self.config.username_attribute)
+                .cloned()
+                .unwrap_or_else(|| username.to_string()),
+            email: first(&self.config.email_attribute)
+                .and_then(|email| mailer::normalize_address(email).ok()),
+            roles,
+        }
+    }
+}
+
+impl<D: Directory> Authenticator for LdapAuthenticator<D> {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Authentication, app::Error>> {
+        Box::pin(async move {
+            let filter = self.user_filter(username);
+            let attributes = [
+                self.config.username_attribute.as_str(),
+                self.config.email_attribute.as_str(),
+                self.config.group_attribute.as_str(),
+            ];
+            let entries = self.directory.search(&filter, &attributes).await?;
+            let [entry] = &entries[..] else {
+                return Ok(if entries.is_empty() {
+                    Authentication::UnknownUser
+                } else {
+                    // Binding as one of them could log in as the wrong user.
+                    Authentication::Rejected
+                });
+            };
+            // A bind with an empty password is an anonymous bind, which succeeds for any DN.
+            if password.is_empty() || !self.directory.check_bind(&entry.dn, password).await? {
+                return Ok(Authentication::Rejected);
+            }
+            Ok(Authentication::Authenticated(self.account(entry, username)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures_util::future::BoxFuture;
+    use ldap3::SearchEntry;
+
+    use super::{Directory, LdapAuthenticator};
+    use crate::app;
+    use crate::app::authenticator::{Authentication, Authenticator, ExternalAccount};
+    use crate::aspen_config::LdapConfig;
+
+    /// A directory of users and their passwords, answering only the searches
+    /// [`LdapAuthenticator`] makes.
+    struct FakeDirectory {
+        users: Vec<(SearchEntry, &'static str)>,
+    }
+
+    impl Directory for FakeDirectory {
+        fn search<'a>(
+            &'a self,
+            filter: &'a str,
+            _attributes: &'a [&'a str],
+        ) -> BoxFuture<'a, Result<Vec<SearchEntry>, app::Error>> {
+            let entries = self
+                .users
+                .iter()
+                .map(|(entry, _)| entry)
+                .filter(|entry| {
+                    entry.attrs["uid"]
+                        .iter()
+                        .any(|uid| filter == format!("(&(objectClass=person)(uid={uid}))"))
+                })
+                .cloned()
+                .collect();
+            Box::pin(async { Ok(entries) })
+        }
+
+        fn check_bind<'a>(
+            &'a self,
+            dn: &'a str,
+            password: &'a str,
+        ) -> BoxFuture<'a, Result<bool, app::Error>> {
+            let bound = self
+                .users
+                .iter()
+                .any(|(entry, entry_password)| entry.dn == dn && *entry_password == password);
+            Box::pin(async move { Ok(bound) })
+        }
+    }
+
+    fn entry(uid: &str, attrs: &[(&str, &[&str])]) -> SearchEntry {
+        let mut attrs = attrs
+            .iter()
+            .map(|(name, values)| {
+                (
+                    name.to_string(),
+                    values.iter().map(|value| value.to_string()).collect(),
+                )
+            })
+            .collect::<HashMap<_, Vec<_>>>();
+        attrs.insert("uid".into(), vec![uid.into()]);
+        SearchEntry {
+            dn: format!("uid={uid},ou=people,dc=example,dc=com"),
+            attrs,
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    fn authenticator() -> LdapAuthenticator<FakeDirectory> {
+        let config = LdapConfig {
+            url: "ldap://localhost".into(),
+            bind_dn: None,
+            bind_password: None,
+            base_dn: "ou=people,dc=example,dc=com".into(),
+            username_attribute: "uid".into(),
+            user_filter: "(objectClass=person)".into(),
+            email_attribute: "mail".into(),
+            group_attribute: "memberOf".into(),
+            group_roles: HashMap::from([
+                (
+                    "cn=admins,ou=groups,dc=example,dc=com".into(),
+                    "admin".into(),
+                ),
+                (
+                    "cn=mods,ou=groups,dc=example,dc=com".into(),
+                    "moderator".into(),
+                ),
+                (
+                    "cn=staff,ou=groups,dc=example,dc=com".into(),
+                    "moderator".into(),
+                ),
+            ]),
+        };
+        let alice = entry(
+            "alice",
+            &[
+                ("mail", &["Alice@Example.com"]),
+                (
+                    "memberOf",
+                    &[
+                        "CN=Admins,OU=Groups,DC=example,DC=com",
+                        "cn=mods,ou=groups,dc=example,dc=com",
+                        "cn=staff,ou=groups,dc=example,dc=com",
+                        "cn=unmapped,ou=groups,dc=example,dc=com",
+                    ],
+                ),
+            ],
+        );
+        LdapAuthenticator {
+            config,
+            directory: FakeDirectory {
+                users: vec![
+                    (alice, "alice's password"),
+                    (entry("bob", &[]), "bob's password"),
+                ],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_and_maps_groups_to_roles() {
+        let authenticator = authenticator();
+        assert_eq!(
+            authenticator
+                .authenticate("alice", "alice's password")
+                .await
+                .unwrap(),
+            Authentication::Authenticated(ExternalAccount {
+                username: "alice".into(),
+                email: Some("alice@example.com".into()),
+                roles: vec!["admin".into(), "moderator".into()],
+            })
+        );
+        assert_eq!(
+            authenticator
+                .authenticate("bob", "bob's password")
+                .await
+                .unwrap(),
+            Authentication::Authenticated(ExternalAccount {
+                username: "bob".into(),
+                email: None,
+                roles: vec![],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_and_empty_passwords() {
+        let authenticator = authenticator();
+        assert_eq!(
+            authenticator
+                .authenticate("alice", "bob's password")
+                .await
+                .unwrap(),
+            Authentication::Rejected
+        );
+        assert_eq!(
+            authenticator.authenticate("alice", "").await.unwrap(),
+            Authentication::Rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_users_left_to_local_passwords() {
+        assert_eq!(
+            authenticator()
+                .authenticate("carol", "carol's password")
+                .await
+                .unwrap(),
+            Authentication::UnknownUser
+        );
+    }
+
+    #[test]
+    fn usernames_escaped_in_filter() {
+        assert_eq!(
+            authenticator().user_filter("*)(uid=admin"),
+            r"(&(objectClass=person)(uid=\2a\29\28uid=admin))"
+        );
+    }
+}
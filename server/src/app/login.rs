@@ -1,27 +1,42 @@
 use argon2::{
-    PasswordHash, PasswordVerifier as _,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordVerifier as _, Version,
     password_hash::{Salt, SaltString},
 };
 use base64::{Engine, prelude::BASE64_STANDARD};
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
-use diesel::{ExpressionMethods as _, QueryDsl, SelectableHelper};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods as _, OptionalExtension, QueryDsl, SelectableHelper,
+};
 use diesel_async::pooled_connection::deadpool;
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
-use futures_util::{StreamExt, TryFutureExt};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
 use rand::{Rng, RngExt};
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
 use uuid::Uuid;
 
 use crate::api::GlobalServerContext;
+use crate::api::live_sessions::LiveSessions;
 use crate::api::login::authenticated_user;
 use crate::app::Loadable;
-use crate::app::user::User;
+use crate::app::authenticator::{self, Authentication};
+use crate::app::login_session::Device;
+use crate::app::password::PasswordRequirement;
+use crate::app::throttle::Subject;
+use crate::app::user::{User, publish_role_change};
+use crate::app::webauthn::{AssertionCredential, PasskeyRequestOptionsResponse};
+use crate::app::{login_session, password, throttle, totp, webauthn};
+use crate::aspen_config::{PasswordHashing, aspen_config};
 use crate::{CHACHA_RNG, app, app::UserId, database::schema};
 
 const REFRESH_TOKEN_LIFETIME: Duration = Duration::weeks(52);
 const SESSION_TOKEN_LIFETIME: Duration = Duration::hours(3);
 const OTHER_SERVER_AUTH_LIFETIME: Duration = Duration::minutes(10);
+const LOGIN_CHALLENGE_LIFETIME: Duration = Duration::minutes(5);
+/// Wrong second factors allowed per challenge, after which the password has to be entered again.
+const MAX_SECOND_FACTOR_ATTEMPTS: i32 = 5;
 
 #[derive(Serialize, utoipa::ToSchema)]
 #[serde(tag = "status", rename_all = "camelCase")]
@@ -32,7 +47,24 @@ pub enum LoginResponse {
         session_token: String,
         session_token_expires: DateTime<Utc>,
     },
+    /// The password was right but the user enrolled a second factor, an authenticator app or a
+    /// passkey. Finish logging in by sending it along with `challenge` to `/login/second_factor`.
+    SecondFactorRequired {
+        challenge: String,
+    },
     InvalidCredentials,
+    /// The account was created on a server that needs an admin to approve new accounts, and
+    /// hasn't been yet.
+    PendingApproval,
+    /// Logging in would create an account, which `reason` says this server doesn't allow.
+    RegistrationRefused {
+        reason: String,
+    },
+    /// Too many wrong passwords were tried for the account or from the client's IP. Try again in
+    /// `retry_after` seconds.
+    RateLimited {
+        retry_after: u64,
+    },
     ServerError,
 }
 
@@ -41,10 +73,41 @@ pub enum LoginResponse {
 pub struct Login {
     pub username: String,
     pub password: String,
+    /// Shown in the user's list of sessions, see [`Device::client_name`].
+    #[serde(default)]
+    pub client_name: Option<String>,
 }
 
-pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
-    let argon2 = argon2::Argon2::default();
+/// Identifies `pepper` in the hashes made with it, without revealing it.
+fn pepper_id(pepper: &str) -> Result<KeyId, argon2::Error> {
+    KeyId::new(&Sha256::digest(pepper.as_bytes())[..8])
+}
+
+/// An argon2 hasher as configured. Verifying uses the parameters of the stored hash instead.
+fn argon2(config: &PasswordHashing) -> Result<Argon2<'_>, argon2::Error> {
+    let mut params = ParamsBuilder::new();
+    params
+        .m_cost(config.memory_kib)
+        .t_cost(config.iterations)
+        .p_cost(config.parallelism);
+    if let Some(pepper) = &config.pepper {
+        params.keyid(pepper_id(pepper)?);
+    }
+    let params = params.build()?;
+    let algorithm = config.algorithm.into();
+    match &config.pepper {
+        Some(pepper) => {
+            Argon2::new_with_secret(pepper.as_bytes(), algorithm, Version::V0x13, params)
+        }
+        None => Ok(Argon2::new(algorithm, Version::V0x13, params)),
+    }
+}
+
+pub fn hash_password(
+    config: &PasswordHashing,
+    password: &str,
+) -> Result<String, argon2::password_hash::Error> {
+    let argon2 = argon2(config)?;
     CHACHA_RNG.with(|rng| {
         let bytes = rng.borrow_mut().random::<[u8; Salt::RECOMMENDED_LENGTH]>();
         SaltString::encode_b64(&bytes)
@@ -52,8 +115,7 @@ pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Er
     })
 }
 
-pub fn check_password(password: &str, entry_password_hash: &str) -> bool {
-    let argon2 = argon2::Argon2::default();
+pub fn check_password(config: &PasswordHashing, password: &str, entry_password_hash: &str) -> bool {
     let entry_hash = match argon2::PasswordHash::try_from(entry_password_hash) {
         Ok(v) => v,
         Err(e) => {
@@ -61,19 +123,93 @@ pub fn check_password(password: &str, entry_password_hash: &str) -> bool {
             return false;
         }
     };
+    // Hashes made with a pepper name it by its key id, those without one have none.
+    let keyid = entry_hash
+        .params
+        .get_str("keyid")
+        .and_then(|keyid| keyid.parse::<KeyId>().ok());
+    let argon2 = match (keyid, &config.pepper) {
+        (None, _) => Argon2::default(),
+        (Some(keyid), Some(pepper)) if pepper_id(pepper).ok() == Some(keyid) => {
+            match Argon2::new_with_secret(
+                pepper.as_bytes(),
+                Algorithm::default(),
+                Version::default(),
+                Params::default(),
+            ) {
+                Ok(argon2) => argon2,
+                Err(e) => {
+                    error!("error setting up argon2 with pepper {e}");
+                    return false;
+                }
+            }
+        }
+        (Some(_), _) => {
+            error!("user entry password hash made with a pepper that is no longer configured");
+            return false;
+        }
+    };
     argon2
         .verify_password(password.as_bytes(), &entry_hash)
         .is_ok()
 }
 
-fn make_token() -> String {
+/// A new hash of `password` if `entry_password_hash`, which it was just checked against, was made
+/// with other settings than `config`'s.
+pub fn rehash_if_outdated(
+    config: &PasswordHashing,
+    password: &str,
+    entry_password_hash: &str,
+) -> Option<String> {
+    let entry_hash = argon2::PasswordHash::try_from(entry_password_hash).ok()?;
+    let current = argon2(config).ok()?;
+    let up_to_date = entry_hash.algorithm == Algorithm::from(config.algorithm).ident()
+        && entry_hash.version == Some(Version::V0x13.into())
+        && Params::try_from(&entry_hash).is_ok_and(|params| {
+            params.m_cost() == config.memory_kib
+                && params.t_cost() == config.iterations
+                && params.p_cost() == config.parallelism
+                && params.keyid() == current.params().keyid()
+        });
+    if up_to_date {
+        return None;
+    }
+    match hash_password(config, password) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            error!("error rehashing password {e}");
+            None
+        }
+    }
+}
+
+pub fn make_token() -> String {
     BASE64_STANDARD.encode(CHACHA_RNG.with(|rng| rng.borrow_mut().random::<[u8; 32]>()))
 }
 
+/// What is stored of a session, refresh or other server auth token in place of the token itself,
+/// so a database dump holds nothing that can be used to authenticate.
+///
+/// Tokens are looked up by this hash. Without the secret an attacker can't choose hashes, so how
+/// long a lookup takes says nothing about how close a guessed token was.
+pub async fn hash_token(token: &str) -> String {
+    keyed_hash(aspen_config().await.token_secret.as_bytes(), token)
+}
+
+fn keyed_hash(secret: &[u8], token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(token.as_bytes());
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
+
 #[derive(Serialize, utoipa::ToSchema)]
 #[serde(tag = "status", rename_all = "camelCase")]
 pub enum TokenRefreshResponse {
-    Ok { new_session_token: String },
+    /// `new_refresh_token` replaces the one sent, which won't work again.
+    Ok {
+        new_session_token: String,
+        new_refresh_token: String,
+    },
     InvalidToken,
     ServerError,
 }
@@ -87,103 +223,506 @@ pub struct TokenRefresh {
 pub async fn try_login(
     state: &GlobalServerContext,
     login: Login,
+    device: Device,
 ) -> Result<LoginResponse, app::Error> {
     use schema::user::dsl::*;
 
     let mut conn = state.connection_pool.get().await?;
     let conn = conn.as_mut();
-    let Login { username, password } = &login;
-    let user_entry: Result<User, _> = user
+    let Login {
+        username,
+        password,
+        client_name,
+    } = login;
+    let user_entry: Option<User> = user
         .select(User::as_select())
-        .filter(name.eq(username))
+        .filter(name.eq(&username))
+        .first(conn)
+        .await
+        .optional()?;
+    let device = Device {
+        client_name,
+        ..device
+    };
+    let subjects = [
+        user_entry.as_ref().map(|u| Subject::Account(u.id)),
+        device.ip.as_deref().map(Subject::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if let Some(retry_after) = throttle::attempt(conn, &subjects).await? {
+        return Ok(LoginResponse::RateLimited { retry_after });
+    }
+    if let Some(authenticator) = &state.authenticator {
+        match authenticator.authenticate(&username, &password).await? {
+            Authentication::Authenticated(account) => {
+                let Some((user_id, roles_changed)) =
+                    authenticator::local_account(conn, &account).await?
+                else {
+                    warn!(
+                        "not logging in {} from the authenticator, a local account has the name",
+                        account.username
+                    );
+                    return Ok(LoginResponse::InvalidCredentials);
+                };
+                if roles_changed {
+                    publish_role_change(state, conn, user_id).await?;
+                }
+                throttle::succeeded(conn, &subjects).await?;
+                throttle::clear(conn, Subject::Account(user_id)).await?;
+                return finish_login(conn, user_id, &device).await;
+            }
+            Authentication::Rejected => return Ok(LoginResponse::InvalidCredentials),
+            Authentication::UnknownUser => {}
+        }
+    }
+    let hashing = aspen_config().await.password_hashing;
+    let Some(u) = user_entry.filter(|u| check_password(&hashing, &password, &u.password_hash))
+    else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    throttle::succeeded(conn, &subjects).await?;
+    if let Some(rehashed) = rehash_if_outdated(&hashing, &password, &u.password_hash) {
+        diesel::update(user.filter(id.eq(u.id)))
+            .set(password_hash.eq(rehashed))
+            .execute(conn)
+            .await?;
+    }
+    finish_login(conn, u.id, &device).await
+}
+
+/// Asks `user` for a second factor if they have one, and otherwise starts their session.
+async fn finish_login(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    device: &Device,
+) -> Result<LoginResponse, app::Error> {
+    if totp::is_enrolled(conn, user).await? || webauthn::has_credentials(conn, user).await? {
+        return issue_login_challenge(conn, user).await;
+    }
+    issue_tokens(conn, user, device).await
+}
+
+/// Starts a new session for `user`, who has proven who they are.
+pub async fn issue_tokens(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    device: &Device,
+) -> Result<LoginResponse, app::Error> {
+    use crate::database::schema::{refresh_token, session};
+    let approved: bool = schema::user::table
+        .select(schema::user::approved)
+        .filter(schema::user::id.eq(user))
+        .first(conn)
+        .await?;
+    if !approved {
+        return Ok(LoginResponse::PendingApproval);
+    }
+    let session_token = make_token();
+    let refresh_token = make_token();
+    let refresh_token_hash = &hash_token(&refresh_token).await;
+    let session_token_hash = &hash_token(&session_token).await;
+    let family = Uuid::now_v7();
+    let now = chrono::Utc::now();
+    let session_token_expires = now + SESSION_TOKEN_LIFETIME;
+    conn.transaction::<_, app::Error, _>(|conn| {
+        async move {
+            diesel::insert_into(refresh_token::table)
+                .values((
+                    refresh_token::dsl::token.eq(refresh_token_hash),
+                    refresh_token::dsl::user.eq(user),
+                    refresh_token::dsl::family.eq(family),
+                    refresh_token::dsl::expires.eq((now + REFRESH_TOKEN_LIFETIME).naive_utc()),
+                ))
+                .execute(conn)
+                .await?;
+            diesel::insert_into(session::table)
+                .values((
+                    session::dsl::token.eq(session_token_hash),
+                    session::dsl::refresh_token.eq(refresh_token_hash),
+                    session::dsl::expires.eq(session_token_expires.naive_utc()),
+                ))
+                .execute(conn)
+                .await?;
+            login_session::record(conn, family, user, device).await
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(LoginResponse::Ok {
+        user_id: user,
+        refresh_token,
+        session_token,
+        session_token_expires,
+    })
+}
+
+async fn issue_login_challenge(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<LoginResponse, app::Error> {
+    use schema::login_challenge;
+    let challenge = make_token();
+    diesel::insert_into(login_challenge::table)
+        .values((
+            login_challenge::token.eq(hash_token(&challenge).await),
+            login_challenge::user.eq(user),
+            login_challenge::expires.eq((Utc::now() + LOGIN_CHALLENGE_LIFETIME).naive_utc()),
+            login_challenge::attempts.eq(0),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(LoginResponse::SecondFactorRequired { challenge })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecondFactorLogin {
+    challenge: String,
+    #[serde(default)]
+    client_name: Option<String>,
+    #[serde(flatten)]
+    factor: SecondFactor,
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum SecondFactor {
+    /// A code from the user's authenticator app, or one of their recovery codes.
+    Code(String),
+    /// Answers the options from `/login/second_factor/passkey_options`.
+    Passkey(AssertionCredential),
+}
+
+/// The user a second factor is being asked of.
+async fn challenged_user(
+    conn: &mut AsyncPgConnection,
+    challenge: &str,
+) -> Result<Option<UserId>, app::Error> {
+    use schema::login_challenge;
+    let now = Utc::now().naive_utc();
+    Ok(login_challenge::table
+        .select(login_challenge::user)
+        .filter(
+            login_challenge::token
+                .eq(hash_token(challenge).await)
+                .and(login_challenge::expires.ge(now))
+                .and(login_challenge::attempts.lt(MAX_SECOND_FACTOR_ATTEMPTS)),
+        )
+        .first(conn)
+        .await
+        .optional()?)
+}
+
+/// Uses up one of the attempts at answering `challenge`, returning its user and how many
+/// attempts were made including this one. Reserved before the answer is checked, so answers sent
+/// at once can't make more than [`MAX_SECOND_FACTOR_ATTEMPTS`] between them.
+async fn reserve_attempt(
+    conn: &mut AsyncPgConnection,
+    challenge: &str,
+) -> Result<Option<(UserId, i32)>, app::Error> {
+    use schema::login_challenge;
+    let now = Utc::now().naive_utc();
+    Ok(diesel::update(
+        login_challenge::table.filter(
+            login_challenge::token
+                .eq(hash_token(challenge).await)
+                .and(login_challenge::expires.ge(now))
+                .and(login_challenge::attempts.lt(MAX_SECOND_FACTOR_ATTEMPTS)),
+        ),
+    )
+    .set(login_challenge::attempts.eq(login_challenge::attempts + 1))
+    .returning((login_challenge::user, login_challenge::attempts))
+    .get_result(conn)
+    .await
+    .optional()?)
+}
+
+/// Finishes a login that answered [`LoginResponse::SecondFactorRequired`].
+pub async fn try_second_factor_login(
+    state: &GlobalServerContext,
+    l: &SecondFactorLogin,
+    device: Device,
+) -> Result<LoginResponse, app::Error> {
+    use schema::login_challenge;
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let Some((user, attempts)) = reserve_attempt(conn, &l.challenge).await? else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let challenge_hash = hash_token(&l.challenge).await;
+    // Each challenge only allows a few attempts, but a new one is only a password away.
+    let subjects = [
+        Some(Subject::Account(user)),
+        device.ip.as_deref().map(Subject::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if let Some(retry_after) = throttle::attempt(conn, &subjects).await? {
+        return Ok(LoginResponse::RateLimited { retry_after });
+    }
+    let verified = match &l.factor {
+        SecondFactor::Code(code) => totp::verify_second_factor(conn, user, code).await?,
+        SecondFactor::Passkey(credential) => {
+            webauthn::verify_assertion(conn, credential, Some(user))
+                .await?
+                .is_some()
+        }
+    };
+    if !verified {
+        if attempts >= MAX_SECOND_FACTOR_ATTEMPTS {
+            diesel::delete(
+                login_challenge::table.filter(login_challenge::token.eq(&challenge_hash)),
+            )
+            .execute(conn)
+            .await?;
+        }
+        return Ok(LoginResponse::InvalidCredentials);
+    }
+    // Whoever deletes the challenge first gets the session.
+    let consumed =
+        diesel::delete(login_challenge::table.filter(login_challenge::token.eq(&challenge_hash)))
+            .execute(conn)
+            .await?;
+    if consumed == 0 {
+        return Ok(LoginResponse::InvalidCredentials);
+    }
+    throttle::succeeded(conn, &subjects).await?;
+    let device = Device {
+        client_name: l.client_name.clone(),
+        ..device
+    };
+    issue_tokens(conn, user, &device).await
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecondFactorPasskeyOptions {
+    challenge: String,
+}
+
+/// WebAuthn options for answering a [`LoginResponse::SecondFactorRequired`] with a passkey.
+pub async fn try_second_factor_passkey_options(
+    state: &GlobalServerContext,
+    o: &SecondFactorPasskeyOptions,
+) -> Result<PasskeyRequestOptionsResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let Some(user) = challenged_user(conn, &o.challenge).await? else {
+        return Ok(PasskeyRequestOptionsResponse::InvalidChallenge);
+    };
+    Ok(PasskeyRequestOptionsResponse::Ok {
+        options: webauthn::request_options(conn, Some(user)).await?,
+    })
+}
+
+/// WebAuthn options for logging in with a passkey instead of a username and password.
+pub async fn try_passkey_login_options(
+    state: &GlobalServerContext,
+) -> Result<PasskeyRequestOptionsResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    Ok(PasskeyRequestOptionsResponse::Ok {
+        options: webauthn::request_options(conn.as_mut(), None).await?,
+    })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyLogin {
+    credential: AssertionCredential,
+    #[serde(default)]
+    client_name: Option<String>,
+}
+
+/// Logs in with a passkey. It verified its user itself, so no second factor is asked for.
+pub async fn try_passkey_login(
+    state: &GlobalServerContext,
+    l: &PasskeyLogin,
+    device: Device,
+) -> Result<LoginResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    // Whose passkey it is isn't known until it is checked, so only the IP is throttled.
+    let subjects = device
+        .ip
+        .as_deref()
+        .map(Subject::Ip)
+        .into_iter()
+        .collect::<Vec<_>>();
+    if let Some(retry_after) = throttle::attempt(conn, &subjects).await? {
+        return Ok(LoginResponse::RateLimited { retry_after });
+    }
+    match webauthn::verify_assertion(conn, &l.credential, None).await? {
+        Some(user) => {
+            throttle::succeeded(conn, &subjects).await?;
+            let device = Device {
+                client_name: l.client_name.clone(),
+                ..device
+            };
+            issue_tokens(conn, user, &device).await
+        }
+        None => Ok(LoginResponse::InvalidCredentials),
+    }
+}
+
+/// Trades `t.refresh_token` for a new session token and the next refresh token of its family.
+///
+/// A refresh token works once. Presenting one that was already rotated means it was copied, so the
+/// whole family is revoked, logging out both the thief and the user.
+pub async fn try_token_refresh(
+    state: &GlobalServerContext,
+    t: &TokenRefresh,
+) -> Result<TokenRefreshResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    match rotate(conn.as_mut(), &t.refresh_token).await? {
+        Rotation::Rotated {
+            new_session_token,
+            new_refresh_token,
+        } => Ok(TokenRefreshResponse::Ok {
+            new_session_token,
+            new_refresh_token,
+        }),
+        Rotation::Invalid => Ok(TokenRefreshResponse::InvalidToken),
+        Rotation::Reused { user, family } => {
+            warn!("refresh token of {user} reused, revoking its family");
+            LiveSessions::revoke(&state.nats_connection_manager, &[family]).await;
+            Ok(TokenRefreshResponse::InvalidToken)
+        }
+    }
+}
+
+/// What presenting a refresh token came to.
+enum Rotation {
+    Rotated {
+        new_session_token: String,
+        new_refresh_token: String,
+    },
+    /// Unknown or expired.
+    Invalid,
+    /// Already rotated, so its family was deleted. Its streams still need closing.
+    Reused { user: UserId, family: Uuid },
+}
+
+/// Rotates `refresh_token`, or deletes its family if it was rotated before.
+async fn rotate(conn: &mut AsyncPgConnection, refresh_token: &str) -> Result<Rotation, app::Error> {
+    use schema::{refresh_token, session};
+    let presented = hash_token(refresh_token).await;
+    let entry: Option<(UserId, Uuid, NaiveDateTime)> = refresh_token::table
+        .select((
+            refresh_token::user,
+            refresh_token::family,
+            refresh_token::expires,
+        ))
+        .filter(refresh_token::token.eq(&presented))
         .first(conn)
-        .await;
-    match user_entry {
-        Ok(u) => {
-            if check_password(password, &u.password_hash) {
-                use crate::database::schema::{refresh_token, session};
-                let session_token = make_token();
-                let refresh_token = make_token();
-                let now = chrono::Utc::now();
-                let session_token_expires = now + SESSION_TOKEN_LIFETIME;
+        .await
+        .optional()?;
+    let Some((user, family, expires)) = entry else {
+        return Ok(Rotation::Invalid);
+    };
+    if expires.and_utc() < Utc::now() {
+        return Ok(Rotation::Invalid);
+    }
+    let new_refresh_token = make_token();
+    let new_session_token = make_token();
+    let (refresh, session) = (
+        &hash_token(&new_refresh_token).await,
+        &hash_token(&new_session_token).await,
+    );
+    let presented = &presented;
+    let rotated = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                // Only one refresh can rotate the token, any other is treated as reuse.
+                let rotated = diesel::update(
+                    refresh_token::table.filter(
+                        refresh_token::token
+                            .eq(presented)
+                            .and(refresh_token::rotated.eq(false)),
+                    ),
+                )
+                .set(refresh_token::rotated.eq(true))
+                .execute(conn)
+                .await?;
+                if rotated == 0 {
+                    return Ok(false);
+                }
                 diesel::insert_into(refresh_token::table)
                     .values((
-                        refresh_token::dsl::token.eq(&refresh_token),
-                        refresh_token::dsl::user.eq(u.id),
-                        refresh_token::dsl::expires.eq((now + REFRESH_TOKEN_LIFETIME).naive_utc()),
+                        refresh_token::token.eq(refresh),
+                        refresh_token::user.eq(user),
+                        refresh_token::family.eq(family),
+                        refresh_token::expires.eq(expires),
                     ))
                     .execute(conn)
                     .await?;
-
                 diesel::insert_into(session::table)
                     .values((
-                        session::dsl::token.eq(&session_token),
-                        session::dsl::refresh_token.eq(&refresh_token),
-                        session::dsl::expires.eq(session_token_expires.naive_utc()),
+                        session::token.eq(session),
+                        session::expires.eq(Utc::now().naive_utc() + SESSION_TOKEN_LIFETIME),
+                        session::refresh_token.eq(refresh),
                     ))
                     .execute(conn)
                     .await?;
-                Ok(LoginResponse::Ok {
-                    user_id: u.id,
-                    refresh_token,
-                    session_token,
-                    session_token_expires,
-                })
-            } else {
-                return Ok(LoginResponse::InvalidCredentials);
+                Ok(true)
             }
-        }
-        Err(e) => {
-            if let diesel::result::Error::NotFound = e {
-                return Ok(LoginResponse::InvalidCredentials);
-            } else {
-                Err(e.into())
-            }
-        }
+            .scope_boxed()
+        })
+        .await?;
+    if !rotated {
+        delete_families(conn, &[family]).await?;
+        return Ok(Rotation::Reused { user, family });
     }
+    login_session::touch(conn, family).await?;
+    Ok(Rotation::Rotated {
+        new_session_token,
+        new_refresh_token,
+    })
 }
 
-pub async fn try_token_refresh(
-    mut conn: impl AsMut<AsyncPgConnection>,
-    t: &TokenRefresh,
-) -> Result<TokenRefreshResponse, app::Error> {
-    use schema::{refresh_token, session};
-    let conn = conn.as_mut();
-    let expires: Option<NaiveDateTime> = refresh_token::table
-        .select(refresh_token::expires)
-        .filter(refresh_token::dsl::token.eq(&t.refresh_token))
-        .limit(1)
-        .load_stream(conn)
-        .await?
-        .next()
-        .await
-        .transpose()?;
-    match expires {
-        Some(expires) => {
-            let expires = expires.and_utc();
-            if expires < Utc::now() {
-                // Token expired
-                return Ok(TokenRefreshResponse::InvalidToken);
-            }
-        }
-        None => {
-            return Ok(TokenRefreshResponse::InvalidToken);
-        }
+/// Deletes every refresh token of `families` and the session tokens issued from them, closing
+/// their event streams on every node.
+pub async fn revoke_families(
+    state: &GlobalServerContext,
+    conn: &mut AsyncPgConnection,
+    families: &[Uuid],
+) -> Result<(), app::Error> {
+    if families.is_empty() {
+        return Ok(());
     }
-    // If we got here then the token is valid. Issue a refresh.
-    let new_token = make_token();
-    diesel::insert_into(session::table)
-        .values((
-            session::dsl::token.eq(&new_token),
-            session::dsl::expires.eq(Utc::now().naive_utc() + SESSION_TOKEN_LIFETIME),
-            session::dsl::refresh_token.eq(&t.refresh_token),
-        ))
-        .execute(conn)
-        .await?;
+    delete_families(conn, families).await?;
+    LiveSessions::revoke(&state.nats_connection_manager, families).await;
+    Ok(())
+}
 
-    Ok(TokenRefreshResponse::Ok {
-        new_session_token: new_token,
+/// The database half of [`revoke_families`].
+pub(crate) async fn delete_families(
+    conn: &mut AsyncPgConnection,
+    families: &[Uuid],
+) -> Result<(), app::Error> {
+    use schema::{login_session, refresh_token, session};
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let refresh_tokens = refresh_token::table
+                .select(refresh_token::token)
+                .filter(refresh_token::family.eq_any(families));
+            diesel::delete(session::table.filter(session::refresh_token.eq_any(refresh_tokens)))
+                .execute(conn)
+                .await?;
+            diesel::delete(refresh_token::table.filter(refresh_token::family.eq_any(families)))
+                .execute(conn)
+                .await?;
+            diesel::delete(login_session::table.filter(login_session::family.eq_any(families)))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
     })
+    .await?;
+    Ok(())
 }
 
 #[derive(Serialize, utoipa::ToSchema)]
@@ -200,45 +739,92 @@ pub struct Logout {
     refresh_token: String,
 }
 
+/// Logs out the session `t.refresh_token` belongs to, whichever of its family's tokens it is.
 pub async fn try_logout(
-    mut conn: impl AsMut<AsyncPgConnection>,
+    state: &GlobalServerContext,
     t: &Logout,
 ) -> Result<LogoutResponse, app::Error> {
-    use schema::{refresh_token, session};
+    use schema::refresh_token;
+    let mut conn = state.connection_pool.get().await?;
     let conn = conn.as_mut();
-    diesel::delete(session::table)
-        .filter(session::dsl::refresh_token.eq(&t.refresh_token))
-        .execute(conn)
-        .await?;
-    // TODO: Kill any event streams associated with this refresh token
-    // TODO stretch goal: If this server is ever sharded then tell the other shards to kill their event
-    // streams too
-    let rows_deleted = diesel::delete(refresh_token::table)
-        .filter(refresh_token::dsl::token.eq(&t.refresh_token))
-        .execute(conn)
-        .await?;
-    if rows_deleted > 0 {
-        Ok(LogoutResponse::Ok)
-    } else {
-        Ok(LogoutResponse::InvalidToken)
+    let family: Option<Uuid> = refresh_token::table
+        .select(refresh_token::family)
+        .filter(refresh_token::token.eq(hash_token(&t.refresh_token).await))
+        .first(conn)
+        .await
+        .optional()?;
+    match family {
+        Some(family) => {
+            revoke_families(state, conn, &[family]).await?;
+            Ok(LogoutResponse::Ok)
+        }
+        None => Ok(LogoutResponse::InvalidToken),
     }
 }
 
+/// Logs `user` out everywhere, closing their event streams on every node.
+pub async fn revoke_user_sessions(
+    state: &GlobalServerContext,
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<(), app::Error> {
+    let revoked = delete_user_sessions(conn, user).await?;
+    LiveSessions::revoke(&state.nats_connection_manager, &revoked).await;
+    Ok(())
+}
+
+/// The database half of [`revoke_user_sessions`], returning the families that were revoked.
+pub(crate) async fn delete_user_sessions(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<Uuid>, app::Error> {
+    use schema::{login_session, refresh_token, session};
+    let mut revoked: Vec<Uuid> = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let refresh_tokens = refresh_token::table
+                    .select(refresh_token::token)
+                    .filter(refresh_token::user.eq(user));
+                diesel::delete(
+                    session::table.filter(session::refresh_token.eq_any(refresh_tokens)),
+                )
+                .execute(conn)
+                .await?;
+                let revoked = diesel::delete(refresh_token::table)
+                    .filter(refresh_token::user.eq(user))
+                    .returning(refresh_token::family)
+                    .get_results(conn)
+                    .await?;
+                diesel::delete(login_session::table.filter(login_session::user.eq(user)))
+                    .execute(conn)
+                    .await?;
+                Ok(revoked)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    revoked.sort_unstable();
+    revoked.dedup();
+    Ok(revoked)
+}
+
 #[derive(Serialize, utoipa::ToSchema)]
 #[serde(tag = "status", rename_all = "camelCase")]
 pub enum ChangePasswordResponse {
     Ok,
+    /// No user has the ID.
+    InvalidCredentials,
     OldPasswordIncorrect,
-    NewPasswordDoesntMeetRequirements { cause: PasswordRequirement },
+    NewPasswordDoesntMeetRequirements {
+        cause: PasswordRequirement,
+    },
+    /// Like [`LoginResponse::RateLimited`], wrong old passwords count as failed logins.
+    RateLimited {
+        retry_after: u64,
+    },
     ServerError,
 }
 
-#[derive(Serialize, utoipa::ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub enum PasswordRequirement {
-    Length,
-}
-
 #[derive(Deserialize, utoipa::ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ChangePassword {
@@ -247,29 +833,46 @@ pub struct ChangePassword {
     new_password: String,
 }
 
-const PASSWORD_MIN_LENGTH: usize = 8;
-
+/// Changing the password logs the user out everywhere, including the session that changed it.
 pub async fn try_change_password(
-    mut conn: impl AsMut<AsyncPgConnection>,
+    state: &GlobalServerContext,
     c: &ChangePassword,
+    ip: Option<&str>,
 ) -> Result<ChangePasswordResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
     let conn = conn.as_mut();
-    let entry_password_hash: String = schema::user::table
-        .select(schema::user::password_hash)
+    let subjects = [Some(Subject::Account(c.user_id)), ip.map(Subject::Ip)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    if let Some(retry_after) = throttle::attempt(conn, &subjects).await? {
+        return Ok(ChangePasswordResponse::RateLimited { retry_after });
+    }
+    let entry: Option<(String, String)> = schema::user::table
+        .select((schema::user::name, schema::user::password_hash))
         .filter(schema::user::id.eq(&c.user_id.0))
         .first(conn)
-        .await?;
-    if check_password(&c.old_password, &entry_password_hash) {
-        if c.new_password.len() < PASSWORD_MIN_LENGTH {
-            return Ok(ChangePasswordResponse::NewPasswordDoesntMeetRequirements {
-                cause: PasswordRequirement::Length,
-            });
+        .await
+        .optional()?;
+    let Some((username, entry_password_hash)) = entry else {
+        return Ok(ChangePasswordResponse::InvalidCredentials);
+    };
+    let config = aspen_config().await;
+    if check_password(
+        &config.password_hashing,
+        &c.old_password,
+        &entry_password_hash,
+    ) {
+        throttle::succeeded(conn, &subjects).await?;
+        if let Err(cause) = password::check(&config.password_policy, &username, &c.new_password) {
+            return Ok(ChangePasswordResponse::NewPasswordDoesntMeetRequirements { cause });
         }
-        let new_password_hash = hash_password(&c.new_password)?;
+        let new_password_hash = hash_password(&config.password_hashing, &c.new_password)?;
         diesel::update(schema::user::table.filter(schema::user::id.eq(&c.user_id.0)))
             .set(schema::user::password_hash.eq(new_password_hash))
             .execute(conn)
             .await?;
+        revoke_user_sessions(state, conn, c.user_id).await?;
         Ok(ChangePasswordResponse::Ok)
     } else {
         Ok(ChangePasswordResponse::OldPasswordIncorrect)
@@ -280,6 +883,8 @@ pub async fn try_change_password(
 #[serde(rename_all = "camelCase")]
 pub struct OtherServerAuth {
     session_token: String,
+    /// The server the token is for, by the host and port of its `public_origin`. It verifies the
+    /// token with [`app::federation`].
     other_server_domain: String,
 }
 
@@ -304,7 +909,7 @@ pub async fn try_other_server_auth(
     let expires = (Utc::now() + OTHER_SERVER_AUTH_LIFETIME).naive_utc();
     diesel::insert_into(other_server_auth_token::table)
         .values((
-            other_server_auth_token::dsl::token.eq(other_server_auth_token.as_str()),
+            other_server_auth_token::dsl::token.eq(hash_token(&other_server_auth_token).await),
             other_server_auth_token::dsl::user.eq(user.0),
             other_server_auth_token::dsl::expires.eq(expires),
             other_server_auth_token::dsl::domain.eq(&o.other_server_domain),
@@ -315,3 +920,202 @@ pub async fn try_other_server_auth(
         other_server_auth_token,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Params, PasswordHash};
+
+    use diesel::QueryDsl;
+    use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+
+    use super::{
+        LoginResponse, MAX_SECOND_FACTOR_ATTEMPTS, Rotation, check_password, hash_password,
+        hash_token, issue_login_challenge, issue_tokens, keyed_hash, rehash_if_outdated,
+        reserve_attempt, rotate,
+    };
+    use crate::app::UserId;
+    use crate::app::login_session::Device;
+    use crate::aspen_config::{Argon2Algorithm, PasswordHashing, load_test_config};
+    use crate::database::schema::login_challenge;
+    use crate::database::test_database_url;
+
+    /// Cheap settings, standing in for those a hash was made with before the config changed.
+    fn old_hashing() -> PasswordHashing {
+        PasswordHashing {
+            algorithm: Argon2Algorithm::Argon2i,
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+            pepper: None,
+        }
+    }
+
+    fn new_hashing() -> PasswordHashing {
+        PasswordHashing {
+            algorithm: Argon2Algorithm::Argon2id,
+            memory_kib: 2048,
+            iterations: 2,
+            parallelism: 1,
+            pepper: None,
+        }
+    }
+
+    #[test]
+    fn outdated_hash_rewritten_on_login() {
+        let old = hash_password(&old_hashing(), "hunter2 but longer").unwrap();
+        let new_hashing = new_hashing();
+        assert!(check_password(&new_hashing, "hunter2 but longer", &old));
+        let rehashed = rehash_if_outdated(&new_hashing, "hunter2 but longer", &old).unwrap();
+        let parsed = PasswordHash::new(&rehashed).unwrap();
+        assert_eq!(parsed.algorithm.as_str(), "argon2id");
+        let params = Params::try_from(&parsed).unwrap();
+        assert_eq!((params.m_cost(), params.t_cost()), (2048, 2));
+        assert!(check_password(
+            &new_hashing,
+            "hunter2 but longer",
+            &rehashed
+        ));
+        assert!(!check_password(
+            &new_hashing,
+            "hunter3 but longer",
+            &rehashed
+        ));
+        assert_eq!(
+            rehash_if_outdated(&new_hashing, "hunter2 but longer", &rehashed),
+            None
+        );
+    }
+
+    #[test]
+    fn pepper_added_to_existing_hashes() {
+        let unpeppered = hash_password(&new_hashing(), "hunter2 but longer").unwrap();
+        let peppered_hashing = PasswordHashing {
+            pepper: Some("pepper".to_string()),
+            ..new_hashing()
+        };
+        assert!(check_password(
+            &peppered_hashing,
+            "hunter2 but longer",
+            &unpeppered
+        ));
+        let peppered =
+            rehash_if_outdated(&peppered_hashing, "hunter2 but longer", &unpeppered).unwrap();
+        assert!(check_password(
+            &peppered_hashing,
+            "hunter2 but longer",
+            &peppered
+        ));
+        assert_eq!(
+            rehash_if_outdated(&peppered_hashing, "hunter2 but longer", &peppered),
+            None
+        );
+        // Without the pepper the hash can't be checked at all.
+        assert!(!check_password(
+            &new_hashing(),
+            "hunter2 but longer",
+            &peppered
+        ));
+        let other_pepper = PasswordHashing {
+            pepper: Some("other pepper".to_string()),
+            ..new_hashing()
+        };
+        assert!(!check_password(
+            &other_pepper,
+            "hunter2 but longer",
+            &peppered
+        ));
+    }
+
+    #[test]
+    fn token_hash_depends_on_secret() {
+        let hash = keyed_hash(b"secret", "token");
+        assert_eq!(hash, keyed_hash(b"secret", "token"));
+        assert_ne!(hash, keyed_hash(b"other secret", "token"));
+        assert_ne!(hash, keyed_hash(b"secret", "other token"));
+        assert!(!hash.contains("token"));
+    }
+
+    #[tokio::test]
+    async fn second_factor_attempts_limited() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let user = uuid::Uuid::now_v7();
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{user}', 'alice', '');"#
+        ))
+        .await
+        .unwrap();
+        let LoginResponse::SecondFactorRequired { challenge } =
+            issue_login_challenge(&mut conn, UserId(user))
+                .await
+                .unwrap()
+        else {
+            panic!("expected a challenge");
+        };
+        // Only its hash is stored.
+        let stored: String = login_challenge::table
+            .select(login_challenge::token)
+            .first(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(stored, hash_token(&challenge).await);
+        for attempt in 1..=MAX_SECOND_FACTOR_ATTEMPTS {
+            let (reserved_for, attempts) = reserve_attempt(&mut conn, &challenge)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((reserved_for.0, attempts), (user, attempt));
+        }
+        assert!(
+            reserve_attempt(&mut conn, &challenge)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(reserve_attempt(&mut conn, &stored).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_family() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let user = UserId::new();
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{}', 'alice', '');"#,
+            user.0
+        ))
+        .await
+        .unwrap();
+        let device = Device {
+            client_name: None,
+            user_agent: None,
+            ip: None,
+        };
+        let LoginResponse::Ok { refresh_token, .. } =
+            issue_tokens(&mut conn, user, &device).await.unwrap()
+        else {
+            panic!("expected tokens");
+        };
+        let Rotation::Rotated {
+            new_refresh_token, ..
+        } = rotate(&mut conn, &refresh_token).await.unwrap()
+        else {
+            panic!("expected the token to rotate");
+        };
+        // A copy of the first token, presented after the user already rotated it.
+        assert!(matches!(
+            rotate(&mut conn, &refresh_token).await.unwrap(),
+            Rotation::Reused { user: reused, .. } if reused == user
+        ));
+        assert!(matches!(
+            rotate(&mut conn, &new_refresh_token).await.unwrap(),
+            Rotation::Invalid
+        ));
+    }
+}
@@ -0,0 +1,413 @@
+//! The logins of a user, one per refresh token family, along with the device each was made from so
+//! the user can recognize and revoke them.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::api::GlobalServerContext;
+use crate::app::login::{revoke_families, revoke_user_sessions};
+use crate::app::registration::is_admin;
+use crate::database::schema::{login_session, refresh_token, user};
+use crate::{app, app::UserId};
+
+/// What a login was made from, as told by the client.
+pub struct Device {
+    /// Picked by the client, such as "Aspen for Android".
+    pub client_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Records the login of `user` that started refresh token `family`.
+pub async fn record(
+    conn: &mut AsyncPgConnection,
+    family: Uuid,
+    user: UserId,
+    device: &Device,
+) -> Result<(), app::Error> {
+    let now = Utc::now().naive_utc();
+    diesel::insert_into(login_session::table)
+        .values((
+            login_session::family.eq(family),
+            login_session::user.eq(user),
+            login_session::client_name.eq(&device.client_name),
+            login_session::user_agent.eq(&device.user_agent),
+            login_session::ip.eq(&device.ip),
+            login_session::created.eq(now),
+            login_session::last_used.eq(now),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Notes that `family` was just refreshed.
+pub async fn touch(conn: &mut AsyncPgConnection, family: Uuid) -> Result<(), app::Error> {
+    diesel::update(login_session::table.filter(login_session::family.eq(family)))
+        .set(login_session::last_used.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    id: Uuid,
+    client_name: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created: DateTime<Utc>,
+    /// When the session last refreshed its session token, which it does every few hours while in
+    /// use.
+    last_used: DateTime<Utc>,
+    /// Whether this is the session making the request.
+    current: bool,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum SessionsResponse {
+    Ok { sessions: Vec<SessionInfo> },
+    ServerError,
+}
+
+type SessionRow = (
+    Uuid,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    NaiveDateTime,
+    NaiveDateTime,
+);
+
+/// The logins of `user` that haven't expired or been revoked, most recently used first.
+pub async fn try_list(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    current: Uuid,
+) -> Result<SessionsResponse, app::Error> {
+    let now = Utc::now().naive_utc();
+    let live_families = refresh_token::table.select(refresh_token::family).filter(
+        refresh_token::user
+            .eq(user)
+            .and(refresh_token::expires.ge(now)),
+    );
+    let rows: Vec<SessionRow> = login_session::table
+        .select((
+            login_session::family,
+            login_session::client_name,
+            login_session::user_agent,
+            login_session::ip,
+            login_session::created,
+            login_session::last_used,
+        ))
+        .filter(
+            login_session::user
+                .eq(user)
+                .and(login_session::family.eq_any(live_families)),
+        )
+        .order(login_session::last_used.desc())
+        .load(conn)
+        .await?;
+    let sessions = rows
+        .into_iter()
+        .map(
+            |(id, client_name, user_agent, ip, created, last_used)| SessionInfo {
+                id,
+                client_name,
+                user_agent,
+                ip,
+                created: created.and_utc(),
+                last_used: last_used.and_utc(),
+                current: id == current,
+            },
+        )
+        .collect();
+    Ok(SessionsResponse::Ok { sessions })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeSession {
+    id: Uuid,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum RevokeSessionResponse {
+    Ok,
+    NotFound,
+    ServerError,
+}
+
+/// Logs out one of `user`'s sessions, which may be the one making the request.
+pub async fn try_revoke(
+    state: &GlobalServerContext,
+    user: UserId,
+    r: &RevokeSession,
+) -> Result<RevokeSessionResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    match owned_family(conn, user, r.id).await? {
+        Some(family) => {
+            revoke_families(state, conn, &[family]).await?;
+            Ok(RevokeSessionResponse::Ok)
+        }
+        None => Ok(RevokeSessionResponse::NotFound),
+    }
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeUserSessions {
+    user: UserId,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum RevokeUserSessionsResponse {
+    Ok,
+    NotFound,
+    NotAllowed,
+    ServerError,
+}
+
+/// Logs `r.user` out of every session, only admins may.
+pub async fn try_revoke_user(
+    state: &GlobalServerContext,
+    admin: UserId,
+    r: &RevokeUserSessions,
+) -> Result<RevokeUserSessionsResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    if let Some(refusal) = refuse_revoke_user(conn, admin, r.user).await? {
+        return Ok(refusal);
+    }
+    revoke_user_sessions(state, conn, r.user).await?;
+    Ok(RevokeUserSessionsResponse::Ok)
+}
+
+/// Why `admin` can't log `target` out, `None` if they can.
+async fn refuse_revoke_user(
+    conn: &mut AsyncPgConnection,
+    admin: UserId,
+    target: UserId,
+) -> Result<Option<RevokeUserSessionsResponse>, app::Error> {
+    if !is_admin(conn, admin).await? {
+        return Ok(Some(RevokeUserSessionsResponse::NotAllowed));
+    }
+    let exists: bool = diesel::select(diesel::dsl::exists(user::table.filter(user::id.eq(target))))
+        .get_result(conn)
+        .await?;
+    Ok((!exists).then_some(RevokeUserSessionsResponse::NotFound))
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum RevokeOtherSessionsResponse {
+    Ok,
+    ServerError,
+}
+
+/// Logs out every session of `user` besides `current`.
+pub async fn try_revoke_others(
+    state: &GlobalServerContext,
+    user: UserId,
+    current: Uuid,
+) -> Result<RevokeOtherSessionsResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let families = other_families(conn, user, current).await?;
+    revoke_families(state, conn, &families).await?;
+    Ok(RevokeOtherSessionsResponse::Ok)
+}
+
+/// `id` if it is a session of `user`.
+async fn owned_family(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    id: Uuid,
+) -> Result<Option<Uuid>, app::Error> {
+    Ok(login_session::table
+        .select(login_session::family)
+        .filter(
+            login_session::family
+                .eq(id)
+                .and(login_session::user.eq(user)),
+        )
+        .first(conn)
+        .await
+        .optional()?)
+}
+
+/// The sessions of `user` besides `current`.
+async fn other_families(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    current: Uuid,
+) -> Result<Vec<Uuid>, app::Error> {
+    Ok(refresh_token::table
+        .select(refresh_token::family)
+        .filter(
+            refresh_token::user
+                .eq(user)
+                .and(refresh_token::family.ne(current)),
+        )
+        .distinct()
+        .load(conn)
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+    use diesel_async::pooled_connection::deadpool::Pool;
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    use super::{
+        Device, RevokeUserSessionsResponse, SessionsResponse, other_families, owned_family,
+        refuse_revoke_user, try_list,
+    };
+    use crate::api::live_sessions::LiveSessions;
+    use crate::app::UserId;
+    use crate::app::login::{LoginResponse, delete_families, delete_user_sessions, issue_tokens};
+    use crate::aspen_config::load_test_config;
+    use crate::database::test_database_url;
+
+    /// Logs `user` in from `client_name`, returning the session's ID.
+    async fn log_in(conn: &mut AsyncPgConnection, user: UserId, client_name: &str) -> uuid::Uuid {
+        let device = Device {
+            client_name: Some(client_name.into()),
+            user_agent: Some("Mozilla/5.0".into()),
+            ip: Some("192.0.2.1".into()),
+        };
+        assert!(matches!(
+            issue_tokens(conn, user, &device).await.unwrap(),
+            LoginResponse::Ok { .. }
+        ));
+        let SessionsResponse::Ok { sessions } =
+            try_list(conn, user, uuid::Uuid::nil()).await.unwrap()
+        else {
+            panic!("expected sessions");
+        };
+        sessions
+            .into_iter()
+            .find(|session| session.client_name.as_deref() == Some(client_name))
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn sessions_listed_and_revoked_per_user() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (alice, bob) = (UserId::new(), UserId::new());
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash)
+                VALUES ('{}', 'alice', ''), ('{}', 'bob', '');"#,
+            alice.0, bob.0
+        ))
+        .await
+        .unwrap();
+        let laptop = log_in(&mut conn, alice, "laptop").await;
+        let phone = log_in(&mut conn, alice, "phone").await;
+        let bobs = log_in(&mut conn, bob, "bob's laptop").await;
+
+        let SessionsResponse::Ok { sessions } = try_list(&mut conn, alice, phone).await.unwrap()
+        else {
+            panic!("expected sessions");
+        };
+        let listed = sessions
+            .iter()
+            .map(|session| (session.id, session.current))
+            .collect::<Vec<_>>();
+        // Most recently used first.
+        assert_eq!(listed, [(phone, true), (laptop, false)]);
+        assert_eq!(sessions[0].user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(sessions[0].ip.as_deref(), Some("192.0.2.1"));
+
+        assert_eq!(
+            owned_family(&mut conn, alice, laptop).await.unwrap(),
+            Some(laptop)
+        );
+        assert_eq!(owned_family(&mut conn, alice, bobs).await.unwrap(), None);
+        let others = other_families(&mut conn, alice, phone).await.unwrap();
+        assert_eq!(others, [laptop]);
+
+        delete_families(&mut conn, &others).await.unwrap();
+        for (user, remaining) in [(alice, phone), (bob, bobs)] {
+            let SessionsResponse::Ok { sessions } =
+                try_list(&mut conn, user, remaining).await.unwrap()
+            else {
+                panic!("expected sessions");
+            };
+            let ids = sessions
+                .iter()
+                .map(|session| session.id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, [remaining]);
+        }
+    }
+
+    #[tokio::test]
+    async fn admins_log_users_out() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let pool = Pool::builder(AsyncDieselConnectionManager::<AsyncPgConnection>::new(url))
+            .build()
+            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        let (admin, alice) = (UserId::new(), UserId::new());
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash)
+                VALUES ('{}', 'admin', ''), ('{}', 'alice', '');
+            INSERT INTO "user_role"("user", role) VALUES ('{}', 'admin');"#,
+            admin.0, alice.0, admin.0
+        ))
+        .await
+        .unwrap();
+        let laptop = log_in(conn.as_mut(), alice, "laptop").await;
+        let sessions = Arc::new(LiveSessions::default());
+        let mut stream = sessions.register(conn.as_mut(), laptop).await.unwrap();
+
+        assert!(matches!(
+            refuse_revoke_user(conn.as_mut(), alice, admin)
+                .await
+                .unwrap(),
+            Some(RevokeUserSessionsResponse::NotAllowed)
+        ));
+        assert!(matches!(
+            refuse_revoke_user(conn.as_mut(), admin, UserId::new())
+                .await
+                .unwrap(),
+            Some(RevokeUserSessionsResponse::NotFound)
+        ));
+        assert!(
+            refuse_revoke_user(conn.as_mut(), admin, alice)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            delete_user_sessions(conn.as_mut(), alice).await.unwrap(),
+            [laptop]
+        );
+        // Without NATS, the same way a node that missed the revocation closes it.
+        sessions.recheck(&pool).await;
+        tokio::time::timeout(Duration::from_secs(1), stream.revoked())
+            .await
+            .unwrap();
+    }
+}
@@ -1,24 +1,206 @@
-use crate::app::attachment::AttachmentInput;
-use crate::app::channel::Channel;
+use crate::api::GlobalServerContext;
+use crate::api::message_enum::command::{MessageCreateCommand, MessageUpdateCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::app;
+use crate::app::channel::{Channel, channel_community, require_read};
+use crate::app::email;
 use crate::app::user::User;
-use crate::app::{AttachmentId, ChannelId, UserId};
-use crate::app::{MaybeLoaded, MessageId};
-use chrono::Utc;
+use crate::app::{CommunityId, Loadable, MaybeLoaded, MessageId, UserId};
+use crate::database::schema::{self, message, react};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{ExpressionMethods, Insertable, QueryDsl, Queryable, Selectable, SelectableHelper};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = message)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Message {
-    id: MessageId,
-    channel: MaybeLoaded<Channel>,
-    content: String,
-    attachments: Vec<AttachmentId>,
-    author: MaybeLoaded<User>,
-    timestamp: chrono::DateTime<Utc>,
+    pub id: MessageId,
+    pub author: MaybeLoaded<User>,
+    pub channel: MaybeLoaded<Channel>,
+    pub time: NaiveDateTime,
+    pub content: String,
+}
+
+impl Loadable for Message {
+    type Id = MessageId;
+
+    async fn load_from_db(
+        pg_connection: &mut AsyncPgConnection,
+        id: Self::Id,
+    ) -> Result<Self, diesel::result::Error> {
+        schema::message::table
+            .select(Message::as_select())
+            .filter(message::id.eq(id))
+            .first(pg_connection)
+            .await
+    }
+
+    fn id(&self) -> &Self::Id {
+        &self.id
+    }
 }
 
-pub fn create_message(
+/// Only users who may read a channel may post in it.
+pub(crate) async fn create_message(
+    state: GlobalServerContext,
     author: UserId,
-    channel_id: ChannelId,
-    content: String,
-    attachment: Vec<AttachmentInput>,
-) {
-    let id = MessageId::new();
+    command: &MessageCreateCommand,
+) -> Result<Message, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let (community, message) = insert_message(conn.as_mut(), author, command).await?;
+    state
+        .event_publisher
+        .publish_in_channel(
+            community,
+            command.channel_id,
+            &ServerEvent::Message(sub_variant::Message::Create {
+                id: message.id,
+                author,
+                timestamp: message.time.and_utc(),
+                content: message.content.clone(),
+                channel_id: command.channel_id,
+            }),
+        )
+        .await;
+    Ok(message)
+}
+
+/// Stores a new message by `author`, returning it along with the community it was posted in.
+async fn insert_message(
+    pg_connection: &mut AsyncPgConnection,
+    author: UserId,
+    command: &MessageCreateCommand,
+) -> Result<(CommunityId, Message), app::Error> {
+    let community = require_read(pg_connection, author, command.channel_id).await?;
+    email::require_verified_to_post(pg_connection, author).await?;
+    let message = Message {
+        id: MessageId::new(),
+        author: MaybeLoaded::NotLoaded(author),
+        channel: MaybeLoaded::NotLoaded(command.channel_id),
+        time: Utc::now().naive_utc(),
+        content: command.content.clone(),
+    };
+    diesel::insert_into(message::table)
+        .values(message.clone())
+        .execute(pg_connection)
+        .await?;
+    Ok((community, message))
+}
+
+/// Only the author of a message may edit it.
+pub(crate) async fn update_message(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &MessageUpdateCommand,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let message = Message::load_from_db(conn.as_mut(), command.id).await?;
+    if *message.author.id() != user {
+        return Err(app::Error::NotAllowed);
+    }
+    email::require_verified_to_post(conn.as_mut(), user).await?;
+    let community = channel_community(conn.as_mut(), *message.channel.id()).await?;
+    diesel::update(message::table.filter(message::id.eq(command.id)))
+        .set(message::content.eq(&command.content))
+        .execute(conn.as_mut())
+        .await?;
+    state
+        .event_publisher
+        .publish_in_channel(
+            community,
+            *message.channel.id(),
+            &ServerEvent::Message(sub_variant::Message::Update {
+                id: command.id,
+                content: command.content.clone(),
+            }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Only the author of a message may delete it.
+pub(crate) async fn delete_message(
+    state: GlobalServerContext,
+    user: UserId,
+    id: MessageId,
+) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let message = Message::load_from_db(conn.as_mut(), id).await?;
+    if *message.author.id() != user {
+        return Err(app::Error::NotAllowed);
+    }
+    let community = channel_community(conn.as_mut(), *message.channel.id()).await?;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::delete(react::table.filter(react::message.eq(id)))
+                .execute(conn)
+                .await?;
+            diesel::delete(message::table.filter(message::id.eq(id)))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    state
+        .event_publisher
+        .publish_in_channel(
+            community,
+            *message.channel.id(),
+            &ServerEvent::Message(sub_variant::Message::Delete { id }),
+        )
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    use super::insert_message;
+    use crate::api::message_enum::command::MessageCreateCommand;
+    use crate::app::{self, ChannelId, CommunityId, UserId};
+    use crate::aspen_config::load_test_config;
+    use crate::database::test_database_url;
+
+    #[tokio::test]
+    async fn only_readers_post() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (member, community) = (UserId::new(), CommunityId::new());
+        let (public, private) = (ChannelId::new(), ChannelId::new());
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{member}', 'member', '');
+            INSERT INTO "community"(id, name) VALUES ('{community}', 'community');
+            INSERT INTO "community_user"("user", community) VALUES ('{member}', '{community}');
+            INSERT INTO "channel"(id, community, name, ty, sort_index, everyone_deny)
+                VALUES ('{public}', '{community}', 'public', 0, 0, 0),
+                ('{private}', '{community}', 'private', 0, 1, 1);"#,
+            member = member.0,
+            community = community.0,
+            public = public.0,
+            private = private.0,
+        ))
+        .await
+        .unwrap();
+        let post = |channel_id| MessageCreateCommand {
+            channel_id,
+            content: "hello".into(),
+        };
+
+        let (posted_in, _) = insert_message(&mut conn, member, &post(public))
+            .await
+            .unwrap();
+        assert_eq!(posted_in, community);
+        assert!(matches!(
+            insert_message(&mut conn, member, &post(private)).await,
+            Err(app::Error::NotAllowed)
+        ));
+    }
 }
@@ -1,10 +1,10 @@
-use diesel::QueryId;
 use diesel::deserialize::{FromSql, FromSqlRow};
 use diesel::expression::AsExpression;
 use diesel::pg::sql_types::Uuid;
 use diesel::pg::{Pg, PgValue};
 use diesel::serialize::ToSql;
 use diesel::sql_types::{Nullable, SingleValue, Uuid as DieselUuid};
+use diesel::{QueryId, Queryable};
 use diesel_async::AsyncPgConnection;
 use heck::ToKebabCase;
 use serde::{Deserialize, Serialize};
@@ -12,15 +12,29 @@ use std::error::Error as StdError;
 use std::fmt::{Debug, Display, Formatter};
 
 pub mod attachment;
+pub mod audit;
+pub mod authenticator;
 pub mod category;
 pub mod channel;
 pub mod community;
+pub mod email;
 mod error;
+pub mod federation;
 pub mod icon;
+pub mod ldap;
 pub mod login;
+pub mod login_session;
 pub mod message;
+pub mod oidc;
+pub mod password;
+pub mod password_reset;
 pub mod react;
+pub mod ready;
+pub mod registration;
+pub mod throttle;
+pub mod totp;
 pub mod user;
+pub mod webauthn;
 pub use error::Error;
 
 macro_rules! id_type {
@@ -152,6 +166,17 @@ where
     }
 }
 
+impl<SqlType: SingleValue, T: Loadable> Queryable<SqlType, Pg> for MaybeLoaded<T>
+where
+    Self: FromSql<SqlType, Pg>,
+{
+    type Row = Self;
+
+    fn build(row: Self) -> diesel::deserialize::Result<Self> {
+        Ok(row)
+    }
+}
+
 impl<SqlType: SingleValue, T: Loadable + Debug> AsExpression<SqlType> for MaybeLoaded<T>
 where
     T::Id: AsExpression<SqlType>,
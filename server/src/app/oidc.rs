@@ -0,0 +1,825 @@
+//! Single sign-on through an OpenID Connect provider, using the authorization code flow with PKCE.
+//!
+//! The client asks `/login/oidc/start` for a URL at the provider and opens it. Once the user has
+//! logged in there, the provider sends them to the configured redirect URI with a code and the
+//! state from the URL, which the client passes to `/login/oidc` for the same tokens a password login
+//! gets. The provider handles second factors, so Aspen doesn't ask for its own.
+//!
+//! `/login/oidc/start` also hands the client a binding that never goes through the provider, and
+//! `/login/oidc` wants it back. Someone who started a login of their own can't have a victim's
+//! client finish it, which would log the victim in as them.
+
+use aws_lc_rs::signature::{
+    ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::RngExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::api::GlobalServerContext;
+use crate::app::login::{LoginResponse, hash_password, hash_token, issue_tokens, make_token};
+use crate::app::login_session::Device;
+use crate::app::registration;
+use crate::app::user::{User, free_name};
+use crate::aspen_config::{OidcConfig, aspen_config};
+use crate::database::schema::{oidc_identity, oidc_login, user};
+use crate::mailer;
+use crate::{CHACHA_RNG, app, app::UserId};
+
+/// How long the user has to log in at the provider.
+const PENDING_LOGIN_LIFETIME: Duration = Duration::minutes(10);
+/// How far the provider's clock may be ahead of ours, in seconds.
+const CLOCK_SKEW: i64 = 60;
+
+/// Talks to the configured provider.
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+    /// Discovered on first use, so the server starts while the provider is down.
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// A login that was started and waits for the provider to send the user back.
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    code_verifier: String,
+    /// Kept by the client that started the login.
+    binding: String,
+}
+
+impl PendingLogin {
+    fn new() -> Self {
+        let random = || {
+            BASE64_URL_SAFE_NO_PAD
+                .encode(CHACHA_RNG.with(|rng| rng.borrow_mut().random::<[u8; 32]>()))
+        };
+        Self {
+            state: random(),
+            nonce: random(),
+            code_verifier: random(),
+            binding: random(),
+        }
+    }
+
+    fn code_challenge(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+/// Who the provider says logged in.
+#[derive(Debug, PartialEq)]
+struct Identity {
+    issuer: String,
+    subject: String,
+    username: Option<String>,
+    /// Only set if the provider verified it.
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct JwsHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+fn rejected(reason: impl Into<String>) -> app::Error {
+    app::Error::Oidc(reason.into())
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> Result<T, app::Error> {
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| rejected("ID token isn't base64url"))?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Whether `key` made `signature` over `signed` with `alg`.
+fn verify_with(key: &Jwk, alg: &str, signed: &[u8], signature: &[u8]) -> bool {
+    let decode = |part: &Option<String>| {
+        part.as_deref()
+            .and_then(|part| BASE64_URL_SAFE_NO_PAD.decode(part).ok())
+    };
+    match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => {
+            let (Some(n), Some(e)) = (decode(&key.n), decode(&key.e)) else {
+                return false;
+            };
+            RsaPublicKeyComponents { n, e }
+                .verify(&RSA_PKCS1_2048_8192_SHA256, signed, signature)
+                .is_ok()
+        }
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            let (Some(x), Some(y)) = (decode(&key.x), decode(&key.y)) else {
+                return false;
+            };
+            let point = [&[4][..], &x, &y].concat();
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                .verify(signed, signature)
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// The claims of `id_token` if one of `keys` signed it. Nothing about the claims is checked.
+fn verify_id_token(id_token: &str, keys: &JwkSet) -> Result<IdTokenClaims, app::Error> {
+    let Some((signed, signature)) = id_token.rsplit_once('.') else {
+        return Err(rejected("ID token isn't a JWS"));
+    };
+    let Some((header, payload)) = signed.split_once('.') else {
+        return Err(rejected("ID token isn't a JWS"));
+    };
+    let header: JwsHeader = decode_json(header)?;
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| rejected("ID token signature isn't base64url"))?;
+    let signed_by_known_key = keys
+        .keys
+        .iter()
+        .filter(|key| header.kid.is_none() || key.kid == header.kid)
+        .any(|key| verify_with(key, &header.alg, signed.as_bytes(), &signature));
+    if !signed_by_known_key {
+        return Err(rejected(format!(
+            "ID token isn't signed by a published {} key",
+            header.alg
+        )));
+    }
+    decode_json(payload)
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Result<Self, app::Error> {
+        Ok(Self {
+            config,
+            http: reqwest::Client::builder().build()?,
+            metadata: OnceCell::new(),
+        })
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, app::Error> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let issuer = self.config.issuer.trim_end_matches('/');
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(format!("{issuer}/.well-known/openid-configuration"))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if metadata.issuer.trim_end_matches('/') != issuer {
+                    return Err(rejected(format!(
+                        "discovery document is for issuer {}",
+                        metadata.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Where to send the user to log in at the provider.
+    async fn authorization_url(&self, pending: &PendingLogin) -> Result<String, app::Error> {
+        let endpoint = &self.metadata().await?.authorization_endpoint;
+        let params = [
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("scope", &self.config.scopes),
+            ("state", &pending.state),
+            ("nonce", &pending.nonce),
+            ("code_challenge", &pending.code_challenge()),
+            ("code_challenge_method", "S256"),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}={}", utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>()
+        .join("&");
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        Ok(format!("{endpoint}{separator}{params}"))
+    }
+
+    /// Trades the `code` the provider sent the user back with for who they are. Fails with
+    /// [`app::Error::Oidc`] if the provider didn't vouch for them.
+    async fn identify(&self, code: &str, pending: &PendingLogin) -> Result<Identity, app::Error> {
+        let metadata = self.metadata().await?;
+        let mut request = self.http.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &pending.code_verifier),
+        ]);
+        if let Some(secret) = &self.config.client_secret {
+            request = request.basic_auth(&self.config.client_id, Some(secret));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(rejected(format!(
+                "token endpoint answered {}",
+                response.status()
+            )));
+        }
+        let tokens: TokenResponse = response.json().await?;
+        // Fetched every time so rotated keys are picked up, logins are rare enough.
+        let keys: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let claims = verify_id_token(&tokens.id_token, &keys)?;
+        if claims.iss != metadata.issuer {
+            return Err(rejected(format!("ID token is from issuer {}", claims.iss)));
+        }
+        if !claims.aud.contains(&self.config.client_id) {
+            return Err(rejected("ID token is for another client"));
+        }
+        if claims.exp + CLOCK_SKEW < Utc::now().timestamp() {
+            return Err(rejected("ID token expired"));
+        }
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(rejected("ID token nonce doesn't match"));
+        }
+        Ok(Identity {
+            issuer: claims.iss,
+            subject: claims.sub,
+            username: claims.preferred_username,
+            email: claims
+                .email
+                .filter(|_| claims.email_verified)
+                .and_then(|email| mailer::normalize_address(&email).ok()),
+        })
+    }
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum OidcStartResponse {
+    /// Open `authorization_url` for the user to log in at the provider, and keep `binding` to
+    /// finish the login with.
+    Ok {
+        authorization_url: String,
+        binding: String,
+    },
+    /// The server has no provider configured.
+    Unavailable,
+    ServerError,
+}
+
+pub async fn try_start(state: &GlobalServerContext) -> Result<OidcStartResponse, app::Error> {
+    let Some(oidc) = &state.oidc else {
+        return Ok(OidcStartResponse::Unavailable);
+    };
+    let pending = PendingLogin::new();
+    let authorization_url = oidc.authorization_url(&pending).await?;
+    let mut conn = state.connection_pool.get().await?;
+    store_pending(conn.as_mut(), &pending).await?;
+    Ok(OidcStartResponse::Ok {
+        authorization_url,
+        binding: pending.binding,
+    })
+}
+
+async fn store_pending(
+    conn: &mut AsyncPgConnection,
+    pending: &PendingLogin,
+) -> Result<(), app::Error> {
+    diesel::insert_into(oidc_login::table)
+        .values((
+            oidc_login::state.eq(hash_token(&pending.state).await),
+            oidc_login::nonce.eq(&pending.nonce),
+            oidc_login::code_verifier.eq(&pending.code_verifier),
+            oidc_login::expires.eq((Utc::now() + PENDING_LOGIN_LIFETIME).naive_utc()),
+            oidc_login::binding.eq(hash_token(&pending.binding).await),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// The login started with `state` by the client holding `binding`, if it hasn't expired. Deleting
+/// claims the login, so a state can't be used twice.
+async fn claim_pending(
+    conn: &mut AsyncPgConnection,
+    state: &str,
+    binding: &str,
+) -> Result<Option<PendingLogin>, app::Error> {
+    let now = Utc::now().naive_utc();
+    let pending: Option<(String, String)> = diesel::delete(
+        oidc_login::table.filter(
+            oidc_login::state
+                .eq(hash_token(state).await)
+                .and(oidc_login::binding.eq(hash_token(binding).await))
+                .and(oidc_login::expires.ge(now)),
+        ),
+    )
+    .returning((oidc_login::nonce, oidc_login::code_verifier))
+    .get_result(conn)
+    .await
+    .optional()?;
+    Ok(pending.map(|(nonce, code_verifier)| PendingLogin {
+        state: state.into(),
+        nonce,
+        code_verifier,
+        binding: binding.into(),
+    }))
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcLogin {
+    /// From the redirect URI the provider sent the user to.
+    code: String,
+    state: String,
+    /// From [`OidcStartResponse::Ok`].
+    binding: String,
+    /// Shown in the user's list of sessions, see [`Device::client_name`].
+    #[serde(default)]
+    client_name: Option<String>,
+}
+
+/// Finishes a login started with [`try_start`], answering like a password login would.
+pub async fn try_login(
+    state: &GlobalServerContext,
+    login: &OidcLogin,
+    device: Device,
+) -> Result<LoginResponse, app::Error> {
+    let Some(oidc) = &state.oidc else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let Some(pending) = claim_pending(conn, &login.state, &login.binding).await? else {
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let identity = match oidc.identify(&login.code, &pending).await {
+        Ok(identity) => identity,
+        Err(app::Error::Oidc(reason)) => {
+            warn!("rejected OpenID Connect login {reason}");
+            return Ok(LoginResponse::InvalidCredentials);
+        }
+        Err(e) => return Err(e),
+    };
+    let Some(user) = account(conn, &oidc.config, &identity).await? else {
+        info!(
+            "no account for OpenID Connect subject {} and creating one isn't allowed",
+            identity.subject
+        );
+        return Ok(LoginResponse::InvalidCredentials);
+    };
+    let device = Device {
+        client_name: login.client_name.clone(),
+        ..device
+    };
+    issue_tokens(conn, user, &device).await
+}
+
+/// The account `identity` logs in to, linking or creating one if `config` allows.
+async fn account(
+    conn: &mut AsyncPgConnection,
+    config: &OidcConfig,
+    identity: &Identity,
+) -> Result<Option<UserId>, app::Error> {
+    let linked: Option<UserId> = oidc_identity::table
+        .select(oidc_identity::user)
+        .filter(
+            oidc_identity::issuer
+                .eq(&identity.issuer)
+                .and(oidc_identity::subject.eq(&identity.subject)),
+        )
+        .first(conn)
+        .await
+        .optional()?;
+    if linked.is_some() {
+        return Ok(linked);
+    }
+    if config.link_verified_emails
+        && let Some(email) = &identity.email
+    {
+        let same_email: Option<UserId> = user::table
+            .select(user::id)
+            .filter(user::email.eq(email).and(user::email_verified.eq(true)))
+            .first(conn)
+            .await
+            .optional()?;
+        if let Some(user_id) = same_email {
+            link(conn, identity, user_id).await?;
+            return Ok(Some(user_id));
+        }
+    }
+    if config.create_users {
+        return Ok(Some(create_account(conn, identity).await?));
+    }
+    Ok(None)
+}
+
+async fn link(
+    conn: &mut AsyncPgConnection,
+    identity: &Identity,
+    user_id: UserId,
+) -> Result<(), app::Error> {
+    diesel::insert_into(oidc_identity::table)
+        .values((
+            oidc_identity::issuer.eq(&identity.issuer),
+            oidc_identity::subject.eq(&identity.subject),
+            oidc_identity::user.eq(user_id),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Creates an account for `identity`, named after its preferred username if that's free.
+async fn create_account(
+    conn: &mut AsyncPgConnection,
+    identity: &Identity,
+) -> Result<UserId, app::Error> {
+    let base = identity
+        .username
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("user");
+    let name = free_name(conn, base).await?;
+    let email_taken = match &identity.email {
+        Some(email) => {
+            diesel::select(diesel::dsl::exists(
+                user::table.filter(user::email.eq(email)),
+            ))
+            .get_result::<bool>(conn)
+            .await?
+        }
+        None => false,
+    };
+    let email = identity.email.clone().filter(|_| !email_taken);
+    // Nobody knows this password, the user can set one with a password reset.
+    let config = aspen_config().await;
+    let password_hash = hash_password(&config.password_hashing, &make_token())?;
+    let new_user = User {
+        id: UserId::new(),
+        name,
+        icon: None,
+        password_hash,
+        email_verified: email.is_some(),
+        email,
+        approved: registration::starts_approved(config.registration),
+    };
+    let user_id = new_user.id;
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::insert_into(user::table)
+                .values(new_user)
+                .execute(conn)
+                .await?;
+            diesel::insert_into(oidc_identity::table)
+                .values((
+                    oidc_identity::issuer.eq(&identity.issuer),
+                    oidc_identity::subject.eq(&identity.subject),
+                    oidc_identity::user.eq(user_id),
+                ))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+    use axum::extract::State;
+    use axum::routing::{get, post};
+    use axum::{Form, Json, Router};
+    use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+    use chrono::Utc;
+    use percent_encoding::percent_decode_str;
+    use serde_json::{Value, json};
+
+    use diesel_async::{AsyncConnection, AsyncPgConnection};
+
+    use super::{Identity, OidcClient, PendingLogin, claim_pending, store_pending};
+    use crate::app;
+    use crate::aspen_config::{OidcConfig, load_test_config};
+    use crate::database::test_database_url;
+
+    const CODE: &str = "good-code";
+
+    /// A provider that logs everyone in as alice, remembering what the last authorization URL
+    /// asked for.
+    struct MockIssuer {
+        issuer: String,
+        signing_key: EcdsaKeyPair,
+        published_key: EcdsaKeyPair,
+        audience: String,
+        authorization: Mutex<HashMap<String, String>>,
+    }
+
+    fn jwk(key: &EcdsaKeyPair) -> Value {
+        let point = key.public_key().as_ref();
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "key",
+            "x": BASE64_URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": BASE64_URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    fn sign(key: &EcdsaKeyPair, claims: &Value) -> String {
+        let header = BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"key"}"#);
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{header}.{payload}");
+        let signature = key.sign(&SystemRandom::new(), signed.as_bytes()).unwrap();
+        format!("{signed}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    async fn discovery(State(mock): State<Arc<MockIssuer>>) -> Json<Value> {
+        Json(json!({
+            "issuer": mock.issuer,
+            "authorization_endpoint": format!("{}/authorize", mock.issuer),
+            "token_endpoint": format!("{}/token", mock.issuer),
+            "jwks_uri": format!("{}/jwks", mock.issuer),
+        }))
+    }
+
+    async fn jwks(State(mock): State<Arc<MockIssuer>>) -> Json<Value> {
+        Json(json!({ "keys": [jwk(&mock.published_key)] }))
+    }
+
+    async fn token(
+        State(mock): State<Arc<MockIssuer>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, axum::http::StatusCode> {
+        let authorization = mock.authorization.lock().unwrap().clone();
+        let verifier = PendingLogin {
+            state: String::new(),
+            nonce: String::new(),
+            code_verifier: form["code_verifier"].clone(),
+            binding: String::new(),
+        };
+        if form["grant_type"] != "authorization_code"
+            || form["code"] != CODE
+            || verifier.code_challenge() != authorization["code_challenge"]
+        {
+            return Err(axum::http::StatusCode::BAD_REQUEST);
+        }
+        let claims = json!({
+            "iss": mock.issuer,
+            "sub": "alice-subject",
+            "aud": mock.audience,
+            "exp": Utc::now().timestamp() + 300,
+            "iat": Utc::now().timestamp(),
+            "nonce": authorization["nonce"],
+            "preferred_username": "alice",
+            "email": "Alice@Example.com",
+            "email_verified": true,
+        });
+        Ok(Json(
+            json!({ "id_token": sign(&mock.signing_key, &claims), "token_type": "Bearer" }),
+        ))
+    }
+
+    fn key() -> EcdsaKeyPair {
+        EcdsaKeyPair::generate(&ECDSA_P256_SHA256_FIXED_SIGNING).unwrap()
+    }
+
+    /// One key, twice, since a mock issuer signs with one and publishes the other.
+    fn same_key() -> (EcdsaKeyPair, EcdsaKeyPair) {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let load = || EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref());
+        (load().unwrap(), load().unwrap())
+    }
+
+    /// Serves a mock issuer, returning it and a client configured for it.
+    async fn mock_issuer(
+        signing_key: EcdsaKeyPair,
+        published_key: EcdsaKeyPair,
+        audience: &str,
+    ) -> (Arc<MockIssuer>, OidcClient) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Arc::new(MockIssuer {
+            issuer: issuer.clone(),
+            signing_key,
+            published_key,
+            audience: audience.into(),
+            authorization: Mutex::default(),
+        });
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = OidcClient::new(OidcConfig {
+            issuer,
+            client_id: "aspen".into(),
+            client_secret: Some("secret".into()),
+            redirect_uri: "aspen://oidc".into(),
+            scopes: "openid profile email".into(),
+            create_users: false,
+            link_verified_emails: false,
+        })
+        .unwrap();
+        (mock, client)
+    }
+
+    /// Follows the authorization URL as a browser would, up to where the provider redirects.
+    async fn authorize(mock: &MockIssuer, client: &OidcClient, pending: &PendingLogin) {
+        let url = client.authorization_url(pending).await.unwrap();
+        let (endpoint, query) = url.split_once('?').unwrap();
+        assert_eq!(endpoint, format!("{}/authorize", mock.issuer));
+        let params = query
+            .split('&')
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap();
+                let value = percent_decode_str(value).decode_utf8().unwrap();
+                (name.to_string(), value.into_owned())
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "aspen");
+        assert_eq!(params["redirect_uri"], "aspen://oidc");
+        assert_eq!(params["state"], pending.state);
+        assert_eq!(params["code_challenge_method"], "S256");
+        *mock.authorization.lock().unwrap() = params;
+    }
+
+    #[tokio::test]
+    async fn logs_in_through_mock_issuer() {
+        let (key, published) = same_key();
+        let (mock, client) = mock_issuer(key, published, "aspen").await;
+        let pending = PendingLogin::new();
+        authorize(&mock, &client, &pending).await;
+        assert_eq!(
+            client.identify(CODE, &pending).await.unwrap(),
+            Identity {
+                issuer: mock.issuer.clone(),
+                subject: "alice-subject".into(),
+                username: Some("alice".into()),
+                email: Some("alice@example.com".into()),
+            }
+        );
+        assert!(matches!(
+            client.identify("bad-code", &pending).await,
+            Err(app::Error::Oidc(_))
+        ));
+        let replayed = PendingLogin {
+            nonce: "other nonce".into(),
+            ..pending
+        };
+        assert!(matches!(
+            client.identify(CODE, &replayed).await,
+            Err(app::Error::Oidc(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_verifier() {
+        let (key, published) = same_key();
+        let (mock, client) = mock_issuer(key, published, "aspen").await;
+        let pending = PendingLogin::new();
+        authorize(&mock, &client, &pending).await;
+        let guessed = PendingLogin {
+            code_verifier: "guessed".into(),
+            ..pending
+        };
+        assert!(matches!(
+            client.identify(CODE, &guessed).await,
+            Err(app::Error::Oidc(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_unpublished_key() {
+        let (mock, client) = mock_issuer(key(), key(), "aspen").await;
+        let pending = PendingLogin::new();
+        authorize(&mock, &client, &pending).await;
+        assert!(matches!(
+            client.identify(CODE, &pending).await,
+            Err(app::Error::Oidc(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_audience() {
+        let (key, published) = same_key();
+        let (mock, client) = mock_issuer(key, published, "someone-else").await;
+        let pending = PendingLogin::new();
+        authorize(&mock, &client, &pending).await;
+        assert!(matches!(
+            client.identify(CODE, &pending).await,
+            Err(app::Error::Oidc(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_starting_client_finishes_login() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let pending = PendingLogin::new();
+        store_pending(&mut conn, &pending).await.unwrap();
+        let other = PendingLogin::new();
+        assert!(
+            claim_pending(&mut conn, &pending.state, &other.binding)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let claimed = claim_pending(&mut conn, &pending.state, &pending.binding)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.code_verifier, pending.code_verifier);
+        assert!(
+            claim_pending(&mut conn, &pending.state, &pending.binding)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! The rules new passwords have to follow, configured by [`PasswordPolicy`].
+
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+
+use crate::app;
+use crate::aspen_config::PasswordPolicy;
+
+/// Frequently used and breached passwords, checked without asking any online service. Only those
+/// the length limits of the policy allow are kept, the rest can't be chosen anyway.
+static COMMON_PASSWORDS: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// The bundled list of common passwords, plus the one at
+/// [`PasswordPolicy::breached_passwords_file`] if any.
+fn read_common_passwords(policy: &PasswordPolicy) -> Result<HashSet<String>, app::Error> {
+    let breached = match &policy.breached_passwords_file {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    Ok(include_str!("common_passwords.txt")
+        .lines()
+        .chain(breached.lines())
+        .map(|password| password.trim_end_matches('\r').to_lowercase())
+        .filter(|password| {
+            (policy.min_length..=policy.max_length).contains(&password.chars().count())
+        })
+        .collect())
+}
+
+/// Reads the common passwords up front, so a missing breached passwords file stops the server from
+/// starting rather than failing the first sign up.
+pub fn load_common_passwords(policy: &PasswordPolicy) -> Result<(), app::Error> {
+    if COMMON_PASSWORDS.get().is_none() {
+        let _ = COMMON_PASSWORDS.set(read_common_passwords(policy)?);
+    }
+    Ok(())
+}
+
+/// A rule of the [`PasswordPolicy`] a password broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PasswordRequirement {
+    /// Shorter than `min_length`.
+    Length,
+    /// Longer than `max_length`.
+    MaxLength,
+    /// Found in the list of common passwords.
+    Common,
+    ContainsUsername,
+    Lowercase,
+    Uppercase,
+    Digit,
+    /// Needs a character that is neither a letter nor a digit.
+    Symbol,
+}
+
+impl PasswordRequirement {
+    /// Explains the requirement to the user, in their language.
+    pub fn describe(&self, policy: &PasswordPolicy) -> String {
+        match self {
+            PasswordRequirement::Length => {
+                rust_i18n::t!("passwordTooShort", min = policy.min_length).into()
+            }
+            PasswordRequirement::MaxLength => {
+                rust_i18n::t!("passwordTooLong", max = policy.max_length).into()
+            }
+            PasswordRequirement::Common => rust_i18n::t!("passwordCommon").into(),
+            PasswordRequirement::ContainsUsername => {
+                rust_i18n::t!("passwordContainsUsername").into()
+            }
+            PasswordRequirement::Lowercase => rust_i18n::t!("passwordNeedsLowercase").into(),
+            PasswordRequirement::Uppercase => rust_i18n::t!("passwordNeedsUppercase").into(),
+            PasswordRequirement::Digit => rust_i18n::t!("passwordNeedsDigit").into(),
+            PasswordRequirement::Symbol => rust_i18n::t!("passwordNeedsSymbol").into(),
+        }
+    }
+}
+
+/// The first requirement of `policy` that `password` for the user called `username` breaks.
+pub fn check(
+    policy: &PasswordPolicy,
+    username: &str,
+    password: &str,
+) -> Result<(), PasswordRequirement> {
+    let length = password.chars().count();
+    if length < policy.min_length {
+        return Err(PasswordRequirement::Length);
+    }
+    if length > policy.max_length {
+        return Err(PasswordRequirement::MaxLength);
+    }
+    let lowercase = password.to_lowercase();
+    let common = || {
+        COMMON_PASSWORDS.get_or_init(|| {
+            read_common_passwords(policy).expect("loaded at startup, see load_common_passwords")
+        })
+    };
+    if policy.reject_common && common().contains(&lowercase) {
+        return Err(PasswordRequirement::Common);
+    }
+    let username = username.trim().to_lowercase();
+    if policy.reject_username && !username.is_empty() && lowercase.contains(&username) {
+        return Err(PasswordRequirement::ContainsUsername);
+    }
+    let classes = [
+        (
+            policy.require_lowercase,
+            char::is_lowercase as fn(char) -> bool,
+            PasswordRequirement::Lowercase,
+        ),
+        (
+            policy.require_uppercase,
+            char::is_uppercase,
+            PasswordRequirement::Uppercase,
+        ),
+        (
+            policy.require_digit,
+            char::is_numeric,
+            PasswordRequirement::Digit,
+        ),
+        (
+            policy.require_symbol,
+            |c: char| !c.is_alphanumeric(),
+            PasswordRequirement::Symbol,
+        ),
+    ];
+    for (required, class, requirement) in classes {
+        if required && !password.chars().any(class) {
+            return Err(requirement);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PasswordRequirement, check, read_common_passwords};
+    use crate::aspen_config::PasswordPolicy;
+
+    #[test]
+    fn default_policy() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            check(&policy, "alice", "short"),
+            Err(PasswordRequirement::Length)
+        );
+        assert_eq!(
+            check(&policy, "alice", &"a".repeat(policy.max_length + 1)),
+            Err(PasswordRequirement::MaxLength)
+        );
+        assert_eq!(
+            check(&policy, "alice", "Password123"),
+            Err(PasswordRequirement::Common)
+        );
+        assert_eq!(
+            check(&policy, "alice", "ALICE is great"),
+            Err(PasswordRequirement::ContainsUsername)
+        );
+        assert_eq!(check(&policy, "alice", "correct horse battery"), Ok(()));
+    }
+
+    #[test]
+    fn character_classes() {
+        let policy = PasswordPolicy {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..PasswordPolicy::default()
+        };
+        for (password, result) in [
+            ("CORRECT HORSE 1", Err(PasswordRequirement::Lowercase)),
+            ("correct horse 1", Err(PasswordRequirement::Uppercase)),
+            ("Correct horse !", Err(PasswordRequirement::Digit)),
+            ("Correcthorse1", Err(PasswordRequirement::Symbol)),
+            ("Correct horse 1", Ok(())),
+        ] {
+            assert_eq!(check(&policy, "alice", password), result, "{password}");
+        }
+    }
+
+    #[test]
+    fn breached_passwords_file_added_within_length_limits() {
+        let path = std::env::temp_dir().join(format!("aspen-breached-{}", uuid::Uuid::now_v7()));
+        std::fs::write(&path, "Tr0ub4dor&3\r\nshort\nzxcvbnmasdfghjkl\n").unwrap();
+        let policy = PasswordPolicy {
+            max_length: 12,
+            breached_passwords_file: Some(path.clone()),
+            ..PasswordPolicy::default()
+        };
+        let common = read_common_passwords(&policy).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(common.contains("tr0ub4dor&3"));
+        assert!(common.contains("password123"));
+        assert!(!common.contains("short"));
+        assert!(!common.contains("zxcvbnmasdfghjkl"));
+        assert!(
+            common
+                .iter()
+                .all(|password| (8..=12).contains(&password.chars().count()))
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Lets users who forgot their password set a new one by proving they can read mail sent to the
+//! address on their account.
+//!
+//! A reset mails a single use token that expires after [`RESET_TOKEN_LIFETIME`]. Like session
+//! tokens, only [`hash_token`] of it is stored.
+
+use chrono::{Duration, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+use crate::api::GlobalServerContext;
+use crate::app::login::{hash_password, hash_token, make_token, revoke_user_sessions};
+use crate::app::password::PasswordRequirement;
+use crate::app::throttle::Subject;
+use crate::app::{password, throttle};
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{password_reset, user};
+use crate::mailer::{self, Mail};
+use crate::{app, app::UserId};
+
+const RESET_TOKEN_LIFETIME: Duration = Duration::minutes(30);
+/// Requests made sooner than this after a mail was sent don't send another, so the endpoint can't
+/// be used to flood someone's inbox.
+const RESEND_INTERVAL: Duration = Duration::minutes(1);
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestPasswordReset {
+    email: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum RequestPasswordResetResponse {
+    /// Sent whether or not an account has the address, so it can't be used to find out.
+    Ok,
+    /// The server has no mailer configured.
+    Unavailable,
+    ServerError,
+}
+
+/// Mails a reset token to the account `r.email` belongs to, if any. A new token replaces the
+/// previous one.
+pub async fn try_request(
+    state: &GlobalServerContext,
+    r: &RequestPasswordReset,
+) -> Result<RequestPasswordResetResponse, app::Error> {
+    let Some(mailer) = state.mailer.clone() else {
+        return Ok(RequestPasswordResetResponse::Unavailable);
+    };
+    let Ok(email) = mailer::normalize_address(&r.email) else {
+        return Ok(RequestPasswordResetResponse::Ok);
+    };
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let account: Option<(UserId, String)> = user::table
+        .select((user::id, user::name))
+        .filter(user::email.eq(&email))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some((user_id, name)) = account else {
+        return Ok(RequestPasswordResetResponse::Ok);
+    };
+    let now = Utc::now();
+    let recently_sent = diesel::select(diesel::dsl::exists(
+        password_reset::table.filter(
+            password_reset::user
+                .eq(user_id)
+                .and(password_reset::created.gt((now - RESEND_INTERVAL).naive_utc())),
+        ),
+    ))
+    .get_result::<bool>(conn)
+    .await?;
+    if recently_sent {
+        return Ok(RequestPasswordResetResponse::Ok);
+    }
+    diesel::delete(password_reset::table.filter(password_reset::user.eq(user_id)))
+        .execute(conn)
+        .await?;
+    let token = make_token();
+    diesel::insert_into(password_reset::table)
+        .values((
+            password_reset::token.eq(hash_token(&token).await),
+            password_reset::user.eq(user_id),
+            password_reset::created.eq(now.naive_utc()),
+            password_reset::expires.eq((now + RESET_TOKEN_LIFETIME).naive_utc()),
+        ))
+        .execute(conn)
+        .await?;
+    let mail = reset_mail(
+        email,
+        &name,
+        &token,
+        aspen_config().await.public_origin.as_deref(),
+    );
+    mailer::send_in_background(mailer, mail, "password reset");
+    Ok(RequestPasswordResetResponse::Ok)
+}
+
+/// The mail carrying `token` to `name`.
+fn reset_mail(to: String, name: &str, token: &str, public_origin: Option<&str>) -> Mail {
+    let action = mailer::token_action(public_origin, "reset_password", token);
+    Mail {
+        to,
+        subject: rust_i18n::t!("passwordResetMailSubject").into(),
+        body: rust_i18n::t!(
+            "passwordResetMailBody",
+            name = name,
+            action = action,
+            minutes = RESET_TOKEN_LIFETIME.num_minutes()
+        )
+        .into(),
+    }
+}
+
+/// The user a reset token that hasn't expired or been used belongs to.
+async fn token_user(
+    conn: &mut AsyncPgConnection,
+    token: &str,
+) -> Result<Option<UserId>, app::Error> {
+    let now = Utc::now().naive_utc();
+    Ok(password_reset::table
+        .select(password_reset::user)
+        .filter(
+            password_reset::token
+                .eq(hash_token(token).await)
+                .and(password_reset::expires.ge(now)),
+        )
+        .first(conn)
+        .await
+        .optional()?)
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyPasswordReset {
+    token: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum VerifyPasswordResetResponse {
+    Valid,
+    /// Wrong, expired or already used.
+    InvalidToken,
+    ServerError,
+}
+
+/// Checks a token without using it, so the client can tell before asking for a new password.
+pub async fn try_verify(
+    conn: &mut AsyncPgConnection,
+    v: &VerifyPasswordReset,
+) -> Result<VerifyPasswordResetResponse, app::Error> {
+    Ok(match token_user(conn, &v.token).await? {
+        Some(_) => VerifyPasswordResetResponse::Valid,
+        None => VerifyPasswordResetResponse::InvalidToken,
+    })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletePasswordReset {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum CompletePasswordResetResponse {
+    Ok,
+    InvalidToken,
+    /// The token stays valid, so the user can pick another password.
+    NewPasswordDoesntMeetRequirements {
+        cause: PasswordRequirement,
+    },
+    ServerError,
+}
+
+/// Sets the new password, using up the token. Like changing the password, this logs the user out
+/// everywhere.
+pub async fn try_complete(
+    state: &GlobalServerContext,
+    c: &CompletePasswordReset,
+) -> Result<CompletePasswordResetResponse, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let conn = conn.as_mut();
+    let Some(user_id) = token_user(conn, &c.token).await? else {
+        return Ok(CompletePasswordResetResponse::InvalidToken);
+    };
+    let username: String = user::table
+        .select(user::name)
+        .filter(user::id.eq(user_id))
+        .first(conn)
+        .await?;
+    let config = aspen_config().await;
+    if let Err(cause) = password::check(&config.password_policy, &username, &c.new_password) {
+        return Ok(CompletePasswordResetResponse::NewPasswordDoesntMeetRequirements { cause });
+    }
+    // Deleting is what claims the token, so of two requests racing with it only one gets through.
+    let claimed = diesel::delete(
+        password_reset::table.filter(password_reset::token.eq(hash_token(&c.token).await)),
+    )
+    .execute(conn)
+    .await?;
+    if claimed == 0 {
+        return Ok(CompletePasswordResetResponse::InvalidToken);
+    }
+    let new_password_hash = hash_password(&config.password_hashing, &c.new_password)?;
+    diesel::update(user::table.filter(user::id.eq(user_id)))
+        .set(user::password_hash.eq(new_password_hash))
+        .execute(conn)
+        .await?;
+    revoke_user_sessions(state, conn, user_id).await?;
+    throttle::clear(conn, Subject::Account(user_id)).await?;
+    Ok(CompletePasswordResetResponse::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reset_mail;
+
+    #[test]
+    fn mail_links_to_public_origin() {
+        let mail = reset_mail(
+            "alice@example.com".into(),
+            "alice",
+            "a+b/c=",
+            Some("https://aspen.example.com/"),
+        );
+        assert_eq!(mail.to, "alice@example.com");
+        assert!(mail.body.contains("alice"));
+        assert!(
+            mail.body
+                .contains("https://aspen.example.com/reset_password?token=a%2Bb%2Fc%3D")
+        );
+    }
+
+    #[test]
+    fn mail_holds_token_without_public_origin() {
+        let mail = reset_mail("alice@example.com".into(), "alice", "a+b/c=", None);
+        assert!(mail.body.contains("a+b/c="));
+        assert!(!mail.body.contains("reset_password"));
+    }
+}
@@ -1 +1,115 @@
+use crate::api::GlobalServerContext;
+use crate::api::message_enum::command::{ReactCreateCommand, ReactDeleteCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
+use crate::app;
+use crate::app::channel::require_read;
+use crate::app::email;
+use crate::app::message::Message;
+use crate::app::{ChannelId, CommunityId, Loadable, MessageId, UserId};
+use crate::database::schema::react;
+use diesel::{BoolExpressionMethods, ExpressionMethods, Insertable, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
 
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = react)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct React {
+    pub emoji: String,
+    pub author: UserId,
+    pub message: MessageId,
+}
+
+impl React {
+    pub fn new(command: &ReactCreateCommand, author: UserId) -> Self {
+        Self {
+            emoji: command.emoji.clone(),
+            author,
+            message: command.message_id,
+        }
+    }
+
+    pub fn created_event(&self) -> ServerEvent {
+        ServerEvent::React(sub_variant::React::Create {
+            message_id: self.message,
+            emoji: self.emoji.clone(),
+            user_id: self.author,
+        })
+    }
+
+    pub fn deleted_event(&self) -> ServerEvent {
+        ServerEvent::React(sub_variant::React::Delete {
+            message_id: self.message,
+            emoji: self.emoji.clone(),
+            user_id: self.author,
+        })
+    }
+}
+
+/// The community and channel a message was posted in, fails with [`app::Error::NotAllowed`] unless
+/// `user` may read the channel.
+async fn message_community(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+    message: MessageId,
+) -> Result<(CommunityId, ChannelId), app::Error> {
+    let message = Message::load_from_db(pg_connection, message).await?;
+    let channel = *message.channel.id();
+    let community = require_read(pg_connection, user, channel).await?;
+    Ok((community, channel))
+}
+
+pub(crate) async fn create_react(
+    state: GlobalServerContext,
+    author: UserId,
+    command: &ReactCreateCommand,
+) -> Result<React, app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let (community, channel) = message_community(conn.as_mut(), author, command.message_id).await?;
+    email::require_verified_to_post(conn.as_mut(), author).await?;
+    let react = React::new(command, author);
+    diesel::insert_into(react::table)
+        .values(react.clone())
+        .execute(conn.as_mut())
+        .await?;
+    state
+        .event_publisher
+        .publish_in_channel(community, channel, &react.created_event())
+        .await;
+    Ok(react)
+}
+
+/// Users may only remove their own reacts.
+pub(crate) async fn delete_react(
+    state: GlobalServerContext,
+    user: UserId,
+    command: &ReactDeleteCommand,
+) -> Result<(), app::Error> {
+    if command.user_id != user {
+        return Err(app::Error::NotAllowed);
+    }
+    let mut conn = state.connection_pool.get().await?;
+    let (community, channel) = message_community(conn.as_mut(), user, command.message_id).await?;
+    let react = React {
+        emoji: command.emoji.clone(),
+        author: user,
+        message: command.message_id,
+    };
+    let rows_deleted = diesel::delete(
+        react::table.filter(
+            react::emoji
+                .eq(&react.emoji)
+                .and(react::author.eq(react.author))
+                .and(react::message.eq(react.message)),
+        ),
+    )
+    .execute(conn.as_mut())
+    .await?;
+    if rows_deleted == 0 {
+        return Err(diesel::result::Error::NotFound.into());
+    }
+    state
+        .event_publisher
+        .publish_in_channel(community, channel, &react.deleted_event())
+        .await;
+    Ok(())
+}
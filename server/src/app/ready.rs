@@ -0,0 +1,167 @@
+use crate::api::ChannelType;
+use crate::app::category::{Category, sort_index_from_db};
+use crate::app::channel::{Channel, readable, unread_counts};
+use crate::app::community::Community;
+use crate::app::user::User;
+use crate::app::{CategoryId, ChannelId, CommunityId, IconId, Loadable, UserId};
+use crate::database::schema::{category, channel, community, community_user};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods, QueryDsl, SelectableHelper,
+};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Everything a client needs to render its user's communities, sent when an event stream connects.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ready {
+    pub user: ReadyUser,
+    pub communities: Vec<ReadyCommunity>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyUser {
+    pub id: UserId,
+    pub name: String,
+    pub icon: Option<IconId>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyCommunity {
+    pub id: CommunityId,
+    pub name: String,
+    pub icon: Option<IconId>,
+    /// Sorted by `sort_index`.
+    pub categories: Vec<ReadyCategory>,
+    /// Sorted by `sort_index`, regardless of category.
+    pub channels: Vec<ReadyChannel>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyCategory {
+    pub id: CategoryId,
+    pub name: String,
+    pub sort_index: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyChannel {
+    pub id: ChannelId,
+    pub parent_category: Option<CategoryId>,
+    pub name: String,
+    pub ty: ChannelType,
+    pub sort_index: u32,
+    /// Messages from other users since the channel was last marked read.
+    pub unread_count: i64,
+}
+
+/// Loads `user`'s snapshot. It is read in one repeatable read transaction so it is consistent
+/// with itself even while the communities change.
+pub async fn load_ready(
+    pg_connection: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Ready, diesel::result::Error> {
+    pg_connection
+        .build_transaction()
+        .read_only()
+        .repeatable_read()
+        .run(|conn| async move { load_ready_in(conn, user).await }.scope_boxed())
+        .await
+}
+
+async fn load_ready_in(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Ready, diesel::result::Error> {
+    let user = User::load_from_db(conn, user).await?;
+    let communities: Vec<Community> = community::table
+        .inner_join(community_user::table)
+        .filter(community_user::user.eq(user.id))
+        .select(Community::as_select())
+        .order(community::name)
+        .load(conn)
+        .await?;
+    let community_ids = communities.iter().map(|c| c.id).collect::<Vec<_>>();
+    let categories: Vec<Category> = category::table
+        .filter(category::community.eq_any(&community_ids))
+        .select(Category::as_select())
+        .order(category::sort_index)
+        .load(conn)
+        .await?;
+    // Older channels may only be attached to their community through their category.
+    let channels: Vec<(Channel, Option<CommunityId>)> = channel::table
+        .left_join(category::table)
+        .filter(
+            channel::community
+                .eq_any(&community_ids)
+                .or(category::community.eq_any(&community_ids)),
+        )
+        .select((Channel::as_select(), category::community.nullable()))
+        .order(channel::sort_index)
+        .load(conn)
+        .await?;
+    let channel_ids = channels.iter().map(|(c, _)| c.id).collect::<Vec<_>>();
+    // Channels the user can't read are listed, but without a hint of what goes on in them.
+    let readable = readable(conn, user.id, &channel_ids)
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+    let unread = unread_counts(conn, user.id, &readable).await?;
+
+    let mut by_community: HashMap<CommunityId, ReadyCommunity> = HashMap::new();
+    for community in &communities {
+        by_community.insert(
+            community.id,
+            ReadyCommunity {
+                id: community.id,
+                name: community.name.clone(),
+                icon: community.icon.as_ref().map(|icon| *icon.id()),
+                categories: Vec::new(),
+                channels: Vec::new(),
+            },
+        );
+    }
+    for category in categories {
+        if let Some(community) = by_community.get_mut(category.community.id()) {
+            community.categories.push(ReadyCategory {
+                id: category.id,
+                name: category.name,
+                sort_index: sort_index_from_db(category.sort_index),
+            });
+        }
+    }
+    for (channel, category_community) in channels {
+        let community = channel
+            .community
+            .as_ref()
+            .map(|community| *community.id())
+            .or(category_community);
+        if let Some(community) = community.and_then(|c| by_community.get_mut(&c)) {
+            community.channels.push(ReadyChannel {
+                id: channel.id,
+                parent_category: channel.parent_category.as_ref().map(|c| *c.id()),
+                name: channel.name,
+                ty: channel.ty,
+                sort_index: sort_index_from_db(channel.sort_index),
+                unread_count: unread.get(&channel.id).copied().unwrap_or_default(),
+            });
+        }
+    }
+    Ok(Ready {
+        user: ReadyUser {
+            id: user.id,
+            name: user.name,
+            icon: user.icon.as_ref().map(|icon| *icon.id()),
+        },
+        communities: communities
+            .iter()
+            .filter_map(|community| by_community.remove(&community.id))
+            .collect(),
+    })
+}
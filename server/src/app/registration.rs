@@ -0,0 +1,492 @@
+//! Who may sign up through [`create_user`](crate::app::user::create_user), as set by
+//! [`AspenConfig::registration`](crate::aspen_config::AspenConfig::registration).
+//!
+//! Admins, users with the [`ADMIN_ROLE`], hand out invite codes and approve or reject pending
+//! accounts. Accounts named in [`AspenConfig::admins`](crate::aspen_config::AspenConfig::admins)
+//! get the role at startup, see [`grant_configured_admins`].
+//!
+//! Like session tokens, only [`hash_token`] of an invite code is stored.
+
+use chrono::{DateTime, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::app::login::{hash_token, make_token};
+use crate::app::user::delete_account;
+use crate::aspen_config::{RegistrationMode, aspen_config};
+use crate::database::schema::{invite, user, user_role};
+use crate::{app, app::UserId};
+
+/// The role of users who manage registrations, given by LDAP group mapping or by hand.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Why an account couldn't be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Closed,
+    InviteRequired,
+    /// Wrong, expired or used up.
+    InvalidInvite,
+}
+
+impl Refusal {
+    /// Explains the refusal to the user, in their language.
+    pub fn describe(&self) -> String {
+        match self {
+            Refusal::Closed => rust_i18n::t!("registrationClosed").into(),
+            Refusal::InviteRequired => rust_i18n::t!("registrationInviteRequired").into(),
+            Refusal::InvalidInvite => rust_i18n::t!("registrationInvalidInvite").into(),
+        }
+    }
+}
+
+/// Whether an account signing up with `invite_code` may be created under `mode`, and if so whether
+/// it starts out approved. Uses up the invite code, so call it in the transaction creating the
+/// account.
+pub async fn admit(
+    conn: &mut AsyncPgConnection,
+    mode: RegistrationMode,
+    invite_code: Option<&str>,
+) -> Result<bool, app::Error> {
+    match mode {
+        RegistrationMode::Open => Ok(true),
+        RegistrationMode::Closed => Err(app::Error::Registration(Refusal::Closed)),
+        RegistrationMode::Approval => Ok(false),
+        RegistrationMode::Invite => {
+            let Some(code) = invite_code else {
+                return Err(app::Error::Registration(Refusal::InviteRequired));
+            };
+            let code = hash_token(code).await;
+            let redeemed = diesel::update(
+                invite::table.filter(
+                    invite::code
+                        .eq(&code)
+                        .and(invite::uses_left.gt(0))
+                        .and(invite::expires.gt(Utc::now().naive_utc())),
+                ),
+            )
+            .set(invite::uses_left.eq(invite::uses_left - 1))
+            .execute(conn)
+            .await?;
+            if redeemed == 0 {
+                return Err(app::Error::Registration(Refusal::InvalidInvite));
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Whether an account made by OpenID Connect or an authenticator starts out approved under
+/// `mode`. Those are let in by the provider or the directory rather than by invite codes, so only
+/// [`RegistrationMode::Approval`] holds them back.
+pub fn starts_approved(mode: RegistrationMode) -> bool {
+    mode != RegistrationMode::Approval
+}
+
+/// Whether a user of another server logging in for the first time gets an account under `mode`,
+/// and if so whether it starts out approved. Users of `trusted` servers get one even if
+/// registration is closed or needs an invite code, which they don't have.
+pub fn admit_federated(mode: RegistrationMode, trusted: bool) -> Result<bool, app::Error> {
+    match mode {
+        RegistrationMode::Open => Ok(true),
+        RegistrationMode::Approval => Ok(false),
+        _ if trusted => Ok(true),
+        RegistrationMode::Closed => Err(app::Error::Registration(Refusal::Closed)),
+        RegistrationMode::Invite => Err(app::Error::Registration(Refusal::InviteRequired)),
+    }
+}
+
+pub async fn is_admin(conn: &mut AsyncPgConnection, user: UserId) -> Result<bool, app::Error> {
+    Ok(diesel::select(diesel::dsl::exists(
+        user_role::table.filter(user_role::user.eq(user).and(user_role::role.eq(ADMIN_ROLE))),
+    ))
+    .get_result(conn)
+    .await?)
+}
+
+/// Approves the existing accounts named in `admins` and gives them the [`ADMIN_ROLE`], run at
+/// startup. Nobody can take one of those names later, see [`reserved_name`].
+pub async fn grant_configured_admins(
+    conn: &mut AsyncPgConnection,
+    admins: &[String],
+) -> Result<(), app::Error> {
+    conn.transaction::<_, app::Error, _>(|conn| {
+        async move {
+            let ids: Vec<UserId> = diesel::update(user::table.filter(user::name.eq_any(admins)))
+                .set(user::approved.eq(true))
+                .returning(user::id)
+                .get_results(conn)
+                .await?;
+            let roles = ids
+                .into_iter()
+                .map(|id| (user_role::user.eq(id), user_role::role.eq(ADMIN_ROLE)))
+                .collect::<Vec<_>>();
+            diesel::insert_into(user_role::table)
+                .values(roles)
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Whether `name` is one of [`AspenConfig::admins`](crate::aspen_config::AspenConfig::admins),
+/// which no new account may be given or renamed to.
+pub async fn reserved_name(name: &str) -> bool {
+    aspen_config()
+        .await
+        .admins
+        .iter()
+        .any(|admin| admin == name)
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateInvite {
+    /// How many accounts the code creates, 1 for a single use code.
+    uses: i32,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum CreateInviteResponse {
+    /// Only shown now, pass it on to the people to invite.
+    Ok {
+        code: String,
+    },
+    /// `uses` isn't positive or `expires` has passed.
+    Invalid,
+    NotAllowed,
+    ServerError,
+}
+
+/// Makes an invite code, if `admin` is one.
+pub async fn try_create_invite(
+    conn: &mut AsyncPgConnection,
+    admin: UserId,
+    c: &CreateInvite,
+) -> Result<CreateInviteResponse, app::Error> {
+    if !is_admin(conn, admin).await? {
+        return Ok(CreateInviteResponse::NotAllowed);
+    }
+    let now = Utc::now();
+    if c.uses < 1 || c.expires <= now {
+        return Ok(CreateInviteResponse::Invalid);
+    }
+    let code = make_token();
+    diesel::insert_into(invite::table)
+        .values((
+            invite::code.eq(hash_token(&code).await),
+            invite::created_by.eq(admin),
+            invite::uses_left.eq(c.uses),
+            invite::created.eq(now.naive_utc()),
+            invite::expires.eq(c.expires.naive_utc()),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(CreateInviteResponse::Ok { code })
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingUser {
+    id: UserId,
+    name: String,
+    email: Option<String>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PendingUsersResponse {
+    Ok { users: Vec<PendingUser> },
+    NotAllowed,
+    ServerError,
+}
+
+/// The accounts waiting for an admin to approve them, if `admin` is one.
+pub async fn try_list_pending(
+    conn: &mut AsyncPgConnection,
+    admin: UserId,
+) -> Result<PendingUsersResponse, app::Error> {
+    if !is_admin(conn, admin).await? {
+        return Ok(PendingUsersResponse::NotAllowed);
+    }
+    let users = user::table
+        .select((user::id, user::name, user::email))
+        .filter(user::approved.eq(false))
+        .order(user::name)
+        .load::<(UserId, String, Option<String>)>(conn)
+        .await?
+        .into_iter()
+        .map(|(id, name, email)| PendingUser { id, name, email })
+        .collect();
+    Ok(PendingUsersResponse::Ok { users })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReviewUser {
+    user: UserId,
+    /// Rejected accounts are deleted.
+    approve: bool,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum ReviewUserResponse {
+    Ok,
+    /// No pending account has the ID.
+    NotFound,
+    NotAllowed,
+    ServerError,
+}
+
+/// Approves or rejects a pending account, if `admin` is an admin.
+/// Approves or rejects a pending account, returning the refresh token families a rejected account
+/// was logged in with for the caller to revoke.
+pub async fn try_review(
+    conn: &mut AsyncPgConnection,
+    admin: UserId,
+    r: &ReviewUser,
+) -> Result<(ReviewUserResponse, Vec<Uuid>), app::Error> {
+    if !is_admin(conn, admin).await? {
+        return Ok((ReviewUserResponse::NotAllowed, Vec::new()));
+    }
+    let pending = user::table
+        .select(user::id)
+        .filter(user::id.eq(r.user).and(user::approved.eq(false)))
+        .first::<UserId>(conn)
+        .await
+        .optional()?;
+    if pending.is_none() {
+        return Ok((ReviewUserResponse::NotFound, Vec::new()));
+    }
+    if r.approve {
+        diesel::update(user::table.filter(user::id.eq(r.user)))
+            .set(user::approved.eq(true))
+            .execute(conn)
+            .await?;
+        Ok((ReviewUserResponse::Ok, Vec::new()))
+    } else {
+        // Pending accounts have no communities to tell, but may have logged in before they were
+        // held back.
+        let revoked = delete_account(conn, r.user).await?;
+        Ok((ReviewUserResponse::Ok, revoked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    use super::{
+        CreateInvite, CreateInviteResponse, PendingUsersResponse, Refusal, ReviewUser,
+        ReviewUserResponse, admit, admit_federated, grant_configured_admins, is_admin,
+        reserved_name, try_create_invite, try_list_pending, try_review,
+    };
+    use crate::app::{self, UserId};
+    use crate::aspen_config::{RegistrationMode, aspen_config, load_test_config};
+    use crate::database::test_database_url;
+
+    /// Makes `admin` an admin, `member` a plain user and `pending` unapproved users.
+    async fn users(
+        conn: &mut AsyncPgConnection,
+        admin: UserId,
+        member: UserId,
+        pending: &[(UserId, &str)],
+    ) {
+        let mut sql = format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{}', 'admin', ''), ('{}', 'member', '');
+            INSERT INTO "user_role"("user", role) VALUES ('{}', 'admin');"#,
+            admin.0, member.0, admin.0
+        );
+        for (id, name) in pending {
+            sql += &format!(
+                r#"INSERT INTO "user"(id, name, password_hash, approved) VALUES ('{}', '{name}', '', false);"#,
+                id.0
+            );
+        }
+        conn.batch_execute(&sql).await.unwrap();
+    }
+
+    fn refusal<T>(result: Result<T, app::Error>) -> Option<Refusal> {
+        match result {
+            Err(app::Error::Registration(refusal)) => Some(refusal),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn invites_used_up() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (admin, member) = (UserId::new(), UserId::new());
+        users(&mut conn, admin, member, &[]).await;
+        let invite = |uses, expires| CreateInvite { uses, expires };
+        let tomorrow = Utc::now() + Duration::days(1);
+        assert!(matches!(
+            try_create_invite(&mut conn, member, &invite(1, tomorrow))
+                .await
+                .unwrap(),
+            CreateInviteResponse::NotAllowed
+        ));
+        for invalid in [
+            invite(0, tomorrow),
+            invite(1, Utc::now() - Duration::days(1)),
+        ] {
+            assert!(matches!(
+                try_create_invite(&mut conn, admin, &invalid).await.unwrap(),
+                CreateInviteResponse::Invalid
+            ));
+        }
+        let CreateInviteResponse::Ok { code } =
+            try_create_invite(&mut conn, admin, &invite(1, tomorrow))
+                .await
+                .unwrap()
+        else {
+            panic!("admins make invites");
+        };
+        let mode = RegistrationMode::Invite;
+        assert!(admit(&mut conn, mode, Some(&code)).await.unwrap());
+        assert_eq!(
+            refusal(admit(&mut conn, mode, Some(&code)).await),
+            Some(Refusal::InvalidInvite)
+        );
+        assert_eq!(
+            refusal(admit(&mut conn, mode, None).await),
+            Some(Refusal::InviteRequired)
+        );
+        assert_eq!(
+            refusal(admit(&mut conn, RegistrationMode::Closed, Some(&code)).await),
+            Some(Refusal::Closed)
+        );
+        assert!(
+            admit(&mut conn, RegistrationMode::Open, None)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !admit(&mut conn, RegistrationMode::Approval, None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_users_approved_or_rejected() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (admin, member) = (UserId::new(), UserId::new());
+        let (carol, dave) = (UserId::new(), UserId::new());
+        users(
+            &mut conn,
+            admin,
+            member,
+            &[(carol, "carol"), (dave, "dave")],
+        )
+        .await;
+        assert!(matches!(
+            try_list_pending(&mut conn, member).await.unwrap(),
+            PendingUsersResponse::NotAllowed
+        ));
+        let pending = |users: &PendingUsersResponse| match users {
+            PendingUsersResponse::Ok { users } => {
+                users.iter().map(|user| user.name.clone()).collect()
+            }
+            _ => Vec::new(),
+        };
+        assert_eq!(
+            pending(&try_list_pending(&mut conn, admin).await.unwrap()),
+            ["carol", "dave"]
+        );
+
+        let review = |user, approve| ReviewUser { user, approve };
+        assert!(matches!(
+            try_review(&mut conn, member, &review(carol, true))
+                .await
+                .unwrap()
+                .0,
+            ReviewUserResponse::NotAllowed
+        ));
+        assert!(matches!(
+            try_review(&mut conn, admin, &review(carol, true))
+                .await
+                .unwrap()
+                .0,
+            ReviewUserResponse::Ok
+        ));
+        assert!(matches!(
+            try_review(&mut conn, admin, &review(dave, false))
+                .await
+                .unwrap()
+                .0,
+            ReviewUserResponse::Ok
+        ));
+        assert!(pending(&try_list_pending(&mut conn, admin).await.unwrap()).is_empty());
+        for (user, approve) in [(carol, false), (dave, true), (member, true)] {
+            assert!(matches!(
+                try_review(&mut conn, admin, &review(user, approve))
+                    .await
+                    .unwrap()
+                    .0,
+                ReviewUserResponse::NotFound
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn configured_admins_are_admins() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        load_test_config().await;
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (root, member) = (UserId::new(), UserId::new());
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash) VALUES ('{}', 'root', ''), ('{}', 'member', '');"#,
+            root.0, member.0
+        ))
+        .await
+        .unwrap();
+        assert!(!is_admin(&mut conn, root).await.unwrap());
+        let admins = aspen_config().await.admins;
+        grant_configured_admins(&mut conn, &admins).await.unwrap();
+        grant_configured_admins(&mut conn, &admins).await.unwrap();
+        assert!(is_admin(&mut conn, root).await.unwrap());
+        assert!(!is_admin(&mut conn, member).await.unwrap());
+        assert!(reserved_name("root").await);
+        assert!(!reserved_name("member").await);
+    }
+
+    #[test]
+    fn federated_users_admitted_by_mode_or_trust() {
+        for trusted in [false, true] {
+            assert!(admit_federated(RegistrationMode::Open, trusted).unwrap());
+            assert!(!admit_federated(RegistrationMode::Approval, trusted).unwrap());
+        }
+        assert!(admit_federated(RegistrationMode::Closed, true).unwrap());
+        assert!(admit_federated(RegistrationMode::Invite, true).unwrap());
+        assert_eq!(
+            refusal(admit_federated(RegistrationMode::Closed, false)),
+            Some(Refusal::Closed)
+        );
+        assert_eq!(
+            refusal(admit_federated(RegistrationMode::Invite, false)),
+            Some(Refusal::InviteRequired)
+        );
+    }
+}
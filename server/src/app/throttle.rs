@@ -0,0 +1,285 @@
+//! Slows down password guessing. Failed attempts are counted per account and per IP in the
+//! database, so every node sees the same counts. Past a few free failures each one doubles the wait
+//! before the next attempt, until the subject is locked out for [`LOCKOUT_DURATION`].
+//!
+//! Attempts are counted as failures before the password is checked and taken back once it turns
+//! out right. Attempts made while waiting are refused without checking the password and aren't
+//! counted.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+
+use crate::app::audit::{self, AuditEvent};
+use crate::database::schema::auth_throttle;
+use crate::{app, app::UserId};
+
+/// Failures older than this are forgotten.
+const FAILURE_WINDOW: Duration = Duration::days(1);
+const FIRST_DELAY: Duration = Duration::seconds(1);
+const LOCKOUT_DURATION: Duration = Duration::hours(1);
+
+/// Who failed attempts are counted against.
+#[derive(Clone, Copy)]
+pub enum Subject<'a> {
+    Account(UserId),
+    /// Many users can share an IP, so it's allowed more failures than an account.
+    Ip(&'a str),
+}
+
+impl Subject<'_> {
+    fn key(&self) -> String {
+        match self {
+            Subject::Account(user) => format!("account:{user}"),
+            Subject::Ip(ip) => format!("ip:{ip}"),
+        }
+    }
+
+    /// Failures that don't cause any wait.
+    fn free_failures(&self) -> i32 {
+        match self {
+            Subject::Account(_) => 3,
+            Subject::Ip(_) => 10,
+        }
+    }
+
+    /// Failures that cause a lockout.
+    fn lockout_failures(&self) -> i32 {
+        match self {
+            Subject::Account(_) => 10,
+            Subject::Ip(_) => 50,
+        }
+    }
+
+    /// How long to wait after the `failures`th failure in a row.
+    fn delay(&self, failures: i32) -> Option<Duration> {
+        if failures >= self.lockout_failures() {
+            return Some(LOCKOUT_DURATION);
+        }
+        let doublings = failures - self.free_failures() - 1;
+        if doublings < 0 {
+            return None;
+        }
+        let delay = FIRST_DELAY * 2i32.saturating_pow(doublings as u32);
+        Some(delay.min(LOCKOUT_DURATION))
+    }
+}
+
+/// Counts an attempt against each of `subjects` before it is checked, as a failure until
+/// [`succeeded`] takes it back, logging lockouts to the audit log. Counting first means attempts
+/// sent at once can't all slip in before the first failure is counted.
+///
+/// Returns the seconds until `subjects` may try again instead if any of them has to wait, without
+/// counting anything.
+pub async fn attempt(
+    conn: &mut AsyncPgConnection,
+    subjects: &[Subject<'_>],
+) -> Result<Option<u64>, app::Error> {
+    let mut keyed = subjects
+        .iter()
+        .map(|subject| (subject.key(), *subject))
+        .collect::<Vec<_>>();
+    // Locked in the same order by every attempt, so attempts sharing subjects can't deadlock.
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let keyed = &keyed;
+    let now = Utc::now();
+    let counted = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let mut previous = Vec::new();
+                for (key, _) in keyed {
+                    diesel::insert_into(auth_throttle::table)
+                        .values((
+                            auth_throttle::key.eq(key),
+                            auth_throttle::failures.eq(0),
+                            auth_throttle::last_failure.eq(now.naive_utc()),
+                        ))
+                        .on_conflict_do_nothing()
+                        .execute(conn)
+                        .await?;
+                    let row: (i32, NaiveDateTime, Option<NaiveDateTime>) = auth_throttle::table
+                        .select((
+                            auth_throttle::failures,
+                            auth_throttle::last_failure,
+                            auth_throttle::locked_until,
+                        ))
+                        .filter(auth_throttle::key.eq(key))
+                        .for_update()
+                        .first(conn)
+                        .await?;
+                    previous.push(row);
+                }
+                let waits_until = previous
+                    .iter()
+                    .filter_map(|(_, _, locked_until)| locked_until.map(|until| until.and_utc()))
+                    .filter(|until| *until > now)
+                    .max();
+                if let Some(until) = waits_until {
+                    return Ok(Err((until - now).num_seconds().max(1) as u64));
+                }
+                let mut lockouts = Vec::new();
+                for ((key, subject), (failures, last_failure, _)) in keyed.iter().zip(previous) {
+                    let failures = if last_failure.and_utc() < now - FAILURE_WINDOW {
+                        1
+                    } else {
+                        failures + 1
+                    };
+                    let locked_until = subject.delay(failures).map(|delay| now + delay);
+                    diesel::update(auth_throttle::table.filter(auth_throttle::key.eq(key)))
+                        .set((
+                            auth_throttle::failures.eq(failures),
+                            auth_throttle::last_failure.eq(now.naive_utc()),
+                            auth_throttle::locked_until
+                                .eq(locked_until.map(|until| until.naive_utc())),
+                        ))
+                        .execute(conn)
+                        .await?;
+                    if failures >= subject.lockout_failures()
+                        && let Some(until) = locked_until
+                    {
+                        lockouts.push((*subject, failures, until));
+                    }
+                }
+                Ok(Ok(lockouts))
+            }
+            .scope_boxed()
+        })
+        .await?;
+    let lockouts = match counted {
+        Ok(lockouts) => lockouts,
+        Err(retry_after) => return Ok(Some(retry_after)),
+    };
+    for (subject, failures, until) in lockouts {
+        let (user, ip) = match subject {
+            Subject::Account(user) => (Some(user), None),
+            Subject::Ip(ip) => (None, Some(ip)),
+        };
+        audit::record(
+            conn,
+            AuditEvent::Lockout {
+                user,
+                ip,
+                failures,
+                until,
+            },
+        )
+        .await?;
+    }
+    Ok(None)
+}
+
+/// Takes back the attempt [`attempt`] counted against `subjects`, which succeeded. Accounts forget
+/// their failures, IPs shared with others only the one attempt.
+pub async fn succeeded(
+    conn: &mut AsyncPgConnection,
+    subjects: &[Subject<'_>],
+) -> Result<(), app::Error> {
+    for subject in subjects {
+        match subject {
+            Subject::Account(_) => clear(conn, *subject).await?,
+            Subject::Ip(_) => uncount(conn, subject).await?,
+        }
+    }
+    Ok(())
+}
+
+async fn uncount(conn: &mut AsyncPgConnection, subject: &Subject<'_>) -> Result<(), app::Error> {
+    let key = subject.key();
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let row: Option<(i32, NaiveDateTime)> = auth_throttle::table
+                .select((auth_throttle::failures, auth_throttle::last_failure))
+                .filter(auth_throttle::key.eq(&key))
+                .for_update()
+                .first(conn)
+                .await
+                .optional()?;
+            let Some((failures, last_failure)) = row else {
+                return Ok(());
+            };
+            let failures = (failures - 1).max(0);
+            let locked_until = subject
+                .delay(failures)
+                .map(|delay| (last_failure.and_utc() + delay).naive_utc());
+            diesel::update(auth_throttle::table.filter(auth_throttle::key.eq(&key)))
+                .set((
+                    auth_throttle::failures.eq(failures),
+                    auth_throttle::locked_until.eq(locked_until),
+                ))
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(())
+}
+
+/// Forgets the failures of `subject` after it succeeded.
+pub async fn clear(conn: &mut AsyncPgConnection, subject: Subject<'_>) -> Result<(), app::Error> {
+    diesel::delete(auth_throttle::table.filter(auth_throttle::key.eq(subject.key())))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use diesel_async::{AsyncConnection, AsyncPgConnection};
+
+    use super::{LOCKOUT_DURATION, Subject, attempt, succeeded};
+    use crate::app::UserId;
+    use crate::database::test_database_url;
+
+    #[test]
+    fn delay_doubles_until_lockout() {
+        let account = Subject::Account(UserId::from(uuid::Uuid::nil()));
+        let delays = (1..=10)
+            .map(|failures| account.delay(failures).map(|d| d.num_seconds()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [
+                None,
+                None,
+                None,
+                Some(1),
+                Some(2),
+                Some(4),
+                Some(8),
+                Some(16),
+                Some(32),
+                Some(LOCKOUT_DURATION.num_seconds()),
+            ]
+        );
+        let ip = Subject::Ip("192.0.2.1");
+        assert_eq!(ip.delay(10), None);
+        assert_eq!(ip.delay(11), Some(Duration::seconds(1)));
+        assert_eq!(ip.delay(40), Some(LOCKOUT_DURATION));
+        assert_eq!(ip.delay(50), Some(LOCKOUT_DURATION));
+    }
+
+    #[tokio::test]
+    async fn attempts_counted_before_checking() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let account = Subject::Account(UserId::new());
+        let ip = Subject::Ip("192.0.2.1");
+        // Guesses sent at once, none of them checked yet. The fourth makes the next one wait.
+        for _ in 0..4 {
+            assert_eq!(attempt(&mut conn, &[account, ip]).await.unwrap(), None);
+        }
+        assert!(attempt(&mut conn, &[account, ip]).await.unwrap().is_some());
+        // Another account on the same IP only pays for its own attempts once they succeed.
+        let other = Subject::Account(UserId::new());
+        for _ in 0..20 {
+            assert_eq!(attempt(&mut conn, &[other, ip]).await.unwrap(), None);
+            succeeded(&mut conn, &[other, ip]).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! RFC 6238 time-based one-time passwords as a second login factor, and the recovery codes that
+//! stand in for them when the authenticator is lost.
+//!
+//! Enrolling is two steps, [`try_enroll`] hands out a secret and [`try_confirm`] activates it once
+//! the user proves their authenticator produces matching codes. Until then login is unaffected.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use hmac::{Hmac, Mac};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::app::user::User;
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{recovery_code, totp};
+use crate::{CHACHA_RNG, app, app::UserId};
+
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps either side of now are accepted too, for clocks that drift.
+const SKEW_STEPS: i64 = 1;
+/// 160 bits, as RFC 4226 recommends.
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Characters of a recovery code, shown as two groups of five.
+const RECOVERY_CODE_LEN: usize = 10;
+
+/// RFC 4226 HOTP value of `counter`, `digits` long.
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    binary % 10u32.pow(digits)
+}
+
+fn step(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(STEP_SECS)
+}
+
+/// The time step `code` was generated for, if it is valid around `now`.
+fn matching_step(secret: &[u8], code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let now = step(now);
+    (now - SKEW_STEPS..=now + SKEW_STEPS)
+        .find(|step| *step >= 0 && hotp(secret, *step as u64, DIGITS) == code)
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Recovery codes are random enough that a plain digest is as good as a password hash.
+fn hash_recovery_code(code: &str) -> String {
+    BASE64_STANDARD.encode(Sha256::digest(normalize_recovery_code(code).as_bytes()))
+}
+
+fn make_recovery_code() -> String {
+    let bytes = CHACHA_RNG.with(|rng| rng.borrow_mut().random::<[u8; RECOVERY_CODE_LEN]>());
+    let alphabet = BASE32_NOPAD.specification().symbols.to_ascii_lowercase();
+    let code = bytes
+        .iter()
+        .map(|b| alphabet.as_bytes()[*b as usize % alphabet.len()] as char)
+        .collect::<String>();
+    format!(
+        "{}-{}",
+        &code[..RECOVERY_CODE_LEN / 2],
+        &code[RECOVERY_CODE_LEN / 2..]
+    )
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum TotpEnrollResponse {
+    /// `secret` is base32 encoded, `otpauth_uri` carries the same for authenticator apps to scan.
+    Ok {
+        secret: String,
+        otpauth_uri: String,
+    },
+    AlreadyEnrolled,
+    ServerError,
+}
+
+/// Generates a new secret for `user`, replacing any that wasn't confirmed yet.
+pub async fn try_enroll(
+    conn: &mut AsyncPgConnection,
+    user: &User,
+) -> Result<TotpEnrollResponse, app::Error> {
+    let confirmed: Option<bool> = totp::table
+        .select(totp::confirmed)
+        .filter(totp::user.eq(user.id))
+        .first(conn)
+        .await
+        .optional()?;
+    if confirmed == Some(true) {
+        return Ok(TotpEnrollResponse::AlreadyEnrolled);
+    }
+    let secret = CHACHA_RNG.with(|rng| rng.borrow_mut().random::<[u8; SECRET_LEN]>());
+    diesel::insert_into(totp::table)
+        .values((
+            totp::user.eq(user.id),
+            totp::secret.eq(secret.as_slice()),
+            totp::confirmed.eq(false),
+        ))
+        .on_conflict(totp::user)
+        .do_update()
+        .set((
+            totp::secret.eq(secret.as_slice()),
+            totp::last_step.eq(None::<i64>),
+        ))
+        .execute(conn)
+        .await?;
+    let secret = BASE32_NOPAD.encode(&secret);
+    let issuer = aspen_config().await.totp_issuer;
+    let otpauth_uri = format!(
+        "otpauth://totp/{}:{}?secret={secret}&issuer={}&digits={DIGITS}&period={STEP_SECS}",
+        utf8_percent_encode(&issuer, NON_ALPHANUMERIC),
+        utf8_percent_encode(&user.name, NON_ALPHANUMERIC),
+        utf8_percent_encode(&issuer, NON_ALPHANUMERIC),
+    );
+    Ok(TotpEnrollResponse::Ok {
+        secret,
+        otpauth_uri,
+    })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpConfirm {
+    code: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum TotpConfirmResponse {
+    /// The only time the recovery codes are shown, each works once.
+    Ok {
+        recovery_codes: Vec<String>,
+    },
+    InvalidCode,
+    NotEnrolling,
+    ServerError,
+}
+
+/// Activates the secret from [`try_enroll`] if `c` holds a current code for it, from then on
+/// logging in as `user` takes a second factor.
+pub async fn try_confirm(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    c: &TotpConfirm,
+) -> Result<TotpConfirmResponse, app::Error> {
+    let secret: Option<Vec<u8>> = totp::table
+        .select(totp::secret)
+        .filter(totp::user.eq(user).and(totp::confirmed.eq(false)))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some(secret) = secret else {
+        return Ok(TotpConfirmResponse::NotEnrolling);
+    };
+    let Some(step) = matching_step(&secret, &c.code, Utc::now()) else {
+        return Ok(TotpConfirmResponse::InvalidCode);
+    };
+    let recovery_codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| make_recovery_code())
+        .collect::<Vec<_>>();
+    let hashes = recovery_codes
+        .iter()
+        .map(|code| {
+            (
+                recovery_code::code_hash.eq(hash_recovery_code(code)),
+                recovery_code::user.eq(user),
+            )
+        })
+        .collect::<Vec<_>>();
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            diesel::update(totp::table.filter(totp::user.eq(user)))
+                .set((totp::confirmed.eq(true), totp::last_step.eq(step)))
+                .execute(conn)
+                .await?;
+            diesel::delete(recovery_code::table.filter(recovery_code::user.eq(user)))
+                .execute(conn)
+                .await?;
+            diesel::insert_into(recovery_code::table)
+                .values(hashes)
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(TotpConfirmResponse::Ok { recovery_codes })
+}
+
+/// Whether logging in as `user` takes a second factor.
+pub async fn is_enrolled(conn: &mut AsyncPgConnection, user: UserId) -> Result<bool, app::Error> {
+    let confirmed: Option<bool> = totp::table
+        .select(totp::confirmed)
+        .filter(totp::user.eq(user))
+        .first(conn)
+        .await
+        .optional()?;
+    Ok(confirmed == Some(true))
+}
+
+/// Checks `code` as either a current TOTP code or an unused recovery code of `user`. Either is
+/// used up by a successful check, a TOTP code can't be used twice even within its time step.
+pub async fn verify_second_factor(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    code: &str,
+) -> Result<bool, app::Error> {
+    let secret: Option<Vec<u8>> = totp::table
+        .select(totp::secret)
+        .filter(totp::user.eq(user).and(totp::confirmed.eq(true)))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some(secret) = secret else {
+        return Ok(false);
+    };
+    if let Some(step) = matching_step(&secret, code, Utc::now()) {
+        let accepted = diesel::update(
+            totp::table.filter(
+                totp::user
+                    .eq(user)
+                    .and(totp::last_step.is_null().or(totp::last_step.lt(step))),
+            ),
+        )
+        .set(totp::last_step.eq(step))
+        .execute(conn)
+        .await?;
+        return Ok(accepted > 0);
+    }
+    let used = diesel::delete(
+        recovery_code::table.filter(
+            recovery_code::user
+                .eq(user)
+                .and(recovery_code::code_hash.eq(hash_recovery_code(code))),
+        ),
+    )
+    .execute(conn)
+    .await?;
+    Ok(used > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::{DIGITS, STEP_SECS, hash_recovery_code, hotp, make_recovery_code, matching_step};
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    /// The SHA-1 test vectors of RFC 6238 appendix B.
+    #[test]
+    fn rfc_6238_vectors() {
+        for (time, expected) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            assert_eq!(hotp(RFC_SECRET, (time / STEP_SECS) as u64, 8), expected);
+        }
+    }
+
+    #[test]
+    fn adjacent_steps_accepted() {
+        let now = DateTime::from_timestamp(1234567890, 0).unwrap();
+        let step = now.timestamp() / STEP_SECS;
+        for (offset, accepted) in [(-2, false), (-1, true), (0, true), (1, true), (2, false)] {
+            let code = format!(
+                "{:0width$}",
+                hotp(RFC_SECRET, (step + offset) as u64, DIGITS),
+                width = DIGITS as usize
+            );
+            assert_eq!(
+                matching_step(RFC_SECRET, &code, now),
+                accepted.then_some(step + offset),
+                "{offset}"
+            );
+        }
+        assert_eq!(matching_step(RFC_SECRET, "12345", now), None);
+    }
+
+    #[test]
+    fn recovery_code_hash_ignores_formatting() {
+        let code = make_recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&code.replace('-', " ").to_uppercase())
+        );
+    }
+}
@@ -1,14 +1,28 @@
 use crate::api::GlobalServerContext;
-use crate::api::message_enum::command::UserCreateCommand;
+use crate::api::live_sessions::LiveSessions;
+use crate::api::message_enum::command::{UserCreateCommand, UserUpdateCommand};
+use crate::api::message_enum::server_event::{ServerEvent, sub_variant};
 use crate::app;
+use crate::app::community::member_communities;
+use crate::app::email;
 use crate::app::icon::Icon;
 use crate::app::login::hash_password;
+use crate::app::password;
+use crate::app::registration;
 use crate::app::{IconId, Loadable, MaybeLoaded, UserId};
-use crate::database::schema::{self, user};
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{
+    self, channel_read, community, community_user, external_identity, federated_identity, icon,
+    invite, login_challenge, login_session, message, oidc_identity, other_server_auth_token,
+    password_reset, react, recovery_code, refresh_token, session, totp, user, user_role,
+    webauthn_challenge, webauthn_credential,
+};
+use crate::mailer;
 use diesel::result::Error;
 use diesel::{ExpressionMethods, Queryable, Selectable};
 use diesel::{QueryResult, prelude::*};
-use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 
 #[derive(Debug, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = user)]
@@ -18,6 +32,11 @@ pub struct User {
     pub name: String,
     pub icon: Option<MaybeLoaded<Icon>>,
     pub password_hash: String,
+    pub email: Option<String>,
+    /// Whether the user opened a link mailed to `email`, see [`app::email`].
+    pub email_verified: bool,
+    /// False while the account waits for an admin, see [`app::registration`].
+    pub approved: bool,
 }
 
 impl Loadable for User {
@@ -49,28 +68,253 @@ pub async fn create_user(
             return Err(e.into());
         }
     };
-    let password_hash_result = hash_password(&command.password);
+    if registration::reserved_name(&command.name).await {
+        return Err(app::Error::NameReserved);
+    }
+    let config = aspen_config().await;
+    password::check(&config.password_policy, &command.name, &command.password)
+        .map_err(app::Error::PasswordRequirement)?;
+    let password_hash_result = hash_password(&config.password_hashing, &command.password);
     let password_hash = match password_hash_result {
         Ok(s) => s,
         Err(e) => {
             return Err(e.into());
         }
     };
-    let new_user_id = UserId::new();
-    diesel::insert_into(user::table)
-        .values(User {
-            id: new_user_id,
-            name: command.name.clone(),
-            icon: command.icon.map(MaybeLoaded::NotLoaded),
-            password_hash,
-        })
-        .execute(conn.as_mut())
-        .await?;
+    let email = command
+        .email
+        .as_deref()
+        .map(mailer::normalize_address)
+        .transpose()?;
+    let new_user = User {
+        id: UserId::new(),
+        name: command.name.clone(),
+        icon: command.icon.map(MaybeLoaded::NotLoaded),
+        password_hash,
+        email: email.clone(),
+        email_verified: false,
+        approved: false,
+    };
+    let new_user_id = new_user.id;
+    let invite_code = command.invite_code.as_deref();
+    conn.transaction::<_, app::Error, _>(|conn| {
+        async move {
+            let approved = registration::admit(conn, config.registration, invite_code).await?;
+            diesel::insert_into(user::table)
+                .values(User {
+                    approved,
+                    ..new_user
+                })
+                .execute(conn)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    if let Some(email) = email {
+        email::confirm_new_account(&state, new_user_id, &command.name, &email).await;
+    }
     Ok(new_user_id)
 }
 
+/// `base`, or if another user has that name or it's [reserved](registration::reserved_name),
+/// `base` followed by the lowest number that's free.
+pub async fn free_name(conn: &mut AsyncPgConnection, base: &str) -> Result<String, app::Error> {
+    let mut name = base.to_string();
+    let mut suffix = 1;
+    while registration::reserved_name(&name).await
+        || diesel::select(diesel::dsl::exists(
+            user::table.filter(user::name.eq(&name)),
+        ))
+        .get_result::<bool>(conn)
+        .await?
+    {
+        suffix += 1;
+        name = format!("{base}{suffix}");
+    }
+    Ok(name)
+}
+
 pub async fn read_user(state: GlobalServerContext, id: UserId) -> Result<User, app::Error> {
     let mut conn = state.connection_pool.get().await?;
     let user = User::load_from_db(conn.as_mut(), id).await?;
     Ok(user)
 }
+
+/// Users may only update themselves, and not to a [reserved](registration::reserved_name) name
+/// they don't have already. Everyone sharing a community with the user is told.
+pub async fn update_user(
+    state: GlobalServerContext,
+    session_user: UserId,
+    command: &UserUpdateCommand,
+) -> Result<(), app::Error> {
+    if command.id != session_user {
+        return Err(app::Error::NotAllowed);
+    }
+    let mut conn = state.connection_pool.get().await?;
+    if registration::reserved_name(&command.name).await
+        && User::load_from_db(conn.as_mut(), command.id).await?.name != command.name
+    {
+        return Err(app::Error::NotAllowed);
+    }
+    diesel::update(user::table.filter(user::id.eq(command.id)))
+        .set((user::name.eq(&command.name), user::icon.eq(command.icon)))
+        .execute(conn.as_mut())
+        .await?;
+    let event = ServerEvent::User(sub_variant::User::Update {
+        id: command.id,
+        name: command.name.clone(),
+        icon: command.icon,
+    });
+    for community in member_communities(conn.as_mut(), command.id).await? {
+        state.event_publisher.publish(community, &event).await;
+    }
+    Ok(())
+}
+
+/// Tells everyone sharing a community with user `id` that their roles changed, as an update of the
+/// user. The user's event streams re-check which channels they may read when they see it.
+pub async fn publish_role_change(
+    state: &GlobalServerContext,
+    conn: &mut AsyncPgConnection,
+    id: UserId,
+) -> Result<(), app::Error> {
+    let user = User::load_from_db(conn, id).await?;
+    let event = ServerEvent::User(sub_variant::User::Update {
+        id,
+        name: user.name,
+        icon: user.icon.as_ref().map(|icon| *icon.id()),
+    });
+    for community in member_communities(conn, id).await? {
+        state.event_publisher.publish(community, &event).await;
+    }
+    Ok(())
+}
+
+/// Users may only delete themselves. Their messages, reacts, memberships and logins go with them.
+pub async fn delete_user(
+    state: GlobalServerContext,
+    session_user: UserId,
+    id: UserId,
+) -> Result<(), app::Error> {
+    if id != session_user {
+        return Err(app::Error::NotAllowed);
+    }
+    remove_user(&state, id).await
+}
+
+/// Deletes the user `id` along with everything of theirs, logging them out everywhere.
+pub async fn remove_user(state: &GlobalServerContext, id: UserId) -> Result<(), app::Error> {
+    let mut conn = state.connection_pool.get().await?;
+    let communities = member_communities(conn.as_mut(), id).await?;
+    let revoked = delete_account(conn.as_mut(), id).await?;
+    LiveSessions::revoke(&state.nats_connection_manager, &revoked).await;
+    let event = ServerEvent::User(sub_variant::User::Delete { id });
+    for community in communities {
+        state.event_publisher.publish(community, &event).await;
+    }
+    Ok(())
+}
+
+/// Deletes the user `id` and everything of theirs from the database, returning the families of
+/// the refresh tokens they were logged in with. Nobody is told, see [`remove_user`].
+pub(crate) async fn delete_account(
+    conn: &mut AsyncPgConnection,
+    id: UserId,
+) -> Result<Vec<uuid::Uuid>, app::Error> {
+    let mut revoked = conn
+        .transaction::<_, Error, _>(|conn| {
+            async move {
+                let messages = message::table
+                    .select(message::id)
+                    .filter(message::author.eq(id));
+                diesel::delete(
+                    react::table.filter(react::author.eq(id).or(react::message.eq_any(messages))),
+                )
+                .execute(conn)
+                .await?;
+                diesel::delete(message::table.filter(message::author.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(community_user::table.filter(community_user::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(channel_read::table.filter(channel_read::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                let refresh_tokens = refresh_token::table
+                    .select(refresh_token::token)
+                    .filter(refresh_token::user.eq(id));
+                diesel::delete(
+                    session::table.filter(session::refresh_token.eq_any(refresh_tokens)),
+                )
+                .execute(conn)
+                .await?;
+                let revoked: Vec<uuid::Uuid> =
+                    diesel::delete(refresh_token::table.filter(refresh_token::user.eq(id)))
+                        .returning(refresh_token::family)
+                        .get_results(conn)
+                        .await?;
+                diesel::delete(
+                    other_server_auth_token::table.filter(other_server_auth_token::user.eq(id)),
+                )
+                .execute(conn)
+                .await?;
+                diesel::delete(login_session::table.filter(login_session::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(password_reset::table.filter(password_reset::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(oidc_identity::table.filter(oidc_identity::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(federated_identity::table.filter(federated_identity::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(external_identity::table.filter(external_identity::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(login_challenge::table.filter(login_challenge::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(recovery_code::table.filter(recovery_code::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(totp::table.filter(totp::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(webauthn_challenge::table.filter(webauthn_challenge::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(webauthn_credential::table.filter(webauthn_credential::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(user_role::table.filter(user_role::user.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(invite::table.filter(invite::created_by.eq(id)))
+                    .execute(conn)
+                    .await?;
+                diesel::update(community::table.filter(community::owner.eq(id)))
+                    .set(community::owner.eq(None::<UserId>))
+                    .execute(conn)
+                    .await?;
+                diesel::update(icon::table.filter(icon::owner.eq(id)))
+                    .set(icon::owner.eq(None::<UserId>))
+                    .execute(conn)
+                    .await?;
+                diesel::delete(user::table.filter(user::id.eq(id)))
+                    .execute(conn)
+                    .await?;
+                Ok(revoked)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    // Every rotation of a refresh token leaves a row of the same family.
+    revoked.sort_unstable();
+    revoked.dedup();
+    Ok(revoked)
+}
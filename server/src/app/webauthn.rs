@@ -0,0 +1,953 @@
+//! WebAuthn credentials, used as passkeys to log in without a password or as a second factor
+//! after one.
+//!
+//! Registration asks for no attestation, so the attestation statement is not checked and any
+//! authenticator is accepted. ES256 and EdDSA keys are supported, which covers every common
+//! authenticator. Binary values travel base64url encoded, as in the browser's `toJSON()` forms of
+//! the WebAuthn types.
+
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_ASN1, ED25519, UnparsedPublicKey};
+use base64::Engine;
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use ciborium::Value;
+use diesel::{BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::app::user::User;
+use crate::aspen_config::aspen_config;
+use crate::database::schema::{webauthn_challenge, webauthn_credential};
+use crate::{CHACHA_RNG, app, app::UserId};
+
+const CHALLENGE_LIFETIME: Duration = Duration::minutes(5);
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// base64url, padded or not.
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Where credentials are valid, from the configured `public_origin`.
+struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    async fn from_config() -> Result<Self, app::Error> {
+        let origin = aspen_config()
+            .await
+            .public_origin
+            .ok_or(app::Error::PublicOriginNotConfigured)?;
+        Self::from_origin(origin).ok_or(app::Error::PublicOriginNotConfigured)
+    }
+
+    fn from_origin(origin: String) -> Option<Self> {
+        let origin = origin.trim_end_matches('/').to_string();
+        let (_, authority) = origin.split_once("://")?;
+        let id = authority.split(':').next()?.to_string();
+        (!id.is_empty()).then_some(Self { id, origin })
+    }
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    id: String,
+}
+
+impl CredentialDescriptor {
+    fn new(id: &[u8]) -> Self {
+        Self {
+            ty: "public-key",
+            id: BASE64_URL.encode(id),
+        }
+    }
+}
+
+/// `PublicKeyCredentialCreationOptionsJSON`, for `navigator.credentials.create()`.
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    rp: CreationRelyingParty,
+    user: CreationUser,
+    challenge: String,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    timeout: i64,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+    attestation: &'static str,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct CreationRelyingParty {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationUser {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    alg: i64,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    resident_key: &'static str,
+    user_verification: &'static str,
+}
+
+/// `PublicKeyCredentialRequestOptionsJSON`, for `navigator.credentials.get()`.
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    challenge: String,
+    timeout: i64,
+    rp_id: String,
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+}
+
+/// The `toJSON()` of the credential `navigator.credentials.create()` resolves to.
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// The `toJSON()` of the credential `navigator.credentials.get()` resolves to.
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ty: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Only present when registering.
+    attested: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Parses authenticator data, see the WebAuthn spec §6.1.
+fn parse_authenticator_data(data: &[u8]) -> Option<AuthenticatorData<'_>> {
+    let rp_id_hash = data.get(..32)?;
+    let flags = *data.get(32)?;
+    let sign_count = u32::from_be_bytes(data.get(33..37)?.try_into().ok()?);
+    let attested = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // Skips the 16 byte AAGUID.
+        let id_len = u16::from_be_bytes(data.get(53..55)?.try_into().ok()?) as usize;
+        let id = data.get(55..55 + id_len)?.to_vec();
+        let mut rest = data.get(55 + id_len..)?;
+        let before = rest.len();
+        // Extensions may follow the key, so its length is only known by parsing it.
+        let _: Value = ciborium::from_reader(&mut rest).ok()?;
+        let key = data[55 + id_len..55 + id_len + before - rest.len()].to_vec();
+        Some((id, key))
+    } else {
+        None
+    };
+    Some(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        sign_count,
+        attested,
+    })
+}
+
+enum PublicKey {
+    /// Uncompressed SEC1 point.
+    Es256(Vec<u8>),
+    EdDsa(Vec<u8>),
+}
+
+impl PublicKey {
+    /// Reads a COSE key, returning `None` for anything besides ES256 and EdDSA.
+    fn from_cose(cose: &[u8]) -> Option<Self> {
+        let Value::Map(entries) = ciborium::from_reader::<Value, _>(cose).ok()? else {
+            return None;
+        };
+        let get = |label: i64| {
+            entries
+                .iter()
+                .find_map(|(key, value)| (key.as_integer() == Some(label.into())).then_some(value))
+        };
+        let alg = i64::try_from(get(3)?.as_integer()?).ok()?;
+        match alg {
+            COSE_ALG_ES256 => {
+                let x = get(-2)?.as_bytes()?;
+                let y = get(-3)?.as_bytes()?;
+                if x.len() != 32 || y.len() != 32 {
+                    return None;
+                }
+                Some(PublicKey::Es256([&[0x04][..], x, y].concat()))
+            }
+            COSE_ALG_EDDSA => {
+                let x = get(-2)?.as_bytes()?;
+                (x.len() == 32).then(|| PublicKey::EdDsa(x.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Es256(key) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key)
+                .verify(message, signature)
+                .is_ok(),
+            PublicKey::EdDsa(key) => UnparsedPublicKey::new(&ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// Checks the client data of a ceremony of type `ty`, returning the challenge it answers.
+fn check_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    ty: &str,
+) -> Result<String, &'static str> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| "malformed client data")?;
+    if client_data.ty != ty {
+        return Err("wrong ceremony type");
+    }
+    if client_data.origin != rp.origin {
+        return Err("wrong origin");
+    }
+    Ok(client_data.challenge)
+}
+
+fn check_authenticator_data<'a>(
+    rp: &RelyingParty,
+    data: &'a [u8],
+    require_user_verified: bool,
+) -> Result<AuthenticatorData<'a>, &'static str> {
+    let data = parse_authenticator_data(data).ok_or("malformed authenticator data")?;
+    if data.rp_id_hash != Sha256::digest(rp.id.as_bytes()).as_slice() {
+        return Err("wrong relying party");
+    }
+    if data.flags & FLAG_USER_PRESENT == 0 {
+        return Err("user not present");
+    }
+    if require_user_verified && data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err("user not verified");
+    }
+    Ok(data)
+}
+
+struct Registration {
+    challenge: String,
+    id: Vec<u8>,
+    public_key: Vec<u8>,
+    sign_count: u32,
+}
+
+fn check_registration(
+    rp: &RelyingParty,
+    credential: &RegistrationCredential,
+) -> Result<Registration, &'static str> {
+    let client_data_json = BASE64_URL
+        .decode(&credential.response.client_data_json)
+        .map_err(|_| "malformed client data")?;
+    let challenge = check_client_data(rp, &client_data_json, "webauthn.create")?;
+    let attestation_object = BASE64_URL
+        .decode(&credential.response.attestation_object)
+        .map_err(|_| "malformed attestation object")?;
+    let Ok(Value::Map(attestation)) = ciborium::from_reader::<Value, _>(&attestation_object[..])
+    else {
+        return Err("malformed attestation object");
+    };
+    let auth_data = attestation
+        .iter()
+        .find_map(|(key, value)| (key.as_text() == Some("authData")).then_some(value))
+        .and_then(Value::as_bytes)
+        .ok_or("attestation object without authenticator data")?;
+    let data = check_authenticator_data(rp, auth_data, false)?;
+    let (id, public_key) = data.attested.ok_or("no attested credential")?;
+    if BASE64_URL.decode(&credential.id).ok().as_ref() != Some(&id) {
+        return Err("credential id differs from the attested one");
+    }
+    if PublicKey::from_cose(&public_key).is_none() {
+        return Err("unsupported public key");
+    }
+    Ok(Registration {
+        challenge,
+        id,
+        public_key,
+        sign_count: data.sign_count,
+    })
+}
+
+struct Assertion {
+    challenge: String,
+    sign_count: u32,
+}
+
+fn check_assertion(
+    rp: &RelyingParty,
+    credential: &AssertionCredential,
+    public_key: &[u8],
+    require_user_verified: bool,
+) -> Result<Assertion, &'static str> {
+    let client_data_json = BASE64_URL
+        .decode(&credential.response.client_data_json)
+        .map_err(|_| "malformed client data")?;
+    let challenge = check_client_data(rp, &client_data_json, "webauthn.get")?;
+    let auth_data = BASE64_URL
+        .decode(&credential.response.authenticator_data)
+        .map_err(|_| "malformed authenticator data")?;
+    let data = check_authenticator_data(rp, &auth_data, require_user_verified)?;
+    let signature = BASE64_URL
+        .decode(&credential.response.signature)
+        .map_err(|_| "malformed signature")?;
+    let public_key = PublicKey::from_cose(public_key).ok_or("unsupported public key")?;
+    let signed = [&auth_data[..], &Sha256::digest(&client_data_json)].concat();
+    if !public_key.verify(&signed, &signature) {
+        return Err("bad signature");
+    }
+    Ok(Assertion {
+        challenge,
+        sign_count: data.sign_count,
+    })
+}
+
+async fn issue_challenge(
+    conn: &mut AsyncPgConnection,
+    user: Option<UserId>,
+    registration: bool,
+) -> Result<String, app::Error> {
+    let challenge = BASE64_URL.encode(CHACHA_RNG.with(|rng| rng.borrow_mut().random::<[u8; 32]>()));
+    diesel::insert_into(webauthn_challenge::table)
+        .values((
+            webauthn_challenge::challenge.eq(&challenge),
+            webauthn_challenge::user.eq(user),
+            webauthn_challenge::registration.eq(registration),
+            webauthn_challenge::expires.eq((Utc::now() + CHALLENGE_LIFETIME).naive_utc()),
+        ))
+        .execute(conn)
+        .await?;
+    Ok(challenge)
+}
+
+/// Uses up `challenge` if it was issued for this ceremony and hasn't expired.
+async fn consume_challenge(
+    conn: &mut AsyncPgConnection,
+    challenge: &str,
+    user: Option<UserId>,
+    registration: bool,
+) -> Result<bool, app::Error> {
+    let now = Utc::now().naive_utc();
+    let matching = webauthn_challenge::challenge
+        .eq(challenge)
+        .and(webauthn_challenge::registration.eq(registration))
+        .and(webauthn_challenge::expires.ge(now));
+    let consumed = match user {
+        Some(user) => {
+            diesel::delete(
+                webauthn_challenge::table.filter(matching.and(webauthn_challenge::user.eq(user))),
+            )
+            .execute(conn)
+            .await?
+        }
+        None => {
+            diesel::delete(
+                webauthn_challenge::table.filter(matching.and(webauthn_challenge::user.is_null())),
+            )
+            .execute(conn)
+            .await?
+        }
+    };
+    Ok(consumed > 0)
+}
+
+async fn credential_ids(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<Vec<Vec<u8>>, app::Error> {
+    Ok(webauthn_credential::table
+        .select(webauthn_credential::id)
+        .filter(webauthn_credential::user.eq(user))
+        .load(conn)
+        .await?)
+}
+
+/// Whether `user` registered any credential, which makes logging in with their password take a
+/// second factor.
+pub async fn has_credentials(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<bool, app::Error> {
+    Ok(!credential_ids(conn, user).await?.is_empty())
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PasskeyRegisterOptionsResponse {
+    Ok { options: Box<CreationOptions> },
+    ServerError,
+}
+
+pub async fn try_register_options(
+    conn: &mut AsyncPgConnection,
+    user: &User,
+) -> Result<PasskeyRegisterOptionsResponse, app::Error> {
+    let rp = RelyingParty::from_config().await?;
+    let challenge = issue_challenge(conn, Some(user.id), true).await?;
+    let exclude_credentials = credential_ids(conn, user.id)
+        .await?
+        .iter()
+        .map(|id| CredentialDescriptor::new(id))
+        .collect();
+    Ok(PasskeyRegisterOptionsResponse::Ok {
+        options: Box::new(CreationOptions {
+            rp: CreationRelyingParty {
+                name: rp.id.clone(),
+                id: rp.id,
+            },
+            user: CreationUser {
+                id: BASE64_URL.encode(user.id.0.as_bytes()),
+                name: user.name.clone(),
+                display_name: user.name.clone(),
+            },
+            challenge,
+            pub_key_cred_params: [COSE_ALG_ES256, COSE_ALG_EDDSA]
+                .into_iter()
+                .map(|alg| CredentialParameters {
+                    ty: "public-key",
+                    alg,
+                })
+                .collect(),
+            timeout: CHALLENGE_LIFETIME.num_milliseconds(),
+            exclude_credentials,
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred",
+                user_verification: "preferred",
+            },
+            attestation: "none",
+        }),
+    })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyRegister {
+    /// Lets the user tell their passkeys apart.
+    name: String,
+    credential: RegistrationCredential,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PasskeyRegisterResponse {
+    Ok { credential_id: String },
+    InvalidCredential,
+    ServerError,
+}
+
+pub async fn try_register(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    r: &PasskeyRegister,
+) -> Result<PasskeyRegisterResponse, app::Error> {
+    let rp = RelyingParty::from_config().await?;
+    let registration = match check_registration(&rp, &r.credential) {
+        Ok(registration) => registration,
+        Err(reason) => {
+            info!("rejected passkey registration for {user}, {reason}");
+            return Ok(PasskeyRegisterResponse::InvalidCredential);
+        }
+    };
+    if !consume_challenge(conn, &registration.challenge, Some(user), true).await? {
+        return Ok(PasskeyRegisterResponse::InvalidCredential);
+    }
+    let inserted = diesel::insert_into(webauthn_credential::table)
+        .values((
+            webauthn_credential::id.eq(&registration.id),
+            webauthn_credential::user.eq(user),
+            webauthn_credential::public_key.eq(&registration.public_key),
+            webauthn_credential::sign_count.eq(i64::from(registration.sign_count)),
+            webauthn_credential::name.eq(&r.name),
+            webauthn_credential::created.eq(Utc::now().naive_utc()),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+    if inserted == 0 {
+        return Ok(PasskeyRegisterResponse::InvalidCredential);
+    }
+    Ok(PasskeyRegisterResponse::Ok {
+        credential_id: BASE64_URL.encode(&registration.id),
+    })
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyInfo {
+    /// base64url, as in [`PasskeyRegisterResponse::Ok`].
+    id: String,
+    name: String,
+    created: DateTime<Utc>,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PasskeysResponse {
+    Ok { passkeys: Vec<PasskeyInfo> },
+    ServerError,
+}
+
+/// The passkeys `user` registered, oldest first.
+pub async fn try_list(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+) -> Result<PasskeysResponse, app::Error> {
+    let passkeys = webauthn_credential::table
+        .select((
+            webauthn_credential::id,
+            webauthn_credential::name,
+            webauthn_credential::created,
+        ))
+        .filter(webauthn_credential::user.eq(user))
+        .order(webauthn_credential::created)
+        .load::<(Vec<u8>, String, NaiveDateTime)>(conn)
+        .await?
+        .into_iter()
+        .map(|(id, name, created)| PasskeyInfo {
+            id: BASE64_URL.encode(id),
+            name,
+            created: created.and_utc(),
+        })
+        .collect();
+    Ok(PasskeysResponse::Ok { passkeys })
+}
+
+#[derive(Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletePasskey {
+    id: String,
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum DeletePasskeyResponse {
+    Ok,
+    /// `user` has no passkey with the ID.
+    NotFound,
+    ServerError,
+}
+
+/// Deletes one of `user`'s passkeys, for example of a lost authenticator.
+pub async fn try_delete(
+    conn: &mut AsyncPgConnection,
+    user: UserId,
+    d: &DeletePasskey,
+) -> Result<DeletePasskeyResponse, app::Error> {
+    let Ok(id) = BASE64_URL.decode(&d.id) else {
+        return Ok(DeletePasskeyResponse::NotFound);
+    };
+    let deleted = diesel::delete(
+        webauthn_credential::table.filter(
+            webauthn_credential::id
+                .eq(&id)
+                .and(webauthn_credential::user.eq(user)),
+        ),
+    )
+    .execute(conn)
+    .await?;
+    Ok(if deleted == 0 {
+        DeletePasskeyResponse::NotFound
+    } else {
+        DeletePasskeyResponse::Ok
+    })
+}
+
+#[derive(Serialize, utoipa::ToSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum PasskeyRequestOptionsResponse {
+    Ok {
+        options: RequestOptions,
+    },
+    /// The login challenge being answered is unknown or expired.
+    InvalidChallenge,
+    ServerError,
+}
+
+/// Options for asserting a credential of `user`, or any discoverable credential when logging in
+/// without a user.
+pub async fn request_options(
+    conn: &mut AsyncPgConnection,
+    user: Option<UserId>,
+) -> Result<RequestOptions, app::Error> {
+    let rp = RelyingParty::from_config().await?;
+    let challenge = issue_challenge(conn, user, false).await?;
+    let allow_credentials = match user {
+        Some(user) => credential_ids(conn, user)
+            .await?
+            .iter()
+            .map(|id| CredentialDescriptor::new(id))
+            .collect(),
+        None => Vec::new(),
+    };
+    Ok(RequestOptions {
+        challenge,
+        timeout: CHALLENGE_LIFETIME.num_milliseconds(),
+        rp_id: rp.id,
+        allow_credentials,
+        user_verification: if user.is_some() {
+            "discouraged"
+        } else {
+            "required"
+        },
+    })
+}
+
+/// Checks `credential` against a challenge from [`request_options`] for the same `user`, returning
+/// whose credential it is. A passkey standing in for a password must have verified its user.
+pub async fn verify_assertion(
+    conn: &mut AsyncPgConnection,
+    credential: &AssertionCredential,
+    user: Option<UserId>,
+) -> Result<Option<UserId>, app::Error> {
+    let rp = RelyingParty::from_config().await?;
+    let Ok(id) = BASE64_URL.decode(&credential.id) else {
+        return Ok(None);
+    };
+    let stored: Option<(UserId, Vec<u8>)> = webauthn_credential::table
+        .select((webauthn_credential::user, webauthn_credential::public_key))
+        .filter(webauthn_credential::id.eq(&id))
+        .first(conn)
+        .await
+        .optional()?;
+    let Some((owner, public_key)) = stored else {
+        return Ok(None);
+    };
+    if user.is_some_and(|user| user != owner) {
+        return Ok(None);
+    }
+    let assertion = match check_assertion(&rp, credential, &public_key, user.is_none()) {
+        Ok(assertion) => assertion,
+        Err(reason) => {
+            info!("rejected passkey assertion for {owner}, {reason}");
+            return Ok(None);
+        }
+    };
+    if !consume_challenge(conn, &assertion.challenge, user, false).await? {
+        return Ok(None);
+    }
+    // Authenticators that count signatures must only ever count up, otherwise it has been cloned.
+    // Checked in the update, so two assertions with the same count can't both pass.
+    let sign_count = i64::from(assertion.sign_count);
+    let this_credential = webauthn_credential::id.eq(&id);
+    let counted = if sign_count == 0 {
+        diesel::update(
+            webauthn_credential::table
+                .filter(this_credential.and(webauthn_credential::sign_count.eq(0))),
+        )
+        .set(webauthn_credential::sign_count.eq(sign_count))
+        .execute(conn)
+        .await?
+    } else {
+        diesel::update(
+            webauthn_credential::table
+                .filter(this_credential.and(webauthn_credential::sign_count.lt(sign_count))),
+        )
+        .set(webauthn_credential::sign_count.eq(sign_count))
+        .execute(conn)
+        .await?
+    };
+    if counted == 0 {
+        info!("rejected passkey assertion for {owner}, signature counter went backwards");
+        return Ok(None);
+    }
+    Ok(Some(owner))
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{
+        ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, Ed25519KeyPair, KeyPair,
+    };
+    use base64::Engine;
+    use ciborium::Value;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    use super::{
+        AssertionCredential, AssertionResponse, AttestationResponse, BASE64_URL, COSE_ALG_EDDSA,
+        COSE_ALG_ES256, DeletePasskey, DeletePasskeyResponse, PasskeysResponse,
+        RegistrationCredential, RelyingParty, check_assertion, check_registration, try_delete,
+        try_list,
+    };
+    use crate::app::UserId;
+    use crate::database::test_database_url;
+
+    const ORIGIN: &str = "https://aspen.example.com";
+
+    enum Key {
+        Es256(EcdsaKeyPair),
+        EdDsa(Ed25519KeyPair),
+    }
+
+    /// Just enough of an authenticator to produce credentials offline.
+    struct SoftwareAuthenticator {
+        id: Vec<u8>,
+        key: Key,
+        sign_count: u32,
+    }
+
+    impl SoftwareAuthenticator {
+        fn new(key: Key) -> Self {
+            Self {
+                id: b"software credential".to_vec(),
+                key,
+                sign_count: 0,
+            }
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let entries = match &self.key {
+                Key::Es256(key) => {
+                    let point = key.public_key().as_ref();
+                    vec![
+                        (1.into(), 2.into()),
+                        (3.into(), COSE_ALG_ES256.into()),
+                        ((-1).into(), 1.into()),
+                        ((-2).into(), Value::Bytes(point[1..33].to_vec())),
+                        ((-3).into(), Value::Bytes(point[33..].to_vec())),
+                    ]
+                }
+                Key::EdDsa(key) => vec![
+                    (1.into(), 1.into()),
+                    (3.into(), COSE_ALG_EDDSA.into()),
+                    ((-1).into(), 6.into()),
+                    (
+                        (-2).into(),
+                        Value::Bytes(key.public_key().as_ref().to_vec()),
+                    ),
+                ],
+            };
+            let mut out = Vec::new();
+            ciborium::into_writer(&Value::Map(entries), &mut out).unwrap();
+            out
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, attested: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags | if attested { 0x40 } else { 0 });
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.id);
+                data.extend_from_slice(&self.cose_key());
+            }
+            data
+        }
+
+        fn client_data(ty: &str, challenge: &str) -> Vec<u8> {
+            serde_json::to_vec(&json!({ "type": ty, "challenge": challenge, "origin": ORIGIN }))
+                .unwrap()
+        }
+
+        fn create(&self, rp_id: &str, challenge: &str) -> RegistrationCredential {
+            let attestation = Value::Map(vec![
+                ("fmt".into(), "none".into()),
+                ("attStmt".into(), Value::Map(Vec::new())),
+                (
+                    "authData".into(),
+                    Value::Bytes(self.authenticator_data(rp_id, 0x05, true)),
+                ),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+            RegistrationCredential {
+                id: BASE64_URL.encode(&self.id),
+                response: AttestationResponse {
+                    client_data_json: BASE64_URL
+                        .encode(Self::client_data("webauthn.create", challenge)),
+                    attestation_object: BASE64_URL.encode(attestation_object),
+                },
+            }
+        }
+
+        fn get(&mut self, rp_id: &str, challenge: &str, flags: u8) -> AssertionCredential {
+            self.sign_count += 1;
+            let authenticator_data = self.authenticator_data(rp_id, flags, false);
+            let client_data = Self::client_data("webauthn.get", challenge);
+            let signed = [&authenticator_data[..], &Sha256::digest(&client_data)].concat();
+            let signature = match &self.key {
+                Key::Es256(key) => key
+                    .sign(&SystemRandom::new(), &signed)
+                    .unwrap()
+                    .as_ref()
+                    .to_vec(),
+                Key::EdDsa(key) => key.sign(&signed).as_ref().to_vec(),
+            };
+            AssertionCredential {
+                id: BASE64_URL.encode(&self.id),
+                response: AssertionResponse {
+                    client_data_json: BASE64_URL.encode(client_data),
+                    authenticator_data: BASE64_URL.encode(authenticator_data),
+                    signature: BASE64_URL.encode(signature),
+                },
+            }
+        }
+    }
+
+    fn keys() -> [Key; 2] {
+        [
+            Key::Es256(EcdsaKeyPair::generate(&ECDSA_P256_SHA256_ASN1_SIGNING).unwrap()),
+            Key::EdDsa(Ed25519KeyPair::generate().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn register_then_assert() {
+        let rp = RelyingParty::from_origin(ORIGIN.to_string()).unwrap();
+        assert_eq!(rp.id, "aspen.example.com");
+        for key in keys() {
+            let mut authenticator = SoftwareAuthenticator::new(key);
+            let registration =
+                check_registration(&rp, &authenticator.create(&rp.id, "register")).unwrap();
+            assert_eq!(registration.challenge, "register");
+            assert_eq!(registration.id, authenticator.id);
+
+            let assertion = authenticator.get(&rp.id, "login", 0x05);
+            let asserted =
+                check_assertion(&rp, &assertion, &registration.public_key, true).unwrap();
+            assert_eq!(asserted.challenge, "login");
+            assert_eq!(asserted.sign_count, 1);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_assertions() {
+        let rp = RelyingParty::from_origin(ORIGIN.to_string()).unwrap();
+        let mut authenticator = SoftwareAuthenticator::new(
+            EcdsaKeyPair::generate(&ECDSA_P256_SHA256_ASN1_SIGNING)
+                .map(Key::Es256)
+                .unwrap(),
+        );
+        let public_key = check_registration(&rp, &authenticator.create(&rp.id, "register"))
+            .unwrap()
+            .public_key;
+
+        let other_rp = authenticator.get("evil.example.com", "login", 0x05);
+        assert!(check_assertion(&rp, &other_rp, &public_key, false).is_err());
+
+        let unverified = authenticator.get(&rp.id, "login", 0x01);
+        assert!(check_assertion(&rp, &unverified, &public_key, false).is_ok());
+        assert!(check_assertion(&rp, &unverified, &public_key, true).is_err());
+
+        let mut tampered = authenticator.get(&rp.id, "login", 0x05);
+        tampered.response.client_data_json =
+            BASE64_URL.encode(SoftwareAuthenticator::client_data("webauthn.get", "other"));
+        assert!(check_assertion(&rp, &tampered, &public_key, false).is_err());
+
+        let registration = authenticator.create(&rp.id, "register");
+        let wrong_ceremony = AssertionCredential {
+            id: registration.id,
+            response: AssertionResponse {
+                client_data_json: registration.response.client_data_json,
+                ..authenticator.get(&rp.id, "login", 0x05).response
+            },
+        };
+        assert!(check_assertion(&rp, &wrong_ceremony, &public_key, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn only_own_passkeys_listed_and_deleted() {
+        let Some(url) = test_database_url().await else {
+            return;
+        };
+        let mut conn = AsyncPgConnection::establish(&url).await.unwrap();
+        let (alice, bob) = (UserId::new(), UserId::new());
+        let (alice_id, bob_id) = (alice.0, bob.0);
+        conn.batch_execute(&format!(
+            r#"INSERT INTO "user"(id, name, password_hash)
+                VALUES ('{alice_id}', 'alice', ''), ('{bob_id}', 'bob', '');
+            INSERT INTO webauthn_credential(id, "user", public_key, sign_count, name, created)
+                VALUES ('\x01', '{alice_id}', '', 0, 'laptop', now()),
+                    ('\x02', '{bob_id}', '', 0, 'phone', now());"#
+        ))
+        .await
+        .unwrap();
+        let PasskeysResponse::Ok { passkeys } = try_list(&mut conn, alice).await.unwrap() else {
+            panic!("expected passkeys");
+        };
+        assert_eq!(passkeys.len(), 1);
+        assert_eq!(passkeys[0].name, "laptop");
+        let bobs = DeletePasskey {
+            id: BASE64_URL.encode([2]),
+        };
+        assert!(matches!(
+            try_delete(&mut conn, alice, &bobs).await.unwrap(),
+            DeletePasskeyResponse::NotFound
+        ));
+        assert!(matches!(
+            try_delete(&mut conn, bob, &bobs).await.unwrap(),
+            DeletePasskeyResponse::Ok
+        ));
+        let PasskeysResponse::Ok { passkeys } = try_list(&mut conn, bob).await.unwrap() else {
+            panic!("expected passkeys");
+        };
+        assert!(passkeys.is_empty());
+    }
+}
@@ -1 +1,31 @@
 pub mod schema;
+
+/// The URL of a fresh schema with every migration applied, for tests that need a database. `None`,
+/// so the test is skipped, unless `ASPEN_TEST_DATABASE_URL` names a database to make it in.
+#[cfg(test)]
+pub async fn test_database_url() -> Option<String> {
+    use diesel_async::{AsyncConnection, AsyncPgConnection, SimpleAsyncConnection};
+
+    let url = std::env::var("ASPEN_TEST_DATABASE_URL").ok()?;
+    let schema = format!("aspen_test_{}", uuid::Uuid::now_v7().simple());
+    let mut conn = AsyncPgConnection::establish(&url)
+        .await
+        .expect("test database unreachable");
+    conn.batch_execute(&format!(
+        "CREATE SCHEMA {schema}; SET search_path TO {schema};"
+    ))
+    .await
+    .unwrap();
+    let mut migrations = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    migrations.sort();
+    for migration in migrations {
+        let up = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(&up).await.unwrap();
+    }
+    let separator = if url.contains('?') { '&' } else { '?' };
+    Some(format!("{url}{separator}options=-csearch_path%3D{schema}"))
+}
@@ -8,6 +8,26 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    audit_log (id) {
+        id -> Uuid,
+        time -> Timestamp,
+        kind -> Text,
+        user -> Nullable<Uuid>,
+        ip -> Nullable<Text>,
+        detail -> Text,
+    }
+}
+
+diesel::table! {
+    auth_throttle (key) {
+        key -> Text,
+        failures -> Int4,
+        last_failure -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     category (id) {
         id -> Uuid,
@@ -25,6 +45,25 @@ diesel::table! {
         name -> Text,
         ty -> Int4,
         sort_index -> Int4,
+        everyone_allow -> Int4,
+        everyone_deny -> Int4,
+    }
+}
+
+diesel::table! {
+    channel_role_overwrite (channel, role) {
+        channel -> Uuid,
+        role -> Text,
+        allow -> Int4,
+        deny -> Int4,
+    }
+}
+
+diesel::table! {
+    channel_read (user, channel) {
+        user -> Uuid,
+        channel -> Uuid,
+        last_read -> Timestamp,
     }
 }
 
@@ -33,6 +72,7 @@ diesel::table! {
         id -> Uuid,
         name -> Text,
         icon -> Nullable<Uuid>,
+        owner -> Nullable<Uuid>,
     }
 }
 
@@ -43,11 +83,58 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    external_identity (username) {
+        username -> Text,
+        user -> Uuid,
+    }
+}
+
+diesel::table! {
+    federated_identity (server, remote_user) {
+        server -> Text,
+        remote_user -> Uuid,
+        user -> Uuid,
+    }
+}
+
 diesel::table! {
     icon (id) {
         id -> Uuid,
         data -> Bytea,
         icon_mime_type -> Text,
+        owner -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    invite (code) {
+        code -> Text,
+        created_by -> Uuid,
+        uses_left -> Int4,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_challenge (token) {
+        token -> Text,
+        user -> Uuid,
+        expires -> Timestamp,
+        attempts -> Int4,
+    }
+}
+
+diesel::table! {
+    login_session (family) {
+        family -> Uuid,
+        user -> Uuid,
+        client_name -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        ip -> Nullable<Text>,
+        created -> Timestamp,
+        last_used -> Timestamp,
     }
 }
 
@@ -61,6 +148,24 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    oidc_identity (issuer, subject) {
+        issuer -> Text,
+        subject -> Text,
+        user -> Uuid,
+    }
+}
+
+diesel::table! {
+    oidc_login (state) {
+        state -> Text,
+        nonce -> Text,
+        code_verifier -> Text,
+        expires -> Timestamp,
+        binding -> Text,
+    }
+}
+
 diesel::table! {
     other_server_auth_token (token) {
         token -> Text,
@@ -70,6 +175,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    password_reset (token) {
+        token -> Text,
+        user -> Uuid,
+        created -> Timestamp,
+        expires -> Timestamp,
+    }
+}
+
 diesel::table! {
     react (emoji, author, message) {
         emoji -> Text,
@@ -78,11 +192,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_code (code_hash) {
+        code_hash -> Text,
+        user -> Uuid,
+    }
+}
+
 diesel::table! {
     refresh_token (token) {
         token -> Text,
         expires -> Timestamp,
         user -> Uuid,
+        family -> Uuid,
+        rotated -> Bool,
     }
 }
 
@@ -94,39 +217,113 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    totp (user) {
+        user -> Uuid,
+        secret -> Bytea,
+        confirmed -> Bool,
+        last_step -> Nullable<Int8>,
+    }
+}
+
 diesel::table! {
     user (id) {
         id -> Uuid,
         name -> Text,
         password_hash -> Text,
         icon -> Nullable<Uuid>,
+        email -> Nullable<Text>,
+        email_verified -> Bool,
+        approved -> Bool,
+    }
+}
+
+diesel::table! {
+    user_role (user, role) {
+        user -> Uuid,
+        role -> Text,
+        external -> Bool,
+    }
+}
+
+diesel::table! {
+    webauthn_challenge (challenge) {
+        challenge -> Text,
+        user -> Nullable<Uuid>,
+        registration -> Bool,
+        expires -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webauthn_credential (id) {
+        id -> Bytea,
+        user -> Uuid,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        name -> Text,
+        created -> Timestamp,
     }
 }
 
 diesel::joinable!(category -> community (community));
 diesel::joinable!(channel -> category (parent_category));
 diesel::joinable!(channel -> community (community));
+diesel::joinable!(channel_read -> channel (channel));
+diesel::joinable!(channel_role_overwrite -> channel (channel));
+diesel::joinable!(channel_read -> user (user));
+diesel::joinable!(community -> user (owner));
 diesel::joinable!(community_user -> community (community));
 diesel::joinable!(community_user -> user (user));
+diesel::joinable!(external_identity -> user (user));
+diesel::joinable!(federated_identity -> user (user));
+diesel::joinable!(icon -> user (owner));
+diesel::joinable!(invite -> user (created_by));
+diesel::joinable!(login_challenge -> user (user));
+diesel::joinable!(login_session -> user (user));
 diesel::joinable!(message -> channel (channel));
 diesel::joinable!(message -> user (author));
+diesel::joinable!(oidc_identity -> user (user));
 diesel::joinable!(other_server_auth_token -> user (user));
+diesel::joinable!(password_reset -> user (user));
 diesel::joinable!(react -> message (message));
 diesel::joinable!(react -> user (author));
+diesel::joinable!(recovery_code -> user (user));
 diesel::joinable!(refresh_token -> user (user));
 diesel::joinable!(session -> refresh_token (refresh_token));
+diesel::joinable!(totp -> user (user));
+diesel::joinable!(user_role -> user (user));
+diesel::joinable!(webauthn_challenge -> user (user));
+diesel::joinable!(webauthn_credential -> user (user));
 
 diesel::allow_tables_to_appear_in_same_query!(
     attachment,
+    audit_log,
+    auth_throttle,
     category,
     channel,
+    channel_read,
+    channel_role_overwrite,
     community,
     community_user,
+    external_identity,
+    federated_identity,
     icon,
+    invite,
+    login_challenge,
+    login_session,
     message,
+    oidc_identity,
+    oidc_login,
     other_server_auth_token,
+    password_reset,
     react,
+    recovery_code,
     refresh_token,
     session,
+    totp,
     user,
+    user_role,
+    webauthn_challenge,
+    webauthn_credential,
 );
@@ -0,0 +1,180 @@
+//! Outgoing mail, such as password reset links. Which [`Mailer`] is used is picked by
+//! [`MailerConfig`].
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use lettre::address::AddressError;
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use tracing::error;
+
+use crate::app;
+use crate::aspen_config::MailerConfig;
+
+/// A plain text mail to a single recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), app::Error>>;
+}
+
+/// Builds the mailer `config` asks for.
+pub fn from_config(config: &MailerConfig) -> Result<Arc<dyn Mailer>, app::Error> {
+    Ok(match config {
+        MailerConfig::Smtp { url, from } => Arc::new(SmtpMailer {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+            from: from.parse()?,
+        }),
+        MailerConfig::File { directory } => Arc::new(FileMailer {
+            directory: directory.clone(),
+        }),
+    })
+}
+
+/// The form an email address is stored and looked up in, or an error if it isn't one.
+///
+/// The domain is case insensitive and hardly any mail server treats the local part differently, so
+/// the whole address is lowercased to keep one person from having several accounts by it.
+pub fn normalize_address(address: &str) -> Result<String, AddressError> {
+    let address: Address = address.trim().parse()?;
+    Ok(address.to_string().to_lowercase())
+}
+
+/// Sends `mail` without waiting for it, logging if it fails. Sending takes a while, and waiting
+/// would let requests that sent mail be told apart from those that didn't.
+pub fn send_in_background(mailer: Arc<dyn Mailer>, mail: Mail, what: &'static str) {
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&mail).await {
+            error!("error sending {what} mail {e}");
+        }
+    });
+}
+
+/// What a mail asks its reader to do with `token`: open `path` under `public_origin` with it, or
+/// without a `public_origin` paste it into the client.
+pub fn token_action(public_origin: Option<&str>, path: &str, token: &str) -> String {
+    match public_origin {
+        Some(origin) => rust_i18n::t!(
+            "mailOpenLink",
+            link = format!(
+                "{}/{path}?token={}",
+                origin.trim_end_matches('/'),
+                utf8_percent_encode(token, NON_ALPHANUMERIC)
+            )
+        )
+        .into(),
+        None => rust_i18n::t!("mailEnterToken", token = token).into(),
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), app::Error>> {
+        Box::pin(async move {
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(mail.to.parse()?)
+                .subject(&mail.subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(mail.body.clone())?;
+            self.transport.send(message).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Writes mail to files named after when they were sent, so they can be read without a mail
+/// server.
+pub struct FileMailer {
+    directory: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), app::Error>> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.directory).await?;
+            let path = self.directory.join(format!("{}.eml", uuid::Uuid::now_v7()));
+            let contents = format!(
+                "Date: {}\nTo: {}\nSubject: {}\n\n{}\n",
+                Utc::now().to_rfc2822(),
+                mail.to,
+                mail.subject,
+                mail.body
+            );
+            tokio::fs::write(path, contents).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Keeps mail for tests to look at.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryMailer {
+    pub sent: std::sync::Mutex<Vec<Mail>>,
+}
+
+#[cfg(test)]
+impl Mailer for MemoryMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), app::Error>> {
+        self.sent.lock().unwrap().push(mail.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileMailer, Mail, Mailer, MemoryMailer, normalize_address};
+
+    fn mail() -> Mail {
+        Mail {
+            to: "alice@example.com".into(),
+            subject: "Hello".into(),
+            body: "Hi Alice".into(),
+        }
+    }
+
+    #[test]
+    fn addresses_normalized() {
+        assert_eq!(
+            normalize_address(" Alice@Example.COM ").unwrap(),
+            "alice@example.com"
+        );
+        assert!(normalize_address("alice").is_err());
+        assert!(normalize_address("alice@").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_mailer_keeps_mail() {
+        let mailer = MemoryMailer::default();
+        mailer.send(&mail()).await.unwrap();
+        assert_eq!(*mailer.sent.lock().unwrap(), [mail()]);
+    }
+
+    #[tokio::test]
+    async fn file_mailer_writes_mail() {
+        let directory = std::env::temp_dir().join(format!("aspen-mail-{}", uuid::Uuid::now_v7()));
+        let mailer = FileMailer {
+            directory: directory.clone(),
+        };
+        mailer.send(&mail()).await.unwrap();
+        let mut files = std::fs::read_dir(&directory).unwrap();
+        let contents = std::fs::read_to_string(files.next().unwrap().unwrap().path()).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(contents.contains("To: alice@example.com\nSubject: Hello\n\nHi Alice\n"));
+    }
+}
@@ -13,6 +13,7 @@ use std::{
 };
 
 use anyhow::{Context, Result, bail};
+use axum::extract::ConnectInfo;
 use clap::Parser;
 use futures_util::{StreamExt, stream::FuturesUnordered};
 use hyper::{Request, body::Incoming};
@@ -37,7 +38,9 @@ mod api;
 mod app;
 mod aspen_config;
 mod database;
+mod mailer;
 mod nats_connection_manager;
+mod server_key;
 
 #[derive(Parser, Debug)]
 #[clap(name = "server")]
@@ -78,6 +81,14 @@ thread_local! {
 
 i18n!("locales");
 
+/// Where the server keeps the files it makes itself, such as its self-signed certificate.
+pub fn data_dir() -> PathBuf {
+    directories_next::ProjectDirs::from("org", "aspen-chat", "aspen-server")
+        .unwrap()
+        .data_local_dir()
+        .to_path_buf()
+}
+
 fn main() {
     if let Err(e) = aspen_config::load_config() {
         eprintln!("failed to load config from aspen.toml or environment. {e}");
@@ -146,9 +157,7 @@ async fn run(options: Opt) -> Result<()> {
 
         (cert_chain, key)
     } else {
-        let dirs =
-            directories_next::ProjectDirs::from("org", "aspen-chat", "aspen-server").unwrap();
-        let path = dirs.data_local_dir();
+        let path = &data_dir();
         let cert_path = path.join("cert.der");
         let key_path = path.join("key.der");
         let (cert, key) = match fs::read(&cert_path).and_then(|x| Ok((x, fs::read(&key_path)?))) {
@@ -211,10 +220,10 @@ async fn run(options: Opt) -> Result<()> {
 
     loop {
         let mut listeners = FuturesUnordered::from_iter(listeners.iter().map(|l| l.accept()));
-        let socket = tokio::select! {
+        let (socket, remote_addr) = tokio::select! {
             maybe_socket = listeners.next() => {
                 match maybe_socket {
-                    Some(Ok((socket, _remote_addr))) => socket,
+                    Some(Ok(accepted)) => accepted,
                     Some(Err(e)) => {
                         error!("TCP I/O error {e}");
                         continue;
@@ -231,9 +240,11 @@ async fn run(options: Opt) -> Result<()> {
         let tls_acceptor = tls_acceptor.clone();
         let service = app.clone();
         tokio::spawn(async move {
-            let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-                service.clone().call(request)
-            });
+            let hyper_service =
+                hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(remote_addr));
+                    service.clone().call(request)
+                });
 
             /// Using a macro to do compile time duck typing over TlsStream and TcpStream.
             macro_rules! handle_stream {
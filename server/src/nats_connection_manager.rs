@@ -114,6 +114,10 @@ impl NatsConnectionManager {
         self.client.send_request(subject, request).await
     }
 
+    pub fn jetstream(&self) -> async_nats::jetstream::Context {
+        async_nats::jetstream::new(self.client.clone())
+    }
+
     pub fn new_inbox(&self) -> String {
         self.client.new_inbox()
     }
@@ -0,0 +1,452 @@
+//! The Ed25519 key this server signs its requests to other servers with, and the keys of other
+//! servers that their requests are checked against.
+//!
+//! A signed request names the domain of the server sending it in [`SERVER_HEADER`] and when it
+//! was sent in [`DATE_HEADER`]. [`SIGNATURE_HEADER`] signs those along with the method, path,
+//! receiving domain and a hash of the body, see [`signed_message`], so a request can't be replayed
+//! to another server or much later. Servers publish their public key as a [`ServerInfo`] at
+//! `/.well-known/aspen/server`.
+//!
+//! Anyone can claim to be any server, so keys are fetched with
+//! [`peer_client`](crate::app::federation::peer_client), and at most once per
+//! [`PEER_KEY_REFETCH_INTERVAL`] for each domain whether or not that found one.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use axum::http::{HeaderMap, HeaderValue};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::app;
+use crate::app::federation::{domain, server_origin};
+
+pub const SERVER_HEADER: &str = "aspen-server";
+/// Unix timestamp.
+pub const DATE_HEADER: &str = "aspen-date";
+pub const SIGNATURE_HEADER: &str = "aspen-signature";
+/// How far the time a request was signed at may be from ours.
+const MAX_CLOCK_SKEW: Duration = Duration::minutes(5);
+/// How long the key of another server is used before fetching it again.
+const PEER_KEY_LIFETIME: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// How long after fetching the key of another server, or failing to, a request it doesn't verify
+/// may fetch it again.
+const PEER_KEY_REFETCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+/// How many servers keys are kept for before old ones are forgotten.
+const MAX_PEER_KEYS: usize = 10_000;
+
+/// Served at `/.well-known/aspen/server`.
+#[derive(Debug, Deserialize, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    /// Ed25519, in unpadded URL safe base64.
+    pub public_key: String,
+}
+
+pub struct ServerKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ServerKey {
+    /// Reads the key at `path`, generating one there if there isn't any yet. Only the user the
+    /// server runs as can read a generated key.
+    pub fn load_or_generate(path: &Path) -> Result<Self, app::Error> {
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("generating server signing key");
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+                if let Some(directory) = path.parent() {
+                    std::fs::create_dir_all(directory)?;
+                }
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                match options.open(path) {
+                    Ok(mut file) => {
+                        file.write_all(pkcs8.as_ref())?;
+                        pkcs8.as_ref().to_vec()
+                    }
+                    // Another server process generated one first.
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => std::fs::read(path)?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            key_pair: Ed25519KeyPair::from_pkcs8(&pkcs8)?,
+        })
+    }
+
+    /// A key that isn't kept anywhere.
+    #[cfg(test)]
+    pub fn generate() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Self {
+            key_pair: Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap(),
+        }
+    }
+
+    pub fn info(&self) -> ServerInfo {
+        ServerInfo {
+            public_key: BASE64_URL_SAFE_NO_PAD.encode(self.key_pair.public_key().as_ref()),
+        }
+    }
+
+    /// Signs `request` as sent by the server at domain `from`.
+    pub fn sign_request(&self, request: &mut reqwest::Request, from: &str, now: DateTime<Utc>) {
+        let url = request.url();
+        let to = domain(url).unwrap_or_default();
+        let path = path_and_query(url);
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default();
+        let timestamp = now.timestamp();
+        let message = signed_message(request.method().as_str(), &path, from, &to, timestamp, body);
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(self.key_pair.sign(&message).as_ref());
+        let headers = request.headers_mut();
+        for (name, value) in [
+            (SERVER_HEADER, from.to_string()),
+            (DATE_HEADER, timestamp.to_string()),
+            (SIGNATURE_HEADER, signature),
+        ] {
+            headers.insert(
+                name,
+                HeaderValue::from_str(&value).expect("domains and base64 are valid header values"),
+            );
+        }
+    }
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    }
+}
+
+/// What the signature of a request covers.
+fn signed_message(
+    method: &str,
+    path_and_query: &str,
+    from: &str,
+    to: &str,
+    timestamp: i64,
+    body: &[u8],
+) -> Vec<u8> {
+    let body_hash = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(body));
+    format!(
+        "aspen server request\n{method}\n{path_and_query}\n{from}\n{to}\n{timestamp}\n{body_hash}"
+    )
+    .into_bytes()
+}
+
+/// The public key of another server, or that it couldn't be had, and when it was fetched.
+struct PeerKey {
+    key: Option<Vec<u8>>,
+    fetched: Instant,
+}
+
+/// The public keys of other servers, fetched from them as needed.
+pub struct PeerKeys {
+    http: reqwest::Client,
+    /// Where the server at a domain is reached, only not [`server_origin`] in tests.
+    origin: fn(&str) -> Option<Url>,
+    cache: Mutex<HashMap<String, PeerKey>>,
+}
+
+impl PeerKeys {
+    /// `http` should be a [`peer_client`](crate::app::federation::peer_client).
+    pub fn new(http: reqwest::Client) -> Self {
+        Self {
+            http,
+            origin: server_origin,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Reaches other servers over plain HTTP at any address, for tests with mock servers.
+    #[cfg(test)]
+    pub fn plain_http() -> Self {
+        Self {
+            origin: |domain| Url::parse(&format!("http://{domain}")).ok(),
+            ..Self::new(reqwest::Client::new())
+        }
+    }
+
+    /// The public key of the server at `domain` if it has one, and how long ago that was found
+    /// out.
+    fn cached(&self, domain: &str) -> Option<(Option<Vec<u8>>, std::time::Duration)> {
+        let cache = self.cache.lock().unwrap();
+        let peer = cache.get(domain)?;
+        Some((peer.key.clone(), peer.fetched.elapsed()))
+    }
+
+    /// Asks the server at `domain` for its public key, remembering the answer even if there is
+    /// none.
+    async fn fetch(&self, domain: &str) -> Result<Option<Vec<u8>>, app::Error> {
+        let key = self.request_key(domain).await;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_PEER_KEYS {
+            cache.retain(|_, peer| peer.fetched.elapsed() < PEER_KEY_LIFETIME);
+        }
+        if cache.len() >= MAX_PEER_KEYS {
+            cache.clear();
+        }
+        cache.insert(
+            domain.to_string(),
+            PeerKey {
+                key: key.as_ref().ok().cloned().flatten(),
+                fetched: Instant::now(),
+            },
+        );
+        key
+    }
+
+    async fn request_key(&self, domain: &str) -> Result<Option<Vec<u8>>, app::Error> {
+        let Some(origin) = (self.origin)(domain) else {
+            return Ok(None);
+        };
+        let url = origin
+            .join(".well-known/aspen/server")
+            .expect("a relative path joins any origin");
+        let info: ServerInfo = self
+            .http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(BASE64_URL_SAFE_NO_PAD.decode(&info.public_key).ok())
+    }
+}
+
+/// The domain of the server that signed a request to the server at domain `to`, if the signature
+/// in `headers` is right and recent.
+pub async fn check_signature(
+    peers: &PeerKeys,
+    method: &str,
+    path_and_query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    to: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, app::Error> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(from), Some(timestamp), Some(signature)) = (
+        header(SERVER_HEADER),
+        header(DATE_HEADER).and_then(|date| date.parse::<i64>().ok()),
+        header(SIGNATURE_HEADER).and_then(|s| BASE64_URL_SAFE_NO_PAD.decode(s).ok()),
+    ) else {
+        return Ok(None);
+    };
+    if (now.timestamp() - timestamp).abs() > MAX_CLOCK_SKEW.num_seconds() {
+        return Ok(None);
+    }
+    let message = signed_message(method, path_and_query, from, to, timestamp, body);
+    let verifies = |key: &[u8]| {
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(&message, &signature)
+            .is_ok()
+    };
+    let cached = peers.cached(from);
+    if let Some((Some(key), age)) = &cached
+        && *age < PEER_KEY_LIFETIME
+        && verifies(key)
+    {
+        return Ok(Some(from.to_string()));
+    }
+    // Not fetched yet, fetched long ago, or the peer has a new key. Requests with bad signatures
+    // can't make this server ask any more often than that.
+    if cached.is_some_and(|(_, age)| age < PEER_KEY_REFETCH_INTERVAL) {
+        return Ok(None);
+    }
+    let key = peers.fetch(from).await?;
+    Ok(key.filter(|key| verifies(key)).map(|_| from.to_string()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use chrono::{Duration, Utc};
+
+    use super::{PeerKeys, ServerInfo, ServerKey, check_signature, path_and_query};
+
+    /// Serves the well-known document of `key`, returning the domain it's reached at.
+    pub(crate) async fn mock_peer(key: Arc<ServerKey>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let domain = listener.local_addr().unwrap().to_string();
+        let router = Router::new().route(
+            "/.well-known/aspen/server",
+            get(move || async move { Json::<ServerInfo>(key.info()) }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        domain
+    }
+
+    fn peer_keys() -> PeerKeys {
+        PeerKeys::plain_http()
+    }
+
+    /// Signs a request from `from` to `aspen.example.org`.
+    fn signed(key: &ServerKey, from: &str, body: &str) -> reqwest::Request {
+        let mut request = reqwest::Client::new()
+            .post("https://aspen.example.org/other_server_auth/verify?x=1")
+            .body(body.to_string())
+            .build()
+            .unwrap();
+        key.sign_request(&mut request, from, Utc::now());
+        request
+    }
+
+    async fn check(
+        peers: &PeerKeys,
+        request: &reqwest::Request,
+        body: &str,
+        to: &str,
+    ) -> Option<String> {
+        check_signature(
+            peers,
+            request.method().as_str(),
+            &path_and_query(request.url()),
+            request.headers(),
+            body.as_bytes(),
+            to,
+            Utc::now(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn key_kept_across_restarts() {
+        let path = std::env::temp_dir()
+            .join(format!("aspen-key-{}", uuid::Uuid::now_v7()))
+            .join("server_key.der");
+        let generated = ServerKey::load_or_generate(&path).unwrap();
+        let loaded = ServerKey::load_or_generate(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(generated.info().public_key, loaded.info().public_key);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_verify() {
+        let key = Arc::new(ServerKey::generate());
+        let from = mock_peer(key.clone()).await;
+        let peers = peer_keys();
+        let request = signed(&key, &from, "{}");
+        assert_eq!(
+            check(&peers, &request, "{}", "aspen.example.org").await,
+            Some(from.clone())
+        );
+        assert_eq!(
+            check(&peers, &request, "{\"a\":1}", "aspen.example.org").await,
+            None
+        );
+        assert_eq!(
+            check(&peers, &request, "{}", "evil.example.org").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_impostors_and_stale_requests() {
+        let key = Arc::new(ServerKey::generate());
+        let impostor = ServerKey::generate();
+        let from = mock_peer(key.clone()).await;
+        let peers = peer_keys();
+        let forged = signed(&impostor, &from, "{}");
+        assert_eq!(
+            check(&peers, &forged, "{}", "aspen.example.org").await,
+            None
+        );
+        let mut stale = reqwest::Client::new()
+            .post("https://aspen.example.org/other_server_auth/verify?x=1")
+            .body("{}")
+            .build()
+            .unwrap();
+        key.sign_request(&mut stale, &from, Utc::now() - Duration::minutes(10));
+        assert_eq!(check(&peers, &stale, "{}", "aspen.example.org").await, None);
+    }
+
+    #[tokio::test]
+    async fn keys_not_refetched_for_every_bad_signature() {
+        let key = Arc::new(ServerKey::generate());
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let from = listener.local_addr().unwrap().to_string();
+        let router = Router::new().route(
+            "/.well-known/aspen/server",
+            get({
+                let key = key.clone();
+                let fetches = fetches.clone();
+                move || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    Json::<ServerInfo>(key.info())
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let peers = peer_keys();
+        let impostor = ServerKey::generate();
+        for _ in 0..3 {
+            let forged = signed(&impostor, &from, "{}");
+            assert_eq!(
+                check(&peers, &forged, "{}", "aspen.example.org").await,
+                None
+            );
+        }
+        let request = signed(&key, &from, "{}");
+        assert_eq!(
+            check(&peers, &request, "{}", "aspen.example.org").await,
+            Some(from)
+        );
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_remembered() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let from = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let peers = peer_keys();
+        let request = signed(&ServerKey::generate(), &from, "{}");
+        let path = path_and_query(request.url());
+        let check = || {
+            check_signature(
+                &peers,
+                request.method().as_str(),
+                &path,
+                request.headers(),
+                b"{}",
+                "aspen.example.org",
+                Utc::now(),
+            )
+        };
+        assert!(check().await.is_err());
+        assert_eq!(check().await.unwrap(), None);
+    }
+}